@@ -0,0 +1,65 @@
+//! Compares the linear palette search with the k-d tree and the lookup table, run with `cargo bench`.
+//!
+//! Maps a color wheel to palettes of increasing size, without dithering and with Floyd-Steinberg, and
+//! prints the time of the best of a few runs for every search. The times include building the tree
+//! and the table.
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use dithers::distance::DistanceMetric;
+use dithers::dither::{DitherMethod, DitherOptions, dither_with};
+use dithers::kdtree::KdTree;
+use dithers::lut::ColorLut;
+use dithers::palette::{Color, ColorPalette, Quantizer, TieBreak, TieBroken};
+use dithers::patterns::{Pattern, Size, generate};
+
+const SIZE: Size = Size { width: 1024, height: 1024 };
+const RUNS: u32 = 5;
+
+fn best_of<Q: Quantizer>(buffer: &[u8], method: DitherMethod, quantizer: impl Fn() -> Q) -> Duration {
+  (0..RUNS)
+    .map(|_| {
+      let mut buffer = buffer.to_vec();
+      let start = Instant::now();
+      let quantizer = quantizer();
+      dither_with(&mut buffer, method, &quantizer, SIZE.width, SIZE.height, &DitherOptions::default()).unwrap();
+      black_box(buffer);
+      start.elapsed()
+    })
+    .min()
+    .unwrap_or_default()
+}
+
+fn main() {
+  let image = generate(Pattern::Colorwheel, SIZE);
+  let palettes: [(&str, &[Color]); 5] = [
+    ("color8", ColorPalette::COLOR8.colors()),
+    ("color16", ColorPalette::COLOR16.colors()),
+    ("nes", ColorPalette::Nes.colors()),
+    ("web-safe", ColorPalette::WebSafe.colors()),
+    ("vga256", ColorPalette::Vga256.colors()),
+  ];
+  let timing = |duration: Duration, linear: Duration| format!("{:.1}ms {:.1}x", duration.as_secs_f64() * 1000.0, linear.as_secs_f64() / duration.as_secs_f64());
+  for method in [DitherMethod::None, DitherMethod::FloydSteinberg] {
+    println!("{method:?}, {}x{}", SIZE.width, SIZE.height);
+    println!("{:<10} {:>7} {:>10} {:>15} {:>15}", "palette", "colors", "linear", "k-d tree", "lut 32");
+    for (name, colors) in palettes {
+      let linear = best_of(&image.buffer, method, || TieBroken {
+        colors,
+        metric: DistanceMetric::Rgb,
+        tie_break: TieBreak::Darker,
+      });
+      let tree = best_of(&image.buffer, method, || KdTree::new(colors, TieBreak::Darker));
+      let lut = best_of(&image.buffer, method, || ColorLut::new(colors, 32, TieBreak::Darker));
+      println!(
+        "{name:<10} {:>7} {:>8.1}ms {:>15} {:>15}",
+        colors.len(),
+        linear.as_secs_f64() * 1000.0,
+        timing(tree, linear),
+        timing(lut, linear)
+      );
+    }
+    println!();
+  }
+}
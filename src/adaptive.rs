@@ -0,0 +1,588 @@
+//! Palettes generated from the colors of the image.
+//!
+//! Instead of a fixed palette, `--quantizer` picks `--colors` colors that represent the image best and
+//! dithers to those. The palette is chosen after resizing and the color adjustments, from the pixels
+//! that are actually dithered, so transparent pixels do not take up entries.
+
+use std::collections::HashMap;
+
+use crate::palette::Color;
+use crate::pipeline::Image;
+
+/// Number of colors of a generated palette when none is given.
+pub const DEFAULT_COLORS: u16 = 16;
+
+/// Algorithms generating a palette from an image.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
+pub enum PaletteQuantizer {
+  /// Octree color reduction, fast and memory-bounded for very large images
+  #[default]
+  Octree,
+  /// Octree palette refined by k-means clustering, slower but closer to the colors of the image
+  Kmeans,
+  /// Xiaolin Wu's variance minimization, splits the color cube where it reduces the error most
+  Wu,
+  /// Anthony Dekker's NeuQuant neural network, the common choice for 256-color GIF output
+  Neuquant,
+}
+
+serde_value_enum!(PaletteQuantizer);
+
+/// Returns a palette of at most `colors` colors for the opaque pixels of an image.
+///
+/// Images with fewer distinct colors get exactly those, an image without opaque pixels gets black.
+#[must_use]
+pub fn palette(image: &Image, quantizer: PaletteQuantizer, colors: usize) -> Vec<Color> {
+  let pixels = || {
+    image
+      .buffer
+      .chunks_exact(3)
+      .enumerate()
+      .filter(|&(i, _)| image.alpha.as_ref().is_none_or(|alpha| alpha[i] != 0))
+      .map(|(_, pixel)| Color::from(pixel))
+  };
+  let palette = match quantizer {
+    PaletteQuantizer::Octree => octree(pixels(), colors.max(1)),
+    PaletteQuantizer::Kmeans => kmeans(&histogram(pixels()), octree(pixels(), colors.max(1)), colors.max(1)),
+    PaletteQuantizer::Wu => wu(pixels(), colors.max(1)),
+    PaletteQuantizer::Neuquant => neuquant(pixels(), colors.max(1)),
+  };
+  if palette.is_empty() {
+    return vec![Color { r: 0, g: 0, b: 0 }];
+  }
+  palette
+}
+
+/// Depth of the octree, one level per bit of the channels.
+const DEPTH: usize = 8;
+
+/// Leaves per palette color the tree may grow to while pixels are added, bounding its memory.
+const LEAVES_PER_COLOR: usize = 64;
+
+/// A node of the octree, with the sum and number of the pixels below it.
+#[derive(Clone, Default)]
+struct Node {
+  // indices into the arena, 0 for none since the root is never a child
+  children: [usize; 8],
+  sum: [u64; 3],
+  count: u64,
+  leaf: bool,
+}
+
+/// Octree quantization after Gervautz and Purgathofer.
+///
+/// Every pixel is sorted into the tree by the bits of its channels, from the most significant. When
+/// there are too many leaves, the deepest node with the fewest pixels is merged into a single leaf,
+/// so the least common fine distinctions go first. The leaves end up as the palette, each the mean
+/// of its pixels, the most common first. A merge can remove up to seven leaves at once, so the
+/// palette may have a few colors less than asked for.
+fn octree(pixels: impl Iterator<Item = Color>, colors: usize) -> Vec<Color> {
+  let mut nodes = vec![Node::default()];
+  let mut free: Vec<usize> = Vec::new();
+  // non-leaf nodes by level, the candidates for merging
+  let mut reducible: Vec<Vec<usize>> = vec![Vec::new(); DEPTH];
+  reducible[0].push(0);
+  let mut leaves = 0;
+  let budget = colors.saturating_mul(LEAVES_PER_COLOR);
+
+  for color in pixels {
+    let channels = [color.r, color.g, color.b];
+    let mut node = 0;
+    for level in 0..=DEPTH {
+      let current = &mut nodes[node];
+      current.count += 1;
+      for (sum, &value) in current.sum.iter_mut().zip(&channels) {
+        *sum += u64::from(value);
+      }
+      if current.leaf || level == DEPTH {
+        break;
+      }
+      let bit = DEPTH - 1 - level;
+      let index = channels.iter().fold(0, |index, &value| index << 1 | usize::from(value >> bit & 1));
+      if nodes[node].children[index] == 0 {
+        let child = Node {
+          leaf: level + 1 == DEPTH,
+          ..Node::default()
+        };
+        let id = match free.pop() {
+          Some(id) => {
+            nodes[id] = child;
+            id
+          }
+          None => {
+            nodes.push(child);
+            nodes.len() - 1
+          }
+        };
+        if level + 1 == DEPTH {
+          leaves += 1;
+        } else {
+          reducible[level + 1].push(id);
+        }
+        nodes[node].children[index] = id;
+      }
+      node = nodes[node].children[index];
+    }
+    while leaves > budget {
+      leaves -= reduce(&mut nodes, &mut reducible, &mut free);
+    }
+  }
+  while leaves > colors {
+    leaves -= reduce(&mut nodes, &mut reducible, &mut free);
+  }
+
+  let mut found: Vec<(u64, Color)> = Vec::with_capacity(leaves);
+  let mut stack = vec![0];
+  while let Some(node) = stack.pop() {
+    let node = &nodes[node];
+    if node.leaf {
+      let mean = |sum: u64| ((sum + node.count / 2) / node.count) as u8;
+      found.push((
+        node.count,
+        Color {
+          r: mean(node.sum[0]),
+          g: mean(node.sum[1]),
+          b: mean(node.sum[2]),
+        },
+      ));
+    } else {
+      stack.extend(node.children.iter().filter(|&&child| child != 0));
+    }
+  }
+  found.sort_unstable_by_key(|&(count, color)| (std::cmp::Reverse(count), color.r, color.g, color.b));
+  let mut palette: Vec<Color> = Vec::with_capacity(found.len());
+  for (_, color) in found {
+    if !palette.contains(&color) {
+      palette.push(color);
+    }
+  }
+  palette
+}
+
+/// Merges the children of the deepest non-leaf node with the fewest pixels into it.
+///
+/// Returns by how much the number of leaves went down.
+fn reduce(nodes: &mut [Node], reducible: &mut [Vec<usize>], free: &mut Vec<usize>) -> usize {
+  let Some(level) = reducible.iter().rposition(|candidates| !candidates.is_empty()) else {
+    return 0;
+  };
+  let candidates = &mut reducible[level];
+  let position = (0..candidates.len())
+    .min_by_key(|&i| (nodes[candidates[i]].count, candidates[i]))
+    .expect("the level has candidates");
+  let node = candidates.swap_remove(position);
+  // nodes on the deepest reducible level only have leaves as children
+  let children = std::mem::take(&mut nodes[node].children);
+  let merged = children.iter().filter(|&&child| child != 0).count();
+  free.extend(children.iter().filter(|&&child| child != 0));
+  nodes[node].leaf = true;
+  // a node whose only child was merged turns from one leaf into another
+  merged.saturating_sub(1)
+}
+
+/// Maximum number of k-means iterations, they usually settle after a few.
+const KMEANS_ITERATIONS: usize = 8;
+
+/// Returns the distinct colors with their number of pixels, in a stable order.
+fn histogram(pixels: impl Iterator<Item = Color>) -> Vec<(Color, u64)> {
+  let mut counts: HashMap<Color, u64> = HashMap::new();
+  for color in pixels {
+    *counts.entry(color).or_default() += 1;
+  }
+  let mut histogram: Vec<(Color, u64)> = counts.into_iter().collect();
+  histogram.sort_unstable_by_key(|&(color, _)| (color.r, color.g, color.b));
+  histogram
+}
+
+/// Refines a palette with Lloyd's k-means algorithm.
+///
+/// Palettes with fewer than `colors` entries first get the colors worst served by them, weighted by
+/// their number of pixels. Then every color of the histogram is assigned to its closest entry and
+/// every entry moves to the mean of its colors, until no entry moves any more. Entries without colors
+/// stay where they are. The most used entries come first.
+fn kmeans(histogram: &[(Color, u64)], mut palette: Vec<Color>, colors: usize) -> Vec<Color> {
+  while palette.len() < colors.min(histogram.len()) {
+    let worst = histogram
+      .iter()
+      .max_by_key(|&&(color, count)| palette.iter().map(|&entry| u64::from(distance(color, entry)) * count).min())
+      .map(|&(color, _)| color)
+      .expect("the histogram has more colors than the palette");
+    palette.push(worst);
+  }
+  let mut weights = vec![0; palette.len()];
+  for iteration in 0..KMEANS_ITERATIONS {
+    let mut sums = vec![[0u64; 3]; palette.len()];
+    weights.fill(0);
+    for &(color, count) in histogram {
+      let closest = (0..palette.len()).min_by_key(|&i| distance(color, palette[i])).expect("palettes are not empty");
+      for (sum, value) in sums[closest].iter_mut().zip([color.r, color.g, color.b]) {
+        *sum += u64::from(value) * count;
+      }
+      weights[closest] += count;
+    }
+    let mut moved = false;
+    for ((entry, sum), &weight) in palette.iter_mut().zip(&sums).zip(&weights) {
+      if weight == 0 {
+        continue;
+      }
+      let mean = |sum: u64| ((sum + weight / 2) / weight) as u8;
+      let centroid = Color {
+        r: mean(sum[0]),
+        g: mean(sum[1]),
+        b: mean(sum[2]),
+      };
+      moved |= centroid != *entry;
+      *entry = centroid;
+    }
+    log::trace!("k-means iteration {}", iteration + 1);
+    if !moved {
+      break;
+    }
+  }
+  let mut order: Vec<usize> = (0..palette.len()).collect();
+  order.sort_by_key(|&i| std::cmp::Reverse(weights[i]));
+  let mut refined: Vec<Color> = Vec::with_capacity(palette.len());
+  for color in order.into_iter().map(|i| palette[i]) {
+    if !refined.contains(&color) {
+      refined.push(color);
+    }
+  }
+  refined
+}
+
+/// Cells of the Wu histogram per channel, 5 bits per channel plus a row of zeros.
+const SIDE: usize = 33;
+
+/// Index of a cell of the Wu histogram.
+fn cell(coordinates: [usize; 3]) -> usize {
+  (coordinates[0] * SIDE + coordinates[1]) * SIDE + coordinates[2]
+}
+
+/// Cumulative moments of the Wu histogram: the number of pixels, the sums of their channels and of
+/// their squared channels in the box from the origin to each cell.
+struct Moments {
+  weight: Vec<i64>,
+  sums: [Vec<i64>; 3],
+  squares: Vec<f64>,
+}
+
+/// A box of histogram cells, from after `low` up to and including `high` on each channel.
+#[derive(Clone, Copy)]
+struct Cube {
+  low: [usize; 3],
+  high: [usize; 3],
+}
+
+impl Cube {
+  /// Returns the number of histogram cells in the box.
+  fn cells(&self) -> usize {
+    (0..3).map(|axis| self.high[axis] - self.low[axis]).product()
+  }
+}
+
+/// Sum of the moment over the cross section of the box at `position` along `axis`, including everything below it.
+fn face<T: Copy + std::ops::Add<Output = T> + std::ops::Sub<Output = T>>(cube: &Cube, axis: usize, position: usize, moment: &[T]) -> T {
+  let (first, second) = ((axis + 1) % 3, (axis + 2) % 3);
+  let at = |a: usize, b: usize| {
+    let mut coordinates = [0; 3];
+    coordinates[axis] = position;
+    coordinates[first] = a;
+    coordinates[second] = b;
+    moment[cell(coordinates)]
+  };
+  let (low1, high1, low2, high2) = (cube.low[first], cube.high[first], cube.low[second], cube.high[second]);
+  at(high1, high2) - at(high1, low2) - at(low1, high2) + at(low1, low2)
+}
+
+/// Sum of the moment over the box.
+fn volume<T: Copy + std::ops::Add<Output = T> + std::ops::Sub<Output = T>>(cube: &Cube, moment: &[T]) -> T {
+  face(cube, 0, cube.high[0], moment) - face(cube, 0, cube.low[0], moment)
+}
+
+impl Moments {
+  /// Builds the cumulative moments of the pixels.
+  fn new(pixels: impl Iterator<Item = Color>) -> Self {
+    let size = SIDE * SIDE * SIDE;
+    let mut moments = Moments {
+      weight: vec![0; size],
+      sums: [vec![0; size], vec![0; size], vec![0; size]],
+      squares: vec![0.0; size],
+    };
+    for color in pixels {
+      let channels = [color.r, color.g, color.b];
+      let index = cell(channels.map(|value| usize::from(value >> 3) + 1));
+      moments.weight[index] += 1;
+      for (sum, &value) in moments.sums.iter_mut().zip(&channels) {
+        sum[index] += i64::from(value);
+      }
+      moments.squares[index] += channels.iter().map(|&value| f64::from(value).powi(2)).sum::<f64>();
+    }
+    // running sums along each axis in turn turn the histogram into cumulative moments
+    for axis in 0..3 {
+      for a in 1..SIDE {
+        for b in 1..SIDE {
+          for c in 1..SIDE {
+            let mut coordinates = [a, b, c];
+            let index = cell(coordinates);
+            coordinates[axis] -= 1;
+            let previous = cell(coordinates);
+            moments.weight[index] += moments.weight[previous];
+            for sum in &mut moments.sums {
+              sum[index] += sum[previous];
+            }
+            moments.squares[index] += moments.squares[previous];
+          }
+        }
+      }
+    }
+    moments
+  }
+
+  /// Returns the channel sums and the number of pixels of a box.
+  fn totals(&self, cube: &Cube) -> ([i64; 3], i64) {
+    (self.sums.each_ref().map(|sum| volume(cube, sum)), volume(cube, &self.weight))
+  }
+
+  /// Returns the sum of squared deviations from the mean color of the pixels in a box.
+  fn variance(&self, cube: &Cube) -> f64 {
+    let (sums, weight) = self.totals(cube);
+    if weight == 0 {
+      return 0.0;
+    }
+    let squared: f64 = sums.iter().map(|&sum| (sum as f64).powi(2)).sum();
+    volume(cube, &self.squares) - squared / weight as f64
+  }
+
+  /// Splits a box where the two halves have the least variance, shrinking it to the lower half.
+  ///
+  /// Returns the upper half, `None` if the box cannot be split.
+  fn cut(&self, cube: &mut Cube) -> Option<Cube> {
+    let (whole, whole_weight) = self.totals(cube);
+    let mut best: Option<(f64, usize, usize)> = None;
+    for axis in 0..3 {
+      let base = self.sums.each_ref().map(|sum| face(cube, axis, cube.low[axis], sum));
+      let base_weight = face(cube, axis, cube.low[axis], &self.weight);
+      for position in cube.low[axis] + 1..cube.high[axis] {
+        let lower: [i64; 3] = std::array::from_fn(|c| face(cube, axis, position, &self.sums[c]) - base[c]);
+        let lower_weight = face(cube, axis, position, &self.weight) - base_weight;
+        let upper_weight = whole_weight - lower_weight;
+        if lower_weight == 0 || upper_weight == 0 {
+          continue;
+        }
+        // the variance of both halves is lowest where this is highest
+        let score = |sums: [i64; 3], weight: i64| sums.iter().map(|&sum| (sum as f64).powi(2)).sum::<f64>() / weight as f64;
+        let value = score(lower, lower_weight) + score(std::array::from_fn(|c| whole[c] - lower[c]), upper_weight);
+        if best.is_none_or(|(max, _, _)| value > max) {
+          best = Some((value, axis, position));
+        }
+      }
+    }
+    let (_, axis, position) = best?;
+    let mut upper = *cube;
+    upper.low[axis] = position;
+    cube.high[axis] = position;
+    Some(upper)
+  }
+}
+
+/// Xiaolin Wu's color quantization.
+///
+/// The pixels are counted in a histogram of 5 bits per channel. Starting with the whole color cube,
+/// the box with the largest variance is split in two along the plane that leaves the least variance,
+/// until there are `colors` boxes or none can be split any more. Each box contributes the exact mean
+/// of its pixels, the boxes with the most pixels first.
+fn wu(pixels: impl Iterator<Item = Color>, colors: usize) -> Vec<Color> {
+  let moments = Moments::new(pixels);
+  let mut cubes = vec![Cube {
+    low: [0; 3],
+    high: [SIDE - 1; 3],
+  }];
+  let mut variances = vec![0.0];
+  let mut next = 0;
+  while cubes.len() < colors {
+    match moments.cut(&mut cubes[next]) {
+      Some(upper) => {
+        let variance = |cube: &Cube| if cube.cells() > 1 { moments.variance(cube) } else { 0.0 };
+        variances[next] = variance(&cubes[next]);
+        variances.push(variance(&upper));
+        cubes.push(upper);
+      }
+      None => variances[next] = 0.0,
+    }
+    next = (0..cubes.len())
+      .max_by(|&a, &b| variances[a].total_cmp(&variances[b]).then(b.cmp(&a)))
+      .expect("there are cubes");
+    if variances[next] <= 0.0 {
+      break;
+    }
+  }
+  let mut found: Vec<(i64, Color)> = cubes
+    .iter()
+    .map(|cube| moments.totals(cube))
+    .filter(|&(_, weight)| weight > 0)
+    .map(|(sums, weight)| {
+      let mean = |sum: i64| ((sum + weight / 2) / weight) as u8;
+      (
+        weight,
+        Color {
+          r: mean(sums[0]),
+          g: mean(sums[1]),
+          b: mean(sums[2]),
+        },
+      )
+    })
+    .collect();
+  found.sort_by_key(|&(weight, _)| std::cmp::Reverse(weight));
+  let mut palette: Vec<Color> = Vec::with_capacity(found.len());
+  for (_, color) in found {
+    if !palette.contains(&color) {
+      palette.push(color);
+    }
+  }
+  palette
+}
+
+/// Learning sample factor of NeuQuant, every 10th pixel trains the network, 1 would use all of them.
+const NEUQUANT_SAMPLING: i32 = 10;
+
+/// NeuQuant quantization, a self-organizing map of `colors` neurons trained on the pixels.
+///
+/// Every neuron becomes an entry, the ones nearest to the most pixels first.
+fn neuquant(pixels: impl Iterator<Item = Color>, colors: usize) -> Vec<Color> {
+  let rgba: Vec<u8> = pixels.flat_map(|color| [color.r, color.g, color.b, 255]).collect();
+  if rgba.is_empty() {
+    return Vec::new();
+  }
+  let network = color_quant::NeuQuant::new(NEUQUANT_SAMPLING, colors, &rgba);
+  let mut counts = vec![0usize; colors];
+  for pixel in rgba.chunks_exact(4) {
+    counts[network.index_of(pixel)] += 1;
+  }
+  let mut found: Vec<(usize, Color)> = network
+    .color_map_rgb()
+    .chunks_exact(3)
+    .map(Color::from)
+    .enumerate()
+    .map(|(i, color)| (counts[i], color))
+    .collect();
+  found.sort_by_key(|&(count, _)| std::cmp::Reverse(count));
+  let mut palette: Vec<Color> = Vec::with_capacity(colors);
+  for (_, color) in found {
+    if !palette.contains(&color) {
+      palette.push(color);
+    }
+  }
+  palette
+}
+
+/// Squared distance between two colors.
+fn distance(a: Color, b: Color) -> u32 {
+  let d = |a: u8, b: u8| u32::from(a.abs_diff(b)).pow(2);
+  d(a.r, b.r) + d(a.g, b.g) + d(a.b, b.b)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn image(pixels: &[Color]) -> Image {
+    Image {
+      buffer: pixels.iter().flat_map(|color| [color.r, color.g, color.b]).collect(),
+      alpha: None,
+      width: pixels.len() as u32,
+      height: 1,
+    }
+  }
+
+  #[test]
+  fn test_octree_keeps_few_colors_exactly() {
+    let red = Color { r: 200, g: 10, b: 10 };
+    let blue = Color { r: 5, g: 20, b: 250 };
+    let palette = palette(&image(&[red, blue, red, red]), PaletteQuantizer::Octree, 16);
+    assert_eq!(palette, vec![red, blue], "The most common color comes first");
+
+    let transparent = Image {
+      alpha: Some(vec![0; 4]),
+      ..image(&[red; 4])
+    };
+    assert_eq!(super::palette(&transparent, PaletteQuantizer::Octree, 16), vec![Color { r: 0, g: 0, b: 0 }]);
+  }
+
+  #[test]
+  fn test_kmeans_moves_entries_to_cluster_means() {
+    // two tight clusters, the seed entries start off their centers
+    let histogram = [
+      (Color { r: 10, g: 10, b: 10 }, 3),
+      (Color { r: 14, g: 14, b: 14 }, 1),
+      (Color { r: 200, g: 100, b: 0 }, 1),
+      (Color { r: 210, g: 110, b: 0 }, 1),
+    ];
+    let seed = vec![Color { r: 150, g: 80, b: 0 }, Color { r: 40, g: 40, b: 40 }];
+    assert_eq!(
+      kmeans(&histogram, seed.clone(), 2),
+      vec![Color { r: 11, g: 11, b: 11 }, Color { r: 205, g: 105, b: 0 }]
+    );
+
+    assert_eq!(kmeans(&histogram, seed[..1].to_vec(), 3).len(), 3, "Missing entries are added");
+
+    let pixels = [Color { r: 1, g: 2, b: 3 }, Color { r: 250, g: 0, b: 0 }];
+    assert_eq!(palette(&image(&pixels), PaletteQuantizer::Kmeans, 4).len(), 2, "Few colors are kept exactly");
+  }
+
+  #[test]
+  fn test_wu_splits_at_the_largest_variance() {
+    let red = Color { r: 200, g: 10, b: 10 };
+    let blue = Color { r: 5, g: 20, b: 250 };
+    assert_eq!(palette(&image(&[red, blue, red]), PaletteQuantizer::Wu, 16), vec![red, blue]);
+
+    // three pairs of close colors, each becomes the mean of its pair
+    let pixels = [
+      Color { r: 0, g: 0, b: 0 },
+      Color { r: 8, g: 8, b: 8 },
+      Color { r: 250, g: 250, b: 250 },
+      Color { r: 240, g: 240, b: 240 },
+      Color { r: 245, g: 0, b: 0 },
+      Color { r: 235, g: 0, b: 0 },
+    ];
+    let mut palette = palette(&image(&pixels), PaletteQuantizer::Wu, 3);
+    palette.sort_unstable_by_key(|color| (color.r, color.g, color.b));
+    assert_eq!(
+      palette,
+      vec![Color { r: 4, g: 4, b: 4 }, Color { r: 240, g: 0, b: 0 }, Color { r: 245, g: 245, b: 245 }]
+    );
+  }
+
+  #[test]
+  fn test_neuquant_learns_the_colors() {
+    let colors = [(200, 10, 10), (5, 20, 250), (20, 180, 40), (240, 240, 200)].map(|(r, g, b)| Color { r, g, b });
+    let pixels: Vec<Color> = (0..4000).map(|i| colors[i % 7 % 4]).collect();
+    let palette = palette(&image(&pixels), PaletteQuantizer::Neuquant, 16);
+    assert!(palette.len() <= 16);
+    for color in colors {
+      assert!(
+        palette.iter().any(|&entry| distance(entry, color) < 12),
+        "{color} is not learned in {palette:?}"
+      );
+    }
+    assert_eq!(super::palette(&image(&[]), PaletteQuantizer::Neuquant, 16), vec![Color { r: 0, g: 0, b: 0 }]);
+  }
+
+  #[test]
+  fn test_octree_reduces_to_the_requested_colors() {
+    // every 12-bit color, far more than fit into the tree budget for 8 colors
+    let pixels: Vec<Color> = (0..4096u32)
+      .map(|v| Color::from(((v >> 8) * 0x11) << 16 | ((v >> 4 & 15) * 0x11) << 8 | ((v & 15) * 0x11)))
+      .collect();
+    let palette = palette(&image(&pixels), PaletteQuantizer::Octree, 8);
+    assert_eq!(palette.len(), 8);
+    // evenly spread colors split evenly, one entry per octant of the color cube
+    for color in palette {
+      assert!(
+        [color.r, color.g, color.b]
+          .iter()
+          .all(|&value| (59..=61).contains(&value) || (194..=197).contains(&value)),
+        "{color}"
+      );
+    }
+  }
+}
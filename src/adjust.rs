@@ -0,0 +1,131 @@
+//! Color adjustments applied before quantization.
+//!
+//! Adjustments work on the 8-bit RGB buffer, but every pass is computed in floating point and
+//! rounded only once, so correcting a photo here loses less precision than a round trip through
+//! another tool.
+
+/// Decodes an sRGB channel value (0-255) into linear light (0.0-1.0).
+#[must_use]
+pub fn srgb_to_linear(value: u8) -> f32 {
+  let v = f32::from(value) / 255.0;
+  if v <= 0.04045 { v / 12.92 } else { ((v + 0.055) / 1.055).powf(2.4) }
+}
+
+/// Encodes linear light (0.0-1.0, clamped) into an sRGB channel value (0-255).
+#[must_use]
+pub fn linear_to_srgb(value: f32) -> u8 {
+  let v = value.clamp(0.0, 1.0);
+  let v = if v <= 0.003_130_8 { v * 12.92 } else { 1.055 * v.powf(1.0 / 2.4) - 0.055 };
+  (v * 255.0).round() as u8
+}
+
+/// Returns the linear-light gain of an exposure change in stops.
+#[must_use]
+pub fn exposure_gain(stops: f32) -> f32 {
+  stops.exp2()
+}
+
+/// Returns the red, green and blue gains of a simple white balance correction.
+///
+/// `temperature` warms (positive) or cools (negative) the image, `tint` shifts it towards magenta
+/// (positive) or green (negative). Both range from -1.0 to 1.0.
+#[must_use]
+pub fn white_balance_gains(temperature: f32, tint: f32) -> [f32; 3] {
+  const STRENGTH: f32 = 0.3;
+  let temperature = temperature.clamp(-1.0, 1.0) * STRENGTH;
+  let tint = tint.clamp(-1.0, 1.0) * STRENGTH;
+  [1.0 + temperature, 1.0 - tint, 1.0 - temperature]
+}
+
+/// Multiplies the linear-light red, green and blue channels of a buffer by the given gains.
+pub fn apply_gains(buffer: &mut [u8], gains: [f32; 3]) {
+  // one lookup table per channel, there are only 256 input values
+  let luts: Vec<[u8; 256]> = gains
+    .iter()
+    .map(|&gain| std::array::from_fn(|value| linear_to_srgb(srgb_to_linear(value as u8) * gain)))
+    .collect();
+  for pixel in buffer.chunks_exact_mut(3) {
+    for (channel, lut) in pixel.iter_mut().zip(&luts) {
+      *channel = lut[usize::from(*channel)];
+    }
+  }
+}
+
+/// Changes the saturation of a buffer around the luma of each pixel.
+///
+/// `saturation` scales all colors alike, from -1.0 (grayscale) over 0.0 (unchanged) to 1.0 (double).
+/// `vibrance` works the same but mostly affects muted colors and leaves saturated ones alone.
+pub fn apply_saturation(buffer: &mut [u8], saturation: f32, vibrance: f32) {
+  let saturation = 1.0 + saturation.max(-1.0);
+  for pixel in buffer.chunks_exact_mut(3) {
+    let [r, g, b] = [pixel[0], pixel[1], pixel[2]].map(f32::from);
+    let luma = 0.2126 * r + 0.7152 * g + 0.0722 * b;
+    let current = (r.max(g).max(b) - r.min(g).min(b)) / 255.0;
+    let factor = (saturation * (1.0 + vibrance * (1.0 - current))).max(0.0);
+    for (channel, value) in pixel.iter_mut().zip([r, g, b]) {
+      *channel = (luma + (value - luma) * factor).round().clamp(0.0, 255.0) as u8;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_srgb_round_trip() {
+    for value in 0..=255 {
+      assert_eq!(linear_to_srgb(srgb_to_linear(value)), value);
+    }
+  }
+
+  #[test]
+  fn test_exposure_one_stop_doubles_light() {
+    let mut buffer = vec![0, 128, 255];
+    apply_gains(&mut buffer, [exposure_gain(1.0); 3]);
+    assert_eq!(buffer[0], 0);
+    assert_eq!(buffer[1], linear_to_srgb(srgb_to_linear(128) * 2.0));
+    assert_eq!(buffer[2], 255, "Highlights should clip at white");
+
+    let mut buffer = vec![128; 3];
+    apply_gains(&mut buffer, [exposure_gain(0.0); 3]);
+    assert_eq!(buffer, vec![128; 3], "Zero stops should be a no-op");
+  }
+
+  #[test]
+  fn test_white_balance_gains() {
+    assert_eq!(white_balance_gains(0.0, 0.0), [1.0, 1.0, 1.0]);
+
+    let [r, g, b] = white_balance_gains(0.5, 0.0);
+    assert!(r > 1.0 && b < 1.0 && g == 1.0, "Warming should boost red and cut blue");
+
+    let [_, g, _] = white_balance_gains(0.0, -1.0);
+    assert!(g > 1.0, "Negative tint should boost green");
+
+    assert_eq!(white_balance_gains(5.0, 0.0), white_balance_gains(1.0, 0.0), "Out of range values are clamped");
+  }
+
+  #[test]
+  fn test_saturation() {
+    let mut buffer = vec![200, 100, 100, 128, 128, 128];
+    apply_saturation(&mut buffer, -1.0, 0.0);
+    assert_eq!(buffer[0], buffer[1], "Full desaturation should give gray");
+    assert_eq!(buffer[1], buffer[2]);
+    assert_eq!(&buffer[3..], &[128, 128, 128], "Gray should stay gray");
+
+    let mut buffer = vec![200, 100, 100];
+    apply_saturation(&mut buffer, 0.5, 0.0);
+    assert!(buffer[0] > 200 && buffer[1] < 100, "Boosting should spread the channels, got {:?}", buffer);
+  }
+
+  #[test]
+  fn test_vibrance_spares_saturated_colors() {
+    let mut saturated = vec![255, 0, 0];
+    apply_saturation(&mut saturated, 0.0, 1.0);
+    assert_eq!(saturated, vec![255, 0, 0]);
+
+    let mut muted = vec![140, 120, 120];
+    apply_saturation(&mut muted, 0.0, 1.0);
+    assert!(muted[0] - muted[1] > 20, "Muted colors should be boosted, got {:?}", muted);
+  }
+}
@@ -0,0 +1,148 @@
+//! ANSI art rendering with half-block characters.
+//!
+//! Every character cell shows two pixels: the upper one as the foreground color of `▀` and the lower one
+//! as its background. Colors are written with the most compatible escape sequences that represent them
+//! exactly, so an image dithered to [`ColorPalette::Ansi16`](crate::palette::ColorPalette::Ansi16) gives a
+//! classic 16-color `.ans` file that can be `cat`-ed in any terminal.
+
+use std::collections::HashMap;
+use std::fmt::Write as _;
+
+use crate::palette::{Color, PALETTE_ANSI16, PALETTE_XTERM256};
+use crate::pipeline::Image;
+
+/// Escape sequences used for the colors of rendered images.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ColorCodes {
+  /// The 16 ANSI colors, `30`-`37` and `90`-`97` for the foreground
+  Ansi16,
+  /// xterm 256-color indices, `38;5;n` for the foreground
+  Xterm256,
+  /// 24-bit colors, `38;2;r;g;b` for the foreground
+  TrueColor,
+}
+
+impl ColorCodes {
+  /// Returns the most compatible codes that show all colors of the image exactly.
+  #[must_use]
+  pub fn for_image(image: &Image) -> Self {
+    let uses_only = |palette: &[Color]| image.buffer.chunks_exact(3).all(|p| palette.contains(&Color::from(p)));
+    if uses_only(&PALETTE_ANSI16) {
+      ColorCodes::Ansi16
+    } else if uses_only(&PALETTE_XTERM256) {
+      ColorCodes::Xterm256
+    } else {
+      ColorCodes::TrueColor
+    }
+  }
+}
+
+/// Renders an image as lines of half-block characters.
+///
+/// Lines are separated by `\r\n`, so the output also works in raw mode, and end with a reset. Colors
+/// are only written when they change. Colors that the codes cannot show are written as 24-bit colors.
+#[must_use]
+pub fn render(image: &Image, codes: ColorCodes) -> String {
+  let indices: HashMap<Color, usize> = match codes {
+    ColorCodes::Ansi16 => index_of(&PALETTE_ANSI16),
+    ColorCodes::Xterm256 => index_of(&PALETTE_XTERM256),
+    ColorCodes::TrueColor => HashMap::new(),
+  };
+  // writing into a string cannot fail
+  let sgr = |out: &mut String, color: Color, background: bool| {
+    let _ = match (codes, indices.get(&color)) {
+      (ColorCodes::Ansi16, Some(&i)) => {
+        let base = if background { 40 } else { 30 };
+        let code = if i < 8 { base + i } else { base + 60 + i - 8 };
+        write!(out, "\x1b[{code}m")
+      }
+      (ColorCodes::Xterm256, Some(&i)) => write!(out, "\x1b[{};5;{i}m", if background { 48 } else { 38 }),
+      _ => write!(out, "\x1b[{};2;{};{};{}m", if background { 48 } else { 38 }, color.r, color.g, color.b),
+    };
+  };
+  let pixel = |x: u32, y: u32| {
+    let i = (y * image.width + x) as usize * 3;
+    Color::from(&image.buffer[i..i + 3])
+  };
+
+  let mut out = String::new();
+  for y in (0..image.height).step_by(2) {
+    if y > 0 {
+      out.push_str("\r\n");
+    }
+    let (mut foreground, mut background) = (None, None);
+    for x in 0..image.width {
+      let top = pixel(x, y);
+      if foreground != Some(top) {
+        sgr(&mut out, top, false);
+        foreground = Some(top);
+      }
+      let bottom = (y + 1 < image.height).then(|| pixel(x, y + 1));
+      if background != Some(bottom) {
+        match bottom {
+          Some(color) => sgr(&mut out, color, true),
+          None => out.push_str("\x1b[49m"),
+        }
+        background = Some(bottom);
+      }
+      out.push('\u{2580}');
+    }
+    out.push_str("\x1b[0m");
+  }
+  out
+}
+
+/// Encodes an image as the contents of an `.ans` file, see [`render`].
+#[must_use]
+pub fn encode(image: &Image) -> Vec<u8> {
+  let mut out = render(image, ColorCodes::for_image(image));
+  // end with a new line, the shell prompt starts below the image
+  out.push_str("\r\n");
+  out.into_bytes()
+}
+
+fn index_of(palette: &[Color]) -> HashMap<Color, usize> {
+  // duplicate colors keep their first index
+  palette.iter().enumerate().rev().map(|(i, &color)| (color, i)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn image(buffer: Vec<u8>, width: u32, height: u32) -> Image {
+    Image {
+      buffer,
+      alpha: None,
+      width,
+      height,
+    }
+  }
+
+  #[test]
+  fn test_color_codes_for_image() {
+    assert_eq!(ColorCodes::for_image(&image(vec![0xaa, 0, 0, 0xff, 0xff, 0xff], 2, 1)), ColorCodes::Ansi16);
+    assert_eq!(ColorCodes::for_image(&image(vec![0xff, 0, 0], 1, 1)), ColorCodes::Xterm256);
+    assert_eq!(ColorCodes::for_image(&image(vec![1, 2, 3], 1, 1)), ColorCodes::TrueColor);
+  }
+
+  #[test]
+  fn test_render_ansi16() {
+    // bright red over black, then black over bright red
+    let image = image(vec![0xff, 0x55, 0x55, 0, 0, 0, 0, 0, 0, 0xff, 0x55, 0x55], 2, 2);
+    assert_eq!(render(&image, ColorCodes::Ansi16), "\x1b[91m\x1b[40m\u{2580}\x1b[30m\x1b[101m\u{2580}\x1b[0m");
+  }
+
+  #[test]
+  fn test_render_skips_unchanged_colors() {
+    let image = image(vec![0xff, 0, 0, 0xff, 0, 0, 0xff, 0, 0], 3, 1);
+    assert_eq!(render(&image, ColorCodes::Xterm256), "\x1b[38;5;196m\x1b[49m\u{2580}\u{2580}\u{2580}\x1b[0m");
+  }
+
+  #[test]
+  fn test_encode_ends_with_new_line() {
+    let encoded = String::from_utf8(encode(&image(vec![0; 2 * 3 * 3], 2, 3))).unwrap();
+    assert_eq!(encoded.matches("\r\n").count(), 2);
+    assert!(encoded.starts_with("\x1b[30m\x1b[40m"));
+  }
+}
@@ -2,23 +2,32 @@
 
 use crate::dither::DitherMethod;
 use crate::palette::ColorPalette;
-use clap::Parser;
+use crate::patterns::{Pattern, Size};
+use crate::pipeline::Settings;
+use crate::template::OutputTemplate;
+use clap::{Parser, Subcommand};
 use std::path::PathBuf;
 
 /// Command-line arguments for the dithers CLI tool.
 ///
 /// A simple command-line tool for dithering images with various algorithms and color palettes.
 #[derive(Parser, Debug)]
-#[clap(author, version, about)]
+#[clap(author, version, about, subcommand_negates_reqs = true)]
 pub struct Args {
   /// Input image file path
-  #[clap(short, long = "in")]
-  pub in_img: PathBuf,
+  #[clap(short, long = "in", required_unless_present = "jobs_file")]
+  pub in_img: Option<PathBuf>,
 
   /// Output image file path (optional)
   #[clap(short, long = "out", default_value = "out.png")]
   pub out_img: Option<PathBuf>,
 
+  /// Output file name template, e.g. "{stem}_{method}_{palette}.{ext}"
+  ///
+  /// Placeholders: {stem}, {ext}, {method}, {palette}, {size}, {date}
+  #[clap(long = "out-template", conflicts_with = "out_img")]
+  pub out_template: Option<OutputTemplate>,
+
   /// Dithering algorithm to use
   #[clap(short, long = "dither", default_value_t, value_enum)]
   pub dither_type: DitherMethod,
@@ -26,6 +35,147 @@ pub struct Args {
   /// Color palette for quantization
   #[clap(short, long = "color", default_value_t, value_enum)]
   pub color_palette: ColorPalette,
+
+  /// Jobs manifest (.toml or .csv) listing input, output, method and palette per image
+  #[clap(short, long = "jobs-file", conflicts_with = "in_img")]
+  pub jobs_file: Option<PathBuf>,
+
+  /// Write one output per size, each resized so its longer side has that many pixels, e.g. 16,32,64,128
+  #[clap(long, value_delimiter = ',', value_name = "PIXELS")]
+  pub sizes: Vec<u32>,
+
+  /// Cache file recording finished jobs, unchanged jobs are skipped on the next run
+  #[clap(long, value_name = "FILE")]
+  pub cache: Option<PathBuf>,
+
+  /// Skip jobs whose output file already exists, to resume an interrupted batch
+  #[clap(long)]
+  pub skip_existing: bool,
+
+  /// Processing options, also available per job in a jobs file
+  #[clap(flatten)]
+  pub settings: Settings,
+
+  /// Log more details, repeat for even more (-v debug, -vv trace)
+  #[clap(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+  pub verbose: u8,
+
+  /// Only log errors
+  #[clap(short, long, global = true)]
+  pub quiet: bool,
+
+  /// Tool to run instead of dithering an image
+  #[clap(subcommand)]
+  pub command: Option<Command>,
+}
+
+/// Subcommands of the dithers CLI tool.
+#[derive(Subcommand, Debug)]
+pub enum Command {
+  /// Generate a synthetic test image
+  Generate(GenerateArgs),
+  /// Re-run the jobs of a jobs file and compare the outputs against stored references
+  Verify(VerifyArgs),
+  /// Dither every image of a directory into one labeled contact sheet
+  ContactSheet(ContactSheetArgs),
+  /// Compare the original and dithered image interactively in the terminal
+  Preview(PreviewArgs),
+  /// Print the dimensions, colors and best-fitting palette of an image
+  Info(InfoArgs),
+  /// Answer dithering requests on a local socket, for editors and build watchers
+  Daemon(DaemonArgs),
+}
+
+/// Arguments of the `generate` subcommand.
+#[derive(clap::Args, Debug)]
+pub struct GenerateArgs {
+  /// Test pattern to generate
+  #[clap(value_enum)]
+  pub pattern: Pattern,
+
+  /// Image size as <width>x<height>, e.g. 640x480
+  pub size: Size,
+
+  /// Output image file path (optional, defaults to <pattern>_<size>.png)
+  #[clap(short, long = "out")]
+  pub out_img: Option<PathBuf>,
+}
+
+/// Arguments of the `verify` subcommand.
+#[derive(clap::Args, Debug)]
+pub struct VerifyArgs {
+  /// Jobs manifest (.toml or .csv) whose jobs are re-run
+  #[clap(short, long = "jobs-file")]
+  pub jobs_file: PathBuf,
+
+  /// Directory holding the reference outputs, matched by output file name
+  #[clap(long, value_name = "DIR")]
+  pub against: PathBuf,
+
+  /// Largest accepted mean absolute difference per channel (0-255), 0 requires identical outputs
+  #[clap(long, default_value_t)]
+  pub tolerance: f64,
+
+  /// Store the current outputs as the new references instead of comparing
+  #[clap(long)]
+  pub bless: bool,
+}
+
+/// Arguments of the `contact-sheet` subcommand.
+#[derive(clap::Args, Debug)]
+pub struct ContactSheetArgs {
+  /// Directory whose images are put on the sheet
+  pub dir: PathBuf,
+
+  /// Output image file path
+  #[clap(short, long = "out", default_value = "contact_sheet.png")]
+  pub out_img: PathBuf,
+
+  /// Dithering algorithm to use
+  #[clap(short, long = "dither", default_value_t, value_enum)]
+  pub dither_type: DitherMethod,
+
+  /// Color palette for quantization
+  #[clap(short, long = "color", default_value_t, value_enum)]
+  pub color_palette: ColorPalette,
+
+  /// Number of thumbnails per row
+  #[clap(long, default_value_t = 4)]
+  pub columns: u32,
+
+  /// Largest thumbnail width and height in pixels
+  #[clap(long = "thumb-size", default_value_t = 200)]
+  pub thumb_size: u32,
+}
+
+/// Arguments of the `preview` subcommand.
+#[derive(clap::Args, Debug)]
+pub struct PreviewArgs {
+  /// Input image file path
+  pub in_img: PathBuf,
+
+  /// Dithering algorithm shown first
+  #[clap(short, long = "dither", default_value_t, value_enum)]
+  pub dither_type: DitherMethod,
+
+  /// Color palette for quantization
+  #[clap(short, long = "color", default_value_t, value_enum)]
+  pub color_palette: ColorPalette,
+}
+
+/// Arguments of the `info` subcommand.
+#[derive(clap::Args, Debug)]
+pub struct InfoArgs {
+  /// Image file to describe
+  pub in_img: PathBuf,
+}
+
+/// Arguments of the `daemon` subcommand.
+#[derive(clap::Args, Debug)]
+pub struct DaemonArgs {
+  /// Unix socket to listen on [default: dithers.sock in $XDG_RUNTIME_DIR or the temporary directory]
+  #[clap(long, value_name = "PATH")]
+  pub socket: Option<PathBuf>,
 }
 
 #[cfg(test)]
@@ -35,9 +185,9 @@ mod tests {
   #[test]
   fn test_args_default_values() {
     // Test that default values work as expected when parsing minimal args
-    let args = Args::try_parse_from(&["dithers", "-i", "test.jpg"]).unwrap();
+    let args = Args::try_parse_from(["dithers", "-i", "test.jpg"]).unwrap();
 
-    assert_eq!(args.in_img, PathBuf::from("test.jpg"));
+    assert_eq!(args.in_img, Some(PathBuf::from("test.jpg")));
     assert_eq!(args.out_img, Some(PathBuf::from("out.png")));
     assert_eq!(args.dither_type, DitherMethod::FloydSteinberg);
     assert_eq!(args.color_palette, ColorPalette::Monochrome);
@@ -45,9 +195,9 @@ mod tests {
 
   #[test]
   fn test_args_full_specification() {
-    let args = Args::try_parse_from(&["dithers", "-i", "input.png", "-o", "output.jpg", "-d", "atkinson", "-c", "color16"]).unwrap();
+    let args = Args::try_parse_from(["dithers", "-i", "input.png", "-o", "output.jpg", "-d", "atkinson", "-c", "color16"]).unwrap();
 
-    assert_eq!(args.in_img, PathBuf::from("input.png"));
+    assert_eq!(args.in_img, Some(PathBuf::from("input.png")));
     assert_eq!(args.out_img, Some(PathBuf::from("output.jpg")));
     assert_eq!(args.dither_type, DitherMethod::Atkinson);
     assert_eq!(args.color_palette, ColorPalette::COLOR16);
@@ -55,13 +205,157 @@ mod tests {
 
   #[test]
   fn test_args_missing_input_fails() {
-    let result = Args::try_parse_from(&["dithers"]);
+    let result = Args::try_parse_from(["dithers"]);
     assert!(result.is_err(), "Should fail when input file is not specified");
   }
 
+  #[test]
+  fn test_args_out_template() {
+    let args = Args::try_parse_from(["dithers", "-i", "test.jpg", "--out-template", "{stem}_{method}.png"]).unwrap();
+    assert_eq!(args.out_template, Some("{stem}_{method}.png".parse().unwrap()));
+
+    let result = Args::try_parse_from(["dithers", "-i", "test.jpg", "--out-template", "{bogus}.png"]);
+    assert!(result.is_err(), "Should reject unknown template placeholders");
+  }
+
+  #[test]
+  fn test_args_ink_and_paper() {
+    let args = Args::try_parse_from(["dithers", "-i", "test.jpg", "--ink", "#222", "--paper", "f5e9d0"]).unwrap();
+    assert_eq!(args.settings.ink, Some("#222222".parse().unwrap()));
+    assert_eq!(args.settings.paper, Some("#f5e9d0".parse().unwrap()));
+
+    assert!(Args::try_parse_from(["dithers", "-i", "test.jpg", "--ink", "black"]).is_err());
+  }
+
+  #[test]
+  fn test_args_negative_adjustments() {
+    let args = Args::try_parse_from(["dithers", "-i", "test.jpg", "--exposure", "-1.5", "--temperature", "-0.2", "--tint", "0.1"]).unwrap();
+    assert_eq!(args.settings.exposure, -1.5);
+    assert_eq!(args.settings.temperature, -0.2);
+    assert_eq!(args.settings.tint, 0.1);
+  }
+
+  #[test]
+  fn test_args_jobs_file_replaces_input() {
+    let args = Args::try_parse_from(["dithers", "--jobs-file", "jobs.toml"]).unwrap();
+    assert_eq!(args.in_img, None);
+    assert_eq!(args.jobs_file, Some(PathBuf::from("jobs.toml")));
+  }
+
+  #[test]
+  fn test_args_cache() {
+    let args = Args::try_parse_from(["dithers", "--jobs-file", "jobs.toml", "--cache", ".dithers-cache.toml"]).unwrap();
+    assert_eq!(args.cache, Some(PathBuf::from(".dithers-cache.toml")));
+    assert!(!args.skip_existing);
+    let args = Args::try_parse_from(["dithers", "--jobs-file", "jobs.toml", "--skip-existing"]).unwrap();
+    assert!(args.skip_existing);
+  }
+
+  #[test]
+  fn test_args_jobs_file_conflicts_with_input() {
+    let result = Args::try_parse_from(["dithers", "-i", "test.jpg", "--jobs-file", "jobs.toml"]);
+    assert!(result.is_err(), "Should fail when both an input file and a jobs file are given");
+  }
+
+  #[test]
+  fn test_args_generate_subcommand() {
+    let args = Args::try_parse_from(["dithers", "generate", "zoneplate", "320x240", "-o", "zp.png"]).unwrap();
+    let Some(Command::Generate(generate)) = args.command else {
+      panic!("Expected the generate subcommand");
+    };
+    assert_eq!(generate.pattern, Pattern::Zoneplate);
+    assert_eq!(generate.size, Size { width: 320, height: 240 });
+    assert_eq!(generate.out_img, Some(PathBuf::from("zp.png")));
+
+    assert!(Args::try_parse_from(["dithers", "generate", "zoneplate", "320"]).is_err());
+  }
+
+  #[test]
+  fn test_args_verify_subcommand() {
+    let args = Args::try_parse_from(["dithers", "verify", "-j", "jobs.toml", "--against", "refs", "--tolerance", "0.5"]).unwrap();
+    let Some(Command::Verify(verify)) = args.command else {
+      panic!("Expected the verify subcommand");
+    };
+    assert_eq!(verify.jobs_file, PathBuf::from("jobs.toml"));
+    assert_eq!(verify.against, PathBuf::from("refs"));
+    assert_eq!(verify.tolerance, 0.5);
+    assert!(!verify.bless);
+
+    assert!(Args::try_parse_from(["dithers", "verify", "-j", "jobs.toml"]).is_err(), "--against is required");
+  }
+
+  #[test]
+  fn test_args_contact_sheet_subcommand() {
+    let args = Args::try_parse_from(["dithers", "contact-sheet", "scans", "-d", "atkinson", "--columns", "6"]).unwrap();
+    let Some(Command::ContactSheet(sheet)) = args.command else {
+      panic!("Expected the contact-sheet subcommand");
+    };
+    assert_eq!(sheet.dir, PathBuf::from("scans"));
+    assert_eq!(sheet.out_img, PathBuf::from("contact_sheet.png"));
+    assert_eq!(sheet.dither_type, DitherMethod::Atkinson);
+    assert_eq!(sheet.columns, 6);
+    assert_eq!(sheet.thumb_size, 200);
+  }
+
+  #[test]
+  fn test_args_preview_subcommand() {
+    let args = Args::try_parse_from(["dithers", "preview", "photo.jpg", "-c", "color8"]).unwrap();
+    let Some(Command::Preview(preview)) = args.command else {
+      panic!("Expected the preview subcommand");
+    };
+    assert_eq!(preview.in_img, PathBuf::from("photo.jpg"));
+    assert_eq!(preview.dither_type, DitherMethod::FloydSteinberg);
+    assert_eq!(preview.color_palette, ColorPalette::COLOR8);
+  }
+
+  #[test]
+  fn test_args_sizes() {
+    let args = Args::try_parse_from(["dithers", "-i", "logo.png", "--sizes", "16,32,64"]).unwrap();
+    assert_eq!(args.sizes, vec![16, 32, 64]);
+    assert!(Args::try_parse_from(["dithers", "-i", "logo.png", "--sizes", "16,big"]).is_err());
+  }
+
+  #[test]
+  fn test_args_bayer_size() {
+    let args = Args::try_parse_from(["dithers", "-i", "photo.jpg", "-d", "bayer", "--bayer-size", "16"]).unwrap();
+    assert_eq!(args.settings.bayer_size, Some(16));
+    assert!(Args::try_parse_from(["dithers", "-i", "photo.jpg", "-d", "bayer", "--bayer-size", "12"]).is_err());
+    assert!(Args::try_parse_from(["dithers", "-i", "photo.jpg", "-d", "bayer", "--bayer-size", "512"]).is_err());
+  }
+
+  #[test]
+  fn test_args_info_subcommand() {
+    let args = Args::try_parse_from(["dithers", "info", "image.png"]).unwrap();
+    let Some(Command::Info(info)) = args.command else {
+      panic!("Expected the info subcommand");
+    };
+    assert_eq!(info.in_img, PathBuf::from("image.png"));
+  }
+
+  #[test]
+  fn test_args_daemon_subcommand() {
+    let args = Args::try_parse_from(["dithers", "daemon", "--socket", "/tmp/d.sock"]).unwrap();
+    let Some(Command::Daemon(daemon)) = args.command else {
+      panic!("Expected the daemon subcommand");
+    };
+    assert_eq!(daemon.socket, Some(PathBuf::from("/tmp/d.sock")));
+  }
+
+  #[test]
+  fn test_args_verbosity() {
+    let args = Args::try_parse_from(["dithers", "-i", "test.jpg", "-vv"]).unwrap();
+    assert_eq!(args.verbose, 2);
+    assert!(!args.quiet);
+
+    let args = Args::try_parse_from(["dithers", "generate", "ramp", "8x8", "-q"]).unwrap();
+    assert!(args.quiet, "Verbosity flags should work after subcommands");
+
+    assert!(Args::try_parse_from(["dithers", "-i", "test.jpg", "-v", "-q"]).is_err());
+  }
+
   #[test]
   fn test_args_help_works() {
-    let result = Args::try_parse_from(&["dithers", "--help"]);
+    let result = Args::try_parse_from(["dithers", "--help"]);
     assert!(result.is_err()); // clap returns Err for --help, but its a special case
   }
 
@@ -84,7 +378,7 @@ mod tests {
     ];
 
     for method in methods {
-      let args = Args::try_parse_from(&["dithers", "-i", "test.jpg", "-d", method]);
+      let args = Args::try_parse_from(["dithers", "-i", "test.jpg", "-d", method]);
       assert!(args.is_ok(), "Should be able to parse dither method: {}", method);
     }
   }
@@ -94,7 +388,7 @@ mod tests {
     let palettes = ["monochrome", "color8", "color16"];
 
     for palette in palettes {
-      let args = Args::try_parse_from(&["dithers", "-i", "test.jpg", "-c", palette]);
+      let args = Args::try_parse_from(["dithers", "-i", "test.jpg", "-c", palette]);
       assert!(args.is_ok(), "Should be able to parse color palette: {}", palette);
     }
   }
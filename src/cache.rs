@@ -0,0 +1,215 @@
+//! Opt-in result cache for repeated batch runs.
+//!
+//! The cache file maps a key derived from the content of a job's input and all of its parameters to
+//! the output that was written for it. A job whose key is found, and whose output still exists, would
+//! produce the same result again and can be skipped. Keys include the crate version, so upgrading
+//! re-runs everything. Deleting an output, or the cache file, forces the jobs to run again.
+//!
+//! The file is TOML with one entry per key:
+//!
+//! ```toml
+//! [entries]
+//! 8f0c2b7e4d1a9356 = "photos/cat_out.jpg"
+//! ```
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard, PoisonError};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::DitherError;
+use crate::jobs::Job;
+use crate::output;
+
+/// Cache of job results, safe to share between jobs running in parallel.
+#[derive(Debug)]
+pub struct Cache {
+  path: PathBuf,
+  entries: Mutex<BTreeMap<String, PathBuf>>,
+}
+
+/// Layout of the cache file.
+#[derive(Default, Serialize, Deserialize)]
+struct CacheFile {
+  #[serde(default)]
+  entries: BTreeMap<String, PathBuf>,
+}
+
+impl Cache {
+  /// Opens the cache stored in `path`, a missing file is an empty cache.
+  pub fn open(path: &Path) -> Result<Self, DitherError> {
+    let file = match fs::read_to_string(path) {
+      Ok(content) => toml::from_str(&content).map_err(|err| DitherError::CacheFile {
+        path: path.to_path_buf(),
+        message: err.to_string(),
+      })?,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => CacheFile::default(),
+      Err(source) => {
+        return Err(DitherError::Io {
+          path: path.to_path_buf(),
+          source,
+        });
+      }
+    };
+    log::debug!("opened cache {:?} with {} entries", path, file.entries.len());
+    Ok(Cache {
+      path: path.to_path_buf(),
+      entries: Mutex::new(file.entries),
+    })
+  }
+
+  /// Returns the output recorded for a key, if the file still exists.
+  #[must_use]
+  pub fn lookup(&self, key: &str) -> Option<PathBuf> {
+    let entries = self.entries();
+    entries.get(key).filter(|output| output.exists()).cloned()
+  }
+
+  /// Records the output written for a key.
+  ///
+  /// Entries of other keys for the same output are removed, the file no longer holds their result.
+  pub fn insert(&self, key: String, output: PathBuf) {
+    let mut entries = self.entries();
+    entries.retain(|_, existing| *existing != output);
+    entries.insert(key, output);
+  }
+
+  /// Writes the cache back to its file.
+  pub fn save(&self) -> Result<(), DitherError> {
+    let file = CacheFile {
+      entries: self.entries().clone(),
+    };
+    let content = toml::to_string(&file).map_err(|err| DitherError::CacheFile {
+      path: self.path.clone(),
+      message: err.to_string(),
+    })?;
+    output::write_atomically(&self.path, content.as_bytes())
+  }
+
+  /// Locks the entries, a job that panicked while holding the lock cannot have left them half-changed.
+  fn entries(&self) -> MutexGuard<'_, BTreeMap<String, PathBuf>> {
+    self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+  }
+}
+
+/// Returns the cache key of a job, a hash of its input file content and all its parameters.
+pub fn job_key(job: &Job) -> Result<String, DitherError> {
+  let content = fs::read(&job.input).map_err(|source| DitherError::Io {
+    path: job.input.clone(),
+    source,
+  })?;
+  // the debug representation covers every parameter, it only has to be stable within one version
+  let parameters = format!(
+    "{} {:?} {:?} {:?} {:?} {:?}",
+    env!("CARGO_PKG_VERSION"),
+    job.output,
+    job.out_template,
+    job.method,
+    job.palette,
+    job.settings
+  );
+  // palette files, reference images and overlays can change without changing their path
+  let read = |path: &Option<PathBuf>| match path {
+    Some(path) => fs::read(path).map_err(|source| DitherError::Io { path: path.clone(), source }),
+    None => Ok(Vec::new()),
+  };
+  let palette = read(&job.settings.palette_file)?;
+  let reference = read(&job.settings.palette_from)?;
+  let overlay = read(&job.settings.overlay)?;
+  Ok(format!("{:016x}", fnv1a(&[parameters.as_bytes(), &content, &palette, &reference, &overlay])))
+}
+
+/// 64-bit FNV-1a hash, stable across platforms and Rust versions.
+fn fnv1a(parts: &[&[u8]]) -> u64 {
+  const OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+  const PRIME: u64 = 0x0000_0100_0000_01b3;
+  parts
+    .iter()
+    .flat_map(|part| part.iter())
+    .fold(OFFSET, |hash, &byte| (hash ^ u64::from(byte)).wrapping_mul(PRIME))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::dither::DitherMethod;
+
+  #[test]
+  fn test_fnv1a() {
+    assert_eq!(fnv1a(&[b""]), 0xcbf2_9ce4_8422_2325);
+    assert_eq!(fnv1a(&[b"a"]), 0xaf63_dc4c_8601_ec8c);
+    assert_eq!(fnv1a(&[b"ab", b"c"]), fnv1a(&[b"abc"]));
+  }
+
+  #[test]
+  fn test_job_key_covers_content_and_parameters() {
+    let input = std::env::temp_dir().join("dithers_cache_key_test.bin");
+    fs::write(&input, b"first").unwrap();
+    let job = Job::new(&input);
+    let key = job_key(&job).unwrap();
+    assert_eq!(job_key(&job).unwrap(), key, "Keys should be deterministic");
+
+    let mut atkinson = job.clone();
+    atkinson.method = DitherMethod::Atkinson;
+    assert_ne!(job_key(&atkinson).unwrap(), key);
+
+    let mut darker = job.clone();
+    darker.settings.exposure = -1.0;
+    assert_ne!(job_key(&darker).unwrap(), key);
+
+    let palette = std::env::temp_dir().join("dithers_cache_key_test.hex");
+    fs::write(&palette, "#000\n#fff\n").unwrap();
+    let mut recolored = job.clone();
+    recolored.settings.palette_file = Some(palette.clone());
+    let recolored_key = job_key(&recolored).unwrap();
+    fs::write(&palette, "#000\n#f00\n").unwrap();
+    assert_ne!(job_key(&recolored).unwrap(), recolored_key, "Keys should cover the palette file");
+    recolored.settings.palette_file = None;
+    recolored.settings.palette_from = Some(palette.clone());
+    let recolored_key = job_key(&recolored).unwrap();
+    fs::write(&palette, "a different reference").unwrap();
+    assert_ne!(job_key(&recolored).unwrap(), recolored_key, "Keys should cover the reference image");
+    fs::remove_file(&palette).unwrap();
+
+    let logo = std::env::temp_dir().join("dithers_cache_key_test_logo.png");
+    fs::write(&logo, b"first logo").unwrap();
+    let mut overlaid = job.clone();
+    overlaid.settings.overlay = Some(logo.clone());
+    let overlaid_key = job_key(&overlaid).unwrap();
+    fs::write(&logo, b"second logo").unwrap();
+    assert_ne!(job_key(&overlaid).unwrap(), overlaid_key, "Keys should cover the overlay");
+    fs::remove_file(&logo).unwrap();
+
+    fs::write(&input, b"second").unwrap();
+    assert_ne!(job_key(&job).unwrap(), key);
+    fs::remove_file(&input).unwrap();
+  }
+
+  #[test]
+  fn test_cache_round_trip() {
+    let dir = std::env::temp_dir().join("dithers_cache_test");
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("cache.toml");
+    let output = dir.join("out.png");
+    let _ = fs::remove_file(&path);
+    fs::write(&output, b"result").unwrap();
+
+    let cache = Cache::open(&path).unwrap();
+    assert_eq!(cache.lookup("0123"), None);
+    cache.insert("0123".to_string(), output.clone());
+    cache.insert("4567".to_string(), dir.join("deleted.png"));
+    cache.save().unwrap();
+
+    let cache = Cache::open(&path).unwrap();
+    assert_eq!(cache.lookup("0123"), Some(output.clone()));
+    assert_eq!(cache.lookup("4567"), None, "Missing outputs should not count as cached");
+
+    // a different result written to the same output replaces the entry
+    cache.insert("89ab".to_string(), output.clone());
+    assert_eq!(cache.lookup("0123"), None);
+    assert_eq!(cache.lookup("89ab"), Some(output.clone()));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,265 @@
+//! Attribute clash constraints of retro consoles.
+//!
+//! Many consoles do not store a palette per tile, but a small number of shared sub-palettes and, per
+//! cell, which sub-palette it uses. The Game Boy Color has 8 sub-palettes of 4 colors for its 8x8 tiles,
+//! the NES 4 sub-palettes of 4 colors per 16x16 area, and the ZX Spectrum any 2 colors per 8x8 cell.
+//! The solver picks the sub-palettes and the assignment of cells to them, then every cell is dithered
+//! with the colors of its sub-palette only.
+//!
+//! Sub-palettes are found with a k-means style refinement: starting from the ideal palettes of the
+//! cells that are worst served, every cell is assigned to the sub-palette that represents its pixels
+//! best, and every sub-palette is rebuilt from the pixels of its cells, until the assignment is stable.
+
+use crate::dither::{DitherMethod, DitherOptions};
+use crate::error::DitherError;
+use crate::palette::{self, Color, TieBroken};
+use crate::sprite::{self, SpriteLayout};
+use crate::tiles;
+
+/// Largest number of refinement rounds of the solver.
+const MAX_ROUNDS: usize = 16;
+
+/// Tile constraints of common retro hardware.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum Console {
+  /// Nintendo Entertainment System: 16x16 areas, 4 sub-palettes of 4 colors
+  Nes,
+  /// Game Boy Color: 8x8 tiles, 8 sub-palettes of 4 colors
+  Gbc,
+  /// ZX Spectrum: 8x8 cells of 2 colors each
+  ZxSpectrum,
+}
+
+serde_value_enum!(Console);
+
+impl Console {
+  /// Returns the cell layout of the console.
+  #[must_use]
+  pub fn layout(self) -> SpriteLayout {
+    let layout = |tile_size, tile_colors, sub_palettes| SpriteLayout {
+      tile_size,
+      tile_colors,
+      sub_palettes,
+    };
+    match self {
+      Console::Nes => layout(16, 4, Some(4)),
+      Console::Gbc => layout(8, 4, Some(8)),
+      Console::ZxSpectrum => layout(8, 2, None),
+    }
+  }
+}
+
+/// Sub-palettes and their assignment to cells.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Attributes {
+  /// Shared sub-palettes
+  pub palettes: Vec<Vec<Color>>,
+  /// Index of the sub-palette of every cell, in the order of [`tiles::grid`]
+  pub assignment: Vec<usize>,
+}
+
+/// Chooses the sub-palettes of the layout from the colors of `palette` and assigns one of them to every cell.
+///
+/// The colors of a sub-palette are picked with the distance metric and tie rule of `palette`. Without a
+/// number of sub-palettes every cell gets its own. Fully transparent pixels are ignored when an alpha
+/// channel is given.
+#[must_use]
+pub fn solve(buffer: &[u8], width: u32, height: u32, palette: TieBroken, layout: SpriteLayout, alpha: Option<&[u8]>) -> Attributes {
+  let cells: Vec<Vec<u8>> = tiles::grid(width, height, layout.tile_size)
+    .map(|tile| sprite::opaque_pixels(buffer, width, &tile, alpha))
+    .collect();
+  let count = layout.sub_palettes.unwrap_or(cells.len());
+  let best = |pixels: &[u8]| sprite::tile_palette(pixels, palette.colors, layout.tile_colors, palette.metric, palette.tie_break);
+
+  // seed with the ideal palette of the cell served worst so far, which spreads the seeds out
+  let mut palettes: Vec<Vec<Color>> = Vec::new();
+  while palettes.len() < count.max(1) && palettes.len() < cells.len() {
+    let worst = cells
+      .iter()
+      .enumerate()
+      .max_by_key(|(i, pixels)| {
+        (
+          palettes.iter().map(|palette| error(pixels, palette)).min().unwrap_or(u64::MAX),
+          std::cmp::Reverse(*i),
+        )
+      })
+      .map(|(i, _)| i)
+      .expect("there are cells");
+    palettes.push(best(&cells[worst]));
+  }
+  if palettes.is_empty() {
+    palettes.push(best(&[]));
+  }
+
+  let mut assignment = vec![usize::MAX; cells.len()];
+  for round in 0..MAX_ROUNDS {
+    let next: Vec<usize> = cells.iter().map(|pixels| closest_palette(pixels, &palettes)).collect();
+    if next == assignment {
+      log::debug!("attribute assignment stable after {round} round(s)");
+      break;
+    }
+    assignment = next;
+    for (index, palette) in palettes.iter_mut().enumerate() {
+      let pooled: Vec<u8> = cells
+        .iter()
+        .zip(&assignment)
+        .filter(|(_, assigned)| **assigned == index)
+        .flat_map(|(pixels, _)| pixels.iter().copied())
+        .collect();
+      // unused sub-palettes keep their colors
+      if !pooled.is_empty() {
+        *palette = best(&pooled);
+      }
+    }
+  }
+  Attributes { palettes, assignment }
+}
+
+/// Dithers an RGB buffer so that every cell only uses the colors of one of the shared sub-palettes.
+pub fn dither(
+  buffer: &mut [u8],
+  method: DitherMethod,
+  palette: TieBroken,
+  width: u32,
+  height: u32,
+  layout: SpriteLayout,
+  options: &DitherOptions,
+) -> Result<(), DitherError> {
+  options.validate(buffer, width, height, 3)?;
+  let attributes = solve(buffer, width, height, palette, layout, options.alpha);
+  let quantizers: Vec<TieBroken> = attributes
+    .assignment
+    .iter()
+    .map(|&index| TieBroken {
+      colors: &attributes.palettes[index],
+      ..palette
+    })
+    .collect();
+  sprite::dither_with_palettes(buffer, method, &quantizers, width, height, layout.tile_size, options)
+}
+
+/// Returns the index of the sub-palette that represents the pixels with the least error.
+fn closest_palette(pixels: &[u8], palettes: &[Vec<Color>]) -> usize {
+  (0..palettes.len()).min_by_key(|&i| error(pixels, &palettes[i])).unwrap_or(0)
+}
+
+/// Sum of the squared distances of the pixels to their closest palette color.
+fn error(pixels: &[u8], palette: &[Color]) -> u64 {
+  pixels
+    .chunks_exact(3)
+    .map(|pixel| {
+      let color = Color::from(pixel);
+      let closest = palette::map_to_palette(color, palette).0;
+      [(color.r, closest.r), (color.g, closest.g), (color.b, closest.b)]
+        .iter()
+        .map(|&(a, b)| u64::from(a.abs_diff(b)).pow(2))
+        .sum::<u64>()
+    })
+    .sum()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::distance::DistanceMetric;
+  use crate::palette::{ColorPalette, TieBreak};
+  use std::collections::HashSet;
+
+  /// An image of 8x8 cells, each filled with two colors of the 16-color palette in stripes.
+  fn striped(cells: &[(usize, usize)]) -> (Vec<u8>, u32, u32) {
+    let colors = ColorPalette::COLOR16.colors();
+    let width = 8 * cells.len() as u32;
+    let mut buffer = Vec::new();
+    for y in 0..8 {
+      for x in 0..width {
+        let (a, b) = cells[x as usize / 8];
+        let color = colors[if y % 2 == 0 { a } else { b }];
+        buffer.extend([color.r, color.g, color.b]);
+      }
+    }
+    (buffer, width, 8)
+  }
+
+  #[test]
+  fn test_solve_shares_sub_palettes() {
+    let (buffer, width, height) = striped(&[(0, 2), (3, 8), (0, 2), (3, 8), (2, 0)]);
+    let layout = SpriteLayout {
+      tile_size: 8,
+      tile_colors: 2,
+      sub_palettes: Some(2),
+    };
+    let palette = TieBroken {
+      colors: ColorPalette::COLOR16.colors(),
+      metric: DistanceMetric::Rgb,
+      tie_break: TieBreak::default(),
+    };
+    let attributes = solve(&buffer, width, height, palette, layout, None);
+
+    assert_eq!(attributes.palettes.len(), 2);
+    assert_eq!(attributes.assignment[0], attributes.assignment[2]);
+    assert_eq!(attributes.assignment[0], attributes.assignment[4]);
+    assert_eq!(attributes.assignment[1], attributes.assignment[3]);
+    assert_ne!(attributes.assignment[0], attributes.assignment[1]);
+  }
+
+  #[test]
+  fn test_solve_uses_the_metric() {
+    // closest to entry 14 by the RGB distance, but to entry 13 in Oklab
+    let buffer = [55, 121, 177].repeat(64);
+    let layout = SpriteLayout {
+      tile_size: 8,
+      tile_colors: 1,
+      sub_palettes: Some(1),
+    };
+    let colors = ColorPalette::COLOR16.colors();
+    for (metric, expected) in [(DistanceMetric::Rgb, colors[14]), (DistanceMetric::Oklab, colors[13])] {
+      let palette = TieBroken {
+        colors,
+        metric,
+        tie_break: TieBreak::default(),
+      };
+      assert_eq!(solve(&buffer, 8, 8, palette, layout, None).palettes, [vec![expected]], "{metric:?}");
+    }
+  }
+
+  #[test]
+  fn test_dither_respects_constraints() {
+    let (buffer, width, height) = striped(&[(0, 2), (3, 8), (9, 10), (13, 14), (5, 6), (11, 12)]);
+    let mut dithered = buffer.clone();
+    let palette = TieBroken {
+      colors: ColorPalette::COLOR16.colors(),
+      metric: DistanceMetric::Rgb,
+      tie_break: TieBreak::Darker,
+    };
+    let layout = SpriteLayout {
+      tile_size: 8,
+      tile_colors: 3,
+      sub_palettes: Some(2),
+    };
+    dither(
+      &mut dithered,
+      DitherMethod::FloydSteinberg,
+      palette,
+      width,
+      height,
+      layout,
+      &DitherOptions::default(),
+    )
+    .unwrap();
+
+    let sub_palettes: Vec<HashSet<Color>> = tiles::grid(width, height, 8)
+      .map(|tile| tile.extract(&dithered, width, 3).chunks_exact(3).map(Color::from).collect())
+      .collect();
+    assert!(sub_palettes.iter().all(|used| used.len() <= 3));
+    // every cell uses a subset of one of at most two sub-palettes, so the union of all cells has at most 6 colors
+    let all: HashSet<Color> = sub_palettes.iter().flatten().copied().collect();
+    assert!(all.len() <= 6, "got {} colors", all.len());
+  }
+
+  #[test]
+  fn test_console_layout() {
+    assert_eq!(Console::Gbc.layout().sub_palettes, Some(8));
+    assert_eq!(Console::Nes.layout().tile_size, 16);
+    assert_eq!(Console::ZxSpectrum.layout().tile_colors, 2);
+  }
+}
@@ -0,0 +1,165 @@
+//! Contact sheets for reviewing a whole batch at a glance.
+//!
+//! Every image of a directory is shrunk to a thumbnail, dithered, and placed in a grid with its file
+//! name below. Thumbnails are dithered after shrinking, so the sheet shows the dither pattern at its
+//! real pixel size instead of a blurred down-scale of it.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use image::ImageFormat;
+
+use crate::dither::DitherMethod;
+use crate::error::DitherError;
+use crate::font;
+use crate::input;
+use crate::palette::{Color, ColorPalette};
+use crate::pipeline::{self, Image, Settings};
+
+/// Space around and between the cells of the sheet, in pixels.
+const PADDING: u32 = 8;
+/// Space between a thumbnail and its label, in pixels.
+const LABEL_GAP: u32 = 4;
+/// Background of the sheet, light gray so that white paper stands out.
+const BACKGROUND: u8 = 0xe0;
+const LABEL_COLOR: Color = Color { r: 0, g: 0, b: 0 };
+
+/// Returns the image files of a directory, sorted by name.
+///
+/// Files are recognized by their extension, subdirectories are not searched.
+pub fn images_in(dir: &Path) -> Result<Vec<PathBuf>, DitherError> {
+  let io_error = |source| DitherError::Io {
+    path: dir.to_path_buf(),
+    source,
+  };
+  let mut paths = Vec::new();
+  for entry in fs::read_dir(dir).map_err(io_error)? {
+    let path = entry.map_err(io_error)?.path();
+    if path.is_file() && is_image(&path) {
+      paths.push(path);
+    }
+  }
+  paths.sort();
+  Ok(paths)
+}
+
+fn is_image(path: &Path) -> bool {
+  #[cfg(feature = "raw")]
+  if crate::raw::is_raw(path) {
+    return true;
+  }
+  ImageFormat::from_path(path).is_ok_and(|format| format.reading_enabled())
+}
+
+/// Loads an image, shrinks it to fit a square of `size` pixels and dithers it.
+///
+/// Images that are already small enough keep their size, the alpha channel is dropped.
+pub fn thumbnail(path: &Path, size: u32, method: DitherMethod, palette: ColorPalette, settings: &Settings) -> Result<Image, DitherError> {
+  let image = input::load_with(path, &settings.load_options())?;
+  let mut thumbnail = shrink(image, size);
+  let colors = pipeline::palette_colors(path, palette, settings)?;
+  pipeline::process_with_colors(&mut thumbnail, method, &colors, settings)?;
+  Ok(thumbnail)
+}
+
+fn shrink(image: Image, size: u32) -> Image {
+  Image {
+    alpha: None,
+    ..image.shrink_to_fit(size, size)
+  }
+}
+
+/// Composes labeled thumbnails into a grid with `columns` cells per row.
+///
+/// Each cell is `size` pixels wide and high, thumbnails are centered in it and labels that do not fit
+/// are shortened.
+#[must_use]
+pub fn compose(thumbnails: &[(String, Image)], columns: u32, size: u32) -> Image {
+  let columns = columns.clamp(1, (thumbnails.len() as u32).max(1));
+  let rows = (thumbnails.len() as u32).div_ceil(columns);
+  let label_height = LABEL_GAP + font::GLYPH_HEIGHT;
+  let (cell_width, cell_height) = (size + PADDING, size + label_height + PADDING);
+  let width = PADDING + columns * cell_width;
+  let height = PADDING + rows * cell_height;
+  let mut sheet = Image {
+    buffer: vec![BACKGROUND; width as usize * height as usize * 3],
+    alpha: None,
+    width,
+    height,
+  };
+
+  for (i, (label, thumbnail)) in thumbnails.iter().enumerate() {
+    let (left, top) = (PADDING + i as u32 % columns * cell_width, PADDING + i as u32 / columns * cell_height);
+    // center the thumbnail in its square, thumbnails larger than the cell are cropped
+    let (x, y) = (left + size.saturating_sub(thumbnail.width) / 2, top + size.saturating_sub(thumbnail.height) / 2);
+    for row in 0..thumbnail.height.min(size) {
+      let src = (row * thumbnail.width) as usize * 3;
+      let dst = ((y + row) * width + x) as usize * 3;
+      let len = thumbnail.width.min(size) as usize * 3;
+      sheet.buffer[dst..dst + len].copy_from_slice(&thumbnail.buffer[src..src + len]);
+    }
+    font::draw_text(&mut sheet, &fit_label(label, size), left, top + size + LABEL_GAP, 1, LABEL_COLOR);
+  }
+  sheet
+}
+
+/// Shortens a label to fit the width, marking the cut with "..".
+fn fit_label(label: &str, width: u32) -> String {
+  if font::text_width(label, 1) <= width {
+    return label.to_string();
+  }
+  let keep = ((width + 1) / font::ADVANCE).saturating_sub(2) as usize;
+  label.chars().take(keep).chain("..".chars()).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn solid(value: u8, width: u32, height: u32) -> Image {
+    Image {
+      buffer: vec![value; (width * height * 3) as usize],
+      alpha: None,
+      width,
+      height,
+    }
+  }
+
+  #[test]
+  fn test_shrink_keeps_aspect_ratio() {
+    let thumbnail = shrink(solid(128, 400, 100), 100);
+    assert_eq!((thumbnail.width, thumbnail.height), (100, 25));
+    assert_eq!(thumbnail.buffer.len(), 100 * 25 * 3);
+
+    let small = shrink(solid(128, 20, 10), 100);
+    assert_eq!((small.width, small.height), (20, 10), "Small images should not be enlarged");
+  }
+
+  #[test]
+  fn test_compose_grid() {
+    let thumbnails: Vec<(String, Image)> = (0..5).map(|i| (format!("{i}.png"), solid(0, 10, 10))).collect();
+    let sheet = compose(&thumbnails, 3, 10);
+    // 3 columns and 2 rows of 10 pixel cells with labels below
+    assert_eq!(sheet.width, PADDING + 3 * (10 + PADDING));
+    assert_eq!(sheet.height, PADDING + 2 * (10 + LABEL_GAP + font::GLYPH_HEIGHT + PADDING));
+
+    let pixel = |x: u32, y: u32| sheet.buffer[((y * sheet.width + x) * 3) as usize];
+    assert_eq!(pixel(PADDING, PADDING), 0, "First thumbnail");
+    assert_eq!(pixel(PADDING - 1, PADDING), BACKGROUND);
+    assert_eq!(pixel(sheet.width - PADDING - 1, sheet.height - PADDING - 1), BACKGROUND, "Empty sixth cell");
+  }
+
+  #[test]
+  fn test_compose_fewer_images_than_columns() {
+    let sheet = compose(&[("a".to_string(), solid(0, 4, 4))], 8, 4);
+    assert_eq!(sheet.width, PADDING + 4 + PADDING);
+  }
+
+  #[test]
+  fn test_fit_label() {
+    assert_eq!(fit_label("short.png", 100), "short.png");
+    let label = fit_label("a_very_long_file_name.png", 40);
+    assert!(label.ends_with(".."));
+    assert!(font::text_width(&label, 1) <= 40, "{label:?} should fit");
+  }
+}
@@ -0,0 +1,410 @@
+//! Daemon answering dithering requests over a local socket.
+//!
+//! Editor plugins and build watchers dithering many small images pay the process startup for every
+//! invocation. The daemon stays running and accepts requests on a Unix domain socket instead, every
+//! connection is served on its own thread and may send any number of requests.
+//!
+//! Requests and responses are JSON objects, one per line. A request names an input file or carries
+//! raw pixels, plus the fields of a job (see [`crate::jobs`]):
+//!
+//! ```text
+//! {"input": "icon.png", "output": "icon_out.png", "method": "atkinson", "palette": "color8"}
+//! {"pixels": {"width": 2, "height": 1, "data": [0, 0, 0, 200, 200, 200]}, "method": "none"}
+//! ```
+//!
+//! Requests with an `output` are saved there and answered with its path, all others are answered with
+//! the dithered pixels. Failures are reported in the response, the connection stays usable:
+//!
+//! ```text
+//! {"ok":true,"output":"icon_out.png"}
+//! {"ok":true,"pixels":{"width":2,"height":1,"data":[0,0,0,255,255,255]},"tiles":[{"x":0,"y":0,"width":2,"height":1}]}
+//! {"ok":false,"error":"invalid request: expected value at line 1 column 1"}
+//! ```
+//!
+//! A connection keeps the result of its last request with raw pixels. When the next one has the same
+//! size and options, only the [tiles](crate::tiles) whose pixels changed are dithered again, and the
+//! response lists them so that a client can redraw just those.
+
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::dither::DitherMethod;
+use crate::error::DitherError;
+use crate::jobs::Job;
+use crate::output;
+use crate::palette::{Color, ColorPalette};
+use crate::pipeline::{self, Image, Settings};
+use crate::tiles::{self, IncrementalDither, Tile};
+
+/// A single request to the daemon.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Request {
+  /// Input image file path, required unless `pixels` are given
+  #[serde(default)]
+  pub input: Option<PathBuf>,
+  /// Raw pixels to dither instead of an input file
+  #[serde(default)]
+  pub pixels: Option<Pixels>,
+  /// Output image file path, the dithered pixels are returned without one
+  #[serde(default)]
+  pub output: Option<PathBuf>,
+  /// Dithering algorithm to use
+  #[serde(default)]
+  pub method: DitherMethod,
+  /// Color palette for quantization
+  #[serde(default)]
+  pub palette: ColorPalette,
+  /// Processing options, given as additional fields of the request
+  #[serde(flatten)]
+  pub settings: Settings,
+}
+
+/// Raw pixels of a request or response.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Pixels {
+  /// Image width in pixels
+  pub width: u32,
+  /// Image height in pixels
+  pub height: u32,
+  /// RGB pixel data, 3 bytes per pixel
+  pub data: Vec<u8>,
+  /// Alpha channel, 1 byte per pixel, if the image has one
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub alpha: Option<Vec<u8>>,
+}
+
+impl From<Pixels> for Image {
+  fn from(pixels: Pixels) -> Self {
+    Image {
+      buffer: pixels.data,
+      alpha: pixels.alpha,
+      width: pixels.width,
+      height: pixels.height,
+    }
+  }
+}
+
+impl From<Image> for Pixels {
+  fn from(image: Image) -> Self {
+    Pixels {
+      width: image.width,
+      height: image.height,
+      data: image.buffer,
+      alpha: image.alpha,
+    }
+  }
+}
+
+/// The answer to a request.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Response {
+  /// Whether the request succeeded
+  pub ok: bool,
+  /// Path the result was saved to
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub output: Option<PathBuf>,
+  /// The dithered pixels, for requests without an output
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub pixels: Option<Pixels>,
+  /// The tiles of the pixels that were dithered again, for raw pixels sent on a connection
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub tiles: Option<Vec<Tile>>,
+  /// Why the request failed
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub error: Option<String>,
+}
+
+impl Response {
+  /// Creates the response of a request whose result was saved to `output`.
+  #[must_use]
+  pub fn saved(output: PathBuf) -> Self {
+    Response {
+      ok: true,
+      output: Some(output),
+      ..Response::default()
+    }
+  }
+
+  /// Creates the response of a failed request.
+  #[must_use]
+  pub fn failure(error: impl ToString) -> Self {
+    Response {
+      error: Some(error.to_string()),
+      ..Response::default()
+    }
+  }
+}
+
+/// The state of a connection, the dithered image of its last request with raw pixels.
+#[derive(Debug, Default)]
+pub struct Session {
+  engine: Option<IncrementalDither>,
+}
+
+impl Session {
+  /// Answers a request line, updating the last dithered image of the connection for raw pixels.
+  pub fn handle(&mut self, line: &str) -> Response {
+    parse(line).and_then(|request| request.run_in(Some(self))).unwrap_or_else(Response::failure)
+  }
+
+  /// Dithers raw pixels, only the tiles that changed since the last request if it had the same options.
+  fn update(&mut self, image: &Image, method: DitherMethod, colors: Vec<Color>, settings: &Settings) -> Result<(&Image, Vec<Tile>), DitherError> {
+    let engine = match self.engine.take() {
+      Some(engine) if engine.matches(method, &colors, settings) => self.engine.insert(engine),
+      _ => self
+        .engine
+        .insert(IncrementalDither::new(method, colors, settings.clone(), tiles::DEFAULT_TILE_SIZE)),
+    };
+    let changed = engine.update(image)?;
+    Ok((engine.output(), changed))
+  }
+}
+
+impl Request {
+  /// Dithers the input file or the pixels of the request.
+  ///
+  /// Output options such as `format` or `indexed` only apply when the result is saved.
+  pub fn run(self) -> Result<Response, DitherError> {
+    self.run_in(None)
+  }
+
+  fn run_in(self, session: Option<&mut Session>) -> Result<Response, DitherError> {
+    let image = match (self.input, self.pixels) {
+      (Some(input), None) => {
+        let job = Job {
+          output: self.output,
+          method: self.method,
+          palette: self.palette,
+          settings: self.settings,
+          ..Job::new(input)
+        };
+        if job.output.is_some() {
+          return Ok(Response::saved(job.run()?));
+        }
+        job.render()?
+      }
+      (None, Some(pixels)) => {
+        let mut image = Image::from(pixels);
+        image.validate()?;
+        if self.settings.input_palette {
+          log::warn!("raw pixels have no embedded palette, using {:?}", self.palette);
+        }
+        let colors = self.settings.dither_colors(self.palette)?;
+        if let Some(session) = session {
+          let (image, changed) = session.update(&image, self.method, colors, &self.settings)?;
+          if let Some(output) = self.output {
+            output::save(image, &output, &self.settings)?;
+            return Ok(Response::saved(output));
+          }
+          return Ok(Response {
+            ok: true,
+            pixels: Some(image.clone().into()),
+            tiles: Some(changed),
+            ..Response::default()
+          });
+        }
+        pipeline::process_with_colors(&mut image, self.method, &colors, &self.settings)?;
+        if let Some(output) = self.output {
+          output::save(&image, &output, &self.settings)?;
+          return Ok(Response::saved(output));
+        }
+        image
+      }
+      (Some(_), Some(_)) => return Err(invalid("a request takes either an input or pixels, not both")),
+      (None, None) => return Err(invalid("a request needs an input or pixels")),
+    };
+    Ok(Response {
+      ok: true,
+      pixels: Some(image.into()),
+      ..Response::default()
+    })
+  }
+}
+
+fn invalid(message: impl ToString) -> DitherError {
+  DitherError::InvalidRequest { message: message.to_string() }
+}
+
+fn parse(line: &str) -> Result<Request, DitherError> {
+  let request: Request = serde_json::from_str(line).map_err(invalid)?;
+  log::debug!("request for {:?}", request.input.as_deref().unwrap_or(Path::new("<pixels>")));
+  Ok(request)
+}
+
+/// Answers a single request line, without the state of a connection.
+#[must_use]
+pub fn handle(line: &str) -> Response {
+  parse(line).and_then(Request::run).unwrap_or_else(Response::failure)
+}
+
+/// Answers the requests of a connection until the client closes it.
+///
+/// Empty lines are ignored, every other line is answered with exactly one response line. The
+/// connection keeps a [`Session`], so raw pixels only re-dither the tiles that changed.
+pub fn serve_connection(reader: impl BufRead, mut writer: impl Write) -> io::Result<()> {
+  let mut session = Session::default();
+  for line in reader.lines() {
+    let line = line?;
+    if line.trim().is_empty() {
+      continue;
+    }
+    let response = session.handle(&line);
+    if let Some(error) = &response.error {
+      log::warn!("request failed: {error}");
+    }
+    let mut encoded = serde_json::to_vec(&response).map_err(io::Error::other)?;
+    encoded.push(b'\n');
+    writer.write_all(&encoded)?;
+    writer.flush()?;
+  }
+  Ok(())
+}
+
+/// Returns the socket path used when none is given, `dithers.sock` in the runtime or temporary directory.
+#[must_use]
+pub fn default_socket() -> PathBuf {
+  std::env::var_os("XDG_RUNTIME_DIR")
+    .map(PathBuf::from)
+    .unwrap_or_else(std::env::temp_dir)
+    .join("dithers.sock")
+}
+
+/// Listens on a Unix domain socket and serves every connection on its own thread, until the process ends.
+///
+/// A stale socket file left by a daemon that did not exit cleanly is replaced, a socket another
+/// daemon still listens on is an error.
+#[cfg(unix)]
+pub fn run(socket: &Path) -> Result<(), DitherError> {
+  use std::os::unix::net::{UnixListener, UnixStream};
+
+  let io_error = |source| DitherError::Io {
+    path: socket.to_path_buf(),
+    source,
+  };
+  if socket.exists() {
+    if UnixStream::connect(socket).is_ok() {
+      return Err(io_error(io::Error::new(io::ErrorKind::AddrInUse, "another daemon is listening")));
+    }
+    std::fs::remove_file(socket).map_err(io_error)?;
+  }
+  let listener = UnixListener::bind(socket).map_err(io_error)?;
+  log::info!("Listening on {:?}", socket);
+  for stream in listener.incoming() {
+    let stream = match stream {
+      Ok(stream) => stream,
+      Err(err) => {
+        log::warn!("failed to accept a connection: {err}");
+        continue;
+      }
+    };
+    std::thread::spawn(move || {
+      let reader = match stream.try_clone() {
+        Ok(reader) => io::BufReader::new(reader),
+        Err(err) => return log::warn!("failed to read from a connection: {err}"),
+      };
+      if let Err(err) = serve_connection(reader, stream) {
+        log::warn!("connection closed: {err}");
+      }
+    });
+  }
+  Ok(())
+}
+
+/// Listening needs Unix domain sockets, which this platform does not have.
+#[cfg(not(unix))]
+pub fn run(socket: &Path) -> Result<(), DitherError> {
+  Err(DitherError::Io {
+    path: socket.to_path_buf(),
+    source: io::Error::new(io::ErrorKind::Unsupported, "the daemon needs Unix domain sockets"),
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_handle_pixels() {
+    let response = handle(r#"{"pixels": {"width": 2, "height": 1, "data": [10, 10, 10, 200, 200, 200]}, "method": "none"}"#);
+    assert_eq!(
+      response,
+      Response {
+        ok: true,
+        pixels: Some(Pixels {
+          width: 2,
+          height: 1,
+          data: vec![0, 0, 0, 255, 255, 255],
+          alpha: None,
+        }),
+        ..Response::default()
+      }
+    );
+  }
+
+  #[test]
+  fn test_handle_reports_errors() {
+    assert!(handle("not json").error.is_some());
+    assert!(
+      handle(r#"{"input": "a.png", "colour": "red"}"#)
+        .error
+        .unwrap()
+        .contains("unknown field `colour`")
+    );
+    assert_eq!(handle("{}").error.as_deref(), Some("invalid request: a request needs an input or pixels"));
+    let response = handle(r#"{"pixels": {"width": 2, "height": 2, "data": [0, 0, 0]}}"#);
+    assert!(!response.ok);
+    assert!(response.error.unwrap().contains("does not hold 2x2 pixels"));
+    assert!(handle(r#"{"input": "missing.png"}"#).error.unwrap().contains("missing.png"));
+  }
+
+  #[test]
+  fn test_serve_connection_answers_every_line() {
+    let requests = "{\"pixels\": {\"width\": 1, \"height\": 1, \"data\": [9, 9, 9]}}\n\n{\"method\": \"atkinson\"}\n";
+    let mut written = Vec::new();
+    serve_connection(requests.as_bytes(), &mut written).unwrap();
+    let responses: Vec<Response> = String::from_utf8(written)
+      .unwrap()
+      .lines()
+      .map(|line| serde_json::from_str(line).unwrap())
+      .collect();
+    assert_eq!(responses.len(), 2);
+    assert_eq!(responses[0].pixels.as_ref().map(|pixels| pixels.data.clone()), Some(vec![0, 0, 0]));
+    assert!(!responses[1].ok);
+  }
+
+  #[test]
+  fn test_session_redithers_changed_tiles() {
+    let request = |data: &[u8]| {
+      let pixels = Pixels {
+        width: 100,
+        height: 70,
+        data: data.to_vec(),
+        alpha: None,
+      };
+      format!(
+        r#"{{"pixels": {}, "method": "bayer8x8", "palette": "color8"}}"#,
+        serde_json::to_string(&pixels).unwrap()
+      )
+    };
+    let mut data: Vec<u8> = (0..100 * 70 * 3).map(|i| (i * 7 % 256) as u8).collect();
+    let mut session = Session::default();
+    let first = session.handle(&request(&data));
+    assert_eq!(first.tiles.as_deref().map(<[Tile]>::len), Some(1));
+    data[(66 * 100 + 80) * 3] ^= 0xff;
+
+    let second = session.handle(&request(&data));
+    let tile = Tile {
+      x: 64,
+      y: 64,
+      width: 36,
+      height: 6,
+    };
+    assert_eq!(second.tiles, Some(vec![tile]));
+    assert_eq!(second.pixels, handle(&request(&data)).pixels, "Patched tiles should match a full dither");
+    let other = request(&data).replace("bayer8x8", "bayer4x4");
+    assert_eq!(session.handle(&other).tiles.as_deref().map(<[Tile]>::len), Some(1), "Other options start over");
+  }
+}
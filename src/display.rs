@@ -0,0 +1,87 @@
+//! Response curves of target displays.
+//!
+//! Dithering assumes the output is shown on an sRGB display. Many devices respond differently to their
+//! drive values: the duty cycle of PWM driven LED matrices is linear in light, e-ink panels and OLEDs
+//! have their own curves. Mapping the image through a lookup table from sRGB to the device's drive
+//! values before quantization makes dithered midtones come out at the intended brightness on the device.
+
+use crate::adjust;
+
+/// Preset response curves of common displays.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum Display {
+  /// Standard sRGB monitor, output is unchanged
+  Srgb,
+  /// E-ink panel, gamma 1.5
+  Eink,
+  /// PWM driven LED matrix, light is linear in the drive value (gamma 1.0)
+  Led,
+  /// OLED panel driven with PWM, gamma 2.4
+  Oled,
+}
+
+serde_value_enum!(Display);
+
+impl Display {
+  /// Returns the gamma of the display, `None` for sRGB which has its own curve.
+  #[must_use]
+  pub fn gamma(self) -> Option<f32> {
+    match self {
+      Display::Srgb => None,
+      Display::Eink => Some(1.5),
+      Display::Led => Some(1.0),
+      Display::Oled => Some(2.4),
+    }
+  }
+}
+
+/// Builds the table mapping sRGB values to the drive values of a display with the given gamma.
+///
+/// A display with gamma `g` emits `drive ^ g` of its full light, so the drive value showing the light of
+/// an sRGB value is its linear light raised to `1 / g`.
+#[must_use]
+pub fn response_lut(gamma: f32) -> [u8; 256] {
+  let mut lut = [0; 256];
+  for (value, entry) in lut.iter_mut().enumerate() {
+    let linear = adjust::srgb_to_linear(value as u8);
+    *entry = (linear.powf(1.0 / gamma) * 255.0).round().clamp(0.0, 255.0) as u8;
+  }
+  lut
+}
+
+/// Maps every channel of an RGB buffer through a lookup table.
+pub fn apply_lut(buffer: &mut [u8], lut: &[u8; 256]) {
+  buffer.iter_mut().for_each(|value| *value = lut[usize::from(*value)]);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_response_lut_keeps_black_and_white() {
+    for gamma in [1.0, 1.5, 2.4] {
+      let lut = response_lut(gamma);
+      assert_eq!((lut[0], lut[255]), (0, 255));
+      assert!(lut.windows(2).all(|pair| pair[0] <= pair[1]), "The table should be monotonic");
+    }
+  }
+
+  #[test]
+  fn test_linear_display_darkens_midtones() {
+    // sRGB 128 is about 21.6% of the light, which a linear display shows at that drive value
+    assert_eq!(response_lut(1.0)[128], 55);
+    // a display close to sRGB barely changes anything
+    assert!(response_lut(2.2)[128].abs_diff(128) <= 2);
+  }
+
+  #[test]
+  fn test_apply_lut() {
+    let mut lut = [0; 256];
+    lut[10] = 20;
+    lut[255] = 255;
+    let mut buffer = vec![10, 255, 3];
+    apply_lut(&mut buffer, &lut);
+    assert_eq!(buffer, vec![20, 255, 0]);
+  }
+}
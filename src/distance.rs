@@ -0,0 +1,294 @@
+//! Color distances the closest palette color is chosen by.
+//!
+//! The default compares the RGB values as they are stored, which is fast and exact, but weighs all
+//! differences alike: the eye tells dark colors apart far better than light ones, and some hue shifts
+//! better than others. The perceptual metrics compare colors in a color space that follows the eye.
+
+use crate::adjust;
+use crate::palette::{Color, Quantizer, TieBreak};
+
+/// Ways to measure how different two colors are.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
+pub enum DistanceMetric {
+  /// Euclidean distance of the RGB values
+  #[default]
+  Rgb,
+  /// RGB distance weighted by the mean red level ("redmean"), a cheap approximation of perceptual distance
+  Redmean,
+  /// Euclidean distance in CIELAB (CIE76), matches dark colors and skin tones better
+  Lab,
+  /// Euclidean distance in OKLab, nearly as accurate as CIEDE2000 and as fast as CIELAB
+  Oklab,
+  /// CIEDE2000 color difference, the most accurate and slowest
+  Ciede2000,
+}
+
+serde_value_enum!(DistanceMetric);
+
+impl DistanceMetric {
+  /// Returns the coordinates of a color that this metric compares.
+  #[must_use]
+  pub fn coordinates(self, color: Color) -> [f32; 3] {
+    match self {
+      DistanceMetric::Rgb | DistanceMetric::Redmean => [color.r, color.g, color.b].map(f32::from),
+      DistanceMetric::Lab | DistanceMetric::Ciede2000 => rgb_to_lab(color),
+      DistanceMetric::Oklab => rgb_to_oklab(color),
+    }
+  }
+
+  /// Returns how different two colors are from their [`coordinates`](Self::coordinates), squared for
+  /// the Euclidean metrics since only the order of differences matters.
+  #[must_use]
+  pub fn difference(self, a: [f32; 3], b: [f32; 3]) -> f32 {
+    match self {
+      DistanceMetric::Redmean => {
+        // green counts most, red more in reddish and blue more in bluish colors
+        let red = (a[0] + b[0]) / 2.0;
+        (2.0 + red / 256.0) * (a[0] - b[0]).powi(2) + 4.0 * (a[1] - b[1]).powi(2) + (2.0 + (255.0 - red) / 256.0) * (a[2] - b[2]).powi(2)
+      }
+      DistanceMetric::Ciede2000 => ciede2000(a, b),
+      _ => (a[0] - b[0]).powi(2) + (a[1] - b[1]).powi(2) + (a[2] - b[2]).powi(2),
+    }
+  }
+
+  /// Returns how different two colors are.
+  #[must_use]
+  pub fn distance(self, a: Color, b: Color) -> f32 {
+    self.difference(self.coordinates(a), self.coordinates(b))
+  }
+}
+
+/// Converts an sRGB color to CIELAB relative to the D65 white point of sRGB.
+#[must_use]
+pub fn rgb_to_lab(color: Color) -> [f32; 3] {
+  const WHITE: [f32; 3] = [0.950_47, 1.0, 1.088_83];
+  // linear sRGB to XYZ
+  const MATRIX: [[f32; 3]; 3] = [
+    [0.412_456_4, 0.357_576_1, 0.180_437_5],
+    [0.212_672_9, 0.715_152_2, 0.072_175],
+    [0.019_333_9, 0.119_192, 0.950_304_1],
+  ];
+  let linear = [color.r, color.g, color.b].map(adjust::srgb_to_linear);
+  let f = |row: usize| {
+    let t = (MATRIX[row][0] * linear[0] + MATRIX[row][1] * linear[1] + MATRIX[row][2] * linear[2]) / WHITE[row];
+    if t > (6.0f32 / 29.0).powi(3) {
+      t.cbrt()
+    } else {
+      t / (3.0 * (6.0f32 / 29.0).powi(2)) + 4.0 / 29.0
+    }
+  };
+  let (fx, fy, fz) = (f(0), f(1), f(2));
+  [116.0 * fy - 16.0, 500.0 * (fx - fy), 200.0 * (fy - fz)]
+}
+
+/// Converts an sRGB color to OKLab, see Björn Ottosson, "A perceptual color space for image processing" (2020).
+#[must_use]
+pub fn rgb_to_oklab(color: Color) -> [f32; 3] {
+  // linear sRGB to the cone responses, and their cube roots to lightness and the two opponent axes
+  const LMS: [[f32; 3]; 3] = [
+    [0.412_221_47, 0.536_332_55, 0.051_445_995],
+    [0.211_903_5, 0.680_699_5, 0.107_396_96],
+    [0.088_302_46, 0.281_718_85, 0.629_978_7],
+  ];
+  const LAB: [[f32; 3]; 3] = [
+    [0.210_454_26, 0.793_617_8, -0.004_072_047],
+    [1.977_998_5, -2.428_592_2, 0.450_593_7],
+    [0.025_904_037, 0.782_771_77, -0.808_675_77],
+  ];
+  let multiply = |matrix: &[[f32; 3]; 3], v: [f32; 3]| matrix.map(|row| row[0] * v[0] + row[1] * v[1] + row[2] * v[2]);
+  let linear = [color.r, color.g, color.b].map(adjust::srgb_to_linear);
+  multiply(&LAB, multiply(&LMS, linear).map(f32::cbrt))
+}
+
+/// Returns the CIEDE2000 color difference of two CIELAB colors, with the weights of graphic arts (1, 1, 1).
+///
+/// See Sharma, Wu and Dalal, "The CIEDE2000 Color-Difference Formula: Implementation Notes,
+/// Supplementary Test Data, and Mathematical Observations" (2005).
+#[must_use]
+pub fn ciede2000([l1, a1, b1]: [f32; 3], [l2, a2, b2]: [f32; 3]) -> f32 {
+  let mean_c = (a1.hypot(b1) + a2.hypot(b2)) / 2.0;
+  let g = 0.5 * (1.0 - (mean_c.powi(7) / (mean_c.powi(7) + 25f32.powi(7))).sqrt());
+  let (a1, a2) = ((1.0 + g) * a1, (1.0 + g) * a2);
+  let (c1, c2) = (a1.hypot(b1), a2.hypot(b2));
+  // hue angles in degrees, 0 for neutral colors
+  let hue = |a: f32, b: f32| {
+    if a == 0.0 && b == 0.0 {
+      0.0
+    } else {
+      b.atan2(a).to_degrees().rem_euclid(360.0)
+    }
+  };
+  let (h1, h2) = (hue(a1, b1), hue(a2, b2));
+
+  let delta_l = l2 - l1;
+  let delta_c = c2 - c1;
+  let delta_h = if c1 * c2 == 0.0 {
+    0.0
+  } else if (h2 - h1).abs() <= 180.0 {
+    h2 - h1
+  } else if h2 <= h1 {
+    h2 - h1 + 360.0
+  } else {
+    h2 - h1 - 360.0
+  };
+  let delta_big_h = 2.0 * (c1 * c2).sqrt() * (delta_h / 2.0).to_radians().sin();
+
+  let mean_l = (l1 + l2) / 2.0;
+  let mean_c = (c1 + c2) / 2.0;
+  let mean_h = if c1 * c2 == 0.0 {
+    h1 + h2
+  } else if (h1 - h2).abs() <= 180.0 {
+    (h1 + h2) / 2.0
+  } else if h1 + h2 < 360.0 {
+    (h1 + h2 + 360.0) / 2.0
+  } else {
+    (h1 + h2 - 360.0) / 2.0
+  };
+  let t = 1.0 - 0.17 * (mean_h - 30.0).to_radians().cos() + 0.24 * (2.0 * mean_h).to_radians().cos() + 0.32 * (3.0 * mean_h + 6.0).to_radians().cos()
+    - 0.20 * (4.0 * mean_h - 63.0).to_radians().cos();
+  let delta_theta = 30.0 * (-((mean_h - 275.0) / 25.0).powi(2)).exp();
+  let r_c = 2.0 * (mean_c.powi(7) / (mean_c.powi(7) + 25f32.powi(7))).sqrt();
+  let s_l = 1.0 + 0.015 * (mean_l - 50.0).powi(2) / (20.0 + (mean_l - 50.0).powi(2)).sqrt();
+  let s_c = 1.0 + 0.045 * mean_c;
+  let s_h = 1.0 + 0.015 * mean_c * t;
+  let r_t = -(2.0 * delta_theta).to_radians().sin() * r_c;
+
+  let (l, c, h) = (delta_l / s_l, delta_c / s_c, delta_big_h / s_h);
+  (l * l + c * c + h * h + r_t * c * h).sqrt()
+}
+
+/// A palette searched with a distance metric, its colors are converted once.
+#[derive(Clone, Debug)]
+pub struct MetricPalette<'a> {
+  colors: &'a [Color],
+  coordinates: Vec<[f32; 3]>,
+  metric: DistanceMetric,
+  tie_break: TieBreak,
+}
+
+impl<'a> MetricPalette<'a> {
+  /// Prepares a palette for searches with `metric`, equally close colors are chosen with `tie_break`.
+  #[must_use]
+  pub fn new(colors: &'a [Color], metric: DistanceMetric, tie_break: TieBreak) -> Self {
+    MetricPalette {
+      colors,
+      coordinates: colors.iter().map(|&color| metric.coordinates(color)).collect(),
+      metric,
+      tie_break,
+    }
+  }
+
+  /// Returns the palette color closest to `color`.
+  ///
+  /// # Panics
+  ///
+  /// If the palette is empty.
+  #[must_use]
+  pub fn nearest(&self, color: Color) -> &'a Color {
+    let point = self.metric.coordinates(color);
+    let mut best = (f32::INFINITY, &self.colors[0]);
+    for (candidate, coordinates) in self.colors.iter().zip(&self.coordinates) {
+      let d = self.metric.difference(point, *coordinates);
+      if d < best.0 || (d == best.0 && self.tie_break.prefers(candidate, best.1)) {
+        best = (d, candidate);
+      }
+    }
+    best.1
+  }
+}
+
+impl Quantizer for MetricPalette<'_> {
+  fn quantize(&self, color: Color) -> Color {
+    *self.nearest(color)
+  }
+
+  fn palette(&self) -> Option<&[Color]> {
+    Some(self.colors)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::palette::{self, PALETTE_MONOCHROME};
+
+  fn gray(value: u8) -> Color {
+    Color { r: value, g: value, b: value }
+  }
+
+  #[test]
+  fn test_rgb_to_lab() {
+    let close = |a: [f32; 3], b: [f32; 3]| a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 0.05);
+    assert!(close(rgb_to_lab(gray(0)), [0.0, 0.0, 0.0]));
+    assert!(close(rgb_to_lab(gray(255)), [100.0, 0.0, 0.0]));
+    assert!(close(rgb_to_lab(Color { r: 255, g: 0, b: 0 }), [53.24, 80.09, 67.20]));
+    assert!(close(rgb_to_lab(Color { r: 0, g: 0, b: 255 }), [32.30, 79.19, -107.86]));
+  }
+
+  #[test]
+  fn test_rgb_to_oklab() {
+    let close = |a: [f32; 3], b: [f32; 3]| a.iter().zip(&b).all(|(a, b)| (a - b).abs() < 1e-3);
+    assert!(close(rgb_to_oklab(gray(0)), [0.0, 0.0, 0.0]));
+    assert!(close(rgb_to_oklab(gray(255)), [1.0, 0.0, 0.0]));
+    assert!(close(rgb_to_oklab(Color { r: 255, g: 0, b: 0 }), [0.627_96, 0.224_86, 0.125_85]));
+    assert!(close(rgb_to_oklab(Color { r: 0, g: 0, b: 255 }), [0.452_01, -0.032_46, -0.311_53]));
+  }
+
+  #[test]
+  fn test_ciede2000_reference_pairs() {
+    // pairs 1, 7, 17, 25 and 34 of the test data of Sharma, Wu and Dalal
+    let pairs = [
+      ([50.0, 2.6772, -79.7751], [50.0, 0.0, -82.7485], 2.0425),
+      ([50.0, 0.0, 0.0], [50.0, -1.0, 2.0], 2.3669),
+      ([50.0, 2.5, 0.0], [73.0, 25.0, -18.0], 27.1492),
+      ([60.2574, -34.0099, 36.2677], [60.4626, -34.1751, 39.4387], 1.2644),
+      ([22.7233, 20.0904, -46.6940], [23.0331, 14.9730, -42.5619], 2.0373),
+    ];
+    for (a, b, expected) in pairs {
+      assert!((ciede2000(a, b) - expected).abs() < 1e-3, "{a:?} and {b:?}: {}", ciede2000(a, b));
+      assert!((ciede2000(b, a) - expected).abs() < 1e-3, "the difference is symmetric");
+    }
+    assert_eq!(ciede2000([40.0, 10.0, -5.0], [40.0, 10.0, -5.0]), 0.0);
+  }
+
+  #[test]
+  fn test_redmean_weights_the_channels() {
+    let black = gray(0);
+    let [red, green, blue] = [0xff0000u32, 0x00ff00, 0x0000ff].map(Color::from);
+    let redmean = |a, b| DistanceMetric::Redmean.distance(a, b);
+    assert!(redmean(black, red) < redmean(black, blue), "blue differences count more in dark colors");
+    assert!(redmean(black, blue) < redmean(black, green));
+    assert_eq!(redmean(red, blue), redmean(blue, red));
+    assert_eq!(redmean(red, red), 0.0);
+    assert_eq!(redmean(black, Color { r: 0, g: 10, b: 0 }), 400.0);
+  }
+
+  #[test]
+  fn test_rgb_metric_matches_map_to_palette() {
+    let colors = palette::ColorPalette::COLOR16.colors();
+    let search = MetricPalette::new(colors, DistanceMetric::Rgb, TieBreak::Darker);
+    for i in 0..1024u32 {
+      let color = Color::from(i.wrapping_mul(2_654_435_761) & 0xff_ffff);
+      assert_eq!(*search.nearest(color), *palette::map_to_palette(color, colors).0);
+    }
+  }
+
+  #[test]
+  fn test_lab_splits_grays_by_lightness() {
+    // middle gray in lightness is darker than half the RGB range
+    let rgb = MetricPalette::new(&PALETTE_MONOCHROME, DistanceMetric::Rgb, TieBreak::Darker);
+    let lab = MetricPalette::new(&PALETTE_MONOCHROME, DistanceMetric::Lab, TieBreak::Darker);
+    assert_eq!(*rgb.nearest(gray(125)), gray(0));
+    assert_eq!(*lab.nearest(gray(125)), gray(255));
+    assert_eq!(*lab.nearest(gray(110)), gray(0));
+    let ciede2000 = MetricPalette::new(&PALETTE_MONOCHROME, DistanceMetric::Ciede2000, TieBreak::Darker);
+    assert_eq!(*ciede2000.nearest(gray(125)), gray(255));
+    let oklab = MetricPalette::new(&PALETTE_MONOCHROME, DistanceMetric::Oklab, TieBreak::Darker);
+    assert_eq!(
+      *oklab.nearest(gray(110)),
+      gray(255),
+      "OKLab lightness is perceptually uniform, 50% is about sRGB 99"
+    );
+    assert_eq!(*oklab.nearest(gray(95)), gray(0));
+  }
+}
@@ -1,10 +1,15 @@
 //! Image dithering algorithms and utilities.
 
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::path::{Path, PathBuf};
 
-use image::{ExtendedColorType, ImageReader};
-
-use crate::palette::{Color, ColorPalette, PALETTE_8C, PALETTE_16C, PALETTE_MONOCHROME, map_to_palette};
+use crate::distance::{DistanceMetric, MetricPalette};
+use crate::error::DitherError;
+use crate::halftone::Screen;
+use crate::noise::Rng;
+use crate::palette::{Color, ColorPalette, Quantizer, TieBreak};
+use crate::pipeline::{Image, Settings};
+use crate::{dotdiffusion, halftone, input, knoll, output, riemersma, yliluoma, zhoufang};
 
 /// Available dithering methods.
 #[derive(clap::ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
@@ -20,9 +25,54 @@ pub enum DitherMethod {
   Sierra,
   TwoRowSierra,
   SierraLite,
+  StevensonArce,
+  ShiauFan,
+  ShiauFan2,
+  Fan,
+  Riemersma,
+  ZhouFang,
+  DotDiffusion,
+  Bayer,
   Bayer2x2,
   Bayer4x4,
   Bayer8x8,
+  Clustered4x4,
+  Clustered8x8,
+  Halftone,
+  Random,
+  InterleavedGradient,
+  Yliluoma1,
+  Yliluoma2,
+  Yliluoma3,
+  Knoll,
+}
+
+serde_value_enum!(DitherMethod);
+
+impl DitherMethod {
+  /// Returns whether the result of a pixel only depends on its own color and position.
+  ///
+  /// Such methods do not diffuse errors, so any part of an image can be dithered on its own.
+  #[must_use]
+  pub fn is_ordered(&self) -> bool {
+    matches!(
+      self,
+      DitherMethod::None
+        | DitherMethod::Bayer
+        | DitherMethod::Bayer2x2
+        | DitherMethod::Bayer4x4
+        | DitherMethod::Bayer8x8
+        | DitherMethod::Clustered4x4
+        | DitherMethod::Clustered8x8
+        | DitherMethod::Halftone
+        | DitherMethod::Random
+        | DitherMethod::InterleavedGradient
+        | DitherMethod::Yliluoma1
+        | DitherMethod::Yliluoma2
+        | DitherMethod::Yliluoma3
+        | DitherMethod::Knoll
+    )
+  }
 }
 
 pub struct QuantizationError {
@@ -31,6 +81,18 @@ pub struct QuantizationError {
   pub b: f32,
 }
 
+impl QuantizationError {
+  /// Returns the per-channel error made by replacing `original` with `quantized`.
+  #[must_use]
+  pub fn between(original: Color, quantized: Color) -> Self {
+    QuantizationError {
+      r: original.r as f32 - quantized.r as f32,
+      g: original.g as f32 - quantized.g as f32,
+      b: original.b as f32 - quantized.b as f32,
+    }
+  }
+}
+
 pub const FLOYD_STEINBERG: [f32; 6] = [0.0, 0.0, 7.0 / 16.0, 3.0 / 16.0, 5.0 / 16.0, 1.0 / 16.0];
 pub const JARVIS: [f32; 15] = [
   0.0,
@@ -51,94 +113,148 @@ pub const JARVIS: [f32; 15] = [
 ];
 // Bayer(n)=( 4⋅Bayer(n−1)+0 4⋅Bayer(n−1)+2 )
 //            4⋅Bayer(n−1)+3 4⋅Bayer(n−1)+1
-// Bayer(0)
+// Bayer(0)=( 0 )
+/// Offsets of the four quadrants in the recursion above.
+const BAYER_QUADRANTS: [[usize; 2]; 2] = [[0, 2], [3, 1]];
+
+/// Size of the matrix of [`DitherMethod::Bayer`] by default.
+pub const DEFAULT_BAYER_SIZE: u32 = 8;
+
+/// Largest size of a Bayer matrix, 65536 thresholds are more than any 8-bit channel can tell apart.
+pub const MAX_BAYER_SIZE: u32 = 256;
+
+/// Returns the index of a cell in the Bayer matrix of a power-of-two `size`, from 0 to `size * size - 1`.
+const fn bayer_index(x: usize, y: usize, size: usize) -> usize {
+  if size <= 1 {
+    return 0;
+  }
+  let half = size / 2;
+  4 * bayer_index(x % half, y % half, half) + BAYER_QUADRANTS[y / half][x / half]
+}
+
+/// The Bayer matrix of a power-of-two `SIZE` at compile time, `AREA` is `SIZE * SIZE`.
+const fn bayer_table<const SIZE: usize, const AREA: usize>() -> [f32; AREA] {
+  let mut matrix = [0.0; AREA];
+  let mut i = 0;
+  while i < AREA {
+    matrix[i] = bayer_index(i % SIZE, i / SIZE, SIZE) as f32 / AREA as f32;
+    i += 1;
+  }
+  matrix
+}
+
+/// Returns the Bayer matrix of the given size row by row, with thresholds from 0 to 1.
+///
+/// The size is rounded up to a power of two and limited to [`MAX_BAYER_SIZE`].
+#[must_use]
+pub fn bayer(size: u32) -> Vec<f32> {
+  let size = size.clamp(1, MAX_BAYER_SIZE).next_power_of_two() as usize;
+  (0..size * size)
+    .map(|i| bayer_index(i % size, i / size, size) as f32 / (size * size) as f32)
+    .collect()
+}
+
+/// Parses the size of a Bayer matrix, a power of two from 1 to [`MAX_BAYER_SIZE`].
+pub fn parse_bayer_size(value: &str) -> Result<u32, String> {
+  let size: u32 = value.parse().map_err(|e| format!("{e}"))?;
+  if size.is_power_of_two() && size <= MAX_BAYER_SIZE {
+    Ok(size)
+  } else {
+    Err(format!("{size} is not a power of two from 1 to {MAX_BAYER_SIZE}"))
+  }
+}
+
 /// 2x2 Bayer matrix for ordered dithering
-pub const BAYER2X2: [f32; 4] = [0.0, 2.0 / 4.0, 3.0 / 4.0, 1.0 / 4.0];
+pub const BAYER2X2: [f32; 4] = bayer_table::<2, 4>();
 /// 4x4 Bayer(1) matrix for ordered dithering
-pub const BAYER4X4: [f32; 16] = [
-  0.0,
-  8.0 / 16.0,
-  2.0 / 16.0,
-  10.0 / 16.0,
+pub const BAYER4X4: [f32; 16] = bayer_table::<4, 16>();
+/// 8x8 Bayer(2) matrix for ordered dithering
+pub const BAYER8X8: [f32; 64] = bayer_table::<8, 64>();
+/// 4x4 clustered-dot matrix, the thresholds grow in a spiral from the center like a halftone dot
+pub const CLUSTERED4X4: [f32; 16] = [
   12.0 / 16.0,
-  4.0 / 16.0,
-  14.0 / 16.0,
+  5.0 / 16.0,
   6.0 / 16.0,
-  3.0 / 16.0,
-  11.0 / 16.0,
+  13.0 / 16.0,
+  4.0 / 16.0,
+  0.0,
   1.0 / 16.0,
-  9.0 / 16.0,
-  15.0 / 16.0,
   7.0 / 16.0,
-  13.0 / 16.0,
-  5.0 / 16.0,
+  11.0 / 16.0,
+  3.0 / 16.0,
+  2.0 / 16.0,
+  8.0 / 16.0,
+  15.0 / 16.0,
+  10.0 / 16.0,
+  9.0 / 16.0,
+  14.0 / 16.0,
 ];
-/// 8x8 Bayer(2) matrix for ordered dithering
-pub const BAYER8X8: [f32; 64] = [
-  0.0,
-  32.0 / 64.0,
-  8.0 / 64.0,
-  40.0 / 64.0,
-  2.0 / 64.0,
-  34.0 / 64.0,
-  10.0 / 64.0,
-  42.0 / 64.0,
-  48.0 / 64.0,
-  16.0 / 64.0,
-  56.0 / 64.0,
+/// 8x8 clustered-dot matrix with two dots per cell, a halftone screen at 45 degrees
+pub const CLUSTERED8X8: [f32; 64] = [
   24.0 / 64.0,
-  50.0 / 64.0,
-  18.0 / 64.0,
-  58.0 / 64.0,
-  26.0 / 64.0,
+  10.0 / 64.0,
   12.0 / 64.0,
-  44.0 / 64.0,
-  4.0 / 64.0,
-  36.0 / 64.0,
+  26.0 / 64.0,
+  35.0 / 64.0,
+  47.0 / 64.0,
+  49.0 / 64.0,
+  37.0 / 64.0,
+  8.0 / 64.0,
+  0.0,
+  2.0 / 64.0,
   14.0 / 64.0,
-  46.0 / 64.0,
-  6.0 / 64.0,
-  38.0 / 64.0,
-  60.0 / 64.0,
-  28.0 / 64.0,
-  52.0 / 64.0,
-  20.0 / 64.0,
-  62.0 / 64.0,
-  30.0 / 64.0,
-  54.0 / 64.0,
+  45.0 / 64.0,
+  59.0 / 64.0,
+  61.0 / 64.0,
+  51.0 / 64.0,
   22.0 / 64.0,
-  3.0 / 64.0,
-  35.0 / 64.0,
-  11.0 / 64.0,
+  6.0 / 64.0,
+  4.0 / 64.0,
+  16.0 / 64.0,
   43.0 / 64.0,
-  1.0 / 64.0,
+  57.0 / 64.0,
+  63.0 / 64.0,
+  53.0 / 64.0,
+  30.0 / 64.0,
+  20.0 / 64.0,
+  18.0 / 64.0,
+  28.0 / 64.0,
   33.0 / 64.0,
-  9.0 / 64.0,
   41.0 / 64.0,
-  51.0 / 64.0,
-  19.0 / 64.0,
-  59.0 / 64.0,
-  27.0 / 64.0,
-  49.0 / 64.0,
-  17.0 / 64.0,
-  57.0 / 64.0,
+  55.0 / 64.0,
+  39.0 / 64.0,
+  34.0 / 64.0,
+  46.0 / 64.0,
+  48.0 / 64.0,
+  36.0 / 64.0,
   25.0 / 64.0,
+  11.0 / 64.0,
+  13.0 / 64.0,
+  27.0 / 64.0,
+  44.0 / 64.0,
+  58.0 / 64.0,
+  60.0 / 64.0,
+  50.0 / 64.0,
+  9.0 / 64.0,
+  1.0 / 64.0,
+  3.0 / 64.0,
   15.0 / 64.0,
-  47.0 / 64.0,
+  42.0 / 64.0,
+  56.0 / 64.0,
+  62.0 / 64.0,
+  52.0 / 64.0,
+  23.0 / 64.0,
   7.0 / 64.0,
-  39.0 / 64.0,
-  13.0 / 64.0,
-  45.0 / 64.0,
   5.0 / 64.0,
-  37.0 / 64.0,
-  63.0 / 64.0,
+  17.0 / 64.0,
+  32.0 / 64.0,
+  40.0 / 64.0,
+  54.0 / 64.0,
+  38.0 / 64.0,
   31.0 / 64.0,
-  55.0 / 64.0,
-  23.0 / 64.0,
-  61.0 / 64.0,
-  29.0 / 64.0,
-  53.0 / 64.0,
   21.0 / 64.0,
+  19.0 / 64.0,
+  29.0 / 64.0,
 ];
 
 pub const SIMPLE2D: [f32; 4] = [0.0, 0.5, 0.5, 0.0];
@@ -206,6 +322,43 @@ pub const TWOROWSIERRA: [f32; 10] = [
   1.0 / 16.0,
 ];
 pub const SIERRALITE: [f32; 6] = [0.0, 0.0, 2.0 / 4.0, 1.0 / 4.0, 1.0 / 4.0, 0.0];
+/// Stevenson-Arce kernel, designed for hexagonal grids: on a rectangular one it reaches every other column
+pub const STEVENSON_ARCE: [f32; 28] = [
+  0.0,
+  0.0,
+  0.0,
+  0.0,
+  0.0,
+  32.0 / 200.0,
+  0.0,
+  12.0 / 200.0,
+  0.0,
+  26.0 / 200.0,
+  0.0,
+  30.0 / 200.0,
+  0.0,
+  16.0 / 200.0,
+  0.0,
+  12.0 / 200.0,
+  0.0,
+  26.0 / 200.0,
+  0.0,
+  12.0 / 200.0,
+  0.0,
+  5.0 / 200.0,
+  0.0,
+  12.0 / 200.0,
+  0.0,
+  12.0 / 200.0,
+  0.0,
+  5.0 / 200.0,
+];
+/// Shiau-Fan kernel with four weights, a Floyd-Steinberg alternative with fewer worm artifacts
+pub const SHIAU_FAN: [f32; 8] = [0.0, 0.0, 0.0, 4.0 / 8.0, 1.0 / 8.0, 1.0 / 8.0, 2.0 / 8.0, 0.0];
+/// Shiau-Fan kernel with five weights, wider than [`SHIAU_FAN`]
+pub const SHIAU_FAN2: [f32; 10] = [0.0, 0.0, 0.0, 0.0, 8.0 / 16.0, 1.0 / 16.0, 1.0 / 16.0, 2.0 / 16.0, 4.0 / 16.0, 0.0];
+/// Fan (1993) kernel, 7/16 to the right and 1/16, 3/16, 5/16 to the row below, from two columns left to straight down
+pub const FAN: [f32; 8] = [0.0, 0.0, 0.0, 7.0 / 16.0, 1.0 / 16.0, 3.0 / 16.0, 5.0 / 16.0, 0.0];
 
 /// Opens an image file and returns its RGB buffer, width, and height.
 ///
@@ -214,69 +367,374 @@ pub const SIERRALITE: [f32; 6] = [0.0, 0.0, 2.0 / 4.0, 1.0 / 4.0, 1.0 / 4.0, 0.0
 /// This function will panic if:
 /// - The image file cannot be opened
 /// - The image cannot be decoded
+///
+/// Use [`try_open_image`] to handle these errors instead.
 #[must_use]
-pub fn open_image(path: &PathBuf) -> (Vec<u8>, u32, u32) {
-  //let image = ImageReader::open(path).unwrap().decode().unwrap().into_rgba8();
-  let image = ImageReader::open(path).unwrap().decode().unwrap().into_rgb8();
+pub fn open_image(path: &Path) -> (Vec<u8>, u32, u32) {
+  try_open_image(path).unwrap_or_else(|err| panic!("{err}"))
+}
 
-  let (width, height) = image.dimensions();
-  let buffer = image.into_raw();
-  (buffer, width, height)
+/// Like [`open_image`], but returns an error for missing files and images that cannot be decoded.
+pub fn try_open_image(path: &Path) -> Result<(Vec<u8>, u32, u32), DitherError> {
+  let image = input::load(path)?;
+  Ok((image.buffer, image.width, image.height))
 }
 
+/// Saves an RGB buffer as an image file in the format given by the file extension.
+///
+/// The file is replaced atomically, on failure a previous file at `path` is left unchanged.
+///
+/// # Panics
+///
+/// This function will panic if:
+/// - The buffer does not hold `width` x `height` pixels
+/// - The extension is not a supported format
+/// - The file cannot be written
+///
+/// Use [`try_save_image`] to handle these errors instead.
 pub fn save_image(buffer: Vec<u8>, path: PathBuf, width: u32, height: u32) {
-  let _ = image::save_buffer(path, &buffer, width, height, ExtendedColorType::Rgb8);
+  try_save_image(&buffer, &path, width, height).unwrap_or_else(|err| panic!("{err}"));
 }
 
-pub fn dither(buffer: &mut [u8], dither_type: DitherMethod, color_palette: ColorPalette, width: u32, height: u32) {
-  // get the color palette as slice
-  let color_palette = match color_palette {
-    ColorPalette::Monochrome => &PALETTE_MONOCHROME[..],
-    ColorPalette::COLOR8 => &PALETTE_8C[..],
-    ColorPalette::COLOR16 => &PALETTE_16C[..],
+/// Like [`save_image`], but returns an error for buffers that do not match the dimensions,
+/// unsupported extensions and write errors.
+pub fn try_save_image(buffer: &[u8], path: &Path, width: u32, height: u32) -> Result<(), DitherError> {
+  validate_buffer(buffer, width, height, 3)?;
+  let image = Image {
+    buffer: buffer.to_vec(),
+    alpha: None,
+    width,
+    height,
   };
+  output::save(&image, path, &Settings::default())
+}
+
+/// Orders in which error diffusion visits the pixels.
+///
+/// The error always flows in the scan direction, which shows in the texture, and some devices scan
+/// their output in another order than left to right, top to bottom.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
+pub enum ScanDirection {
+  /// Rows from top to bottom, each from left to right
+  #[default]
+  LeftToRight,
+  /// Rows from top to bottom, each from right to left
+  RightToLeft,
+  /// Rows from bottom to top, each from left to right
+  BottomToTop,
+  /// Mean of all four horizontal and vertical directions, quantized once
+  Averaged,
+}
+
+serde_value_enum!(ScanDirection);
+
+/// Additional inputs controlling how [`dither_with`] processes a buffer.
+#[derive(Clone, Copy, Debug)]
+pub struct DitherOptions<'a> {
+  /// Alpha channel with one value per pixel.
+  ///
+  /// Fully transparent pixels (alpha 0) are left untouched and neither receive nor spread quantization error,
+  /// so dithered sprites keep clean transparent borders.
+  pub alpha: Option<&'a [u8]>,
+  /// Position of the buffer's top left pixel within the whole image.
+  ///
+  /// Ordered methods use it to continue their pattern, so that separately dithered tiles line up.
+  pub origin: (u32, u32),
+  /// Order in which error diffusion visits the pixels, ordered methods, Riemersma, Zhou-Fang and dot diffusion ignore it.
+  pub scan: ScanDirection,
+  /// Halftone screen of [`DitherMethod::Halftone`], other methods ignore it.
+  pub screen: Screen,
+  /// Seed of [`DitherMethod::Random`] and [`DitherMethod::ZhouFang`], equal seeds give equal outputs.
+  pub seed: u64,
+  /// Size of the matrix of [`DitherMethod::Bayer`], see [`bayer`], other methods ignore it.
+  pub bayer_size: u32,
+}
 
+impl Default for DitherOptions<'_> {
+  fn default() -> Self {
+    DitherOptions {
+      alpha: None,
+      origin: (0, 0),
+      scan: ScanDirection::default(),
+      screen: Screen::default(),
+      seed: 0,
+      bayer_size: DEFAULT_BAYER_SIZE,
+    }
+  }
+}
+
+impl DitherOptions<'_> {
+  /// Checks that `buffer` holds `width` x `height` pixels of `channels` bytes and the alpha channel one value per pixel.
+  pub fn validate(&self, buffer: &[u8], width: u32, height: u32, channels: usize) -> Result<(), DitherError> {
+    validate_buffer(buffer, width, height, channels)?;
+    match self.alpha {
+      Some(alpha) => validate_buffer(alpha, width, height, 1),
+      None => Ok(()),
+    }
+  }
+
+  /// Returns whether the pixel with the given index is fully transparent and must be skipped.
+  fn is_transparent(&self, pixel: usize) -> bool {
+    self.alpha.is_some_and(|alpha| alpha[pixel] == 0)
+  }
+}
+
+/// Dithers an RGB buffer in place to a built-in [`ColorPalette`], a custom [`Palette`](crate::palette::Palette) or any list of colors.
+///
+/// The closest palette color of every pixel is chosen by `metric`, [`DistanceMetric::Rgb`] is the fastest.
+///
+/// # Panics
+///
+/// Panics if the buffer does not hold `width` x `height` pixels or the list of colors is empty.
+pub fn dither(buffer: &mut [u8], dither_type: DitherMethod, color_palette: impl AsRef<[Color]>, metric: DistanceMetric, width: u32, height: u32) {
+  let colors = color_palette.as_ref();
+  let options = DitherOptions::default();
+  match metric {
+    DistanceMetric::Rgb => dither_with(buffer, dither_type, colors, width, height, &options),
+    _ => dither_with(
+      buffer,
+      dither_type,
+      &MetricPalette::new(colors, metric, TieBreak::default()),
+      width,
+      height,
+      &options,
+    ),
+  }
+  .unwrap_or_else(|err| panic!("{err}"));
+}
+
+/// Like [`dither`], but checks the size of the buffer and that there are colors first instead of panicking.
+pub fn try_dither(
+  buffer: &mut [u8],
+  dither_type: DitherMethod,
+  color_palette: impl AsRef<[Color]>,
+  metric: DistanceMetric,
+  width: u32,
+  height: u32,
+) -> Result<(), DitherError> {
+  let colors = color_palette.as_ref();
+  if colors.is_empty() {
+    return Err(DitherError::EmptyPalette);
+  }
+  let options = DitherOptions::default();
+  match metric {
+    DistanceMetric::Rgb => dither_with(buffer, dither_type, colors, width, height, &options),
+    _ => dither_with(
+      buffer,
+      dither_type,
+      &MetricPalette::new(colors, metric, TieBreak::default()),
+      width,
+      height,
+      &options,
+    ),
+  }
+}
+
+/// Checks that a buffer holds exactly `width` x `height` pixels of `channels` bytes each.
+///
+/// Sizes that overflow the address space are reported as a mismatch as well.
+pub fn validate_buffer(buffer: &[u8], width: u32, height: u32, channels: usize) -> Result<(), DitherError> {
+  let expected = (width as usize).checked_mul(height as usize).and_then(|pixels| pixels.checked_mul(channels));
+  if expected == Some(buffer.len()) {
+    return Ok(());
+  }
+  Err(DitherError::InvalidBuffer {
+    len: buffer.len(),
+    width,
+    height,
+    channels,
+  })
+}
+
+/// Dithers an RGB buffer with an arbitrary [`Quantizer`], see [`DitherOptions`] for the available controls.
+///
+/// A quantizer can be a plain list of colors (`&[Color]`) or a bit-depth [`Target`](crate::target::Target).
+/// The size of the buffer and of the alpha channel are checked once before dithering, so a mismatch
+/// is reported as [`DitherError::InvalidBuffer`] instead of a panic in the middle of the image.
+pub fn dither_with<Q: Quantizer + ?Sized>(
+  buffer: &mut [u8],
+  dither_type: DitherMethod,
+  quantizer: &Q,
+  width: u32,
+  height: u32,
+  options: &DitherOptions,
+) -> Result<(), DitherError> {
+  options.validate(buffer, width, height, 3)?;
   match dither_type {
     DitherMethod::None => {
       // Just quantize without dithering
       for cy in 0..height {
         for cx in 0..width {
+          if options.is_transparent((cy * width + cx) as usize) {
+            continue;
+          }
           let i = ((cy * width + cx) * 3) as usize;
-          let (new_color, _) = map_to_palette(Color::from(&buffer[i..i + 3]), color_palette);
+          let new_color = quantizer.quantize(Color::from(&buffer[i..i + 3]));
           buffer[i] = new_color.r;
           buffer[i + 1] = new_color.g;
           buffer[i + 2] = new_color.b;
         }
+        log::trace!("quantized row {}/{}", cy + 1, height);
       }
     }
-    DitherMethod::Bayer2x2 | DitherMethod::Bayer4x4 | DitherMethod::Bayer8x8 => {
-      apply_bayer_dithering(buffer, dither_type, color_palette, width, height);
+    DitherMethod::Bayer
+    | DitherMethod::Bayer2x2
+    | DitherMethod::Bayer4x4
+    | DitherMethod::Bayer8x8
+    | DitherMethod::Clustered4x4
+    | DitherMethod::Clustered8x8 => {
+      apply_bayer_dithering(buffer, dither_type, quantizer, width, height, options);
     }
+    DitherMethod::Riemersma => riemersma::dither(buffer, quantizer, width, height, options),
+    DitherMethod::ZhouFang => zhoufang::dither(buffer, quantizer, width, height, options),
+    DitherMethod::DotDiffusion => dotdiffusion::dither(buffer, quantizer, width, height, options),
+    DitherMethod::Halftone => halftone::dither(buffer, quantizer, width, height, options),
+    DitherMethod::Random => apply_thresholds(buffer, quantizer, width, height, options, |x, y| random_threshold(options.seed, x, y)),
+    DitherMethod::InterleavedGradient => apply_thresholds(buffer, quantizer, width, height, options, interleaved_gradient_noise),
+    DitherMethod::Yliluoma1 | DitherMethod::Yliluoma2 | DitherMethod::Yliluoma3 => yliluoma::dither(buffer, dither_type, quantizer, width, height, options)?,
+    DitherMethod::Knoll => knoll::dither(buffer, quantizer, width, height, options)?,
     _ => {
-      apply_error_diffusion(buffer, dither_type, color_palette, width, height);
+      apply_error_diffusion(buffer, dither_type, quantizer, width, height, options);
     }
   }
+  Ok(())
 }
 
-fn apply_error_diffusion(buffer: &mut [u8], dither_type: DitherMethod, color_palette: &[Color], width: u32, height: u32) {
-  // Define kernel patterns for each algorithm
-  let (kernel, kernel_width, kernel_height, kernel_x_offset) = match dither_type {
-    DitherMethod::FloydSteinberg => (&FLOYD_STEINBERG[..], 3, 2, 1),
-    DitherMethod::Simple2D => (&SIMPLE2D[..], 2, 2, 0),
-    DitherMethod::Jarvis => (&JARVIS[..], 5, 3, 2),
-    DitherMethod::Atkinson => (&ATKINSON[..], 4, 3, 1),
-    DitherMethod::Stucki => (&STUCKI[..], 5, 3, 2),
-    DitherMethod::Burkes => (&BURKES[..], 5, 2, 2),
-    DitherMethod::Sierra => (&SIERRA[..], 5, 3, 2),
-    DitherMethod::TwoRowSierra => (&TWOROWSIERRA[..], 5, 2, 2),
-    DitherMethod::SierraLite => (&SIERRALITE[..], 3, 2, 1),
-    _ => return, // Should not reach here
+/// Dithers an alpha channel to fully opaque (255) or fully transparent (0) values.
+///
+/// This produces stipple transparency for formats and hardware that only support binary alpha. The
+/// pattern follows `options` like the colors, so it continues from [`DitherOptions::origin`] and uses
+/// the same seed, screen and Bayer size; [`DitherOptions::alpha`] is ignored.
+pub fn dither_alpha(alpha: &mut [u8], dither_type: DitherMethod, width: u32, height: u32, options: &DitherOptions) -> Result<(), DitherError> {
+  // treat the alpha channel as a grayscale image and dither it to black and white
+  let mut gray: Vec<u8> = alpha.iter().flat_map(|&a| [a, a, a]).collect();
+  let options = DitherOptions { alpha: None, ..*options };
+  dither_with(&mut gray, dither_type, ColorPalette::Monochrome.colors(), width, height, &options)?;
+  for (a, pixel) in alpha.iter_mut().zip(gray.chunks_exact(3)) {
+    *a = pixel[0];
+  }
+  Ok(())
+}
+
+/// Alpha below which pixels become transparent in [`threshold_alpha`] by default.
+pub const DEFAULT_ALPHA_THRESHOLD: u8 = 128;
+
+/// Makes an alpha channel fully transparent (0) below `threshold` and fully opaque (255) otherwise.
+pub fn threshold_alpha(alpha: &mut [u8], threshold: u8) {
+  for a in alpha {
+    *a = if *a < threshold { 0 } else { 255 };
+  }
+}
+
+fn apply_error_diffusion<Q: Quantizer + ?Sized>(buffer: &mut [u8], dither_type: DitherMethod, quantizer: &Q, width: u32, height: u32, options: &DitherOptions) {
+  // other directions mirror the image, diffuse it in the standard order and mirror it back
+  match options.scan {
+    ScanDirection::LeftToRight => diffuse(buffer, dither_type, quantizer, width, height, options, None),
+    ScanDirection::RightToLeft => flipped(buffer, width, height, options, (true, false), |buffer, options| {
+      diffuse(buffer, dither_type, quantizer, width, height, options, None)
+    }),
+    ScanDirection::BottomToTop => flipped(buffer, width, height, options, (false, true), |buffer, options| {
+      diffuse(buffer, dither_type, quantizer, width, height, options, None)
+    }),
+    ScanDirection::Averaged => {
+      // the colors each pass presents to the quantizer, including the diffused error, are averaged
+      // and quantized once, so no single direction dominates the texture
+      let mut sum = vec![0u32; buffer.len()];
+      for flip in [(false, false), (true, false), (false, true), (true, true)] {
+        let mut pass = buffer.to_vec();
+        let mut corrected = vec![0u8; buffer.len()];
+        flipped(&mut pass, width, height, options, flip, |pass, options| {
+          diffuse(pass, dither_type, quantizer, width, height, options, Some(&mut corrected))
+        });
+        mirror(&mut corrected, 3, width, height, flip);
+        for (sum, &value) in sum.iter_mut().zip(&corrected) {
+          *sum += u32::from(value);
+        }
+      }
+      for (i, pixel) in buffer.chunks_exact_mut(3).enumerate() {
+        if options.is_transparent(i) {
+          continue;
+        }
+        let mean = |c: usize| ((sum[i * 3 + c] + 2) / 4) as u8;
+        let new_color = quantizer.quantize(Color {
+          r: mean(0),
+          g: mean(1),
+          b: mean(2),
+        });
+        pixel.copy_from_slice(&[new_color.r, new_color.g, new_color.b]);
+      }
+    }
+  }
+}
+
+/// Mirrors an image (and its alpha channel) as given by `flip` (horizontal, vertical), runs `run` on it
+/// and mirrors the buffer back.
+fn flipped(buffer: &mut [u8], width: u32, height: u32, options: &DitherOptions, flip: (bool, bool), run: impl FnOnce(&mut [u8], &DitherOptions)) {
+  let alpha = options.alpha.map(|alpha| {
+    let mut alpha = alpha.to_vec();
+    mirror(&mut alpha, 1, width, height, flip);
+    alpha
+  });
+  mirror(buffer, 3, width, height, flip);
+  run(
+    buffer,
+    &DitherOptions {
+      alpha: alpha.as_deref(),
+      ..*options
+    },
+  );
+  mirror(buffer, 3, width, height, flip);
+}
+
+/// Mirrors pixel data with `channels` bytes per pixel horizontally and/or vertically, in place.
+fn mirror(data: &mut [u8], channels: usize, width: u32, height: u32, (horizontal, vertical): (bool, bool)) {
+  let (width, height) = (width as usize, height as usize);
+  let row_len = width * channels;
+  if horizontal {
+    for row in data.chunks_exact_mut(row_len) {
+      for x in 0..width / 2 {
+        for c in 0..channels {
+          row.swap(x * channels + c, (width - 1 - x) * channels + c);
+        }
+      }
+    }
+  }
+  if vertical {
+    for y in 0..height / 2 {
+      let (top, bottom) = data.split_at_mut((height - 1 - y) * row_len);
+      top[y * row_len..(y + 1) * row_len].swap_with_slice(&mut bottom[..row_len]);
+    }
+  }
+}
+
+/// Diffuses the quantization error from the top left to the bottom right.
+///
+/// `corrected` receives the color of every pixel, including the diffused error, as it was quantized.
+fn diffuse<Q: Quantizer + ?Sized>(
+  buffer: &mut [u8],
+  dither_type: DitherMethod,
+  quantizer: &Q,
+  width: u32,
+  height: u32,
+  options: &DitherOptions,
+  mut corrected: Option<&mut [u8]>,
+) {
+  let Some((kernel, kernel_width, kernel_height, kernel_x_offset)) = diffusion_kernel(dither_type) else {
+    return; // Should not reach here
   };
 
   for cy in 0..height {
     for cx in 0..width {
+      if options.is_transparent((cy * width + cx) as usize) {
+        continue;
+      }
       let i = ((cy * width + cx) * 3) as usize;
-      let (new_color, qe) = map_to_palette(Color::from(&buffer[i..i + 3]), color_palette);
+      let color = Color::from(&buffer[i..i + 3]);
+      if let Some(corrected) = corrected.as_deref_mut() {
+        corrected[i..i + 3].copy_from_slice(&buffer[i..i + 3]);
+      }
+      let new_color = quantizer.quantize(color);
+      let qe = QuantizationError::between(color, new_color);
       buffer[i] = new_color.r;
       buffer[i + 1] = new_color.g;
       buffer[i + 2] = new_color.b;
@@ -301,6 +759,11 @@ fn apply_error_diffusion(buffer: &mut [u8], dither_type: DitherMethod, color_pal
             continue;
           }
 
+          // Transparent pixels do not absorb error, it is not visible there
+          if options.is_transparent(ny as usize * width as usize + nx as usize) {
+            continue;
+          }
+
           let ni = ((ny as u32 * width + nx as u32) * 3) as usize;
           buffer[ni] = (f32::from(buffer[ni]) + (qe.r * kernel[ki])).round().clamp(0.0, 255.0) as u8;
           buffer[ni + 1] = (f32::from(buffer[ni + 1]) + (qe.g * kernel[ki])).round().clamp(0.0, 255.0) as u8;
@@ -308,35 +771,116 @@ fn apply_error_diffusion(buffer: &mut [u8], dither_type: DitherMethod, color_pal
         }
       }
     }
+    log::trace!("diffused row {}/{}", cy + 1, height);
   }
 }
 
-fn apply_bayer_dithering(buffer: &mut [u8], dither_type: DitherMethod, color_palette: &[Color], width: u32, height: u32) {
-  let (matrix, matrix_size) = match dither_type {
-    DitherMethod::Bayer2x2 => (&BAYER2X2[..], 2),
-    DitherMethod::Bayer4x4 => (&BAYER4X4[..], 4),
-    DitherMethod::Bayer8x8 => (&BAYER8X8[..], 8),
-    _ => return,
+/// Returns the error diffusion kernel of a method: the weights, the width and height of the kernel and
+/// the column of the current pixel within its first row. `None` for methods that do not diffuse with a kernel.
+#[must_use]
+pub fn diffusion_kernel(dither_type: DitherMethod) -> Option<(&'static [f32], u32, u32, u32)> {
+  Some(match dither_type {
+    DitherMethod::FloydSteinberg => (&FLOYD_STEINBERG[..], 3, 2, 1),
+    DitherMethod::Simple2D => (&SIMPLE2D[..], 2, 2, 0),
+    DitherMethod::Jarvis => (&JARVIS[..], 5, 3, 2),
+    DitherMethod::Atkinson => (&ATKINSON[..], 4, 3, 1),
+    DitherMethod::Stucki => (&STUCKI[..], 5, 3, 2),
+    DitherMethod::Burkes => (&BURKES[..], 5, 2, 2),
+    DitherMethod::Sierra => (&SIERRA[..], 5, 3, 2),
+    DitherMethod::TwoRowSierra => (&TWOROWSIERRA[..], 5, 2, 2),
+    DitherMethod::SierraLite => (&SIERRALITE[..], 3, 2, 1),
+    DitherMethod::StevensonArce => (&STEVENSON_ARCE[..], 7, 4, 3),
+    DitherMethod::ShiauFan => (&SHIAU_FAN[..], 4, 2, 2),
+    DitherMethod::ShiauFan2 => (&SHIAU_FAN2[..], 5, 2, 3),
+    DitherMethod::Fan => (&FAN[..], 4, 2, 2),
+    _ => return None,
+  })
+}
+
+/// Returns the threshold matrix of a Bayer or clustered-dot method and its size. `None` for other methods.
+///
+/// [`DitherMethod::Bayer`] generates the matrix of `bayer_size`, the fixed sizes are borrowed.
+#[must_use]
+pub fn bayer_matrix(dither_type: DitherMethod, bayer_size: u32) -> Option<(Cow<'static, [f32]>, usize)> {
+  let borrowed = |matrix: &'static [f32], size: usize| Some((Cow::Borrowed(matrix), size));
+  match dither_type {
+    DitherMethod::Bayer => {
+      let matrix = bayer(bayer_size);
+      let size = matrix.len().isqrt();
+      Some((Cow::Owned(matrix), size))
+    }
+    DitherMethod::Bayer2x2 => borrowed(&BAYER2X2[..], 2),
+    DitherMethod::Bayer4x4 => borrowed(&BAYER4X4[..], 4),
+    DitherMethod::Bayer8x8 => borrowed(&BAYER8X8[..], 8),
+    DitherMethod::Clustered4x4 => borrowed(&CLUSTERED4X4[..], 4),
+    DitherMethod::Clustered8x8 => borrowed(&CLUSTERED8X8[..], 8),
+    _ => None,
+  }
+}
+
+/// Applies a Bayer threshold to a channel value, the result is quantized afterwards.
+#[must_use]
+pub fn apply_threshold(value: u8, threshold: f32) -> u8 {
+  ((f32::from(value) / 255.0 + threshold - 0.5).clamp(0.0, 1.0) * 255.0) as u8
+}
+
+/// Returns the uniform threshold from 0 to 1 of [`DitherMethod::Random`] at a pixel of the whole image.
+///
+/// The threshold only depends on the seed and the position, so separately dithered parts line up. The
+/// position is hashed before the seed is mixed in, so another seed gives another field and not the
+/// same thresholds at swapped positions.
+#[must_use]
+pub fn random_threshold(seed: u64, x: u32, y: u32) -> f32 {
+  let position = Rng::new(u64::from(y) << 32 | u64::from(x)).next_u64();
+  Rng::new(position ^ seed).next_f32()
+}
+
+/// Returns the interleaved gradient noise (Jimenez 2014) from 0 to 1 at a pixel of the whole image.
+///
+/// The noise of real-time graphics: computed from the position alone without any table, and with
+/// its repetitions spread along the diagonals finer than white noise and less regular than Bayer.
+#[must_use]
+pub fn interleaved_gradient_noise(x: u32, y: u32) -> f32 {
+  (52.982_918 * (0.067_110_56 * x as f32 + 0.005_837_15 * y as f32).fract()).fract()
+}
+
+fn apply_bayer_dithering<Q: Quantizer + ?Sized>(buffer: &mut [u8], dither_type: DitherMethod, quantizer: &Q, width: u32, height: u32, options: &DitherOptions) {
+  let Some((matrix, matrix_size)) = bayer_matrix(dither_type, options.bayer_size) else {
+    return;
   };
+  let size = matrix_size as u32;
+  apply_thresholds(buffer, quantizer, width, height, options, |x, y| matrix[(y % size * size + x % size) as usize]);
+}
 
+/// Quantizes every pixel after applying the threshold that `threshold` returns for its position in the whole image.
+fn apply_thresholds<Q: Quantizer + ?Sized>(
+  buffer: &mut [u8],
+  quantizer: &Q,
+  width: u32,
+  height: u32,
+  options: &DitherOptions,
+  threshold: impl Fn(u32, u32) -> f32,
+) {
   for cy in 0..height {
     for cx in 0..width {
+      if options.is_transparent((cy * width + cx) as usize) {
+        continue;
+      }
       let i = ((cy * width + cx) * 3) as usize;
-      let matrix_x = (cx % matrix_size as u32) as usize;
-      let matrix_y = (cy % matrix_size as u32) as usize;
-      let threshold = matrix[matrix_y * matrix_size + matrix_x];
+      let threshold = threshold(cx + options.origin.0, cy + options.origin.1);
 
       // Apply threshold to each color channel
       let mut color = Color::from(&buffer[i..i + 3]);
-      color.r = ((f32::from(color.r) / 255.0 + threshold - 0.5).clamp(0.0, 1.0) * 255.0) as u8;
-      color.g = ((f32::from(color.g) / 255.0 + threshold - 0.5).clamp(0.0, 1.0) * 255.0) as u8;
-      color.b = ((f32::from(color.b) / 255.0 + threshold - 0.5).clamp(0.0, 1.0) * 255.0) as u8;
+      color.r = apply_threshold(color.r, threshold);
+      color.g = apply_threshold(color.g, threshold);
+      color.b = apply_threshold(color.b, threshold);
 
-      let (new_color, _) = map_to_palette(color, color_palette);
+      let new_color = quantizer.quantize(color);
       buffer[i] = new_color.r;
       buffer[i + 1] = new_color.g;
       buffer[i + 2] = new_color.b;
     }
+    log::trace!("thresholded row {}/{}", cy + 1, height);
   }
 }
 
@@ -353,6 +897,47 @@ mod tests {
     assert_eq!(error.b, 0.0);
   }
 
+  #[test]
+  fn test_quantization_error_between() {
+    let error = QuantizationError::between(Color { r: 10, g: 200, b: 0 }, Color { r: 0, g: 255, b: 0 });
+    assert_eq!(error.r, 10.0);
+    assert_eq!(error.g, -55.0);
+    assert_eq!(error.b, 0.0);
+  }
+
+  #[test]
+  fn test_try_dither_rejects_mismatched_buffers() {
+    let mut buffer = vec![128; 4 * 4 * 3];
+    assert!(try_dither(&mut buffer, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, DistanceMetric::Rgb, 4, 4).is_ok());
+    let err = try_dither(&mut buffer, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, DistanceMetric::Rgb, 5, 4).unwrap_err();
+    assert!(matches!(
+      err,
+      DitherError::InvalidBuffer {
+        len: 48,
+        width: 5,
+        height: 4,
+        channels: 3
+      }
+    ));
+
+    let alpha = [255; 3];
+    let options = DitherOptions {
+      alpha: Some(&alpha),
+      ..DitherOptions::default()
+    };
+    assert!(dither_with(&mut buffer, DitherMethod::Bayer2x2, &PALETTE_MONOCHROME, 4, 4, &options).is_err());
+    assert!(
+      dither_with(&mut buffer, DitherMethod::FloydSteinberg, &PALETTE_MONOCHROME, 4, 4, &options).is_err(),
+      "Checked before the first pixel"
+    );
+    assert!(validate_buffer(&[], u32::MAX, u32::MAX, usize::MAX).is_err(), "Overflowing sizes never match");
+    let no_colors: Vec<Color> = Vec::new();
+    assert!(matches!(
+      try_dither(&mut buffer, DitherMethod::None, no_colors, DistanceMetric::Rgb, 4, 4),
+      Err(DitherError::EmptyPalette)
+    ));
+  }
+
   #[test]
   fn test_dither_method_default() {
     assert_eq!(DitherMethod::default(), DitherMethod::FloydSteinberg);
@@ -386,6 +971,18 @@ mod tests {
 
     // Sierra Lite: 2x3 = 6 elements
     assert_eq!(SIERRALITE.len(), 6);
+
+    // Stevenson-Arce: 4x7 = 28 elements
+    assert_eq!(STEVENSON_ARCE.len(), 28);
+
+    // Shiau-Fan: 2x4 = 8 elements
+    assert_eq!(SHIAU_FAN.len(), 8);
+
+    // Shiau-Fan 2: 2x5 = 10 elements
+    assert_eq!(SHIAU_FAN2.len(), 10);
+
+    // Fan: 2x4 = 8 elements
+    assert_eq!(FAN.len(), 8);
   }
 
   #[test]
@@ -393,6 +990,69 @@ mod tests {
     assert_eq!(BAYER2X2.len(), 4); // 2x2
     assert_eq!(BAYER4X4.len(), 16); // 4x4
     assert_eq!(BAYER8X8.len(), 64); // 8x8
+    assert_eq!(CLUSTERED4X4.len(), 16); // 4x4
+    assert_eq!(CLUSTERED8X8.len(), 64); // 8x8
+  }
+
+  #[test]
+  fn test_bayer_generator_follows_the_recursion() {
+    let expected = [0, 8, 2, 10, 12, 4, 14, 6, 3, 11, 1, 9, 15, 7, 13, 5].map(|index| index as f32 / 16.0);
+    assert_eq!(BAYER4X4, expected);
+    assert_eq!(BAYER8X8[..8], [0, 32, 8, 40, 2, 34, 10, 42].map(|index| index as f32 / 64.0));
+    assert_eq!(bayer(2), BAYER2X2);
+    assert_eq!(bayer(4), BAYER4X4);
+    assert_eq!(bayer(8), BAYER8X8);
+    // every quadrant holds the smaller matrix, its indices times four plus the offset of the quadrant
+    let (small, large) = (bayer(16), bayer(32));
+    for y in 0..32 {
+      for x in 0..32 {
+        let offset = [[0.0, 2.0], [3.0, 1.0]][y / 16][x / 16];
+        assert_eq!(large[y * 32 + x] * 1024.0, small[y % 16 * 16 + x % 16] * 1024.0 + offset, "({x}, {y})");
+      }
+    }
+  }
+
+  #[test]
+  fn test_bayer_sizes() {
+    for size in [1usize, 16, 64, 256] {
+      let mut indices: Vec<usize> = bayer(size as u32).iter().map(|threshold| (threshold * (size * size) as f32) as usize).collect();
+      indices.sort_unstable();
+      assert_eq!(indices, (0..size * size).collect::<Vec<_>>(), "{size}x{size}");
+    }
+    assert_eq!(bayer(12).len(), 16 * 16);
+    assert_eq!(bayer(0).len(), 1);
+    assert_eq!(bayer(1000).len(), (MAX_BAYER_SIZE * MAX_BAYER_SIZE) as usize);
+  }
+
+  #[test]
+  fn test_parse_bayer_size() {
+    assert_eq!(parse_bayer_size("1"), Ok(1));
+    assert_eq!(parse_bayer_size("256"), Ok(256));
+    for value in ["0", "12", "512", "-4", "eight"] {
+      assert!(parse_bayer_size(value).is_err(), "{value} should be rejected");
+    }
+  }
+
+  #[test]
+  fn test_bayer_sizes_alias_the_fixed_matrices() {
+    let gray: Vec<u8> = (0..16 * 16 * 3).map(|i| (i % 256) as u8).collect();
+    let dithered = |method: DitherMethod, bayer_size: u32| {
+      let mut buffer = gray.clone();
+      let options = DitherOptions {
+        bayer_size,
+        ..DitherOptions::default()
+      };
+      dither_with(&mut buffer, method, &PALETTE_8C, 16, 16, &options).unwrap();
+      buffer
+    };
+    assert_eq!(dithered(DitherMethod::Bayer, 2), dithered(DitherMethod::Bayer2x2, 2));
+    assert_eq!(dithered(DitherMethod::Bayer, 4), dithered(DitherMethod::Bayer4x4, 2));
+    assert_eq!(dithered(DitherMethod::Bayer, DEFAULT_BAYER_SIZE), dithered(DitherMethod::Bayer8x8, 2));
+    assert_ne!(
+      dithered(DitherMethod::Bayer, 16),
+      dithered(DitherMethod::Bayer8x8, 16),
+      "16x16 should only apply to bayer"
+    );
   }
 
   #[test]
@@ -404,6 +1064,18 @@ mod tests {
     // Sierra Lite weights should sum to 1.0
     let sierra_lite_sum: f32 = SIERRALITE.iter().sum();
     assert!((sierra_lite_sum - 1.0).abs() < f32::EPSILON);
+
+    let stevenson_arce_sum: f32 = STEVENSON_ARCE.iter().sum();
+    assert!((stevenson_arce_sum - 1.0).abs() < 1e-6);
+
+    let shiau_fan_sum: f32 = SHIAU_FAN.iter().sum();
+    assert!((shiau_fan_sum - 1.0).abs() < f32::EPSILON);
+
+    let shiau_fan2_sum: f32 = SHIAU_FAN2.iter().sum();
+    assert!((shiau_fan2_sum - 1.0).abs() < f32::EPSILON);
+
+    let fan_sum: f32 = FAN.iter().sum();
+    assert!((fan_sum - 1.0).abs() < f32::EPSILON);
   }
 
   #[test]
@@ -411,7 +1083,7 @@ mod tests {
     let mut buffer = vec![128, 128, 128, 64, 64, 64]; // 2 pixels: gray, dark gray
     let original = buffer.clone();
 
-    dither(&mut buffer, DitherMethod::None, ColorPalette::Monochrome, 2, 1);
+    dither(&mut buffer, DitherMethod::None, ColorPalette::Monochrome, DistanceMetric::Rgb, 2, 1);
 
     // Should be quantized to black and white, but no error diffusion
     assert_ne!(buffer, original);
@@ -427,12 +1099,27 @@ mod tests {
     }
   }
 
+  #[test]
+  fn test_dither_with_distance_metric() {
+    let quantized = |metric| {
+      let mut buffer = vec![125, 125, 125, 100, 100, 100];
+      dither(&mut buffer, DitherMethod::None, ColorPalette::Monochrome, metric, 2, 1);
+      buffer
+    };
+    assert_eq!(quantized(DistanceMetric::Rgb), [0, 0, 0, 0, 0, 0]);
+    assert_eq!(quantized(DistanceMetric::Oklab), [255, 255, 255, 255, 255, 255]);
+    assert_eq!(quantized(DistanceMetric::Lab), [255, 255, 255, 0, 0, 0]);
+    let mut buffer = vec![125, 125, 125];
+    assert!(try_dither(&mut buffer, DitherMethod::None, ColorPalette::Monochrome, DistanceMetric::Ciede2000, 1, 1).is_ok());
+    assert_eq!(buffer, [255, 255, 255]);
+  }
+
   #[test]
   fn test_dither_modifies_buffer() {
     let mut buffer = vec![100, 150, 200, 50, 75, 25]; // 2 pixels
     let original = buffer.clone();
 
-    dither(&mut buffer, DitherMethod::FloydSteinberg, ColorPalette::COLOR8, 2, 1);
+    dither(&mut buffer, DitherMethod::FloydSteinberg, ColorPalette::COLOR8, DistanceMetric::Rgb, 2, 1);
 
     assert_ne!(buffer, original, "Dithering should modify the buffer");
   }
@@ -443,7 +1130,7 @@ mod tests {
     let mut buffer = vec![128, 128, 128]; // 1x1 pixel
 
     // This should not panic
-    dither(&mut buffer, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, 1, 1);
+    dither(&mut buffer, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, DistanceMetric::Rgb, 1, 1);
 
     assert_eq!(buffer.len(), 3); // Should still be RGB
   }
@@ -456,7 +1143,7 @@ mod tests {
       200, 200, 200, // (1,0)
     ];
 
-    apply_error_diffusion(&mut buffer, DitherMethod::FloydSteinberg, &PALETTE_MONOCHROME, 2, 1);
+    apply_error_diffusion(&mut buffer, DitherMethod::FloydSteinberg, &PALETTE_MONOCHROME, 2, 1, &DitherOptions::default());
 
     // Should not panic and buffer should be modified
     assert_eq!(buffer.len(), 6);
@@ -471,12 +1158,196 @@ mod tests {
       75, 75, 75, // (1,1)
     ];
 
-    apply_bayer_dithering(&mut buffer, DitherMethod::Bayer2x2, &PALETTE_8C, 2, 2);
+    apply_bayer_dithering(&mut buffer, DitherMethod::Bayer2x2, &PALETTE_8C, 2, 2, &DitherOptions::default());
 
     // Should not panic and buffer should be modified
     assert_eq!(buffer.len(), 12);
   }
 
+  #[test]
+  fn test_random_dithering_depends_on_the_seed() {
+    let random = |seed: u64| {
+      let mut buffer = vec![128; 16 * 16 * 3];
+      let options = DitherOptions {
+        seed,
+        ..DitherOptions::default()
+      };
+      dither_with(&mut buffer, DitherMethod::Random, &PALETTE_MONOCHROME, 16, 16, &options).unwrap();
+      buffer
+    };
+    assert_eq!(random(1), random(1), "Equal seeds should give equal outputs");
+    assert_ne!(random(1), random(2));
+    let white = random(1).chunks_exact(3).filter(|pixel| pixel[0] == 255).count();
+    assert!((96..160).contains(&white), "About half of the pixels should be white, got {white}");
+  }
+
+  #[test]
+  fn test_random_thresholds_are_not_permuted_by_the_seed() {
+    let field = |seed: u64| -> Vec<f32> { (0..16).flat_map(|y| (0..16).map(move |x| random_threshold(seed, x, y))).collect() };
+    let first = field(0);
+    for seed in [1, 2, 1 << 32] {
+      let shared = field(seed).iter().filter(|threshold| first.contains(threshold)).count();
+      assert!(shared < 4, "seed {seed} shares {shared} thresholds with seed 0");
+    }
+  }
+
+  #[test]
+  fn test_ordered_methods_continue_from_origin() {
+    let gray: Vec<u8> = (0..32 * 16 * 3).map(|i| (i * 7 % 256) as u8).collect();
+    let methods = <DitherMethod as clap::ValueEnum>::value_variants().iter().filter(|method| method.is_ordered());
+    for &method in methods {
+      let mut whole = gray.clone();
+      dither_with(&mut whole, method, &PALETTE_MONOCHROME, 32, 16, &DitherOptions::default()).unwrap();
+      // the right half on its own, placed at its position
+      let mut right: Vec<u8> = gray.chunks_exact(32 * 3).flat_map(|row| row[16 * 3..].to_vec()).collect();
+      let options = DitherOptions {
+        origin: (16, 0),
+        ..DitherOptions::default()
+      };
+      dither_with(&mut right, method, &PALETTE_MONOCHROME, 16, 16, &options).unwrap();
+      let expected: Vec<u8> = whole.chunks_exact(32 * 3).flat_map(|row| row[16 * 3..].to_vec()).collect();
+      assert_eq!(right, expected, "{method:?} depends on more than the position");
+    }
+  }
+
+  #[test]
+  fn test_interleaved_gradient_noise() {
+    let noise: Vec<f32> = (0..64 * 64).map(|i| interleaved_gradient_noise(i % 64, i / 64)).collect();
+    assert!(noise.iter().all(|n| (0.0..1.0).contains(n)));
+    let mean = noise.iter().sum::<f32>() / noise.len() as f32;
+    assert!((mean - 0.5).abs() < 0.02, "Noise should be centered, mean {mean}");
+    // neighbors differ strongly, unlike smooth noise
+    let step = noise.windows(2).map(|pair| (pair[0] - pair[1]).abs()).sum::<f32>() / (noise.len() - 1) as f32;
+    assert!(step > 0.2, "Neighbors should differ, mean step {step}");
+  }
+
+  #[test]
+  fn test_clustered_dot_grows_from_the_center() {
+    // a light gray leaves a single black dot in the middle of every cell
+    let mut buffer = vec![200; 4 * 4 * 3];
+    dither(&mut buffer, DitherMethod::Clustered4x4, ColorPalette::Monochrome, DistanceMetric::Rgb, 4, 4);
+    let black: Vec<usize> = buffer.chunks_exact(3).enumerate().filter(|(_, pixel)| pixel[0] == 0).map(|(i, _)| i).collect();
+    assert_eq!(black, vec![5, 6, 9, 10]);
+  }
+
+  #[test]
+  fn test_transparent_pixels_are_untouched() {
+    // 3x1 image: opaque gray, transparent gray, opaque gray
+    let alpha = [255, 0, 255];
+    let options = DitherOptions {
+      alpha: Some(&alpha),
+      ..DitherOptions::default()
+    };
+
+    for method in [DitherMethod::None, DitherMethod::FloydSteinberg, DitherMethod::Bayer2x2] {
+      let mut buffer = vec![100, 100, 100, 100, 100, 100, 100, 100, 100];
+      dither_with(&mut buffer, method, &PALETTE_MONOCHROME, 3, 1, &options).unwrap();
+      assert_eq!(&buffer[3..6], &[100, 100, 100], "Transparent pixel should be untouched by {:?}", method);
+    }
+  }
+
+  #[test]
+  fn test_transparent_pixels_do_not_absorb_error() {
+    // the error of the first pixel would push its right neighbor to white, unless it is transparent
+    let mut buffer = vec![100, 100, 100, 100, 100, 100, 100, 100, 100];
+    let alpha = [255, 0, 255];
+    dither_with(
+      &mut buffer,
+      DitherMethod::FloydSteinberg,
+      &PALETTE_MONOCHROME,
+      3,
+      1,
+      &DitherOptions {
+        alpha: Some(&alpha),
+        ..DitherOptions::default()
+      },
+    )
+    .unwrap();
+    assert_eq!(&buffer[6..9], &[0, 0, 0], "Error should not bleed across the transparent pixel");
+
+    let mut buffer = vec![100, 100, 100, 100, 100, 100, 100, 100, 100];
+    dither_with(&mut buffer, DitherMethod::FloydSteinberg, &PALETTE_MONOCHROME, 3, 1, &DitherOptions::default()).unwrap();
+    assert_eq!(&buffer[3..6], &[255, 255, 255], "Opaque neighbor should receive the diffused error");
+  }
+
+  fn gradient(width: u32, height: u32) -> Vec<u8> {
+    (0..width * height)
+      .flat_map(|i| [(i * 255 / (width * height)) as u8, (i % width * 20) as u8, 90])
+      .collect()
+  }
+
+  fn scanned(buffer: &[u8], scan: ScanDirection, width: u32, height: u32) -> Vec<u8> {
+    let mut buffer = buffer.to_vec();
+    let options = DitherOptions {
+      scan,
+      ..DitherOptions::default()
+    };
+    dither_with(&mut buffer, DitherMethod::FloydSteinberg, &PALETTE_8C, width, height, &options).unwrap();
+    buffer
+  }
+
+  #[test]
+  fn test_scan_directions_mirror_the_standard_scan() {
+    let (width, height) = (7, 5);
+    let input = gradient(width, height);
+    for (scan, flip) in [(ScanDirection::RightToLeft, (true, false)), (ScanDirection::BottomToTop, (false, true))] {
+      let mut mirrored = input.clone();
+      mirror(&mut mirrored, 3, width, height, flip);
+      let mut expected = scanned(&mirrored, ScanDirection::LeftToRight, width, height);
+      mirror(&mut expected, 3, width, height, flip);
+      assert_eq!(scanned(&input, scan, width, height), expected, "{:?}", scan);
+    }
+    assert_ne!(
+      scanned(&input, ScanDirection::RightToLeft, width, height),
+      scanned(&input, ScanDirection::LeftToRight, width, height)
+    );
+  }
+
+  #[test]
+  fn test_averaged_scan_has_no_direction() {
+    let (width, height) = (8, 6);
+    let input = gradient(width, height);
+    let averaged = scanned(&input, ScanDirection::Averaged, width, height);
+    assert!(averaged.chunks_exact(3).all(|p| PALETTE_8C.contains(&Color::from(p))));
+
+    // mirroring the input mirrors the result, as every direction is part of the mean
+    let mut mirrored = input.clone();
+    mirror(&mut mirrored, 3, width, height, (true, true));
+    let mut expected = averaged.clone();
+    mirror(&mut expected, 3, width, height, (true, true));
+    assert_eq!(scanned(&mirrored, ScanDirection::Averaged, width, height), expected);
+  }
+
+  #[test]
+  fn test_mirror() {
+    let mut data = vec![1, 2, 3, 4, 5, 6];
+    mirror(&mut data, 1, 3, 2, (true, false));
+    assert_eq!(data, vec![3, 2, 1, 6, 5, 4]);
+    mirror(&mut data, 1, 3, 2, (false, true));
+    assert_eq!(data, vec![6, 5, 4, 3, 2, 1]);
+  }
+
+  #[test]
+  fn test_dither_alpha_is_binary() {
+    // horizontal alpha ramp over 16x4 pixels
+    let mut alpha: Vec<u8> = (0..64).map(|i| ((i % 16) * 17) as u8).collect();
+    let coverage: u32 = alpha.iter().map(|&a| u32::from(a)).sum();
+
+    dither_alpha(&mut alpha, DitherMethod::FloydSteinberg, 16, 4, &DitherOptions::default()).unwrap();
+
+    assert!(alpha.iter().all(|&a| a == 0 || a == 255), "Alpha should be fully opaque or transparent");
+    let dithered: u32 = alpha.iter().map(|&a| u32::from(a)).sum();
+    let difference = (dithered as f32 - coverage as f32).abs() / coverage as f32;
+    assert!(difference < 0.1, "Average opacity should be preserved, off by {:.1}%", difference * 100.0);
+  }
+
+  #[test]
+  fn test_threshold_alpha() {
+    let mut alpha = vec![0, 127, 128, 255];
+    threshold_alpha(&mut alpha, 128);
+    assert_eq!(alpha, vec![0, 0, 255, 255]);
+  }
+
   #[test]
   fn test_all_algorithms_dont_panic() {
     let buffer = vec![128, 64, 192, 32, 160, 96]; // 2x1 image
@@ -492,16 +1363,33 @@ mod tests {
       DitherMethod::Sierra,
       DitherMethod::TwoRowSierra,
       DitherMethod::SierraLite,
+      DitherMethod::StevensonArce,
+      DitherMethod::ShiauFan,
+      DitherMethod::ShiauFan2,
+      DitherMethod::Fan,
+      DitherMethod::Riemersma,
+      DitherMethod::ZhouFang,
+      DitherMethod::DotDiffusion,
+      DitherMethod::Bayer,
       DitherMethod::Bayer2x2,
       DitherMethod::Bayer4x4,
       DitherMethod::Bayer8x8,
+      DitherMethod::Clustered4x4,
+      DitherMethod::Clustered8x8,
+      DitherMethod::Halftone,
+      DitherMethod::Random,
+      DitherMethod::InterleavedGradient,
+      DitherMethod::Yliluoma1,
+      DitherMethod::Yliluoma2,
+      DitherMethod::Yliluoma3,
+      DitherMethod::Knoll,
     ];
 
     for algorithm in algorithms {
       let mut test_buffer = buffer.clone();
 
       // None of these should panic
-      dither(&mut test_buffer, algorithm, ColorPalette::COLOR8, 2, 1);
+      dither(&mut test_buffer, algorithm, ColorPalette::COLOR8, DistanceMetric::Rgb, 2, 1);
 
       assert_eq!(test_buffer.len(), 6, "Buffer size should remain consistent for {:?}", algorithm);
     }
@@ -0,0 +1,112 @@
+//! Knuth's dot diffusion.
+//!
+//! The image is tiled with a class matrix that numbers the pixels of every 8x8 cell. The pixels are
+//! quantized class by class, and each one diffuses its error to the neighbors of a higher class,
+//! which are quantized later. Pixels of one class never share a neighbor, so the pixels of a class do
+//! not depend on each other and could be quantized in parallel; this implementation still visits
+//! them one after the other. The error gathers in clustered dots that survive printing. The error
+//! of pixels without later neighbors (the "barons") is lost.
+
+use crate::dither::{DitherOptions, QuantizationError};
+use crate::palette::{Color, Quantizer};
+
+/// Knuth's 8x8 class matrix, the order in which the pixels of a cell are quantized.
+pub const CLASS_MATRIX: [u8; 64] = [
+  34, 48, 40, 32, 29, 15, 23, 31, //
+  42, 58, 56, 53, 21, 5, 7, 10, //
+  50, 62, 61, 45, 13, 1, 2, 18, //
+  38, 46, 54, 37, 25, 17, 9, 26, //
+  28, 14, 22, 30, 35, 49, 41, 33, //
+  20, 4, 6, 11, 43, 59, 57, 52, //
+  12, 0, 3, 19, 51, 63, 60, 44, //
+  24, 16, 8, 27, 39, 47, 55, 36, //
+];
+
+/// Side of the class matrix.
+const SIZE: usize = 8;
+
+/// Offsets of the eight neighbors of a pixel.
+const NEIGHBORS: [(isize, isize); 8] = [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)];
+
+/// Dithers an RGB buffer with dot diffusion, see the [module documentation](self).
+///
+/// The class matrix continues from [`DitherOptions::origin`]. Transparent pixels neither receive nor
+/// spread error, the scan direction does not apply.
+pub fn dither<Q: Quantizer + ?Sized>(buffer: &mut [u8], quantizer: &Q, width: u32, height: u32, options: &DitherOptions) {
+  let (width, height) = (width as usize, height as usize);
+  let transparent = |pixel: usize| options.alpha.is_some_and(|alpha| alpha[pixel] == 0);
+  let class = |x: usize, y: usize| CLASS_MATRIX[(y + options.origin.1 as usize) % SIZE * SIZE + (x + options.origin.0 as usize) % SIZE];
+  // the pixels of every class, in the order they are quantized
+  let mut order: Vec<usize> = (0..width * height).collect();
+  order.sort_by_key(|&pixel| class(pixel % width, pixel / width));
+  let mut errors = vec![[0.0f32; 3]; width * height];
+  for pixel in order {
+    if transparent(pixel) {
+      continue;
+    }
+    let (x, y) = (pixel % width, pixel / width);
+    let i = pixel * 3;
+    let corrected = |c: usize| (f32::from(buffer[i + c]) + errors[pixel][c]).round().clamp(0.0, 255.0) as u8;
+    let color = Color {
+      r: corrected(0),
+      g: corrected(1),
+      b: corrected(2),
+    };
+    let new_color = quantizer.quantize(color);
+    buffer[i..i + 3].copy_from_slice(&[new_color.r, new_color.g, new_color.b]);
+
+    // the error goes to the neighbors quantized later, direct ones weigh twice as much as diagonal ones
+    let own = class(x, y);
+    let later = |(dx, dy): (isize, isize)| {
+      let (nx, ny) = (x.checked_add_signed(dx)?, y.checked_add_signed(dy)?);
+      let weight = if dx == 0 || dy == 0 { 2.0 } else { 1.0 };
+      (nx < width && ny < height && class(nx, ny) > own && !transparent(ny * width + nx)).then_some((ny * width + nx, weight))
+    };
+    let total: f32 = NEIGHBORS.into_iter().filter_map(later).map(|(_, weight)| weight).sum();
+    let error = QuantizationError::between(color, new_color);
+    for (neighbor, weight) in NEIGHBORS.into_iter().filter_map(later) {
+      let share = weight / total;
+      errors[neighbor][0] += error.r * share;
+      errors[neighbor][1] += error.g * share;
+      errors[neighbor][2] += error.b * share;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::palette::PALETTE_MONOCHROME;
+
+  #[test]
+  fn test_class_matrix_is_a_permutation() {
+    let mut classes = CLASS_MATRIX.to_vec();
+    classes.sort_unstable();
+    assert_eq!(classes, (0..64).collect::<Vec<u8>>());
+  }
+
+  #[test]
+  fn test_dither_preserves_gray_level() {
+    let (width, height) = (64, 64);
+    for level in [64u8, 128, 192] {
+      let mut buffer = vec![level; width * height * 3];
+      dither(&mut buffer, &PALETTE_MONOCHROME, width as u32, height as u32, &DitherOptions::default());
+
+      assert!(buffer.iter().all(|&v| v == 0 || v == 255));
+      let white = buffer.chunks_exact(3).filter(|pixel| pixel[0] == 255).count() as f32 / (width * height) as f32;
+      assert!((white - f32::from(level) / 255.0).abs() < 0.05, "{white} white for level {level}");
+    }
+  }
+
+  #[test]
+  fn test_dither_skips_transparent_pixels() {
+    let mut buffer = vec![100; 4 * 3];
+    let alpha = [255, 0, 0, 255];
+    let options = DitherOptions {
+      alpha: Some(&alpha),
+      ..DitherOptions::default()
+    };
+    dither(&mut buffer, &PALETTE_MONOCHROME, 2, 2, &options);
+    assert_eq!(&buffer[3..9], &[100; 6]);
+  }
+}
@@ -0,0 +1,101 @@
+//! Combining several dithering runs with different seeds.
+//!
+//! A stochastic method (e.g. dithering with injected noise) gives a different result for every seed.
+//! Running it several times and combining the results per pixel trades time for smoother output with
+//! fewer recognizable patterns.
+
+/// Ways to combine the results of an ensemble.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
+pub enum EnsembleMode {
+  /// The color most runs chose for the pixel, ties go to the earlier run
+  #[default]
+  Majority,
+  /// The color of the run whose local average (3x3) is closest to the original
+  ErrorMinimizing,
+}
+
+serde_value_enum!(EnsembleMode);
+
+/// Combines dithered versions of `original` into one RGB buffer.
+///
+/// Every output pixel is taken from one of the candidates, so the result only contains their colors.
+///
+/// # Panics
+///
+/// Panics if there are no candidates.
+#[must_use]
+pub fn combine(candidates: &[Vec<u8>], original: &[u8], width: u32, height: u32, mode: EnsembleMode) -> Vec<u8> {
+  assert!(!candidates.is_empty(), "an ensemble needs at least one candidate");
+  let pixels = width as usize * height as usize;
+  let choice: Vec<usize> = match mode {
+    EnsembleMode::Majority => (0..pixels).map(|i| majority(candidates, i)).collect(),
+    EnsembleMode::ErrorMinimizing => {
+      let errors: Vec<Vec<f32>> = candidates.iter().map(|candidate| local_error(candidate, original, width, height)).collect();
+      (0..pixels)
+        .map(|i| (0..candidates.len()).min_by(|&a, &b| errors[a][i].total_cmp(&errors[b][i])).unwrap_or(0))
+        .collect()
+    }
+  };
+  choice
+    .iter()
+    .enumerate()
+    .flat_map(|(i, &candidate)| candidates[candidate][i * 3..i * 3 + 3].iter().copied())
+    .collect()
+}
+
+/// Returns the index of the candidate whose color of pixel `i` is the most common one.
+fn majority(candidates: &[Vec<u8>], i: usize) -> usize {
+  let color = |c: usize| &candidates[c][i * 3..i * 3 + 3];
+  let votes = |c: usize| candidates.iter().filter(|other| &other[i * 3..i * 3 + 3] == color(c)).count();
+  // max_by_key returns the last maximum, so search from the back to prefer earlier runs
+  (0..candidates.len()).rev().max_by_key(|&c| votes(c)).unwrap_or(0)
+}
+
+/// Returns the squared error of the 3x3 mean of a candidate against the original, per pixel.
+fn local_error(candidate: &[u8], original: &[u8], width: u32, height: u32) -> Vec<f32> {
+  let (width, height) = (width as usize, height as usize);
+  let difference: Vec<f32> = candidate.iter().zip(original).map(|(&c, &o)| f32::from(c) - f32::from(o)).collect();
+  let mut errors = Vec::with_capacity(width * height);
+  for y in 0..height {
+    for x in 0..width {
+      let mut sum = [0.0f32; 3];
+      let mut count = 0.0;
+      for ny in y.saturating_sub(1)..(y + 2).min(height) {
+        for nx in x.saturating_sub(1)..(x + 2).min(width) {
+          let i = (ny * width + nx) * 3;
+          sum.iter_mut().zip(&difference[i..i + 3]).for_each(|(s, d)| *s += d);
+          count += 1.0;
+        }
+      }
+      errors.push(sum.iter().map(|s| (s / count).powi(2)).sum());
+    }
+  }
+  errors
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_majority() {
+    let candidates = vec![vec![0, 0, 0, 255, 255, 255], vec![255, 255, 255, 255, 255, 255], vec![0, 0, 0, 0, 0, 0]];
+    assert_eq!(combine(&candidates, &[0; 6], 2, 1, EnsembleMode::Majority), vec![0, 0, 0, 255, 255, 255]);
+  }
+
+  #[test]
+  fn test_majority_tie_prefers_first_run() {
+    let candidates = vec![vec![255, 0, 0], vec![0, 255, 0]];
+    assert_eq!(combine(&candidates, &[0; 3], 1, 1, EnsembleMode::Majority), vec![255, 0, 0]);
+  }
+
+  #[test]
+  fn test_error_minimizing_picks_closest_local_average() {
+    // mid-gray: alternating black and white averages to gray, solid white does not
+    let original = vec![128; 4 * 3];
+    let checker = vec![0, 0, 0, 255, 255, 255, 0, 0, 0, 255, 255, 255];
+    let white = vec![255; 4 * 3];
+    let combined = combine(&[white, checker.clone()], &original, 4, 1, EnsembleMode::ErrorMinimizing);
+    assert_eq!(combined, checker);
+  }
+}
@@ -0,0 +1,124 @@
+//! Error types for the dithers library.
+
+use std::path::PathBuf;
+
+use thiserror::Error;
+
+use crate::stream::ByteSize;
+
+/// Errors that can occur while loading, processing or saving images.
+#[derive(Debug, Error)]
+pub enum DitherError {
+  /// An image file could not be opened, decoded or encoded.
+  #[error("image error for {path:?}: {source}")]
+  Image {
+    /// Path of the file being read or written
+    path: PathBuf,
+    /// Underlying error reported by the `image` crate
+    #[source]
+    source: image::ImageError,
+  },
+  /// A multi-page TIFF file could not be decoded or encoded.
+  #[error("TIFF error for {path:?}: {source}")]
+  Tiff {
+    /// Path of the file being read or written
+    path: PathBuf,
+    /// Underlying error reported by the `tiff` crate
+    #[source]
+    source: tiff::TiffError,
+  },
+  /// A camera RAW file could not be decoded.
+  #[error("RAW error for {path:?}: {message}")]
+  Raw {
+    /// Path of the RAW file
+    path: PathBuf,
+    /// Description of the decoding failure
+    message: String,
+  },
+  /// An image cannot be stored in the requested output format.
+  #[error("cannot encode {path:?}: {message}")]
+  Encode {
+    /// Path of the file being written
+    path: PathBuf,
+    /// Description of why the image does not fit the format
+    message: String,
+  },
+  /// A file could not be read or written.
+  #[error("I/O error for {path:?}: {source}")]
+  Io {
+    /// Path of the file being read or written
+    path: PathBuf,
+    /// Underlying I/O error
+    #[source]
+    source: std::io::Error,
+  },
+  /// An image needs more memory than allowed and cannot be processed in strips instead.
+  #[error("{path:?} needs about {needed} of memory, more than the limit of {limit}, and {reason}")]
+  MemoryLimit {
+    /// Path of the input image
+    path: PathBuf,
+    /// Estimated memory needed to process the whole image
+    needed: ByteSize,
+    /// Memory limit of the job
+    limit: ByteSize,
+    /// Why the image cannot be processed in strips
+    reason: String,
+  },
+  /// An input image is larger than the configured limits allow.
+  #[error("{path:?} is {width}x{height} pixels, more than the limit of {limit}")]
+  TooLarge {
+    /// Path of the input image
+    path: PathBuf,
+    /// Width of the image in pixels
+    width: u32,
+    /// Height of the image in pixels
+    height: u32,
+    /// Description of the exceeded limit
+    limit: String,
+  },
+  /// A pixel buffer does not have the size its dimensions require.
+  #[error("buffer of {len} bytes does not hold {width}x{height} pixels of {channels} byte(s)")]
+  InvalidBuffer {
+    /// Length of the buffer in bytes
+    len: usize,
+    /// Width of the image in pixels
+    width: u32,
+    /// Height of the image in pixels
+    height: u32,
+    /// Bytes per pixel
+    channels: usize,
+  },
+  /// A daemon request could not be parsed or is incomplete.
+  #[error("invalid request: {message}")]
+  InvalidRequest {
+    /// Description of what is wrong with the request
+    message: String,
+  },
+  /// A palette has no colors to dither to.
+  #[error("a palette needs at least one color")]
+  EmptyPalette,
+  /// A jobs manifest could not be parsed.
+  #[error("invalid jobs file {path:?}: {message}")]
+  JobsFile {
+    /// Path of the jobs file
+    path: PathBuf,
+    /// Description of what is wrong with the file
+    message: String,
+  },
+  /// A palette file could not be parsed.
+  #[error("invalid palette file {path:?}: {message}")]
+  PaletteFile {
+    /// Path of the palette file
+    path: PathBuf,
+    /// Description of what is wrong with the file
+    message: String,
+  },
+  /// A result cache file could not be parsed or its entries could not be serialized.
+  #[error("invalid cache file {path:?}: {message}")]
+  CacheFile {
+    /// Path of the cache file
+    path: PathBuf,
+    /// Description of what is wrong with the file
+    message: String,
+  },
+}
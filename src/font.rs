@@ -0,0 +1,197 @@
+//! A small built-in bitmap font for labeling images.
+//!
+//! Glyphs are 5x7 pixels and cover printable ASCII, other characters are drawn as `?`.
+//! Text is scaled by whole pixels so it stays crisp next to dithered output.
+
+use crate::palette::Color;
+use crate::pipeline::Image;
+
+/// Glyph width in pixels, before scaling.
+pub const GLYPH_WIDTH: u32 = 5;
+/// Glyph height in pixels, before scaling.
+pub const GLYPH_HEIGHT: u32 = 7;
+/// Horizontal distance between the starts of two glyphs, before scaling.
+pub const ADVANCE: u32 = GLYPH_WIDTH + 1;
+
+/// Glyphs of the characters from `' '` to `'~'`, one byte per column with the top row in the lowest bit.
+const GLYPHS: [[u8; 5]; 95] = [
+  [0x00, 0x00, 0x00, 0x00, 0x00], // ' '
+  [0x00, 0x00, 0x5f, 0x00, 0x00], // !
+  [0x00, 0x07, 0x00, 0x07, 0x00], // "
+  [0x14, 0x7f, 0x14, 0x7f, 0x14], // #
+  [0x24, 0x2a, 0x7f, 0x2a, 0x12], // $
+  [0x23, 0x13, 0x08, 0x64, 0x62], // %
+  [0x36, 0x49, 0x55, 0x22, 0x50], // &
+  [0x00, 0x05, 0x03, 0x00, 0x00], // '
+  [0x00, 0x1c, 0x22, 0x41, 0x00], // (
+  [0x00, 0x41, 0x22, 0x1c, 0x00], // )
+  [0x08, 0x2a, 0x1c, 0x2a, 0x08], // *
+  [0x08, 0x08, 0x3e, 0x08, 0x08], // +
+  [0x00, 0x50, 0x30, 0x00, 0x00], // ,
+  [0x08, 0x08, 0x08, 0x08, 0x08], // -
+  [0x00, 0x60, 0x60, 0x00, 0x00], // .
+  [0x20, 0x10, 0x08, 0x04, 0x02], // /
+  [0x3e, 0x51, 0x49, 0x45, 0x3e], // 0
+  [0x00, 0x42, 0x7f, 0x40, 0x00], // 1
+  [0x42, 0x61, 0x51, 0x49, 0x46], // 2
+  [0x21, 0x41, 0x45, 0x4b, 0x31], // 3
+  [0x18, 0x14, 0x12, 0x7f, 0x10], // 4
+  [0x27, 0x45, 0x45, 0x45, 0x39], // 5
+  [0x3c, 0x4a, 0x49, 0x49, 0x30], // 6
+  [0x01, 0x71, 0x09, 0x05, 0x03], // 7
+  [0x36, 0x49, 0x49, 0x49, 0x36], // 8
+  [0x06, 0x49, 0x49, 0x29, 0x1e], // 9
+  [0x00, 0x36, 0x36, 0x00, 0x00], // :
+  [0x00, 0x56, 0x36, 0x00, 0x00], // ;
+  [0x08, 0x14, 0x22, 0x41, 0x00], // <
+  [0x14, 0x14, 0x14, 0x14, 0x14], // =
+  [0x00, 0x41, 0x22, 0x14, 0x08], // >
+  [0x02, 0x01, 0x51, 0x09, 0x06], // ?
+  [0x32, 0x49, 0x79, 0x41, 0x3e], // @
+  [0x7e, 0x11, 0x11, 0x11, 0x7e], // A
+  [0x7f, 0x49, 0x49, 0x49, 0x36], // B
+  [0x3e, 0x41, 0x41, 0x41, 0x22], // C
+  [0x7f, 0x41, 0x41, 0x22, 0x1c], // D
+  [0x7f, 0x49, 0x49, 0x49, 0x41], // E
+  [0x7f, 0x09, 0x09, 0x09, 0x01], // F
+  [0x3e, 0x41, 0x49, 0x49, 0x7a], // G
+  [0x7f, 0x08, 0x08, 0x08, 0x7f], // H
+  [0x00, 0x41, 0x7f, 0x41, 0x00], // I
+  [0x20, 0x40, 0x41, 0x3f, 0x01], // J
+  [0x7f, 0x08, 0x14, 0x22, 0x41], // K
+  [0x7f, 0x40, 0x40, 0x40, 0x40], // L
+  [0x7f, 0x02, 0x0c, 0x02, 0x7f], // M
+  [0x7f, 0x04, 0x08, 0x10, 0x7f], // N
+  [0x3e, 0x41, 0x41, 0x41, 0x3e], // O
+  [0x7f, 0x09, 0x09, 0x09, 0x06], // P
+  [0x3e, 0x41, 0x51, 0x21, 0x5e], // Q
+  [0x7f, 0x09, 0x19, 0x29, 0x46], // R
+  [0x46, 0x49, 0x49, 0x49, 0x31], // S
+  [0x01, 0x01, 0x7f, 0x01, 0x01], // T
+  [0x3f, 0x40, 0x40, 0x40, 0x3f], // U
+  [0x1f, 0x20, 0x40, 0x20, 0x1f], // V
+  [0x3f, 0x40, 0x38, 0x40, 0x3f], // W
+  [0x63, 0x14, 0x08, 0x14, 0x63], // X
+  [0x07, 0x08, 0x70, 0x08, 0x07], // Y
+  [0x61, 0x51, 0x49, 0x45, 0x43], // Z
+  [0x00, 0x7f, 0x41, 0x41, 0x00], // [
+  [0x02, 0x04, 0x08, 0x10, 0x20], // \
+  [0x00, 0x41, 0x41, 0x7f, 0x00], // ]
+  [0x04, 0x02, 0x01, 0x02, 0x04], // ^
+  [0x40, 0x40, 0x40, 0x40, 0x40], // _
+  [0x00, 0x01, 0x02, 0x04, 0x00], // `
+  [0x20, 0x54, 0x54, 0x54, 0x78], // a
+  [0x7f, 0x48, 0x44, 0x44, 0x38], // b
+  [0x38, 0x44, 0x44, 0x44, 0x20], // c
+  [0x38, 0x44, 0x44, 0x48, 0x7f], // d
+  [0x38, 0x54, 0x54, 0x54, 0x18], // e
+  [0x08, 0x7e, 0x09, 0x01, 0x02], // f
+  [0x0c, 0x52, 0x52, 0x52, 0x3e], // g
+  [0x7f, 0x08, 0x04, 0x04, 0x78], // h
+  [0x00, 0x44, 0x7d, 0x40, 0x00], // i
+  [0x20, 0x40, 0x44, 0x3d, 0x00], // j
+  [0x7f, 0x10, 0x28, 0x44, 0x00], // k
+  [0x00, 0x41, 0x7f, 0x40, 0x00], // l
+  [0x7c, 0x04, 0x18, 0x04, 0x78], // m
+  [0x7c, 0x08, 0x04, 0x04, 0x78], // n
+  [0x38, 0x44, 0x44, 0x44, 0x38], // o
+  [0x7c, 0x14, 0x14, 0x14, 0x08], // p
+  [0x08, 0x14, 0x14, 0x18, 0x7c], // q
+  [0x7c, 0x08, 0x04, 0x04, 0x08], // r
+  [0x48, 0x54, 0x54, 0x54, 0x20], // s
+  [0x04, 0x3f, 0x44, 0x40, 0x20], // t
+  [0x3c, 0x40, 0x40, 0x20, 0x7c], // u
+  [0x1c, 0x20, 0x40, 0x20, 0x1c], // v
+  [0x3c, 0x40, 0x30, 0x40, 0x3c], // w
+  [0x44, 0x28, 0x10, 0x28, 0x44], // x
+  [0x0c, 0x50, 0x50, 0x50, 0x3c], // y
+  [0x44, 0x64, 0x54, 0x4c, 0x44], // z
+  [0x00, 0x08, 0x36, 0x41, 0x00], // {
+  [0x00, 0x00, 0x7f, 0x00, 0x00], // |
+  [0x00, 0x41, 0x36, 0x08, 0x00], // }
+  [0x08, 0x04, 0x08, 0x10, 0x08], // ~
+];
+
+/// Returns the width of a text in pixels, without the spacing after the last glyph.
+#[must_use]
+pub fn text_width(text: &str, scale: u32) -> u32 {
+  match text.chars().count() as u32 {
+    0 => 0,
+    n => (n * ADVANCE - 1) * scale,
+  }
+}
+
+/// Draws a line of text with its top left corner at `x`, `y`, clipped to the image.
+pub fn draw_text(image: &mut Image, text: &str, x: u32, y: u32, scale: u32, color: Color) {
+  for (i, c) in text.chars().enumerate() {
+    let glyph = glyph(c);
+    let left = x + i as u32 * ADVANCE * scale;
+    for (column, bits) in glyph.iter().enumerate() {
+      for row in (0..GLYPH_HEIGHT).filter(|row| bits >> row & 1 == 1) {
+        fill(image, left + column as u32 * scale, y + row * scale, scale, color);
+      }
+    }
+  }
+}
+
+fn glyph(c: char) -> &'static [u8; 5] {
+  match c {
+    ' '..='~' => &GLYPHS[c as usize - ' ' as usize],
+    _ => &GLYPHS['?' as usize - ' ' as usize],
+  }
+}
+
+/// Fills a square of `size` pixels, clipped to the image.
+fn fill(image: &mut Image, x: u32, y: u32, size: u32, color: Color) {
+  for py in y..(y + size).min(image.height) {
+    for px in x..(x + size).min(image.width) {
+      let i = (py * image.width + px) as usize * 3;
+      image.buffer[i..i + 3].copy_from_slice(&[color.r, color.g, color.b]);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const BLACK: Color = Color { r: 0, g: 0, b: 0 };
+
+  fn blank(width: u32, height: u32) -> Image {
+    Image {
+      buffer: vec![255; (width * height * 3) as usize],
+      alpha: None,
+      width,
+      height,
+    }
+  }
+
+  #[test]
+  fn test_text_width() {
+    assert_eq!(text_width("", 1), 0);
+    assert_eq!(text_width("A", 1), 5);
+    assert_eq!(text_width("AB", 2), 22);
+  }
+
+  #[test]
+  fn test_draw_text() {
+    let mut image = blank(5, 7);
+    draw_text(&mut image, "|", 0, 0, 1, BLACK);
+    // a single vertical line in the middle column
+    for (i, pixel) in image.buffer.chunks_exact(3).enumerate() {
+      assert_eq!(pixel[0] == 0, i % 5 == 2, "pixel {i}");
+    }
+  }
+
+  #[test]
+  fn test_draw_text_scales_and_clips() {
+    let mut image = blank(4, 4);
+    draw_text(&mut image, "_", 0, 0, 2, BLACK);
+    // the underscore is the bottom row, 12 pixels below the top at scale 2
+    assert!(image.buffer.iter().all(|&v| v == 255));
+
+    let mut image = blank(4, 14);
+    draw_text(&mut image, "\u{e9}", 0, 0, 2, BLACK);
+    assert!(image.buffer.contains(&0), "non-ASCII characters should be drawn as '?'");
+  }
+}
@@ -0,0 +1,168 @@
+//! Halftone screens with a configurable angle and dot size.
+//!
+//! A fixed matrix like [`CLUSTERED8X8`](crate::dither::CLUSTERED8X8) can only place its dots along the
+//! pixel grid or its diagonal. A halftone screen is computed for every pixel instead: the pixel is
+//! rotated into the grid of the screen, and the threshold grows from the center of the nearest dot
+//! outward. The thresholds are ranked among those of a whole cell, so every tone covers the matching
+//! share of the cell and the tone response stays linear at any angle and size.
+
+use std::f32::consts::TAU;
+
+use crate::dither::{self, DitherOptions};
+use crate::palette::{Color, Quantizer};
+
+/// Screen angle in degrees of [`Screen::default`], the traditional angle of black ink.
+pub const DEFAULT_ANGLE: f32 = 45.0;
+
+/// Distance between the dots of [`Screen::default`] in pixels.
+pub const DEFAULT_SIZE: f32 = 6.0;
+
+/// Samples per side of the cell the thresholds are ranked in.
+const SAMPLES: usize = 64;
+
+/// Spot values closer than this are equal, many samples lie on the same contour of the spot function.
+const TIE: f32 = 1e-4;
+
+/// A halftone screen, the grid of dots that [`DitherMethod::Halftone`](crate::dither::DitherMethod::Halftone) draws.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Screen {
+  /// Angle of the grid of dots in degrees, counterclockwise
+  pub angle: f32,
+  /// Distance between neighboring dots in pixels, the resolution divided by the lines per inch, at least 1
+  pub size: f32,
+}
+
+impl Default for Screen {
+  fn default() -> Self {
+    Screen {
+      angle: DEFAULT_ANGLE,
+      size: DEFAULT_SIZE,
+    }
+  }
+}
+
+/// Spot function of a round dot in the middle of every cell, lowest at its center and highest between the dots.
+fn spot(u: f32, v: f32) -> f32 {
+  (TAU * u).cos() + (TAU * v).cos()
+}
+
+/// The thresholds of a screen, ready to be looked up per pixel.
+struct Thresholds {
+  cos: f32,
+  sin: f32,
+  size: f32,
+  /// Spot values of the samples of a cell, in increasing order
+  ranks: Vec<f32>,
+}
+
+impl Thresholds {
+  fn new(screen: Screen) -> Self {
+    let angle = screen.angle.to_radians();
+    let mut ranks: Vec<f32> = (0..SAMPLES * SAMPLES)
+      .map(|i| spot((i % SAMPLES) as f32 / SAMPLES as f32, (i / SAMPLES) as f32 / SAMPLES as f32))
+      .collect();
+    ranks.sort_by(f32::total_cmp);
+    Thresholds {
+      cos: angle.cos(),
+      sin: angle.sin(),
+      size: screen.size.max(1.0),
+      ranks,
+    }
+  }
+
+  /// Returns the threshold of the pixel at `(x, y)` in the whole image, from 0 to 1.
+  fn at(&self, x: u32, y: u32) -> f32 {
+    // pixel centers, rotated into the grid of the screen with y pointing down
+    let (x, y) = (x as f32 + 0.5, y as f32 + 0.5);
+    let u = (x * self.cos - y * self.sin) / self.size;
+    let v = (x * self.sin + y * self.cos) / self.size;
+    let spot = spot(u, v);
+    // the middle of the equal samples, so that rounding errors do not tip the threshold
+    let below = self.ranks.partition_point(|&rank| rank < spot - TIE);
+    let equal = self.ranks[below..].partition_point(|&rank| rank <= spot + TIE);
+    (below as f32 + equal as f32 / 2.0) / self.ranks.len() as f32
+  }
+}
+
+/// Returns the threshold of every pixel of a `width` x `height` area at `origin`, row by row.
+#[must_use]
+pub fn thresholds(screen: Screen, width: u32, height: u32, origin: (u32, u32)) -> Vec<f32> {
+  let thresholds = Thresholds::new(screen);
+  (0..height)
+    .flat_map(|y| (0..width).map(move |x| (x, y)))
+    .map(|(x, y)| thresholds.at(x + origin.0, y + origin.1))
+    .collect()
+}
+
+/// Dithers an RGB buffer with the halftone screen of [`DitherOptions::screen`].
+///
+/// The screen continues from [`DitherOptions::origin`]. Transparent pixels are left untouched.
+pub fn dither<Q: Quantizer + ?Sized>(buffer: &mut [u8], quantizer: &Q, width: u32, height: u32, options: &DitherOptions) {
+  let thresholds = Thresholds::new(options.screen);
+  for (i, pixel) in buffer.chunks_exact_mut(3).enumerate() {
+    if options.alpha.is_some_and(|alpha| alpha[i] == 0) {
+      continue;
+    }
+    let (x, y) = (i as u32 % width + options.origin.0, i as u32 / width + options.origin.1);
+    let threshold = thresholds.at(x, y);
+    let color = Color {
+      r: dither::apply_threshold(pixel[0], threshold),
+      g: dither::apply_threshold(pixel[1], threshold),
+      b: dither::apply_threshold(pixel[2], threshold),
+    };
+    let new_color = quantizer.quantize(color);
+    pixel.copy_from_slice(&[new_color.r, new_color.g, new_color.b]);
+  }
+  log::trace!("screened {width}x{height} pixels");
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::palette::PALETTE_MONOCHROME;
+
+  #[test]
+  fn test_thresholds_are_evenly_spread() {
+    for screen in [Screen::default(), Screen { angle: 15.0, size: 9.5 }, Screen { angle: 0.0, size: 7.3 }] {
+      let thresholds = thresholds(screen, 256, 256, (0, 0));
+      for level in [0.25, 0.5, 0.75] {
+        let below = thresholds.iter().filter(|&&t| t < level).count() as f32 / thresholds.len() as f32;
+        assert!((below - level).abs() < 0.02, "{below} of the thresholds below {level} for {screen:?}");
+      }
+    }
+  }
+
+  #[test]
+  fn test_dots_repeat_with_the_size() {
+    let screen = Screen { angle: 0.0, size: 8.0 };
+    let thresholds = thresholds(screen, 24, 24, (0, 0));
+    for y in 0..16 {
+      for x in 0..16 {
+        let (a, b) = (thresholds[y * 24 + x], thresholds[(y + 8) * 24 + x + 8]);
+        assert!((a - b).abs() < 1e-3, "({x}, {y}): {a} and {b}");
+      }
+    }
+    // the dot grows from the cell center, which is between pixels 3 and 4
+    let lowest = (0..64).min_by(|&a, &b| thresholds[a / 8 * 24 + a % 8].total_cmp(&thresholds[b / 8 * 24 + b % 8]));
+    assert!(lowest.is_some_and(|i| (3..=4).contains(&(i % 8)) && (3..=4).contains(&(i / 8))));
+  }
+
+  #[test]
+  fn test_dither_continues_from_origin() {
+    let screen = Screen { angle: 30.0, size: 5.0 };
+    let gray: Vec<u8> = (0..32 * 16 * 3).map(|i| (i % 251) as u8).collect();
+    let mut whole = gray.clone();
+    let options = DitherOptions {
+      screen,
+      ..DitherOptions::default()
+    };
+    dither(&mut whole, &PALETTE_MONOCHROME, 32, 16, &options);
+
+    // the right half on its own, placed at its position
+    let mut right: Vec<u8> = gray.chunks_exact(32 * 3).flat_map(|row| row[16 * 3..].to_vec()).collect();
+    let options = DitherOptions { origin: (16, 0), ..options };
+    dither(&mut right, &PALETTE_MONOCHROME, 16, 16, &options);
+    let expected: Vec<u8> = whole.chunks_exact(32 * 3).flat_map(|row| row[16 * 3..].to_vec()).collect();
+    assert_eq!(right, expected);
+  }
+}
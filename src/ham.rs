@@ -0,0 +1,191 @@
+//! Amiga hold-and-modify (HAM6) encoding.
+//!
+//! HAM6 stores 6 bits per pixel: either one of 16 base palette entries, or the color of the pixel to
+//! the left with one of its channels replaced by a new 4-bit level. Hard edges need a base color, soft
+//! gradients are reached one channel at a time, which gives the typical color fringes along a scanline.
+//! Colors have 4 bits per channel, like [`Target::Rgb444`].
+//!
+//! The encoder is a [`Quantizer`] that remembers the previous pixel of the scanline, so any dithering
+//! method spreads the residual error of its choices.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::dither::{self, DitherMethod, DitherOptions};
+use crate::error::DitherError;
+use crate::palette::{Color, Quantizer};
+use crate::target::{self, Target};
+
+/// Number of base palette entries of HAM6.
+pub const BASE_COLORS: usize = 16;
+
+/// Picks the base palette for an RGB buffer.
+///
+/// The most common 12-bit color comes first, every further entry is the color whose pixels are worst
+/// served by the entries so far, weighted by how many pixels have it.
+#[must_use]
+pub fn base_palette(buffer: &[u8]) -> Vec<Color> {
+  let mut counts: HashMap<Color, u64> = HashMap::new();
+  for pixel in buffer.chunks_exact(3) {
+    *counts.entry(Target::Rgb444.quantize(Color::from(pixel))).or_default() += 1;
+  }
+  // sorted, so that the choice does not depend on the hash order
+  let mut counts: Vec<(Color, u64)> = counts.into_iter().collect();
+  counts.sort_unstable_by_key(|&(color, count)| (std::cmp::Reverse(count), color.r, color.g, color.b));
+
+  let mut base: Vec<Color> = Vec::with_capacity(BASE_COLORS);
+  let mut served = vec![u64::MAX; counts.len()];
+  while base.len() < BASE_COLORS.min(counts.len()) {
+    let next = (0..counts.len())
+      .max_by_key(|&i| (counts[i].1.saturating_mul(served[i]), std::cmp::Reverse(i)))
+      .map(|i| counts[i].0)
+      .expect("there are colors");
+    for (served, &(color, _)) in served.iter_mut().zip(&counts) {
+      *served = (*served).min(distance(color, next));
+    }
+    base.push(next);
+  }
+  if base.is_empty() {
+    base.push(Color { r: 0, g: 0, b: 0 });
+  }
+  base
+}
+
+/// Quantizer choosing between the base colors and modifying one channel of the previous pixel.
+///
+/// It must see the pixels in rows from left to right and top to bottom, which is the order
+/// [`dither::dither_with`] uses without an alpha channel and with the default scan direction.
+/// Every scanline starts from the first base color, like the border color on the Amiga.
+pub struct Ham6 {
+  base: Vec<Color>,
+  width: u32,
+  // position within the scanline and the color of the previous pixel
+  state: RefCell<(u32, Color)>,
+}
+
+impl Ham6 {
+  /// Creates an encoder for scanlines of `width` pixels with the given base palette.
+  #[must_use]
+  pub fn new(base: Vec<Color>, width: u32) -> Self {
+    let border = base.first().copied().unwrap_or(Color { r: 0, g: 0, b: 0 });
+    Ham6 {
+      base,
+      width: width.max(1),
+      state: RefCell::new((0, border)),
+    }
+  }
+}
+
+impl Quantizer for Ham6 {
+  fn quantize(&self, color: Color) -> Color {
+    let mut state = self.state.borrow_mut();
+    let (x, previous) = *state;
+    let previous = if x == 0 { self.base.first().copied().unwrap_or(previous) } else { previous };
+    let level = |value: u8| target::expand(target::level(value, 4), 4);
+    let modified = [
+      Color { r: level(color.r), ..previous },
+      Color { g: level(color.g), ..previous },
+      Color { b: level(color.b), ..previous },
+    ];
+    // base colors come first, so they win ties and the next pixel can start from an exact color
+    let chosen = self
+      .base
+      .iter()
+      .chain(&modified)
+      .copied()
+      .min_by_key(|&candidate| distance(color, candidate))
+      .unwrap_or(previous);
+    *state = ((x + 1) % self.width, chosen);
+    chosen
+  }
+}
+
+/// Encodes an RGB buffer as HAM6, with a base palette chosen from the image.
+///
+/// Transparent pixels and other scan directions are not supported, the encoder needs every pixel of
+/// a scanline in order. Methods that visit the pixels in another order, Riemersma's along a Hilbert
+/// curve, Zhou-Fang's serpentine rows and dot diffusion's class by class, are replaced by Floyd-Steinberg.
+pub fn dither(buffer: &mut [u8], method: DitherMethod, width: u32, height: u32, options: &DitherOptions) -> Result<(), DitherError> {
+  if options.alpha.is_some() || options.scan != dither::ScanDirection::LeftToRight {
+    log::warn!("HAM6 encodes every pixel from left to right, ignoring transparency and the scan direction");
+  }
+  let method = match method {
+    DitherMethod::Riemersma | DitherMethod::ZhouFang | DitherMethod::DotDiffusion => {
+      log::warn!("HAM6 encodes every pixel from left to right, dithering with floyd-steinberg instead of {method:?}");
+      DitherMethod::FloydSteinberg
+    }
+    method => method,
+  };
+  let ham = Ham6::new(base_palette(buffer), width);
+  let options = DitherOptions {
+    alpha: None,
+    scan: dither::ScanDirection::LeftToRight,
+    ..*options
+  };
+  dither::dither_with(buffer, method, &ham, width, height, &options)
+}
+
+/// Squared distance between two colors.
+fn distance(a: Color, b: Color) -> u64 {
+  let d = |a: u8, b: u8| u64::from(a.abs_diff(b)).pow(2);
+  d(a.r, b.r) + d(a.g, b.g) + d(a.b, b.b)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Returns whether a pixel can follow `previous` in HAM6 with the given base palette.
+  fn reachable(previous: Color, pixel: Color, base: &[Color]) -> bool {
+    let changed = [previous.r != pixel.r, previous.g != pixel.g, previous.b != pixel.b];
+    base.contains(&pixel) || changed.iter().filter(|&&c| c).count() <= 1
+  }
+
+  #[test]
+  fn test_base_palette_is_12_bit() {
+    let buffer: Vec<u8> = (0..64 * 3).map(|i| (i * 37 % 256) as u8).collect();
+    let base = base_palette(&buffer);
+    assert_eq!(base.len(), BASE_COLORS);
+    assert!(base.iter().all(|&color| Target::Rgb444.quantize(color) == color));
+    assert_eq!(base_palette(&[0, 0, 0, 0, 0, 0]), vec![Color { r: 0, g: 0, b: 0 }]);
+  }
+
+  #[test]
+  fn test_dither_keeps_the_scanline_order() {
+    let (width, height) = (32, 8);
+    let buffer: Vec<u8> = (0..width * height).flat_map(|i| [(i * 7) as u8, (i * 3) as u8, (255 - i) as u8]).collect();
+    let dithered = |method| {
+      let mut dithered = buffer.clone();
+      dither(&mut dithered, method, width, height, &DitherOptions::default()).unwrap();
+      dithered
+    };
+    let expected = dithered(DitherMethod::FloydSteinberg);
+    assert_eq!(dithered(DitherMethod::Riemersma), expected);
+    assert_eq!(dithered(DitherMethod::ZhouFang), expected);
+    assert_eq!(dithered(DitherMethod::DotDiffusion), expected);
+  }
+
+  #[test]
+  fn test_dither_only_changes_one_channel_outside_base() {
+    // a smooth gradient with more colors than the base palette has
+    let (width, height) = (48, 4);
+    let mut buffer = Vec::new();
+    for y in 0..height {
+      for x in 0..width {
+        buffer.extend([(x * 5) as u8, (255 - x * 5) as u8, (y * 60) as u8]);
+      }
+    }
+    let base = base_palette(&buffer);
+    dither(&mut buffer, DitherMethod::FloydSteinberg, width, height, &DitherOptions::default()).unwrap();
+
+    for row in buffer.chunks_exact(width as usize * 3) {
+      let mut previous = base[0];
+      for pixel in row.chunks_exact(3) {
+        let pixel = Color::from(pixel);
+        assert_eq!(Target::Rgb444.quantize(pixel), pixel);
+        assert!(reachable(previous, pixel, &base), "{previous} cannot be followed by {pixel}");
+        previous = pixel;
+      }
+    }
+  }
+}
@@ -0,0 +1,166 @@
+//! Palettes of indexed output formats.
+//!
+//! Dithered images use only a few colors, so PNG and GIF can store them as palette indices instead of
+//! true color. The order of the palette entries is free; sorting them groups similar indices, which
+//! compresses better, and lets output follow hardware conventions such as index 0 being black.
+//! A fully transparent entry is always placed at index 0.
+
+use std::collections::HashMap;
+
+use crate::pipeline::Image;
+
+/// Largest number of palette entries of an indexed image.
+pub const MAX_COLORS: usize = 256;
+
+/// Orders of the palette entries of indexed output.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
+pub enum PaletteOrder {
+  /// In the order the colors first appear, row by row from the top left
+  #[default]
+  Appearance,
+  /// From dark to light, so index 0 is the darkest color
+  Luminance,
+  /// From the most to the least used color
+  Usage,
+}
+
+serde_value_enum!(PaletteOrder);
+
+/// An image stored as indices into a palette.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Indexed {
+  /// RGBA palette entries
+  pub palette: Vec<[u8; 4]>,
+  /// Palette index of every pixel, row by row
+  pub indices: Vec<u8>,
+}
+
+impl Indexed {
+  /// Returns whether any palette entry is not fully opaque.
+  #[must_use]
+  pub fn has_transparency(&self) -> bool {
+    self.palette.iter().any(|entry| entry[3] < 255)
+  }
+
+  /// Returns the palette as consecutive RGB triples.
+  #[must_use]
+  pub fn rgb(&self) -> Vec<u8> {
+    self.palette.iter().flat_map(|entry| [entry[0], entry[1], entry[2]]).collect()
+  }
+
+  /// Returns the alpha of the palette entries, without the opaque entries at the end.
+  #[must_use]
+  pub fn alpha(&self) -> Vec<u8> {
+    let len = self.palette.iter().rposition(|entry| entry[3] < 255).map_or(0, |i| i + 1);
+    self.palette[..len].iter().map(|entry| entry[3]).collect()
+  }
+}
+
+/// Builds the palette of an image and maps its pixels to indices.
+///
+/// All fully transparent pixels share one entry at index 0. Returns a description of the problem if the
+/// image has more than [`MAX_COLORS`] colors.
+pub fn index(image: &Image, order: PaletteOrder) -> Result<Indexed, String> {
+  const TRANSPARENT: [u8; 4] = [0; 4];
+  let pixels: Vec<[u8; 4]> = image
+    .buffer
+    .chunks_exact(3)
+    .enumerate()
+    .map(|(i, rgb)| match image.alpha.as_ref().map_or(255, |alpha| alpha[i]) {
+      0 => TRANSPARENT,
+      a => [rgb[0], rgb[1], rgb[2], a],
+    })
+    .collect();
+
+  // first appearance and number of uses of every color
+  let mut colors: HashMap<[u8; 4], (usize, u64)> = HashMap::new();
+  for (i, pixel) in pixels.iter().enumerate() {
+    colors.entry(*pixel).or_insert((i, 0)).1 += 1;
+    if colors.len() > MAX_COLORS {
+      return Err(format!(
+        "indexed output supports at most {MAX_COLORS} colors, dither to a smaller palette first"
+      ));
+    }
+  }
+
+  let mut palette: Vec<[u8; 4]> = colors.keys().copied().collect();
+  let first_seen = |color: &[u8; 4]| colors[color].0;
+  match order {
+    PaletteOrder::Appearance => palette.sort_by_key(first_seen),
+    PaletteOrder::Luminance => palette.sort_by_key(|color| (luma(color), first_seen(color))),
+    PaletteOrder::Usage => palette.sort_by_key(|color| (std::cmp::Reverse(colors[color].1), first_seen(color))),
+  }
+  if let Some(transparent) = palette.iter().position(|&color| color == TRANSPARENT) {
+    palette[..=transparent].rotate_right(1);
+  }
+
+  let position: HashMap<[u8; 4], u8> = palette.iter().enumerate().map(|(i, &color)| (color, i as u8)).collect();
+  let indices = pixels.iter().map(|pixel| position[pixel]).collect();
+  Ok(Indexed { palette, indices })
+}
+
+/// Integer luma with the Rec. 601 weights.
+fn luma(color: &[u8; 4]) -> u32 {
+  299 * u32::from(color[0]) + 587 * u32::from(color[1]) + 114 * u32::from(color[2])
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn image(buffer: Vec<u8>, alpha: Option<Vec<u8>>) -> Image {
+    let width = buffer.len() as u32 / 3;
+    Image {
+      buffer,
+      alpha,
+      width,
+      height: 1,
+    }
+  }
+
+  const WHITE: [u8; 4] = [255, 255, 255, 255];
+  const RED: [u8; 4] = [255, 0, 0, 255];
+  const BLACK: [u8; 4] = [0, 0, 0, 255];
+
+  #[test]
+  fn test_index_orders() {
+    // white, red, black, black, black, red
+    let image = image(vec![255, 255, 255, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255, 0, 0], None);
+
+    let indexed = index(&image, PaletteOrder::Appearance).unwrap();
+    assert_eq!(indexed.palette, vec![WHITE, RED, BLACK]);
+    assert_eq!(indexed.indices, vec![0, 1, 2, 2, 2, 1]);
+
+    let indexed = index(&image, PaletteOrder::Luminance).unwrap();
+    assert_eq!(indexed.palette, vec![BLACK, RED, WHITE]);
+    assert_eq!(indexed.indices, vec![2, 1, 0, 0, 0, 1]);
+
+    let indexed = index(&image, PaletteOrder::Usage).unwrap();
+    assert_eq!(indexed.palette, vec![BLACK, RED, WHITE]);
+  }
+
+  #[test]
+  fn test_index_transparent_first() {
+    // the transparent pixel's color is irrelevant and shares the entry with all other transparent pixels
+    let image = image(vec![255, 255, 255, 10, 20, 30, 0, 0, 0], Some(vec![255, 0, 128]));
+    let indexed = index(&image, PaletteOrder::Appearance).unwrap();
+    assert_eq!(indexed.palette, vec![[0; 4], WHITE, [0, 0, 0, 128]]);
+    assert_eq!(indexed.indices, vec![1, 0, 2]);
+    assert!(indexed.has_transparency());
+    assert_eq!(indexed.alpha(), vec![0, 255, 128]);
+  }
+
+  #[test]
+  fn test_index_alpha_trims_opaque_entries() {
+    let image = image(vec![0, 0, 0, 255, 255, 255], Some(vec![0, 255]));
+    let indexed = index(&image, PaletteOrder::Luminance).unwrap();
+    assert_eq!(indexed.alpha(), vec![0]);
+    assert_eq!(indexed.rgb(), vec![0, 0, 0, 255, 255, 255]);
+  }
+
+  #[test]
+  fn test_index_too_many_colors() {
+    let buffer = (0..=MAX_COLORS as u32).flat_map(|i| [i as u8, (i >> 8) as u8, 0]).collect();
+    assert!(index(&image(buffer, None), PaletteOrder::Appearance).is_err());
+  }
+}
@@ -0,0 +1,219 @@
+//! Summaries of image files for the `info` subcommand.
+//!
+//! Besides the basic properties, the summary tells whether an image is already limited to few enough
+//! colors for indexed output, which built-in palette fits it best, and lists `dithers` text metadata
+//! embedded in PNG files.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+
+use clap::ValueEnum;
+use image::{ImageDecoder, ImageReader};
+
+use crate::error::DitherError;
+use crate::indexed;
+use crate::input;
+use crate::palette::{self, Color, ColorPalette};
+
+/// Keyword prefix of PNG text chunks holding dithers metadata.
+pub const METADATA_KEYWORD: &str = "dithers";
+
+/// Properties of an image file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ImageInfo {
+  /// Detected file format, `None` if it is not one the `image` crate knows
+  pub format: Option<String>,
+  /// Width in pixels
+  pub width: u32,
+  /// Height in pixels
+  pub height: u32,
+  /// Color type as stored in the file, e.g. `Rgb8`
+  pub color_type: Option<String>,
+  /// Number of distinct RGB colors
+  pub unique_colors: usize,
+  /// Built-in palette closest to the image colors
+  pub best_palette: ColorPalette,
+  /// Root mean square distance of the pixels to the best palette, 0 when the image only uses its colors
+  pub palette_error: f64,
+  /// Keywords and text of embedded dithers metadata
+  pub metadata: Vec<(String, String)>,
+}
+
+impl ImageInfo {
+  /// Returns whether the image has few enough colors to be stored as an indexed image.
+  #[must_use]
+  pub fn is_palette_limited(&self) -> bool {
+    self.unique_colors <= indexed::MAX_COLORS
+  }
+}
+
+/// Reads an image file and summarizes it.
+pub fn inspect(path: &Path) -> Result<ImageInfo, DitherError> {
+  // the header tells the stored format and color type, which decoding to RGB loses
+  let (format, color_type) = match ImageReader::open(path).and_then(|reader| reader.with_guessed_format()) {
+    Ok(reader) => {
+      let format = reader.format().map(|format| format!("{format:?}"));
+      let color_type = reader.into_decoder().ok().map(|decoder| format!("{:?}", decoder.original_color_type()));
+      (format, color_type)
+    }
+    Err(_) => (None, None),
+  };
+  let image = input::load(path)?;
+
+  let mut counts: HashMap<Color, u64> = HashMap::new();
+  for pixel in image.buffer.chunks_exact(3) {
+    *counts.entry(Color::from(pixel)).or_default() += 1;
+  }
+  let pixels = (image.width as f64 * image.height as f64).max(1.0);
+  let (best_palette, palette_error) = ColorPalette::value_variants()
+    .iter()
+    .map(|&palette| {
+      let squared: f64 = counts.iter().map(|(&color, &count)| count as f64 * distance(color, palette.colors())).sum();
+      (palette, (squared / pixels).sqrt())
+    })
+    .min_by(|a, b| a.1.total_cmp(&b.1))
+    .expect("there are built-in palettes");
+
+  Ok(ImageInfo {
+    format,
+    width: image.width,
+    height: image.height,
+    color_type,
+    unique_colors: counts.len(),
+    best_palette,
+    palette_error,
+    metadata: metadata(path),
+  })
+}
+
+/// Squared distance of a color to the closest palette entry.
+fn distance(color: Color, colors: &[Color]) -> f64 {
+  let closest = palette::map_to_palette(color, colors).0;
+  [(color.r, closest.r), (color.g, closest.g), (color.b, closest.b)]
+    .iter()
+    .map(|&(a, b)| (f64::from(a) - f64::from(b)).powi(2))
+    .sum()
+}
+
+/// Returns the dithers text chunks of a PNG file, nothing for other files.
+fn metadata(path: &Path) -> Vec<(String, String)> {
+  let Ok(file) = File::open(path) else {
+    return Vec::new();
+  };
+  let Ok(reader) = png::Decoder::new(BufReader::new(file)).read_info() else {
+    return Vec::new();
+  };
+  let info = reader.info();
+  let latin1 = info
+    .uncompressed_latin1_text
+    .iter()
+    .map(|chunk| (chunk.keyword.clone(), Some(chunk.text.clone())));
+  let compressed = info.compressed_latin1_text.iter().map(|chunk| (chunk.keyword.clone(), chunk.get_text().ok()));
+  let utf8 = info.utf8_text.iter().map(|chunk| (chunk.keyword.clone(), chunk.get_text().ok()));
+  latin1
+    .chain(compressed)
+    .chain(utf8)
+    .filter(|(keyword, _)| keyword.starts_with(METADATA_KEYWORD))
+    .filter_map(|(keyword, text)| Some((keyword, text?)))
+    .collect()
+}
+
+impl fmt::Display for ImageInfo {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let name = |palette: ColorPalette| palette.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default();
+    writeln!(f, "format:        {}", self.format.as_deref().unwrap_or("unknown"))?;
+    writeln!(f, "dimensions:    {}x{}", self.width, self.height)?;
+    writeln!(f, "color type:    {}", self.color_type.as_deref().unwrap_or("unknown"))?;
+    writeln!(f, "unique colors: {}", self.unique_colors)?;
+    if self.is_palette_limited() {
+      writeln!(f, "palette:       yes, fits an indexed palette")?;
+    } else {
+      writeln!(f, "palette:       no, more than {} colors", indexed::MAX_COLORS)?;
+    }
+    if self.palette_error == 0.0 {
+      writeln!(f, "best fit:      {} (exact)", name(self.best_palette))?;
+    } else {
+      writeln!(f, "best fit:      {} (RMS error {:.1})", name(self.best_palette), self.palette_error)?;
+    }
+    if self.metadata.is_empty() {
+      write!(f, "metadata:      none")
+    } else {
+      write!(f, "metadata:")?;
+      for (keyword, text) in &self.metadata {
+        write!(f, "\n  {keyword}: {}", text.trim_end().replace('\n', "\n    "))?;
+      }
+      Ok(())
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::output;
+  use crate::pipeline::{Image, Settings};
+
+  #[test]
+  fn test_inspect_dithered_image() {
+    let path = std::env::temp_dir().join("dithers_info_test.png");
+    let image = Image {
+      buffer: vec![0, 0, 0, 255, 255, 255, 255, 255, 255, 0, 0, 0],
+      alpha: None,
+      width: 2,
+      height: 2,
+    };
+    output::save(&image, &path, &Settings::default()).unwrap();
+
+    let info = inspect(&path).unwrap();
+    assert_eq!(info.format.as_deref(), Some("Png"));
+    assert_eq!((info.width, info.height), (2, 2));
+    assert_eq!(info.color_type.as_deref(), Some("Rgb8"));
+    assert_eq!(info.unique_colors, 2);
+    assert!(info.is_palette_limited());
+    assert_eq!(info.best_palette, ColorPalette::Monochrome);
+    assert_eq!(info.palette_error, 0.0);
+    assert!(info.metadata.is_empty());
+    assert!(info.to_string().contains("best fit:      monochrome (exact)"));
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_inspect_reads_metadata() {
+    let path = std::env::temp_dir().join("dithers_info_metadata_test.png");
+    let mut out = Vec::new();
+    let mut encoder = png::Encoder::new(&mut out, 1, 1);
+    encoder.set_color(png::ColorType::Rgb);
+    encoder
+      .add_text_chunk(METADATA_KEYWORD.to_string(), "method = \"atkinson\"".to_string())
+      .unwrap();
+    encoder.add_text_chunk("Software".to_string(), "other".to_string()).unwrap();
+    encoder.write_header().unwrap().write_image_data(&[200, 10, 100]).unwrap();
+    std::fs::write(&path, out).unwrap();
+
+    let info = inspect(&path).unwrap();
+    assert_eq!(info.metadata, vec![(METADATA_KEYWORD.to_string(), "method = \"atkinson\"".to_string())]);
+    assert!(info.palette_error > 0.0);
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_inspect_finds_terminal_palettes() {
+    let path = std::env::temp_dir().join("dithers_info_terminal_test.png");
+    let image = Image {
+      // a gray of the xterm ramp and a color of its cube
+      buffer: vec![128, 128, 128, 0xff, 0x87, 0x00],
+      alpha: None,
+      width: 2,
+      height: 1,
+    };
+    output::save(&image, &path, &Settings::default()).unwrap();
+
+    let info = inspect(&path).unwrap();
+    assert_eq!(info.best_palette, ColorPalette::Xterm256);
+    assert_eq!(info.palette_error, 0.0);
+    std::fs::remove_file(&path).unwrap();
+  }
+}
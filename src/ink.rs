@@ -0,0 +1,71 @@
+//! Total ink coverage limits for print.
+//!
+//! Presses and risographs can only lay down so much ink at one spot before it smears, sets off or
+//! fails to dry, so a print job limits the total area coverage, the sum of all plates in percent.
+//! Palette colors are separated into CMYK with full gray component replacement: the gray part of a color
+//! is printed with black, the rest with at most two of cyan, magenta and yellow. Colors above the limit
+//! are not used for quantization, dithering mixes them from the remaining colors instead.
+
+use crate::palette::Color;
+
+/// Returns the total ink coverage of a color in percent, from 0 (paper) up to 200.
+#[must_use]
+pub fn coverage(color: Color) -> f32 {
+  let [c, m, y] = [color.r, color.g, color.b].map(|value| 1.0 - f32::from(value) / 255.0);
+  let k = c.min(m).min(y);
+  (k + (c - k) + (m - k) + (y - k)) * 100.0
+}
+
+/// Returns the palette colors whose coverage is within `max` percent.
+///
+/// If no color is, the color with the least coverage is kept so that there is something to print.
+#[must_use]
+pub fn limit_palette(colors: &[Color], max: f32) -> Vec<Color> {
+  let allowed: Vec<Color> = colors.iter().copied().filter(|&color| coverage(color) <= max).collect();
+  if allowed.is_empty() {
+    log::warn!("no palette color is within {max}% ink coverage, using the lightest one");
+    return colors
+      .iter()
+      .copied()
+      .min_by(|&a, &b| coverage(a).total_cmp(&coverage(b)))
+      .into_iter()
+      .collect();
+  }
+  for color in colors.iter().filter(|color| !allowed.contains(color)) {
+    log::debug!("{color} exceeds {max}% ink coverage with {:.0}%", coverage(*color));
+  }
+  allowed
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::palette::ColorPalette;
+
+  fn color(hex: &str) -> Color {
+    hex.parse().unwrap()
+  }
+
+  #[test]
+  fn test_coverage() {
+    assert_eq!(coverage(color("#fff")), 0.0);
+    assert_eq!(coverage(color("#000")), 100.0);
+    assert_eq!(coverage(color("#f00")), 200.0);
+    assert_eq!(coverage(color("#0ff")), 100.0);
+    assert!((coverage(color("#808080")) - 49.8).abs() < 0.1);
+  }
+
+  #[test]
+  fn test_limit_palette() {
+    let limited = limit_palette(ColorPalette::COLOR8.colors(), 150.0);
+    assert!(limited.iter().all(|&color| coverage(color) <= 150.0));
+    assert!(limited.contains(&color("#000")) && limited.contains(&color("#fff")));
+    assert_eq!(limited.len(), 7);
+    assert!(!limited.contains(&color("#cc3500")));
+  }
+
+  #[test]
+  fn test_limit_palette_keeps_lightest() {
+    assert_eq!(limit_palette(&[color("#000"), color("#00f")], 10.0), vec![color("#000")]);
+  }
+}
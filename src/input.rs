@@ -0,0 +1,232 @@
+//! Image decoding for the processing pipeline.
+
+use std::fs::File;
+use std::io::{BufReader, Read, Seek};
+use std::path::Path;
+
+use image::metadata::Orientation;
+use image::{DynamicImage, ImageDecoder, ImageFormat, ImageReader, ImageResult};
+use tiff::decoder::{Decoder, DecodingResult};
+use tiff::{ColorType, TiffError, TiffResult, TiffUnsupportedError};
+
+use crate::error::DitherError;
+use crate::pipeline::Image;
+
+/// Options and limits for decoding untrusted inputs.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LoadOptions {
+  /// Turn the image upright according to its EXIF orientation
+  pub auto_orient: bool,
+  /// Largest width and height in pixels
+  pub max_dimension: Option<u32>,
+  /// Largest number of pixels
+  pub max_pixels: Option<u64>,
+}
+
+impl Default for LoadOptions {
+  fn default() -> Self {
+    LoadOptions {
+      auto_orient: true,
+      max_dimension: None,
+      max_pixels: None,
+    }
+  }
+}
+
+impl LoadOptions {
+  /// Checks the dimensions of an image against the limits.
+  pub fn check(&self, path: &Path, width: u32, height: u32) -> Result<(), DitherError> {
+    let too_large = |limit: String| DitherError::TooLarge {
+      path: path.to_path_buf(),
+      width,
+      height,
+      limit,
+    };
+    if let Some(max) = self.max_dimension.filter(|&max| width > max || height > max) {
+      return Err(too_large(format!("{max} pixels per side")));
+    }
+    if let Some(max) = self.max_pixels.filter(|&max| u64::from(width) * u64::from(height) > max) {
+      return Err(too_large(format!("{max} pixels")));
+    }
+    Ok(())
+  }
+}
+
+/// Opens and decodes an image file, turned upright according to its EXIF orientation.
+///
+/// The color channels are converted to 8-bit RGB, the alpha channel is kept separately if the image has one.
+/// With the `raw` feature, camera RAW files are developed to sRGB as well.
+pub fn load(path: &Path) -> Result<Image, DitherError> {
+  load_with(path, &LoadOptions::default())
+}
+
+/// Like [`load`], with options for the orientation and limits for the size of the image.
+///
+/// Phone cameras store photos as captured and record how the phone was held in the orientation.
+/// The image has to be turned before dithering, rotating a dither pattern afterwards changes it.
+/// The limits are checked against the header before any pixels are decoded, so oversized untrusted
+/// inputs fail with [`DitherError::TooLarge`] instead of exhausting the memory.
+pub fn load_with(path: &Path, options: &LoadOptions) -> Result<Image, DitherError> {
+  #[cfg(feature = "raw")]
+  if crate::raw::is_raw(path) {
+    // the RAW decoder has no separate header step, the limits are checked after decoding
+    let image = crate::raw::load(path)?;
+    options.check(path, image.width, image.height)?;
+    return Ok(image);
+  }
+
+  let image_error = |source| DitherError::Image {
+    path: path.to_path_buf(),
+    source,
+  };
+  let reader = ImageReader::open(path).map_err(|source| DitherError::Io {
+    path: path.to_path_buf(),
+    source,
+  })?;
+  let mut decoder = reader.into_decoder().map_err(image_error)?;
+  let (width, height) = decoder.dimensions();
+  options.check(path, width, height)?;
+  // unreadable metadata leaves the image as stored
+  let orientation = if options.auto_orient {
+    decoder.orientation().unwrap_or(Orientation::NoTransforms)
+  } else {
+    Orientation::NoTransforms
+  };
+  let mut decoded = DynamicImage::from_decoder(decoder).map_err(image_error)?;
+  if orientation != Orientation::NoTransforms {
+    log::debug!("turning {:?} upright: {:?}", path, orientation);
+    decoded.apply_orientation(orientation);
+  }
+
+  let alpha = decoded.color().has_alpha().then(|| decoded.to_rgba8().pixels().map(|p| p[3]).collect());
+  let rgb = decoded.into_rgb8();
+  let (width, height) = rgb.dimensions();
+  log::debug!("decoded {:?}: {}x{}{}", path, width, height, if alpha.is_some() { " with alpha" } else { "" });
+  Ok(Image {
+    buffer: rgb.into_raw(),
+    alpha,
+    width,
+    height,
+  })
+}
+
+/// Reads the EXIF orientation of an image file without decoding it, `NoTransforms` if there is none.
+#[must_use]
+pub fn orientation(path: &Path) -> Orientation {
+  ImageReader::open(path)
+    .ok()
+    .and_then(|reader| reader.into_decoder().ok())
+    .and_then(|mut decoder| decoder.orientation().ok())
+    .unwrap_or(Orientation::NoTransforms)
+}
+
+/// Reads the dimensions of an image file from its header, swapped if `auto_orient` is set and the
+/// EXIF orientation turns the image by 90 degrees.
+pub fn dimensions(path: &Path, auto_orient: bool) -> ImageResult<(u32, u32)> {
+  let (width, height) = image::image_dimensions(path)?;
+  let turned = matches!(
+    orientation(path),
+    Orientation::Rotate90 | Orientation::Rotate270 | Orientation::Rotate90FlipH | Orientation::Rotate270FlipH
+  );
+  Ok(if auto_orient && turned { (height, width) } else { (width, height) })
+}
+
+/// Returns whether the file extension selects the TIFF format.
+#[must_use]
+pub fn is_tiff(path: &Path) -> bool {
+  matches!(ImageFormat::from_path(path), Ok(ImageFormat::Tiff))
+}
+
+/// Opens a TIFF file and decodes all of its pages.
+///
+/// Supports 8 and 16-bit grayscale and RGB pages, with or without alpha, 16-bit samples are reduced to 8 bits.
+/// Every page is checked against the limits of the options before it is decoded, the orientation is not applied.
+pub fn load_pages(path: &Path, options: &LoadOptions) -> Result<Vec<Image>, DitherError> {
+  let tiff_error = |source| DitherError::Tiff {
+    path: path.to_path_buf(),
+    source,
+  };
+  let file = File::open(path).map_err(|source| DitherError::Io {
+    path: path.to_path_buf(),
+    source,
+  })?;
+  let mut decoder = Decoder::new(BufReader::new(file)).map_err(tiff_error)?;
+
+  let mut pages = Vec::new();
+  loop {
+    let (width, height) = decoder.dimensions().map_err(tiff_error)?;
+    options.check(path, width, height)?;
+    pages.push(decode_page(&mut decoder).map_err(tiff_error)?);
+    if !decoder.more_images() {
+      break;
+    }
+    decoder.next_image().map_err(tiff_error)?;
+  }
+  log::debug!("decoded {} page(s) from {:?}", pages.len(), path);
+  Ok(pages)
+}
+
+/// Decodes the current page of a TIFF decoder.
+fn decode_page<R: Read + Seek>(decoder: &mut Decoder<R>) -> TiffResult<Image> {
+  let (width, height) = decoder.dimensions()?;
+  let color_type = decoder.colortype()?;
+  let unsupported = || TiffError::UnsupportedError(TiffUnsupportedError::UnsupportedColorType(color_type));
+  let (channels, has_alpha) = match color_type {
+    ColorType::Gray(8 | 16) => (1, false),
+    ColorType::GrayA(8 | 16) => (2, true),
+    ColorType::RGB(8 | 16) => (3, false),
+    ColorType::RGBA(8 | 16) => (4, true),
+    _ => return Err(unsupported()),
+  };
+  let samples = match decoder.read_image()? {
+    DecodingResult::U8(samples) => samples,
+    DecodingResult::U16(samples) => samples.iter().map(|&s| (s >> 8) as u8).collect(),
+    _ => return Err(unsupported()),
+  };
+
+  let pixels = samples.chunks_exact(channels);
+  let color = channels - usize::from(has_alpha);
+  let buffer = pixels.clone().flat_map(|p| if color == 1 { [p[0]; 3] } else { [p[0], p[1], p[2]] }).collect();
+  let alpha = has_alpha.then(|| pixels.map(|p| p[color]).collect());
+  Ok(Image { buffer, alpha, width, height })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_load_with_orientation_and_limits() {
+    let path = std::env::temp_dir().join("dithers_input_orientation_test.png");
+    // big-endian EXIF with a single entry: orientation 6, rotate 90 degrees clockwise
+    let exif = [b"MM\0\x2a\0\0\0\x08\0\x01".as_slice(), &[0x01, 0x12, 0, 3, 0, 0, 0, 1, 0, 6, 0, 0], &[0; 4]].concat();
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, 2, 1);
+    encoder.set_color(png::ColorType::Rgb);
+    let mut writer = encoder.write_header().unwrap();
+    writer.write_chunk(png::chunk::eXIf, &exif).unwrap();
+    writer.write_image_data(&[10, 10, 10, 20, 20, 20]).unwrap();
+    writer.finish().unwrap();
+    std::fs::write(&path, png).unwrap();
+
+    let upright = load(&path).unwrap();
+    assert_eq!((upright.width, upright.height), (1, 2));
+    assert_eq!(upright.buffer, [10, 10, 10, 20, 20, 20]);
+    assert_eq!(dimensions(&path, true).unwrap(), (1, 2));
+
+    let options = LoadOptions {
+      auto_orient: false,
+      ..LoadOptions::default()
+    };
+    let stored = load_with(&path, &options).unwrap();
+    assert_eq!((stored.width, stored.height), (2, 1));
+    assert_eq!(dimensions(&path, false).unwrap(), (2, 1));
+
+    let limited = LoadOptions {
+      max_pixels: Some(1),
+      ..LoadOptions::default()
+    };
+    assert!(matches!(load_with(&path, &limited), Err(DitherError::TooLarge { width: 2, height: 1, .. })));
+    std::fs::remove_file(&path).unwrap();
+  }
+}
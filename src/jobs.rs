@@ -0,0 +1,531 @@
+//! Job manifests for heterogeneous batch runs.
+//!
+//! A jobs file lists one dithering job per entry, each with its own input, output, method and palette,
+//! so that a single process can handle a whole batch with different settings per file.
+//! The format is selected by the file extension.
+//!
+//! TOML (`.toml`), an array of `[[job]]` tables:
+//!
+//! ```toml
+//! [[job]]
+//! input = "photo.jpg"
+//! output = "photo-mono.png"
+//! method = "atkinson"
+//! palette = "monochrome"
+//! ```
+//!
+//! CSV (`.csv`), a header row naming the columns followed by one job per row:
+//!
+//! ```text
+//! input,output,method,palette
+//! photo.jpg,photo-mono.png,atkinson,monochrome
+//! ```
+//!
+//! Only `input` is required; missing or empty fields fall back to the default output path, method and palette.
+//! Any processing option of [`Settings`] can be given per job as well, e.g. `ink = "#222"`.
+//! Instead of a fixed `output`, a job may name its result with an `out_template` (see [`crate::template`]).
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::args::Args;
+use crate::dither::DitherMethod;
+use crate::error::DitherError;
+use crate::palette::{Color, ColorPalette};
+use crate::pipeline::{self, Image, Settings};
+use crate::template::{OutputTemplate, TemplateContext};
+use crate::{input, output, stream};
+
+/// A single dithering job.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Job {
+  /// Input image file path
+  pub input: PathBuf,
+  /// Output image file path, defaults to `<input>_out.<ext>`
+  #[serde(default)]
+  pub output: Option<PathBuf>,
+  /// Template naming the output when no explicit output path is given
+  #[serde(default)]
+  pub out_template: Option<OutputTemplate>,
+  /// Dithering algorithm to use
+  #[serde(default)]
+  pub method: DitherMethod,
+  /// Color palette for quantization
+  #[serde(default)]
+  pub palette: ColorPalette,
+  /// Processing options, given as additional fields of the job
+  #[serde(flatten)]
+  pub settings: Settings,
+}
+
+/// Top-level layout of a TOML jobs file.
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Manifest {
+  #[serde(default)]
+  job: Vec<Job>,
+}
+
+impl Job {
+  /// Creates a job for the given input using the default output path, method, palette and settings.
+  #[must_use]
+  pub fn new(input: impl Into<PathBuf>) -> Self {
+    Job {
+      input: input.into(),
+      output: None,
+      out_template: None,
+      method: DitherMethod::default(),
+      palette: ColorPalette::default(),
+      settings: Settings::default(),
+    }
+  }
+
+  /// Builds the job described by the single-image command-line arguments.
+  ///
+  /// # Panics
+  ///
+  /// Panics if no input image was given, which clap prevents unless a jobs file is used.
+  #[must_use]
+  pub fn from_args(args: &Args) -> Self {
+    Job {
+      input: args.in_img.clone().expect("an input image is required without a jobs file"),
+      // the output flag always has a default value, so a template has to take precedence over it
+      output: if args.out_template.is_some() { None } else { args.out_img.clone() },
+      out_template: args.out_template.clone(),
+      method: args.dither_type,
+      palette: args.color_palette,
+      settings: args.settings.clone(),
+    }
+  }
+
+  /// Returns the path the result of this job is written to, given the output dimensions.
+  ///
+  /// An explicit output path wins over the template, which wins over the `_out` suffix default.
+  #[must_use]
+  pub fn output_path(&self, width: u32, height: u32) -> PathBuf {
+    match (&self.output, &self.out_template) {
+      (Some(output), _) => output.clone(),
+      (None, Some(template)) => template.render(&TemplateContext {
+        input: &self.input,
+        method: self.method,
+        palette: self.palette,
+        width,
+        height,
+      }),
+      (None, None) => default_output_path(&self.input),
+    }
+  }
+
+  /// Returns the output path of this job if that file already exists and holds a finished image.
+  ///
+  /// Empty files and files whose header cannot be read, e.g. those of an interrupted run, are not finished. Only
+  /// the header is read, large outputs are not decoded. Outputs of a `--format` only need to be non-empty, they
+  /// are not image files the `image` crate can read.
+  /// Names that depend on the output size read the dimensions from the input header, without decoding
+  /// the image. Inputs whose header cannot be read are reported as not done, running them shows the error.
+  #[must_use]
+  pub fn existing_output(&self) -> Option<PathBuf> {
+    let (width, height) = match &self.out_template {
+      Some(template) if self.output.is_none() && template.uses("size") => {
+        let (width, height) = input::dimensions(&self.input, !self.settings.no_auto_orient).ok()?;
+        self.settings.output_size(width, height)
+      }
+      _ => (0, 0),
+    };
+    let output = self.output_path(width, height);
+    let non_empty = fs::metadata(&output).is_ok_and(|metadata| metadata.is_file() && metadata.len() > 0);
+    if !output.exists() {
+      None
+    } else if non_empty && (self.settings.format.is_some() || image::image_dimensions(&output).is_ok()) {
+      Some(output)
+    } else {
+      log::warn!("{:?} is empty or its header cannot be read, it is written again", output);
+      None
+    }
+  }
+
+  /// Opens the input image and dithers it without saving the result.
+  pub fn render(&self) -> Result<Image, DitherError> {
+    let colors = pipeline::palette_colors(&self.input, self.palette, &self.settings)?;
+    self.render_with(&colors)
+  }
+
+  /// Like [`Job::render`], with the colors from [`pipeline::palette_colors`].
+  fn render_with(&self, colors: &[Color]) -> Result<Image, DitherError> {
+    log::debug!("job {:?}: method {:?}, palette {:?}", self.input, self.method, self.palette);
+    let mut image = input::load_with(&self.input, &self.settings.load_options())?;
+    pipeline::process_with_colors(&mut image, self.method, colors, &self.settings)?;
+    Ok(image)
+  }
+
+  /// Opens the input image, dithers it and saves the result.
+  ///
+  /// Every page of a multi-page TIFF input is dithered if the output is a TIFF file as well,
+  /// otherwise only the first page is used.
+  ///
+  /// Returns the path of the written output image.
+  pub fn run(&self) -> Result<PathBuf, DitherError> {
+    // --palette-from loads and quantizes another image, which is done once for all pages or strips
+    let colors = pipeline::palette_colors(&self.input, self.palette, &self.settings)?;
+    if let Some(limit) = self.settings.max_memory
+      && let Some(output) = stream::run_if_needed(self, limit, &colors)?
+    {
+      return Ok(output);
+    }
+    let mut pages = match input::is_tiff(&self.input).then(|| input::load_pages(&self.input, &self.settings.load_options())) {
+      Some(Ok(pages)) if pages.len() > 1 => pages,
+      Some(Err(err @ DitherError::TooLarge { .. })) => return Err(err),
+      // single images, and TIFF variants the page decoder does not handle, use the regular decoder
+      _ => {
+        let image = self.render_with(&colors)?;
+        let output = self.output_path(image.width, image.height);
+        output::save(&image, &output, &self.settings)?;
+        return Ok(output);
+      }
+    };
+    let output = self.output_path(pages[0].width, pages[0].height);
+    if pages.len() > 1 && (self.settings.format.is_some() || !input::is_tiff(&output)) {
+      log::warn!("{:?} has {} pages, only the first is written to {:?}", self.input, pages.len(), output);
+      pages.truncate(1);
+    }
+    for page in &mut pages {
+      pipeline::process_with_colors(page, self.method, &colors, &self.settings)?;
+    }
+    match pages.as_slice() {
+      [page] => output::save(page, &output, &self.settings)?,
+      pages => output::save_pages(pages, &output, &self.settings)?,
+    }
+    Ok(output)
+  }
+}
+
+/// Derives an output path next to the input by adding an `_out` suffix to the file stem.
+///
+/// `photos/cat.jpg` becomes `photos/cat_out.jpg`.
+#[must_use]
+pub fn default_output_path(input: &Path) -> PathBuf {
+  let stem = input.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+  let name = match input.extension() {
+    Some(ext) => format!("{}_out.{}", stem, ext.to_string_lossy()),
+    None => format!("{}_out", stem),
+  };
+  input.with_file_name(name)
+}
+
+/// Repeats every job once per size, each resizing its input to that size before dithering.
+///
+/// Outputs named by a template keep it, so it should contain `{size}`. Other outputs get the size
+/// appended to their file stem, `icon.png` becomes `icon_16.png`, `icon_32.png` and so on.
+#[must_use]
+pub fn with_sizes(jobs: Vec<Job>, sizes: &[u32]) -> Vec<Job> {
+  jobs
+    .into_iter()
+    .flat_map(|job| {
+      if job.output.is_none() && job.out_template.as_ref().is_some_and(|template| !template.uses("size")) {
+        log::warn!("the output template of {:?} has no {{size}}, all sizes are written to the same file", job.input);
+      }
+      sizes.iter().map(move |&size| {
+        let mut sized = job.clone();
+        sized.settings.resize = Some(size);
+        if job.out_template.is_none() || job.output.is_some() {
+          let output = job.output.clone().unwrap_or_else(|| default_output_path(&job.input));
+          sized.output = Some(sized_path(&output, size));
+        }
+        sized
+      })
+    })
+    .collect()
+}
+
+/// Appends a size to the file stem of a path, `icon.png` becomes `icon_16.png`.
+fn sized_path(path: &Path, size: u32) -> PathBuf {
+  let stem = path.file_stem().map(|s| s.to_string_lossy()).unwrap_or_default();
+  let name = match path.extension() {
+    Some(ext) => format!("{}_{}.{}", stem, size, ext.to_string_lossy()),
+    None => format!("{}_{}", stem, size),
+  };
+  path.with_file_name(name)
+}
+
+/// Loads the jobs listed in a `.toml` or `.csv` manifest.
+///
+/// Relative paths of the input, the output, the overlay and the palette files are resolved against the directory containing the manifest.
+pub fn load_jobs(path: &Path) -> Result<Vec<Job>, DitherError> {
+  let content = fs::read_to_string(path).map_err(|source| DitherError::Io {
+    path: path.to_path_buf(),
+    source,
+  })?;
+  let invalid = |message: String| DitherError::JobsFile {
+    path: path.to_path_buf(),
+    message,
+  };
+
+  let extension = path.extension().map(|e| e.to_string_lossy().to_lowercase());
+  let mut jobs = match extension.as_deref() {
+    Some("toml") => parse_toml(&content).map_err(invalid)?,
+    Some("csv") => parse_csv(&content).map_err(invalid)?,
+    _ => return Err(invalid("unsupported extension, expected .toml or .csv".to_string())),
+  };
+
+  log::info!("loaded {} job(s) from {:?}", jobs.len(), path);
+  let base = path.parent().unwrap_or(Path::new(""));
+  let resolve = |path: &mut Option<PathBuf>| {
+    if let Some(path) = path {
+      *path = base.join(&*path);
+    }
+  };
+  for job in &mut jobs {
+    job.input = base.join(&job.input);
+    resolve(&mut job.output);
+    resolve(&mut job.settings.overlay);
+    resolve(&mut job.settings.palette_file);
+    resolve(&mut job.settings.palette_from);
+    resolve(&mut job.settings.export_palette);
+  }
+  Ok(jobs)
+}
+
+/// Parses the jobs of a TOML manifest.
+pub fn parse_toml(content: &str) -> Result<Vec<Job>, String> {
+  let manifest: Manifest = toml::from_str(content).map_err(|e| e.to_string())?;
+  Ok(manifest.job)
+}
+
+/// Parses the jobs of a CSV manifest.
+///
+/// Blank lines and lines starting with `#` are ignored.
+pub fn parse_csv(content: &str) -> Result<Vec<Job>, String> {
+  let mut lines = content
+    .lines()
+    .enumerate()
+    .filter(|(_, line)| !line.trim().is_empty() && !line.trim_start().starts_with('#'));
+  let header = match lines.next() {
+    Some((_, line)) => split_csv_line(line),
+    None => return Ok(Vec::new()),
+  };
+
+  lines
+    .map(|(number, line)| {
+      let fields = split_csv_line(line);
+      if fields.len() > header.len() {
+        return Err(format!("line {}: {} fields but only {} columns", number + 1, fields.len(), header.len()));
+      }
+      // build a table from the non-empty fields so that empty cells use the defaults
+      let table: toml::Table = header
+        .iter()
+        .zip(fields)
+        .filter(|(_, value)| !value.is_empty())
+        .map(|(key, value)| (key.clone(), csv_value(value)))
+        .collect();
+      table.try_into().map_err(|e: toml::de::Error| format!("line {}: {}", number + 1, e.message()))
+    })
+    .collect()
+}
+
+/// Converts a CSV field into a typed value, so that flags and numbers deserialize like their TOML counterparts.
+fn csv_value(value: String) -> toml::Value {
+  if let Ok(flag) = value.parse::<bool>() {
+    toml::Value::Boolean(flag)
+  } else if let Ok(integer) = value.parse::<i64>() {
+    toml::Value::Integer(integer)
+  } else if let Ok(float) = value.parse::<f64>() {
+    toml::Value::Float(float)
+  } else {
+    toml::Value::String(value)
+  }
+}
+
+/// Splits a CSV line into trimmed fields, honoring double-quoted fields containing commas.
+fn split_csv_line(line: &str) -> Vec<String> {
+  let mut fields = Vec::new();
+  let mut field = String::new();
+  let mut quoted = false;
+  let mut chars = line.chars().peekable();
+  while let Some(c) = chars.next() {
+    match c {
+      '"' if quoted && chars.peek() == Some(&'"') => {
+        field.push('"');
+        chars.next();
+      }
+      '"' => quoted = !quoted,
+      ',' if !quoted => fields.push(std::mem::take(&mut field).trim().to_string()),
+      _ => field.push(c),
+    }
+  }
+  fields.push(field.trim().to_string());
+  fields
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::palette::Color;
+
+  #[test]
+  fn test_parse_toml_jobs() {
+    let jobs = parse_toml(
+      r#"
+      [[job]]
+      input = "a.jpg"
+      output = "a.png"
+      method = "atkinson"
+      palette = "color16"
+
+      [[job]]
+      input = "b.jpg"
+      "#,
+    )
+    .unwrap();
+
+    assert_eq!(jobs.len(), 2);
+    assert_eq!(jobs[0].input, PathBuf::from("a.jpg"));
+    assert_eq!(jobs[0].output, Some(PathBuf::from("a.png")));
+    assert_eq!(jobs[0].method, DitherMethod::Atkinson);
+    assert_eq!(jobs[0].palette, ColorPalette::COLOR16);
+    assert_eq!(jobs[1].output, None);
+    assert_eq!(jobs[1].method, DitherMethod::FloydSteinberg);
+    assert_eq!(jobs[1].palette, ColorPalette::Monochrome);
+  }
+
+  #[test]
+  fn test_parse_toml_out_template() {
+    let jobs = parse_toml("[[job]]\ninput = \"a.jpg\"\nout_template = \"{stem}-{size}.png\"").unwrap();
+    assert_eq!(jobs[0].output_path(4, 3), PathBuf::from("a-4x3.png"));
+    assert!(parse_toml("[[job]]\ninput = \"a.jpg\"\nout_template = \"{nope}.png\"").is_err());
+  }
+
+  #[test]
+  fn test_output_path_precedence() {
+    let mut job = parse_toml("[[job]]\ninput = \"dir/a.jpg\"\nmethod = \"atkinson\"").unwrap().remove(0);
+    assert_eq!(job.output_path(1, 1), PathBuf::from("dir/a_out.jpg"));
+    job.out_template = Some("{stem}_{method}.{ext}".parse().unwrap());
+    assert_eq!(job.output_path(1, 1), PathBuf::from("dir/a_atkinson.jpg"));
+    job.output = Some(PathBuf::from("b.png"));
+    assert_eq!(job.output_path(1, 1), PathBuf::from("b.png"));
+  }
+
+  #[test]
+  fn test_parse_jobs_with_settings() {
+    let jobs = parse_toml("[[job]]\ninput = \"a.jpg\"\nink = \"#222\"").unwrap();
+    assert_eq!(jobs[0].settings.ink, Some(Color { r: 0x22, g: 0x22, b: 0x22 }));
+
+    let jobs = parse_csv("input,paper,skip_transparent\na.jpg,#f5e9d0,true\n").unwrap();
+    assert_eq!(jobs[0].settings.paper, Some(Color { r: 0xf5, g: 0xe9, b: 0xd0 }));
+    assert!(jobs[0].settings.skip_transparent);
+  }
+
+  #[test]
+  fn test_parse_toml_rejects_unknown_method() {
+    let result = parse_toml("[[job]]\ninput = \"a.jpg\"\nmethod = \"nope\"");
+    assert!(result.is_err());
+  }
+
+  #[test]
+  fn test_parse_csv_jobs() {
+    let jobs = parse_csv("input,output,method,palette\n# comment\na.jpg,a.png,bayer4x4,color8\n\n\"b, c.jpg\",,,\n").unwrap();
+
+    assert_eq!(jobs.len(), 2);
+    assert_eq!(jobs[0].output, Some(PathBuf::from("a.png")));
+    assert_eq!(jobs[0].method, DitherMethod::Bayer4x4);
+    assert_eq!(jobs[0].palette, ColorPalette::COLOR8);
+    assert_eq!(jobs[1].input, PathBuf::from("b, c.jpg"));
+    assert_eq!(jobs[1].output, None);
+    assert_eq!(jobs[1].method, DitherMethod::FloydSteinberg);
+  }
+
+  #[test]
+  fn test_parse_csv_rejects_unknown_column() {
+    assert!(parse_csv("input,speed\na.jpg,fast\n").is_err());
+  }
+
+  #[test]
+  fn test_parse_csv_rejects_missing_input() {
+    assert!(parse_csv("output\na.png\n").is_err());
+  }
+
+  #[test]
+  fn test_with_sizes() {
+    let mut named = Job::new("dir/logo.png");
+    named.output = Some(PathBuf::from("dir/icon.png"));
+    let mut templated = Job::new("dir/mark.png");
+    templated.out_template = Some("{stem}-{size}.png".parse().unwrap());
+    let jobs = with_sizes(vec![named, templated, Job::new("dir/photo.jpg")], &[16, 32]);
+
+    assert_eq!(jobs.len(), 6);
+    assert_eq!(jobs[0].output, Some(PathBuf::from("dir/icon_16.png")));
+    assert_eq!(jobs[1].output, Some(PathBuf::from("dir/icon_32.png")));
+    assert_eq!(jobs[1].settings.resize, Some(32));
+    assert_eq!(jobs[3].output, None);
+    assert_eq!(jobs[3].output_path(32, 32), PathBuf::from("dir/mark-32x32.png"));
+    assert_eq!(jobs[4].output, Some(PathBuf::from("dir/photo_out_16.jpg")));
+  }
+
+  #[test]
+  fn test_load_jobs_resolves_paths_next_to_the_manifest() {
+    let dir = std::env::temp_dir().join("dithers_jobs_paths_test");
+    fs::create_dir_all(dir.join("sub")).unwrap();
+    let manifest = dir.join("sub").join("jobs.toml");
+    fs::write(&manifest, "[[job]]\ninput = \"in.png\"\noutput = \"out.png\"\noverlay = \"logo.png\"\npalette_file = \"palette.hex\"\npalette_from = \"reference.png\"\nexport_palette = \"colors.gpl\"\n").unwrap();
+
+    let jobs = load_jobs(&manifest).unwrap();
+    let sub = dir.join("sub");
+    assert_eq!(jobs[0].input, sub.join("in.png"));
+    assert_eq!(jobs[0].output, Some(sub.join("out.png")));
+    assert_eq!(jobs[0].settings.overlay, Some(sub.join("logo.png")));
+    assert_eq!(jobs[0].settings.palette_file, Some(sub.join("palette.hex")));
+    assert_eq!(jobs[0].settings.palette_from, Some(sub.join("reference.png")));
+    assert_eq!(jobs[0].settings.export_palette, Some(sub.join("colors.gpl")));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_existing_output() {
+    let dir = std::env::temp_dir().join("dithers_jobs_existing_test");
+    fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("in.png");
+    image::RgbImage::new(8, 4).save(&input).unwrap();
+
+    let mut job = Job::new(&input);
+    job.settings.pad = Some(1);
+    job.out_template = Some("{stem}-{size}.png".parse().unwrap());
+    assert_eq!(job.existing_output(), None);
+    image::RgbImage::new(10, 6).save(dir.join("in-10x6.png")).unwrap();
+    assert_eq!(job.existing_output(), Some(dir.join("in-10x6.png")), "The size includes the padding");
+
+    job.output = Some(dir.join("missing.png"));
+    assert_eq!(job.existing_output(), None);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_existing_output_must_decode() {
+    let dir = std::env::temp_dir().join("dithers_jobs_existing_decode_test");
+    fs::create_dir_all(&dir).unwrap();
+    let mut job = Job::new(dir.join("in.png"));
+    job.output = Some(dir.join("out.png"));
+    fs::write(dir.join("out.png"), b"").unwrap();
+    assert_eq!(job.existing_output(), None, "Empty files are not finished");
+    fs::write(dir.join("out.png"), b"\x89PNG\r\n\x1a\n").unwrap();
+    assert_eq!(job.existing_output(), None, "Truncated files are not finished");
+    let mut png = Vec::new();
+    image::RgbImage::new(64, 64)
+      .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+      .unwrap();
+    let data = png.windows(4).position(|window| window == b"IDAT").unwrap() + 4;
+    fs::write(dir.join("out.png"), &png[..data]).unwrap();
+    assert_eq!(job.existing_output(), Some(dir.join("out.png")), "Only the header is read");
+
+    job.settings.format = Some(output::OutputFormat::Rgb565Le);
+    assert_eq!(job.existing_output(), Some(dir.join("out.png")), "Special formats are not decoded");
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_default_output_path() {
+    assert_eq!(default_output_path(Path::new("dir/cat.jpg")), PathBuf::from("dir/cat_out.jpg"));
+    assert_eq!(default_output_path(Path::new("cat")), PathBuf::from("cat_out"));
+  }
+}
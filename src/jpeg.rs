@@ -0,0 +1,345 @@
+//! Progressive JPEG encoding.
+//!
+//! The encoder of the `image` crate only writes baseline JPEG. Progressive files store the same
+//! coefficients in several scans, first the DC (average) of every block, then the remaining frequencies
+//! of each component, so a browser can show a blurry preview before the whole file has arrived.
+//!
+//! Colors are stored as YCbCr without chroma subsampling and with the standard quantization and
+//! Huffman tables of the JPEG specification (Annex K), scaled to a quality from 1 to 100.
+
+/// Quality of JPEG output unless one is given, the default of the `image` crate.
+pub const DEFAULT_QUALITY: u8 = 75;
+
+/// Order in which the coefficients of a block are stored, as indices into the natural row-major order.
+const ZIGZAG: [usize; 64] = [
+  0, 1, 8, 16, 9, 2, 3, 10, 17, 24, 32, 25, 18, 11, 4, 5, 12, 19, 26, 33, 40, 48, 41, 34, 27, 20, 13, 6, 7, 14, 21, 28, 35, 42, 49, 56, 57, 50, 43, 36, 29, 22,
+  15, 23, 30, 37, 44, 51, 58, 59, 52, 45, 38, 31, 39, 46, 53, 60, 61, 54, 47, 55, 62, 63,
+];
+
+/// Luminance quantization table of Annex K in natural order, for quality 50.
+const LUMA_QUANTIZATION: [u16; 64] = [
+  16, 11, 10, 16, 24, 40, 51, 61, 12, 12, 14, 19, 26, 58, 60, 55, 14, 13, 16, 24, 40, 57, 69, 56, 14, 17, 22, 29, 51, 87, 80, 62, 18, 22, 37, 56, 68, 109, 103,
+  77, 24, 35, 55, 64, 81, 104, 113, 92, 49, 64, 78, 87, 103, 121, 120, 101, 72, 92, 95, 98, 112, 100, 103, 99,
+];
+
+/// Chrominance quantization table of Annex K in natural order, for quality 50.
+const CHROMA_QUANTIZATION: [u16; 64] = [
+  17, 18, 24, 47, 99, 99, 99, 99, 18, 21, 26, 66, 99, 99, 99, 99, 24, 26, 56, 99, 99, 99, 99, 99, 47, 66, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+  99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99, 99,
+];
+
+/// A Huffman table as stored in a DHT segment: the number of codes of each length 1 to 16, then the symbols.
+struct HuffmanSpec {
+  counts: [u8; 16],
+  symbols: &'static [u8],
+}
+
+const LUMA_DC: HuffmanSpec = HuffmanSpec {
+  counts: [0, 1, 5, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0],
+  symbols: &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+};
+
+const CHROMA_DC: HuffmanSpec = HuffmanSpec {
+  counts: [0, 3, 1, 1, 1, 1, 1, 1, 1, 1, 1, 0, 0, 0, 0, 0],
+  symbols: &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11],
+};
+
+const LUMA_AC: HuffmanSpec = HuffmanSpec {
+  counts: [0, 2, 1, 3, 3, 2, 4, 3, 5, 5, 4, 4, 0, 0, 1, 0x7d],
+  symbols: &[
+    0x01, 0x02, 0x03, 0x00, 0x04, 0x11, 0x05, 0x12, 0x21, 0x31, 0x41, 0x06, 0x13, 0x51, 0x61, 0x07, 0x22, 0x71, 0x14, 0x32, 0x81, 0x91, 0xa1, 0x08, 0x23, 0x42,
+    0xb1, 0xc1, 0x15, 0x52, 0xd1, 0xf0, 0x24, 0x33, 0x62, 0x72, 0x82, 0x09, 0x0a, 0x16, 0x17, 0x18, 0x19, 0x1a, 0x25, 0x26, 0x27, 0x28, 0x29, 0x2a, 0x34, 0x35,
+    0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66, 0x67,
+    0x68, 0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96, 0x97, 0x98,
+    0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5, 0xc6, 0xc7,
+    0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe1, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf1, 0xf2, 0xf3, 0xf4,
+    0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+  ],
+};
+
+const CHROMA_AC: HuffmanSpec = HuffmanSpec {
+  counts: [0, 2, 1, 2, 4, 4, 3, 4, 7, 5, 4, 4, 0, 1, 2, 0x77],
+  symbols: &[
+    0x00, 0x01, 0x02, 0x03, 0x11, 0x04, 0x05, 0x21, 0x31, 0x06, 0x12, 0x41, 0x51, 0x07, 0x61, 0x71, 0x13, 0x22, 0x32, 0x81, 0x08, 0x14, 0x42, 0x91, 0xa1, 0xb1,
+    0xc1, 0x09, 0x23, 0x33, 0x52, 0xf0, 0x15, 0x62, 0x72, 0xd1, 0x0a, 0x16, 0x24, 0x34, 0xe1, 0x25, 0xf1, 0x17, 0x18, 0x19, 0x1a, 0x26, 0x27, 0x28, 0x29, 0x2a,
+    0x35, 0x36, 0x37, 0x38, 0x39, 0x3a, 0x43, 0x44, 0x45, 0x46, 0x47, 0x48, 0x49, 0x4a, 0x53, 0x54, 0x55, 0x56, 0x57, 0x58, 0x59, 0x5a, 0x63, 0x64, 0x65, 0x66,
+    0x67, 0x68, 0x69, 0x6a, 0x73, 0x74, 0x75, 0x76, 0x77, 0x78, 0x79, 0x7a, 0x82, 0x83, 0x84, 0x85, 0x86, 0x87, 0x88, 0x89, 0x8a, 0x92, 0x93, 0x94, 0x95, 0x96,
+    0x97, 0x98, 0x99, 0x9a, 0xa2, 0xa3, 0xa4, 0xa5, 0xa6, 0xa7, 0xa8, 0xa9, 0xaa, 0xb2, 0xb3, 0xb4, 0xb5, 0xb6, 0xb7, 0xb8, 0xb9, 0xba, 0xc2, 0xc3, 0xc4, 0xc5,
+    0xc6, 0xc7, 0xc8, 0xc9, 0xca, 0xd2, 0xd3, 0xd4, 0xd5, 0xd6, 0xd7, 0xd8, 0xd9, 0xda, 0xe2, 0xe3, 0xe4, 0xe5, 0xe6, 0xe7, 0xe8, 0xe9, 0xea, 0xf2, 0xf3, 0xf4,
+    0xf5, 0xf6, 0xf7, 0xf8, 0xf9, 0xfa,
+  ],
+};
+
+/// Code and length in bits of every symbol of a Huffman table.
+struct HuffmanCodes([(u16, u8); 256]);
+
+impl HuffmanCodes {
+  /// Assigns the canonical codes of a table, ordered by length as in Annex C.
+  fn new(spec: &HuffmanSpec) -> Self {
+    let mut codes = [(0, 0); 256];
+    let mut code = 0u16;
+    let mut symbols = spec.symbols.iter();
+    for (length, &count) in (1..=16u8).zip(&spec.counts) {
+      for &symbol in symbols.by_ref().take(usize::from(count)) {
+        codes[usize::from(symbol)] = (code, length);
+        code += 1;
+      }
+      code <<= 1;
+    }
+    HuffmanCodes(codes)
+  }
+}
+
+/// Writes entropy coded data, inserting a zero byte after every 0xff.
+#[derive(Default)]
+struct BitWriter {
+  out: Vec<u8>,
+  buffer: u32,
+  bits: u8,
+}
+
+impl BitWriter {
+  fn write(&mut self, value: u16, bits: u8) {
+    for shift in (0..bits).rev() {
+      self.buffer = (self.buffer << 1) | u32::from((value >> shift) & 1);
+      self.bits += 1;
+      if self.bits == 8 {
+        self.push_byte();
+      }
+    }
+  }
+
+  fn push_byte(&mut self) {
+    let byte = self.buffer as u8;
+    self.out.push(byte);
+    if byte == 0xff {
+      self.out.push(0);
+    }
+    self.buffer = 0;
+    self.bits = 0;
+  }
+
+  fn symbol(&mut self, codes: &HuffmanCodes, symbol: u8) {
+    let (code, length) = codes.0[usize::from(symbol)];
+    self.write(code, length);
+  }
+
+  /// Writes a coefficient value as its size category and the additional bits.
+  fn value(&mut self, codes: &HuffmanCodes, run: u8, value: i32) {
+    let size = (32 - value.unsigned_abs().leading_zeros()) as u8;
+    self.symbol(codes, (run << 4) | size);
+    // negative values are stored as their one's complement
+    let bits = if value < 0 { value - 1 } else { value };
+    self.write((bits & ((1 << size) - 1)) as u16, size);
+  }
+
+  /// Pads the last byte with one bits, as required at the end of a scan.
+  fn finish(mut self) -> Vec<u8> {
+    if self.bits > 0 {
+      let padding = 8 - self.bits;
+      self.write((1 << padding) - 1, padding);
+    }
+    self.out
+  }
+}
+
+/// Encodes an RGB buffer as a progressive JPEG, with the resolution in the JFIF header if one is given.
+///
+/// The quality goes from 1 to 100 as for the IJG library, values outside are clamped.
+/// Returns a description of the problem if the image is too large for JPEG.
+pub fn encode_progressive(buffer: &[u8], width: u32, height: u32, dpi: Option<u32>, quality: u8) -> Result<Vec<u8>, String> {
+  let too_large = || format!("JPEG supports at most {} pixels per side", u16::MAX);
+  let (width16, height16) = (u16::try_from(width).map_err(|_| too_large())?, u16::try_from(height).map_err(|_| too_large())?);
+  let tables = [quantization(&LUMA_QUANTIZATION, quality), quantization(&CHROMA_QUANTIZATION, quality)];
+  let blocks = blocks(buffer, width, height, &tables);
+
+  let mut out = vec![0xff, 0xd8];
+  // JFIF 1.01, density in dots per inch or only an aspect ratio of 1:1
+  let (unit, density) = match dpi {
+    Some(dpi) => (1, u16::try_from(dpi).unwrap_or(u16::MAX)),
+    None => (0, 1),
+  };
+  let mut jfif = b"JFIF\0\x01\x01".to_vec();
+  jfif.push(unit);
+  jfif.extend(density.to_be_bytes());
+  jfif.extend(density.to_be_bytes());
+  jfif.extend([0, 0]);
+  segment(&mut out, 0xe0, &jfif);
+
+  for (id, table) in tables.iter().enumerate() {
+    let mut dqt = vec![id as u8];
+    dqt.extend(ZIGZAG.iter().map(|&i| table[i] as u8));
+    segment(&mut out, 0xdb, &dqt);
+  }
+
+  // SOF2: progressive, 8 bits precision, three components without subsampling
+  let mut sof = vec![8];
+  sof.extend(height16.to_be_bytes());
+  sof.extend(width16.to_be_bytes());
+  sof.push(3);
+  for (id, table) in [(1, 0), (2, 1), (3, 1)] {
+    sof.extend([id, 0x11, table]);
+  }
+  segment(&mut out, 0xc2, &sof);
+
+  for (class_id, spec) in [(0x00, &LUMA_DC), (0x01, &CHROMA_DC), (0x10, &LUMA_AC), (0x11, &CHROMA_AC)] {
+    let mut dht = vec![class_id];
+    dht.extend_from_slice(&spec.counts);
+    dht.extend_from_slice(spec.symbols);
+    segment(&mut out, 0xc4, &dht);
+  }
+
+  let dc = [HuffmanCodes::new(&LUMA_DC), HuffmanCodes::new(&CHROMA_DC)];
+  let ac = [HuffmanCodes::new(&LUMA_AC), HuffmanCodes::new(&CHROMA_AC)];
+  let table = |component: usize| usize::from(component > 0);
+
+  // first scan: the DC coefficients of all components, interleaved block by block
+  segment(&mut out, 0xda, &[3, 1, 0x00, 2, 0x11, 3, 0x11, 0, 0, 0]);
+  let mut writer = BitWriter::default();
+  let mut predictions = [0i32; 3];
+  for block in &blocks {
+    for (component, coefficients) in block.iter().enumerate() {
+      writer.value(&dc[table(component)], 0, coefficients[0] - predictions[component]);
+      predictions[component] = coefficients[0];
+    }
+  }
+  out.extend(writer.finish());
+
+  // then one scan with all AC coefficients per component
+  for component in 0..3 {
+    let tables = if component == 0 { 0x00 } else { 0x11 };
+    segment(&mut out, 0xda, &[1, component as u8 + 1, tables, 1, 63, 0]);
+    let mut writer = BitWriter::default();
+    for block in &blocks {
+      let coefficients = &block[component];
+      let mut run = 0;
+      for &value in &coefficients[1..] {
+        if value == 0 {
+          run += 1;
+          continue;
+        }
+        while run >= 16 {
+          writer.symbol(&ac[table(component)], 0xf0);
+          run -= 16;
+        }
+        writer.value(&ac[table(component)], run, value);
+        run = 0;
+      }
+      if run > 0 {
+        // end of band, for a run of a single block
+        writer.symbol(&ac[table(component)], 0x00);
+      }
+    }
+    out.extend(writer.finish());
+  }
+
+  out.extend([0xff, 0xd9]);
+  Ok(out)
+}
+
+/// Appends a marker segment with its length.
+fn segment(out: &mut Vec<u8>, marker: u8, data: &[u8]) {
+  out.extend([0xff, marker]);
+  out.extend(((data.len() + 2) as u16).to_be_bytes());
+  out.extend_from_slice(data);
+}
+
+/// Scales a quantization table to a quality from 1 to 100, as the IJG library does.
+fn quantization(table: &[u16; 64], quality: u8) -> [u16; 64] {
+  let quality = u32::from(quality.clamp(1, 100));
+  let scale = if quality < 50 { 5000 / quality } else { 200 - 2 * quality };
+  table.map(|value| ((u32::from(value) * scale + 50) / 100).clamp(1, 255) as u16)
+}
+
+/// Transforms the image into quantized coefficients of 8x8 blocks, Y, Cb and Cr per block, in zigzag order.
+///
+/// Blocks are in row-major order, partial blocks at the right and bottom edges repeat the last pixels.
+fn blocks(buffer: &[u8], width: u32, height: u32, tables: &[[u16; 64]; 2]) -> Vec<[[i32; 64]; 3]> {
+  let (width, height) = (width as usize, height as usize);
+  // weight of sample x in frequency u, including the normalization of Annex A
+  let basis: [[f32; 8]; 8] = std::array::from_fn(|u| {
+    let scale = if u == 0 { 0.5 * std::f32::consts::FRAC_1_SQRT_2 } else { 0.5 };
+    std::array::from_fn(|x| scale * ((2 * x + 1) as f32 * u as f32 * std::f32::consts::PI / 16.0).cos())
+  });
+  let mut blocks = Vec::with_capacity(width.div_ceil(8) * height.div_ceil(8));
+  for by in 0..height.div_ceil(8) {
+    for bx in 0..width.div_ceil(8) {
+      let mut samples = [[0f32; 64]; 3];
+      for y in 0..8 {
+        for x in 0..8 {
+          let i = ((by * 8 + y).min(height - 1) * width + (bx * 8 + x).min(width - 1)) * 3;
+          let (r, g, b) = (f32::from(buffer[i]), f32::from(buffer[i + 1]), f32::from(buffer[i + 2]));
+          // JFIF YCbCr, shifted to be centered on zero
+          samples[0][y * 8 + x] = 0.299 * r + 0.587 * g + 0.114 * b - 128.0;
+          samples[1][y * 8 + x] = -0.168_736 * r - 0.331_264 * g + 0.5 * b;
+          samples[2][y * 8 + x] = 0.5 * r - 0.418_688 * g - 0.081_312 * b;
+        }
+      }
+      blocks.push(std::array::from_fn(|component| {
+        let table = &tables[usize::from(component > 0)];
+        let samples = &samples[component];
+        // the 2D transform is separable: first every row, then every column of the result
+        let rows: [[f32; 8]; 8] = std::array::from_fn(|y| std::array::from_fn(|u| (0..8).map(|x| samples[y * 8 + x] * basis[u][x]).sum()));
+        let mut coefficients = [0i32; 64];
+        for (k, &natural) in ZIGZAG.iter().enumerate() {
+          let (v, u) = (natural / 8, natural % 8);
+          let sum: f32 = (0..8).map(|y| rows[y][u] * basis[v][y]).sum();
+          coefficients[k] = (sum / f32::from(table[natural])).round() as i32;
+        }
+        coefficients
+      }));
+    }
+  }
+  blocks
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_huffman_codes_are_canonical() {
+    let codes = HuffmanCodes::new(&LUMA_DC);
+    assert_eq!(codes.0[0], (0b00, 2));
+    assert_eq!(codes.0[1], (0b010, 3));
+    assert_eq!(codes.0[11], (0b1_1111_1110, 9));
+  }
+
+  #[test]
+  fn test_encode_progressive_round_trip() {
+    let (width, height) = (21, 13);
+    let buffer: Vec<u8> = (0..width * height)
+      .flat_map(|i| {
+        let (x, y) = (i % width, i / width);
+        [(x * 12) as u8, (y * 19) as u8, 200]
+      })
+      .collect();
+    let encoded = encode_progressive(&buffer, width, height, Some(72), DEFAULT_QUALITY).unwrap();
+    assert!(encoded.windows(2).any(|marker| marker == [0xff, 0xc2]), "SOF2 marks a progressive file");
+
+    let decoded = image::load_from_memory_with_format(&encoded, image::ImageFormat::Jpeg).unwrap().into_rgb8();
+    assert_eq!(decoded.dimensions(), (width, height));
+    let error: f64 = decoded.as_raw().iter().zip(&buffer).map(|(&a, &b)| f64::from(a.abs_diff(b))).sum::<f64>() / buffer.len() as f64;
+    assert!(error < 4.0, "mean error {error}");
+  }
+
+  #[test]
+  fn test_encode_progressive_rejects_large_images() {
+    assert!(encode_progressive(&[0; 3 * 70_000], 70_000, 1, None, DEFAULT_QUALITY).is_err());
+  }
+
+  #[test]
+  fn test_encode_progressive_quality() {
+    let (width, height) = (32, 32);
+    let buffer: Vec<u8> = (0..width * height * 3).map(|i| (i * 37 % 251) as u8).collect();
+    let error = |quality| {
+      let encoded = encode_progressive(&buffer, width, height, None, quality).unwrap();
+      let decoded = image::load_from_memory_with_format(&encoded, image::ImageFormat::Jpeg).unwrap().into_rgb8();
+      let error = decoded.as_raw().iter().zip(&buffer).map(|(&a, &b)| f64::from(a.abs_diff(b))).sum::<f64>();
+      (encoded.len(), error)
+    };
+    let (low, high) = (error(10), error(95));
+    assert!(low.0 < high.0, "lower quality should give smaller files");
+    assert!(low.1 > high.1, "lower quality should lose more detail");
+  }
+}
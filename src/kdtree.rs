@@ -0,0 +1,175 @@
+//! Nearest-color search in large palettes with a k-d tree.
+//!
+//! [`map_to_palette`](crate::palette::map_to_palette) compares every pixel with every palette color,
+//! which dominates the runtime for palettes of hundreds of colors. The tree splits the palette at the
+//! median of its widest channel, recursively, so that a search only visits the few colors near the pixel.
+//! It finds exactly the color of the linear search, ties included.
+
+use crate::palette::{Color, Quantizer, TieBreak};
+
+/// Palettes from this size on are searched with a [`KdTree`], smaller ones are faster searched linearly.
+pub const MIN_COLORS: usize = 32;
+
+/// Ranges of up to this many colors are not split further but searched linearly, which is faster.
+const LEAF_COLORS: usize = 8;
+
+/// A palette arranged as a k-d tree for nearest-color queries.
+///
+/// The tree is implicit: every range of colors has its splitting color in the middle, the colors
+/// before it on the lower and those after it on the upper side of the splitting channel. Small
+/// ranges are leaves.
+#[derive(Clone, Debug)]
+pub struct KdTree {
+  colors: Vec<Color>,
+  /// Index in the palette of the color at the same index, for [`TieBreak::First`]
+  positions: Vec<usize>,
+  /// Splitting channel of the color at the same index, 0 for red, 1 for green and 2 for blue
+  axes: Vec<u8>,
+  tie_break: TieBreak,
+}
+
+fn channel(color: &Color, axis: u8) -> u8 {
+  match axis {
+    0 => color.r,
+    1 => color.g,
+    _ => color.b,
+  }
+}
+
+fn distance(a: &Color, b: &Color) -> u32 {
+  let (dr, dg, db) = (u32::from(a.r.abs_diff(b.r)), u32::from(a.g.abs_diff(b.g)), u32::from(a.b.abs_diff(b.b)));
+  dr * dr + dg * dg + db * db
+}
+
+/// Arranges `entries` into the implicit tree, `axes` is the matching range of splitting channels.
+fn build(entries: &mut [(Color, usize)], axes: &mut [u8]) {
+  if entries.len() <= LEAF_COLORS {
+    return;
+  }
+  let spread = |axis: u8| {
+    let values = entries.iter().map(|(color, _)| channel(color, axis));
+    values.clone().max().unwrap_or(0) - values.min().unwrap_or(0)
+  };
+  let axis = (0..3).max_by_key(|&axis| (spread(axis), std::cmp::Reverse(axis))).unwrap_or(0);
+  let mid = entries.len() / 2;
+  entries.select_nth_unstable_by_key(mid, |(color, _)| channel(color, axis));
+  axes[mid] = axis;
+  let (lower, upper) = entries.split_at_mut(mid);
+  let (lower_axes, upper_axes) = axes.split_at_mut(mid);
+  build(lower, lower_axes);
+  build(&mut upper[1..], &mut upper_axes[1..]);
+}
+
+impl KdTree {
+  /// Builds the tree of a palette, equally close colors are chosen with `tie_break`.
+  ///
+  /// # Panics
+  ///
+  /// If the palette is empty.
+  #[must_use]
+  pub fn new(colors: &[Color], tie_break: TieBreak) -> Self {
+    assert!(!colors.is_empty(), "a k-d tree needs at least one color");
+    let mut entries: Vec<(Color, usize)> = colors.iter().copied().zip(0..).collect();
+    let mut axes = vec![0; colors.len()];
+    build(&mut entries, &mut axes);
+    let (colors, positions) = entries.into_iter().unzip();
+    KdTree {
+      colors,
+      positions,
+      axes,
+      tie_break,
+    }
+  }
+
+  /// Returns the palette color closest to `color`.
+  #[must_use]
+  pub fn nearest(&self, color: Color) -> &Color {
+    let mut best = (u32::MAX, 0);
+    self.search(0, self.colors.len(), &color, &mut best);
+    &self.colors[best.1]
+  }
+
+  /// Returns whether the color at `candidate` wins a tie against the one at `current`.
+  fn wins(&self, candidate: usize, current: usize) -> bool {
+    match self.tie_break {
+      // the search does not visit the colors in the order of the palette
+      TieBreak::First => self.positions[candidate] < self.positions[current],
+      rule => rule.prefers(&self.colors[candidate], &self.colors[current]),
+    }
+  }
+
+  fn search(&self, lo: usize, hi: usize, color: &Color, best: &mut (u32, usize)) {
+    if hi - lo <= LEAF_COLORS {
+      for (i, candidate) in self.colors[lo..hi].iter().enumerate() {
+        let d = distance(color, candidate);
+        if d < best.0 || (d == best.0 && self.wins(lo + i, best.1)) {
+          *best = (d, lo + i);
+        }
+      }
+      return;
+    }
+    let mid = lo + (hi - lo) / 2;
+    let candidate = &self.colors[mid];
+    let d = distance(color, candidate);
+    if d < best.0 || (d == best.0 && self.wins(mid, best.1)) {
+      *best = (d, mid);
+    }
+    let axis = self.axes[mid];
+    let offset = i32::from(channel(color, axis)) - i32::from(channel(candidate, axis));
+    let (near, far) = if offset < 0 { ((lo, mid), (mid + 1, hi)) } else { ((mid + 1, hi), (lo, mid)) };
+    self.search(near.0, near.1, color, best);
+    // the far side can hold an equally close color, which may win the tie
+    if (offset * offset) as u32 <= best.0 {
+      self.search(far.0, far.1, color, best);
+    }
+  }
+}
+
+impl Quantizer for KdTree {
+  fn quantize(&self, color: Color) -> Color {
+    *self.nearest(color)
+  }
+
+  fn palette(&self) -> Option<&[Color]> {
+    Some(&self.colors)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::distance::DistanceMetric;
+  use crate::palette::{self, ColorPalette};
+
+  #[test]
+  fn test_nearest_matches_linear_search() {
+    let palettes = [ColorPalette::Vga256.colors(), ColorPalette::WebSafe.colors(), ColorPalette::COLOR16.colors()];
+    for colors in palettes {
+      for tie_break in [TieBreak::First, TieBreak::Darker, TieBreak::Lighter] {
+        let tree = KdTree::new(colors, tie_break);
+        for i in 0..4096u32 {
+          let color = Color::from(i.wrapping_mul(2_654_435_761) & 0xff_ffff);
+          assert_eq!(
+            *tree.nearest(color),
+            *palette::map_to_palette_with(color, colors, DistanceMetric::Rgb, tie_break).0,
+            "{color} with {tie_break:?}"
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_nearest_breaks_ties() {
+    let (bluish, reddish) = (Color { r: 100, g: 100, b: 110 }, Color { r: 110, g: 100, b: 100 });
+    let gray = Color { r: 105, g: 100, b: 105 };
+    assert_eq!(*KdTree::new(&[reddish, bluish], TieBreak::Darker).nearest(gray), bluish);
+    assert_eq!(*KdTree::new(&[bluish, reddish], TieBreak::Lighter).nearest(gray), reddish);
+    assert_eq!(KdTree::new(&[reddish], TieBreak::Darker).quantize(gray), reddish);
+    let mut colors = vec![Color { r: 0, g: 0, b: 0 }; 2 * LEAF_COLORS];
+    colors.extend([reddish, bluish]);
+    assert_eq!(*KdTree::new(&colors, TieBreak::First).nearest(gray), reddish);
+    colors.swap(2 * LEAF_COLORS, 2 * LEAF_COLORS + 1);
+    assert_eq!(*KdTree::new(&colors, TieBreak::First).nearest(gray), bluish);
+  }
+}
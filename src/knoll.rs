@@ -0,0 +1,183 @@
+//! Thomas Knoll's pattern dithering, the "Pattern" mode of Photoshop's indexed colors.
+//!
+//! For every color a list of [`CANDIDATES`] palette colors is built: each candidate is the nearest
+//! color to the original plus the error of the candidates so far, so the list averages to the
+//! original. The corrected color is not clamped, so the error of colors outside the palette's range
+//! still pulls in the colors that come closest. The list is sorted by luma and the threshold of the
+//! 8x8 Bayer matrix picks the candidate of a pixel. Equal colors always get the same pattern, which
+//! makes the output very stable between frames and cheap to compress.
+
+use std::collections::HashMap;
+
+use crate::dither::{self, BAYER8X8, DitherMethod, DitherOptions};
+use crate::error::DitherError;
+use crate::indexed;
+use crate::palette::{Color, Quantizer};
+
+/// Number of candidates per color, one per threshold of the 8x8 Bayer matrix.
+pub const CANDIDATES: usize = 64;
+
+/// Share of the accumulated error the next candidate corrects, below 1 so that the candidates stay near the color.
+const ERROR_MULTIPLIER: f32 = 0.5;
+
+/// Most candidate lists kept at once, the lists are dropped when there are more so that photos with
+/// many colors do not grow the cache without bound.
+pub const MAX_LISTS: usize = 1 << 16;
+
+/// Returns [`CANDIDATES`] colors from `palette` whose average approximates `color`, darkest first.
+///
+/// # Panics
+///
+/// Panics if the palette is empty.
+#[must_use]
+pub fn candidates(color: Color, palette: &[Color]) -> Vec<Color> {
+  let goal = [color.r, color.g, color.b].map(f32::from);
+  let points: Vec<[f32; 3]> = palette.iter().map(|color| [color.r, color.g, color.b].map(f32::from)).collect();
+  let mut error = [0.0f32; 3];
+  let mut list: Vec<Color> = (0..CANDIDATES)
+    .map(|_| {
+      let attempt: [f32; 3] = std::array::from_fn(|c| goal[c] + error[c] * ERROR_MULTIPLIER);
+      let distance = |point: &[f32; 3]| (attempt[0] - point[0]).powi(2) + (attempt[1] - point[1]).powi(2) + (attempt[2] - point[2]).powi(2);
+      let (mut nearest, mut closest) = (0, distance(&points[0]));
+      for (index, point) in points.iter().enumerate().skip(1) {
+        let distance = distance(point);
+        if distance < closest {
+          (nearest, closest) = (index, distance);
+        }
+      }
+      for c in 0..3 {
+        error[c] += goal[c] - points[nearest][c];
+      }
+      palette[nearest]
+    })
+    .collect();
+  list.sort_by_key(|color| 299 * u32::from(color.r) + 587 * u32::from(color.g) + 114 * u32::from(color.b));
+  list
+}
+
+/// Dithers an RGB buffer with Knoll's pattern dithering, see the [module documentation](self).
+///
+/// The candidates are searched by RGB distance, the chosen colors still pass through the quantizer
+/// so hooks see every pixel. Quantizers without a [palette](Quantizer::palette), or with more than
+/// [`indexed::MAX_COLORS`] colors, fall back to the 8x8 Bayer matrix. The matrix continues from
+/// [`DitherOptions::origin`] and transparent pixels are left untouched.
+pub fn dither<Q: Quantizer + ?Sized>(buffer: &mut [u8], quantizer: &Q, width: u32, height: u32, options: &DitherOptions) -> Result<(), DitherError> {
+  let Some(palette) = quantizer.palette().filter(|palette| !palette.is_empty()) else {
+    log::debug!("no palette to pick candidates from, dithering with the 8x8 Bayer matrix");
+    return dither::dither_with(buffer, DitherMethod::Bayer8x8, quantizer, width, height, options);
+  };
+  if palette.len() > indexed::MAX_COLORS {
+    log::warn!(
+      "cannot pick candidates from {} colors, at most {} are supported, dithering with the 8x8 Bayer matrix",
+      palette.len(),
+      indexed::MAX_COLORS
+    );
+    return dither::dither_with(buffer, DitherMethod::Bayer8x8, quantizer, width, height, options);
+  }
+  // equal colors get equal lists, kept as palette indices for at most MAX_LISTS of the colors met
+  let indices: HashMap<Color, u8> = palette.iter().enumerate().rev().map(|(index, &color)| (color, index as u8)).collect();
+  let mut lists: HashMap<Color, [u8; CANDIDATES]> = HashMap::new();
+  let mut listed = 0;
+  for (i, pixel) in buffer.chunks_exact_mut(3).enumerate() {
+    if options.alpha.is_some_and(|alpha| alpha[i] == 0) {
+      continue;
+    }
+    let (x, y) = (i as u32 % width + options.origin.0, i as u32 / width + options.origin.1);
+    let color = Color::from(&pixel[..]);
+    let level = (BAYER8X8[(y % 8 * 8 + x % 8) as usize] * CANDIDATES as f32) as usize;
+    if !lists.contains_key(&color) && lists.len() == MAX_LISTS {
+      lists.clear();
+    }
+    let list = lists.entry(color).or_insert_with(|| {
+      listed += 1;
+      let candidates = candidates(color, palette);
+      std::array::from_fn(|level| indices[&candidates[level]])
+    });
+    let new_color = quantizer.quantize(palette[usize::from(list[level])]);
+    pixel.copy_from_slice(&[new_color.r, new_color.g, new_color.b]);
+  }
+  log::trace!("patterned {width}x{height} pixels from the candidates of {listed} colors");
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::palette::{ColorPalette, PALETTE_MONOCHROME, Palette};
+  use crate::target::Target;
+
+  #[test]
+  fn test_candidates_average_to_the_color() {
+    for level in [32u8, 64, 128, 200] {
+      let list = candidates(Color { r: level, g: level, b: level }, &PALETTE_MONOCHROME);
+      assert_eq!(list.len(), CANDIDATES);
+      let average = list.iter().map(|color| f32::from(color.r)).sum::<f32>() / CANDIDATES as f32;
+      assert!((average - f32::from(level)).abs() < 8.0, "{average} for level {level}");
+      assert!(list.windows(2).all(|pair| pair[0].r <= pair[1].r), "{level} not sorted");
+    }
+    let orange = Color { r: 255, g: 140, b: 0 };
+    let list = candidates(orange, ColorPalette::COLOR16.colors());
+    assert!(list.iter().all(|color| ColorPalette::COLOR16.colors().contains(color)));
+  }
+
+  #[test]
+  fn test_palette_colors_stay_solid() {
+    for &color in ColorPalette::COLOR8.colors() {
+      assert!(candidates(color, ColorPalette::COLOR8.colors()).iter().all(|&candidate| candidate == color));
+    }
+  }
+
+  #[test]
+  fn test_candidates_reach_colors_outside_the_palette() {
+    // no palette color is as red as this pink, the error on red must still be repaid
+    let pink = Color { r: 225, g: 110, b: 140 };
+    let list = candidates(pink, ColorPalette::COLOR8.colors());
+    let red = list.iter().map(|color| f32::from(color.r)).sum::<f32>() / CANDIDATES as f32;
+    assert!(red > 200.0, "{red} of red for {pink:?}");
+  }
+
+  #[test]
+  fn test_dither_without_palette_falls_back_to_bayer() {
+    let gray: Vec<u8> = (0..16 * 16 * 3).map(|i| (i % 256) as u8).collect();
+    let (mut patterned, mut bayer) = (gray.clone(), gray);
+    dither(&mut patterned, &Target::Rgb332, 16, 16, &DitherOptions::default()).unwrap();
+    dither::dither_with(&mut bayer, DitherMethod::Bayer8x8, &Target::Rgb332, 16, 16, &DitherOptions::default()).unwrap();
+    assert_eq!(patterned, bayer);
+  }
+
+  #[test]
+  fn test_dither_skips_transparent_pixels() {
+    let mut buffer = vec![100; 4 * 3];
+    let alpha = [255, 0, 0, 255];
+    let options = DitherOptions {
+      alpha: Some(&alpha),
+      ..DitherOptions::default()
+    };
+    dither(&mut buffer, &PALETTE_MONOCHROME, 2, 2, &options).unwrap();
+    assert_eq!(&buffer[3..9], &[100; 6]);
+  }
+
+  #[test]
+  fn test_dither_keeps_patterning_past_the_list_limit() {
+    // every pixel has its own color, more than the cache holds
+    let (width, height) = (512, MAX_LISTS as u32 / 512 + 2);
+    let buffer: Vec<u8> = (0..width * height).flat_map(|i| [i as u8, (i >> 8) as u8, (i >> 16) as u8 * 64]).collect();
+    let mut patterned = buffer.clone();
+    dither(&mut patterned, &PALETTE_MONOCHROME, width, height, &DitherOptions::default()).unwrap();
+    for (i, (pixel, original)) in patterned.chunks_exact(3).zip(buffer.chunks_exact(3)).enumerate().step_by(997) {
+      let (x, y) = (i as u32 % width, i as u32 / width);
+      let level = (BAYER8X8[(y % 8 * 8 + x % 8) as usize] * CANDIDATES as f32) as usize;
+      assert_eq!(Color::from(pixel), candidates(Color::from(original), &PALETTE_MONOCHROME)[level]);
+    }
+  }
+
+  #[test]
+  fn test_dither_with_too_many_colors_falls_back_to_bayer() {
+    let palette = Palette::new((0..=indexed::MAX_COLORS as u32).map(|i| Color::from(i * 0x0101)).collect()).unwrap();
+    let gray: Vec<u8> = (0..16 * 16 * 3).map(|i| (i % 256) as u8).collect();
+    let (mut patterned, mut bayer) = (gray.clone(), gray);
+    dither(&mut patterned, &palette, 16, 16, &DitherOptions::default()).unwrap();
+    dither::dither_with(&mut bayer, DitherMethod::Bayer8x8, &palette, 16, 16, &DitherOptions::default()).unwrap();
+    assert_eq!(patterned, bayer);
+  }
+}
@@ -3,22 +3,113 @@
 //! A Rust library for applying various dithering algorithms to images.
 //!
 //! This library provides:
-//! - Multiple dithering algorithms (Floyd-Steinberg, Jarvis, Atkinson, etc.)
-//! - Color palette support (Monochrome, 8-color, 16-color)
-//! - Image processing utilities
+//! - Multiple dithering algorithms (Floyd-Steinberg, Jarvis, Atkinson, Riemersma, Zhou-Fang, Knuth's dot diffusion, etc.), including Bayer matrices of any power-of-two size, halftone screens of any angle and size and interleaved gradient noise
+//! - Yliluoma's positional dithering, which plans mixes of palette colors for small fixed palettes, also in linear light
+//! - Knoll's pattern dithering, the stable and compression-friendly pattern mode of image editors
+//! - A fast single-channel path for black and white output, with packed 1-bit buffers
+//! - A k-d tree and an optional lookup table for fast nearest-color search in large palettes
+//! - Color palette support (Monochrome, grayscale, 8-color, 16-color, colorblind-safe, terminal and retro hardware palettes, duotones, gradients, custom palettes or palette files: hex lists, GIMP, JASC, Adobe and Lospec palettes)
+//! - Palettes generated from the image or a reference image (octree, k-means, Wu or NeuQuant quantization)
+//! - Perceptual palette matching with redmean, in CIELAB, OKLab or with CIEDE2000
+//! - Binary transparency with a designated transparent palette color
+//! - Image processing utilities, including ink coverage limits for print
+//! - Borders, margins, captions and watermark overlays drawn before dithering
+//! - Interlaced PNG and progressive JPEG output for the web
+//! - Color adjustments before quantization (exposure, white balance, saturation) and target display response curves
+//! - Seeded noise injection against banding and seeded random dithering, optionally combining several seeds
+//! - Synthetic test patterns
+//! - Verification of outputs against stored references
+//! - Batch processing from job manifests, with an optional result cache and atomic writes
+//! - A daemon answering JSON requests on a Unix socket, for editor plugins and build watchers
+//! - A memory limit, above which large images are processed in strips
+//! - Labeled contact sheets of whole directories
+//! - Image summaries with the best-fitting built-in palette
+//! - Interactive before/after comparison in the terminal and ANSI art export
+//! - Incremental re-dithering of changed tiles
+//! - Sprite-sheet mode with a palette per tile, or shared sub-palettes like retro consoles
+//! - Experimental Amiga HAM6 encoding
+//!
+//! Progress is reported through the [`log`] crate, so the library stays quiet unless a logger is installed.
 //!
 //! ## Example
 //!
 //! ```no_run
-//! use dithers::dither::{open_image, dither, save_image, DitherMethod};
+//! use dithers::dither::{try_open_image, dither, try_save_image, DitherMethod};
+//! use dithers::distance::DistanceMetric;
+//! use dithers::error::DitherError;
 //! use dithers::palette::ColorPalette;
-//! use std::path::PathBuf;
+//! use std::path::Path;
 //!
-//! let (mut buffer, width, height) = open_image(&PathBuf::from("input.png"));
-//! dither(&mut buffer, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, width, height);
-//! save_image(buffer, PathBuf::from("output.png"), width, height);
+//! let (mut buffer, width, height) = try_open_image(Path::new("input.png"))?;
+//! dither(&mut buffer, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, DistanceMetric::Rgb, width, height);
+//! try_save_image(&buffer, Path::new("output.png"), width, height)?;
+//! # Ok::<(), DitherError>(())
 //! ```
 
+/// Implements `serde` (de)serialization for a `clap::ValueEnum` using its command-line names,
+/// so that manifests accept exactly the same spellings as the CLI.
+macro_rules! serde_value_enum {
+  ($ty:ty) => {
+    impl serde::Serialize for $ty {
+      fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let value = clap::ValueEnum::to_possible_value(self).expect("value enums have no skipped variants");
+        serializer.serialize_str(value.get_name())
+      }
+    }
+
+    impl<'de> serde::Deserialize<'de> for $ty {
+      fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = <String as serde::Deserialize>::deserialize(deserializer)?;
+        <$ty as clap::ValueEnum>::from_str(&name, true).map_err(serde::de::Error::custom)
+      }
+    }
+  };
+}
+
+pub mod adaptive;
+pub mod adjust;
+pub mod ansi;
 pub mod args;
+pub mod cache;
+pub mod clash;
+pub mod contact;
+pub mod daemon;
+pub mod display;
+pub mod distance;
 pub mod dither;
+pub mod dotdiffusion;
+pub mod ensemble;
+pub mod error;
+pub mod font;
+pub mod halftone;
+pub mod ham;
+pub mod indexed;
+pub mod info;
+pub mod ink;
+pub mod input;
+pub mod jobs;
+pub mod jpeg;
+pub mod kdtree;
+pub mod knoll;
+pub mod lut;
+pub mod mono;
+pub mod noise;
+pub mod output;
+pub mod overlay;
 pub mod palette;
+pub mod patterns;
+pub mod pdf;
+pub mod pipeline;
+pub mod preview;
+#[cfg(feature = "raw")]
+pub mod raw;
+pub mod riemersma;
+pub mod sprite;
+pub mod stream;
+pub mod swatches;
+pub mod target;
+pub mod template;
+pub mod tiles;
+pub mod verify;
+pub mod yliluoma;
+pub mod zhoufang;
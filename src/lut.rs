@@ -0,0 +1,171 @@
+//! Lookup table from colors to their closest palette color.
+//!
+//! The RGB cube is divided into cells, every cell keeps the few palette colors that can be closest to
+//! any color inside it. A lookup searches only those instead of the whole palette, and still finds
+//! exactly the color of [`map_to_palette_with`], ties included. Building the table takes a pass over
+//! the palette per cell, so it pays off for large palettes and large images.
+
+use rayon::prelude::*;
+
+use crate::distance::DistanceMetric;
+use crate::palette::{Color, Quantizer, TieBreak, map_to_palette_with};
+
+/// Largest number of cells per channel, a table of 64³ cells takes a few megabytes.
+pub const MAX_LUT_SIZE: u16 = 64;
+
+/// A palette with a table of the candidate colors of every cell of the RGB cube.
+#[derive(Clone, Debug)]
+pub struct ColorLut {
+  /// Number of bits a channel is shifted right by to get its cell
+  shift: u32,
+  /// Cells per channel
+  size: usize,
+  /// Start of the candidates of every cell in `candidates`, followed by their end
+  offsets: Vec<u32>,
+  candidates: Vec<Color>,
+  /// Colors of the palette, for methods that mix them
+  colors: Vec<Color>,
+  tie_break: TieBreak,
+}
+
+/// Returns the smallest and the largest squared distance of a channel value to the range `lo..=hi`.
+fn channel_bounds(value: u8, lo: u8, hi: u8) -> (u32, u32) {
+  let near = lo.saturating_sub(value).max(value.saturating_sub(hi));
+  let far = value.abs_diff(lo).max(value.abs_diff(hi));
+  (u32::from(near).pow(2), u32::from(far).pow(2))
+}
+
+/// Returns the colors that can be the closest to a color in the cell starting at `start` with `cell` values per channel,
+/// in the order of the palette so that [`TieBreak::First`] keeps working.
+fn candidates(colors: &[Color], start: [usize; 3], cell: usize) -> Vec<Color> {
+  let [r, g, b] = start.map(|lo| (lo as u8, (lo + cell - 1) as u8));
+  // (smallest, largest) squared distance of every color to the cell
+  let bounds: Vec<(u32, u32)> = colors
+    .iter()
+    .map(|color| {
+      let (r, g, b) = (
+        channel_bounds(color.r, r.0, r.1),
+        channel_bounds(color.g, g.0, g.1),
+        channel_bounds(color.b, b.0, b.1),
+      );
+      (r.0 + g.0 + b.0, r.1 + g.1 + b.1)
+    })
+    .collect();
+  // every color in the cell is at most this far from its closest palette color,
+  // colors that are farther from the whole cell can never be the closest
+  let reach = bounds.iter().map(|bound| bound.1).min().unwrap_or(0);
+  colors
+    .iter()
+    .zip(&bounds)
+    .filter(|(_, bound)| bound.0 <= reach)
+    .map(|(color, _)| *color)
+    .collect()
+}
+
+impl ColorLut {
+  /// Builds the table of a palette with `size` cells per channel, equally close colors are chosen with `tie_break`.
+  ///
+  /// The size is rounded up to a power of two from 2 to 64.
+  ///
+  /// # Panics
+  ///
+  /// If the palette is empty.
+  #[must_use]
+  pub fn new(colors: &[Color], size: u16, tie_break: TieBreak) -> Self {
+    assert!(!colors.is_empty(), "a lookup table needs at least one color");
+    let size = usize::from(size.clamp(2, MAX_LUT_SIZE).next_power_of_two());
+    // the candidates of a cell are among those of the cell containing it at half the size,
+    // so the table is refined from the whole cube on and no level searches the whole palette
+    let mut cells: Vec<Vec<Color>> = vec![colors.to_vec()];
+    let mut level = 1;
+    while level < size {
+      level *= 2;
+      let (parents, half) = (std::mem::take(&mut cells), level / 2);
+      cells = (0..level * level * level)
+        .into_par_iter()
+        .map(|index| {
+          let (r, g, b) = (index / level / level, index / level % level, index % level);
+          let parent = &parents[(r / 2 * half + g / 2) * half + b / 2];
+          candidates(parent, [r, g, b].map(|i| i * (256 / level)), 256 / level)
+        })
+        .collect();
+    }
+    let mut offsets = Vec::with_capacity(cells.len() + 1);
+    let mut candidates = Vec::new();
+    for cell in cells {
+      offsets.push(candidates.len() as u32);
+      candidates.extend(cell);
+    }
+    offsets.push(candidates.len() as u32);
+    log::debug!(
+      "built a lookup table of {size}³ cells with {:.1} candidates per cell",
+      candidates.len() as f32 / (size * size * size) as f32
+    );
+    ColorLut {
+      shift: 8 - size.trailing_zeros(),
+      size,
+      offsets,
+      candidates,
+      colors: colors.to_vec(),
+      tie_break,
+    }
+  }
+
+  /// Returns the number of cells per channel.
+  #[must_use]
+  pub fn size(&self) -> usize {
+    self.size
+  }
+
+  /// Returns the palette color closest to `color`.
+  #[must_use]
+  pub fn nearest(&self, color: Color) -> &Color {
+    let cell = |channel: u8| usize::from(channel >> self.shift);
+    let index = (cell(color.r) * self.size + cell(color.g)) * self.size + cell(color.b);
+    let candidates = &self.candidates[self.offsets[index] as usize..self.offsets[index + 1] as usize];
+    map_to_palette_with(color, candidates, DistanceMetric::Rgb, self.tie_break).0
+  }
+}
+
+impl Quantizer for ColorLut {
+  fn quantize(&self, color: Color) -> Color {
+    *self.nearest(color)
+  }
+
+  fn palette(&self) -> Option<&[Color]> {
+    Some(&self.colors)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::palette::ColorPalette;
+
+  #[test]
+  fn test_nearest_matches_linear_search() {
+    for colors in [ColorPalette::Vga256.colors(), ColorPalette::WebSafe.colors(), ColorPalette::COLOR8.colors()] {
+      for (size, tie_break) in [(32, TieBreak::Darker), (8, TieBreak::Lighter), (16, TieBreak::First)] {
+        let lut = ColorLut::new(colors, size, tie_break);
+        for i in 0..4096u32 {
+          let color = Color::from(i.wrapping_mul(2_654_435_761) & 0xff_ffff);
+          assert_eq!(
+            *lut.nearest(color),
+            *map_to_palette_with(color, colors, DistanceMetric::Rgb, tie_break).0,
+            "{color} with {tie_break:?}"
+          );
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn test_size_is_a_power_of_two() {
+    let colors = ColorPalette::Monochrome.colors();
+    assert_eq!(ColorLut::new(colors, 20, TieBreak::Darker).size(), 32);
+    assert_eq!(ColorLut::new(colors, 0, TieBreak::Darker).size(), 2);
+    assert_eq!(ColorLut::new(colors, 1000, TieBreak::Darker).size(), 64);
+    let lut = ColorLut::new(colors, 64, TieBreak::Darker);
+    assert_eq!(lut.quantize(Color { r: 127, g: 128, b: 128 }), Color { r: 255, g: 255, b: 255 });
+  }
+}
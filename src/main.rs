@@ -3,35 +3,314 @@
 //! This binary provides a command-line interface to the dither library,
 //! allowing users to apply various dithering algorithms to images.
 
-use clap::Parser;
-use dithers::args::Args;
-use dithers::dither;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+use clap::{Parser, ValueEnum};
+use dithers::args::{Args, Command, ContactSheetArgs, DaemonArgs, GenerateArgs, InfoArgs, PreviewArgs, VerifyArgs};
+use dithers::cache::{self, Cache};
+use dithers::error::DitherError;
+use dithers::jobs::{self, Job};
+use dithers::pipeline::Settings;
+use dithers::verify::{self, Outcome};
+use dithers::{contact, daemon, info, input, output, patterns, preview};
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use rayon::prelude::*;
 
 /// Main entry point for the dither CLI application.
-fn main() {
+fn main() -> ExitCode {
   // get cli arguments
   let args = Args::parse();
   //dbg!(args);
+  init_logger(&args);
+
+  match &args.command {
+    Some(Command::Generate(generate_args)) => generate(generate_args),
+    Some(Command::Verify(verify_args)) => verify(verify_args),
+    Some(Command::ContactSheet(sheet_args)) => contact_sheet(sheet_args),
+    Some(Command::Preview(preview_args)) => preview(preview_args),
+    Some(Command::Info(info_args)) => info(info_args),
+    Some(Command::Daemon(daemon_args)) => run_daemon(daemon_args),
+    None => run_jobs(&args),
+  }
+}
+
+/// Writes log records to stderr, the library itself never prints.
+struct StderrLogger;
+
+impl Log for StderrLogger {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    metadata.level() <= log::max_level()
+  }
+
+  fn log(&self, record: &Record) {
+    if !self.enabled(record.metadata()) {
+      return;
+    }
+    match record.level() {
+      Level::Info => eprintln!("{}", record.args()),
+      Level::Error => eprintln!("Error: {}", record.args()),
+      Level::Warn => eprintln!("Warning: {}", record.args()),
+      level => eprintln!("[{level}] {}: {}", record.target(), record.args()),
+    }
+  }
+
+  fn flush(&self) {}
+}
+
+/// Installs the stderr logger with the verbosity requested on the command line.
+fn init_logger(args: &Args) {
+  let level = match (args.quiet, args.verbose) {
+    (true, _) => LevelFilter::Error,
+    (false, 0) => LevelFilter::Info,
+    (false, 1) => LevelFilter::Debug,
+    (false, _) => LevelFilter::Trace,
+  };
+  // only fails if a logger is already installed
+  let _ = log::set_logger(&StderrLogger);
+  log::set_max_level(level);
+}
+
+/// Dithers the images given on the command line or in the jobs file.
+fn run_jobs(args: &Args) -> ExitCode {
+  // collect the jobs to run, either from the manifest or from the single-image arguments
+  let mut jobs = match &args.jobs_file {
+    Some(jobs_file) => match jobs::load_jobs(jobs_file) {
+      Ok(jobs) => jobs,
+      Err(err) => {
+        log::error!("{err}");
+        return ExitCode::FAILURE;
+      }
+    },
+    None => vec![Job::from_args(args)],
+  };
+
+  // the template from the command line names all outputs the jobs file does not name itself
+  if let Some(template) = &args.out_template {
+    for job in jobs.iter_mut().filter(|job| job.output.is_none() && job.out_template.is_none()) {
+      job.out_template = Some(template.clone());
+    }
+  }
+
+  if !args.sizes.is_empty() {
+    jobs = jobs::with_sizes(jobs, &args.sizes);
+  }
+
+  let cache = match args.cache.as_deref().map(Cache::open).transpose() {
+    Ok(cache) => cache,
+    Err(err) => {
+      log::error!("{err}");
+      return ExitCode::FAILURE;
+    }
+  };
+
+  // process images, jobs are independent so a batch runs in parallel
+  let failures = jobs
+    .par_iter()
+    .filter(|job| match run_job(job, cache.as_ref(), args.skip_existing) {
+      Ok(Some(output)) => {
+        log::info!("Saved output image to: {:?}", output);
+        false
+      }
+      Ok(None) => false,
+      Err(err) => {
+        log::error!("{err}");
+        true
+      }
+    })
+    .count();
+
+  if let Some(Err(err)) = cache.map(|cache| cache.save()) {
+    log::error!("{err}");
+    return ExitCode::FAILURE;
+  }
+  if failures > 0 {
+    log::error!("{failures} of {} job(s) failed", jobs.len());
+    return ExitCode::FAILURE;
+  }
+  ExitCode::SUCCESS
+}
+
+/// Runs a job unless the cache holds an unchanged result for it, or its output exists and `skip_existing` is set.
+///
+/// Returns the path of the written output, `None` if the job was skipped.
+fn run_job(job: &Job, cache: Option<&Cache>, skip_existing: bool) -> Result<Option<PathBuf>, DitherError> {
+  if let Some(output) = job.existing_output().filter(|_| skip_existing) {
+    log::info!("Exists, skipped: {:?}", output);
+    return Ok(None);
+  }
+  let Some(cache) = cache else {
+    return job.run().map(Some);
+  };
+  let key = cache::job_key(job)?;
+  if let Some(output) = cache.lookup(&key) {
+    log::info!("Unchanged, skipped: {:?}", output);
+    return Ok(None);
+  }
+  let output = job.run()?;
+  cache.insert(key, output.clone());
+  Ok(Some(output))
+}
+
+/// Writes a synthetic test pattern.
+fn generate(args: &GenerateArgs) -> ExitCode {
+  let image = patterns::generate(args.pattern, args.size);
+  let output = args.out_img.clone().unwrap_or_else(|| {
+    let name = args.pattern.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default();
+    PathBuf::from(format!("{name}_{}.png", args.size))
+  });
+  match output::save(&image, &output, &Settings::default()) {
+    Ok(()) => {
+      log::info!("Saved output image to: {:?}", output);
+      ExitCode::SUCCESS
+    }
+    Err(err) => {
+      log::error!("{err}");
+      ExitCode::FAILURE
+    }
+  }
+}
+
+/// Re-runs the jobs of a jobs file and compares (or with `--bless` replaces) the references.
+fn verify(args: &VerifyArgs) -> ExitCode {
+  let jobs = match jobs::load_jobs(&args.jobs_file) {
+    Ok(jobs) => jobs,
+    Err(err) => {
+      log::error!("{err}");
+      return ExitCode::FAILURE;
+    }
+  };
+
+  let failures = jobs
+    .par_iter()
+    .filter(|job| {
+      if args.bless {
+        return match verify::bless(job, &args.against) {
+          Ok(reference) => {
+            log::info!("Saved reference to: {:?}", reference);
+            false
+          }
+          Err(err) => {
+            log::error!("{err}");
+            true
+          }
+        };
+      }
+      match verify::verify(job, &args.against, args.tolerance) {
+        Ok((reference, outcome)) => {
+          match &outcome {
+            Outcome::Identical => println!("ok       {:?}", reference),
+            Outcome::WithinTolerance(d) => println!("ok       {:?} (mean difference {d:.3})", reference),
+            Outcome::Different(d) => println!("FAILED   {:?} (mean difference {d:.3})", reference),
+            Outcome::SizeMismatch => println!("FAILED   {:?} (size differs)", reference),
+            Outcome::Missing => println!("MISSING  {:?}", reference),
+          }
+          !outcome.passed()
+        }
+        Err(err) => {
+          log::error!("{err}");
+          true
+        }
+      }
+    })
+    .count();
+
+  if failures > 0 {
+    log::error!("{failures} of {} job(s) failed verification", jobs.len());
+    return ExitCode::FAILURE;
+  }
+  ExitCode::SUCCESS
+}
+
+/// Dithers every image of a directory into thumbnails and writes them as one contact sheet.
+fn contact_sheet(args: &ContactSheetArgs) -> ExitCode {
+  let paths = match contact::images_in(&args.dir) {
+    Ok(paths) => paths,
+    Err(err) => {
+      log::error!("{err}");
+      return ExitCode::FAILURE;
+    }
+  };
+  if paths.is_empty() {
+    log::error!("no images found in {:?}", args.dir);
+    return ExitCode::FAILURE;
+  }
+
+  // thumbnails that fail are left out, the sheet still shows the rest of the batch
+  let settings = Settings::default();
+  let results: Vec<_> = paths
+    .par_iter()
+    .map(|path| contact::thumbnail(path, args.thumb_size, args.dither_type, args.color_palette, &settings))
+    .collect();
+  let mut failures = 0;
+  let mut thumbnails = Vec::new();
+  for (path, result) in paths.iter().zip(results) {
+    match result {
+      Ok(thumbnail) => {
+        let label = path.file_name().unwrap_or_default().to_string_lossy().into_owned();
+        thumbnails.push((label, thumbnail));
+      }
+      Err(err) => {
+        log::error!("{err}");
+        failures += 1;
+      }
+    }
+  }
+
+  let sheet = contact::compose(&thumbnails, args.columns, args.thumb_size);
+  if let Err(err) = output::save(&sheet, &args.out_img, &settings) {
+    log::error!("{err}");
+    return ExitCode::FAILURE;
+  }
+  log::info!("Saved contact sheet of {} image(s) to: {:?}", thumbnails.len(), args.out_img);
+  if failures > 0 {
+    log::error!("{failures} of {} image(s) failed", paths.len());
+    return ExitCode::FAILURE;
+  }
+  ExitCode::SUCCESS
+}
+
+/// Shows an image in the terminal, toggling between the original and the dithered versions.
+fn preview(args: &PreviewArgs) -> ExitCode {
+  let image = match input::load(&args.in_img) {
+    Ok(image) => image,
+    Err(err) => {
+      log::error!("{err}");
+      return ExitCode::FAILURE;
+    }
+  };
+  match preview::run(&image, args.dither_type, args.color_palette, &Settings::default()) {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(err) => {
+      log::error!("terminal preview failed: {err}");
+      ExitCode::FAILURE
+    }
+  }
+}
+
+/// Prints a summary of an image file.
+fn info(args: &InfoArgs) -> ExitCode {
+  match info::inspect(&args.in_img) {
+    Ok(info) => {
+      println!("file:          {}", args.in_img.display());
+      println!("{info}");
+      ExitCode::SUCCESS
+    }
+    Err(err) => {
+      log::error!("{err}");
+      ExitCode::FAILURE
+    }
+  }
+}
 
-  // open image
-  let (mut buffer, width, height) = dither::open_image(&args.in_img);
-
-  // process image
-  dither::dither(&mut buffer, args.dither_type, args.color_palette, width, height);
-
-  // save file
-  if let Some(out_img) = args.out_img {
-    println!("Saving output image to: {:?}", out_img);
-    dither::save_image(buffer, out_img, width, height);
-  } else {
-    // if no output image is specified, save to the same path with "_out" suffix
-    let mut out_path = args.in_img.clone();
-    out_path.set_file_name(format!(
-      "{}_out.{}",
-      out_path.file_stem().unwrap().to_str().unwrap(),
-      out_path.extension().unwrap().to_str().unwrap()
-    ));
-    println!("Saving output image to: {:?}", out_path);
-    dither::save_image(buffer, out_path, width, height);
+/// Serves dithering requests on a local socket until the process is stopped.
+fn run_daemon(args: &DaemonArgs) -> ExitCode {
+  let socket = args.socket.clone().unwrap_or_else(daemon::default_socket);
+  match daemon::run(&socket) {
+    Ok(()) => ExitCode::SUCCESS,
+    Err(err) => {
+      log::error!("{err}");
+      ExitCode::FAILURE
+    }
   }
 }
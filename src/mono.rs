@@ -0,0 +1,164 @@
+//! Fast path for black and white output.
+//!
+//! Monochrome is the most common output and needs neither three channels nor a palette search: the
+//! image is reduced to its luma channel, dithered there and thresholded at middle gray without
+//! branches. The pipeline takes this path with `--fast-mono`. For gray inputs the result is exactly
+//! that of [`dither::dither_with`] with the monochrome palette, colored inputs are reduced to their
+//! brightness (Rec. 601 luma) first.
+
+use crate::dither::{self, DitherMethod, DitherOptions, ScanDirection};
+use crate::error::DitherError;
+use crate::palette::{Color, PALETTE_MONOCHROME};
+
+/// Returns whether the colors are exactly black and white, in any order.
+#[must_use]
+pub fn is_monochrome(colors: &[Color]) -> bool {
+  colors.len() == 2 && PALETTE_MONOCHROME.iter().all(|color| colors.contains(color))
+}
+
+/// Reduces an RGB buffer to its luma channel, gray pixels keep their value.
+#[must_use]
+pub fn to_luma(buffer: &[u8]) -> Vec<u8> {
+  buffer
+    .chunks_exact(3)
+    .map(|p| ((299 * u32::from(p[0]) + 587 * u32::from(p[1]) + 114 * u32::from(p[2]) + 500) / 1000) as u8)
+    .collect()
+}
+
+/// Quantizes a luma value to black (0) below 128 and white (255) from 128 on, without branching.
+#[must_use]
+pub fn threshold(value: u8) -> u8 {
+  0u8.wrapping_sub(value >> 7)
+}
+
+/// Dithers a luma channel to black and white in place.
+///
+/// Methods and scan directions without a dedicated single-channel implementation dither a gray RGB
+/// copy with the generic path, so the result never depends on which path was taken.
+pub fn dither(luma: &mut [u8], method: DitherMethod, width: u32, height: u32, options: &DitherOptions) -> Result<(), DitherError> {
+  options.validate(luma, width, height, 1)?;
+  let transparent = |pixel: usize| options.alpha.is_some_and(|alpha| alpha[pixel] == 0);
+  if method == DitherMethod::None {
+    for (i, value) in luma.iter_mut().enumerate() {
+      if !transparent(i) {
+        *value = threshold(*value);
+      }
+    }
+  } else if let Some((matrix, size)) = dither::bayer_matrix(method, options.bayer_size) {
+    for (i, value) in luma.iter_mut().enumerate() {
+      let (x, y) = ((i as u32 % width + options.origin.0) as usize, (i as u32 / width + options.origin.1) as usize);
+      if !transparent(i) {
+        *value = threshold(dither::apply_threshold(*value, matrix[(y % size) * size + x % size]));
+      }
+    }
+  } else if let Some(kernel) = dither::diffusion_kernel(method).filter(|_| options.scan == ScanDirection::LeftToRight) {
+    diffuse(luma, kernel, width, height, options.alpha);
+  } else {
+    let mut rgb: Vec<u8> = luma.iter().flat_map(|&value| [value; 3]).collect();
+    dither::dither_with(&mut rgb, method, &PALETTE_MONOCHROME, width, height, options)?;
+    for (value, pixel) in luma.iter_mut().zip(rgb.chunks_exact(3)) {
+      *value = pixel[0];
+    }
+  }
+  Ok(())
+}
+
+/// Error diffusion on a single channel, with the rounding of the generic path.
+fn diffuse(luma: &mut [u8], (kernel, kernel_width, kernel_height, x_offset): (&[f32], u32, u32, u32), width: u32, height: u32, alpha: Option<&[u8]>) {
+  let (width, height) = (width as usize, height as usize);
+  // the non-zero weights with their column and row offsets
+  let taps: Vec<(isize, usize, f32)> = (0..kernel_height as usize)
+    .flat_map(|ky| (0..kernel_width as usize).map(move |kx| (kx as isize - x_offset as isize, ky, kernel[ky * kernel_width as usize + kx])))
+    .filter(|&(_, _, weight)| weight != 0.0)
+    .collect();
+  let transparent = |pixel: usize| alpha.is_some_and(|alpha| alpha[pixel] == 0);
+  for y in 0..height {
+    for x in 0..width {
+      let i = y * width + x;
+      if transparent(i) {
+        continue;
+      }
+      let old = luma[i];
+      let new = threshold(old);
+      luma[i] = new;
+      if old == new {
+        continue;
+      }
+      let error = f32::from(old) - f32::from(new);
+      for &(dx, dy, weight) in &taps {
+        let (nx, ny) = (x.wrapping_add_signed(dx), y + dy);
+        if nx >= width || ny >= height || transparent(ny * width + nx) {
+          continue;
+        }
+        let n = ny * width + nx;
+        luma[n] = (f32::from(luma[n]) + error * weight).round().clamp(0.0, 255.0) as u8;
+      }
+    }
+    log::trace!("diffused row {}/{}", y + 1, height);
+  }
+}
+
+/// Packs a black and white luma channel into bits, 1 for white, from the most significant bit.
+///
+/// Every row starts on a new byte, like 1-bit framebuffers and e-paper displays expect.
+#[must_use]
+pub fn pack(luma: &[u8], width: u32, height: u32) -> Vec<u8> {
+  let row_bytes = (width as usize).div_ceil(8);
+  let mut packed = vec![0u8; row_bytes * height as usize];
+  for (row, bits) in luma.chunks_exact(width.max(1) as usize).zip(packed.chunks_exact_mut(row_bytes.max(1))) {
+    for (x, &value) in row.iter().enumerate() {
+      bits[x / 8] |= (value >> 7) << (7 - x % 8);
+    }
+  }
+  packed
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_threshold_and_pack() {
+    assert_eq!([0, 127, 128, 255].map(threshold), [0, 0, 255, 255]);
+    assert_eq!(
+      pack(&[255, 0, 255, 0, 0, 0, 0, 0, 255, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 255], 10, 2),
+      [0xa0, 0x80, 0, 0x40]
+    );
+    assert_eq!(to_luma(&[17, 17, 17, 255, 0, 0]), [17, 76]);
+    assert!(is_monochrome(&[PALETTE_MONOCHROME[1], PALETTE_MONOCHROME[0]]));
+  }
+
+  #[test]
+  fn test_dither_matches_generic_path_for_gray() {
+    let (width, height) = (37, 23);
+    let luma: Vec<u8> = (0..width * height).map(|i| ((i * 7 + i / width * 13) % 256) as u8).collect();
+    let alpha: Vec<u8> = (0..width * height).map(|i| if i % 11 == 0 { 0 } else { 255 }).collect();
+    let methods = [
+      DitherMethod::None,
+      DitherMethod::Bayer,
+      DitherMethod::Bayer4x4,
+      DitherMethod::FloydSteinberg,
+      DitherMethod::Jarvis,
+      DitherMethod::Atkinson,
+      DitherMethod::StevensonArce,
+      DitherMethod::ShiauFan,
+      DitherMethod::ShiauFan2,
+      DitherMethod::Fan,
+    ];
+    for method in methods {
+      for scan in [ScanDirection::LeftToRight, ScanDirection::Averaged] {
+        let options = DitherOptions {
+          alpha: Some(&alpha),
+          origin: (3, 5),
+          scan,
+          ..DitherOptions::default()
+        };
+        let mut fast = luma.clone();
+        dither(&mut fast, method, width, height, &options).unwrap();
+        let mut generic: Vec<u8> = luma.iter().flat_map(|&value| [value; 3]).collect();
+        dither::dither_with(&mut generic, method, &PALETTE_MONOCHROME, width, height, &options).unwrap();
+        assert_eq!(to_luma(&generic), fast, "{method:?} with {scan:?}");
+      }
+    }
+  }
+}
@@ -0,0 +1,144 @@
+//! Seeded noise injected before quantization.
+//!
+//! A little noise breaks up the flat bands left by [`DitherMethod::None`](crate::dither::DitherMethod::None)
+//! or weak ordered dithering. The same seed always gives the same noise, so outputs are reproducible.
+
+/// Default noise standard deviation in 8-bit levels.
+pub const DEFAULT_AMPLITUDE: f32 = 16.0;
+
+/// Kinds of noise that can be injected.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum Noise {
+  /// White gaussian noise, like film grain
+  Gaussian,
+  /// High-pass filtered noise without low-frequency clumps
+  Blue,
+}
+
+serde_value_enum!(Noise);
+
+/// Small, fast and seedable pseudo random number generator (SplitMix64).
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+  /// Creates a generator, equal seeds give equal sequences.
+  #[must_use]
+  pub fn new(seed: u64) -> Self {
+    Rng(seed)
+  }
+
+  /// Returns the next 64 random bits.
+  pub fn next_u64(&mut self) -> u64 {
+    self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+    let mut z = self.0;
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    z ^ (z >> 31)
+  }
+
+  /// Returns a uniformly distributed value in `[0, 1)`.
+  pub fn next_f32(&mut self) -> f32 {
+    (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+  }
+
+  /// Returns a normally distributed value with mean 0 and standard deviation 1.
+  pub fn next_gaussian(&mut self) -> f32 {
+    // Box-Muller transform, 1 - u keeps the logarithm finite
+    let u = 1.0 - self.next_f32();
+    let v = self.next_f32();
+    (-2.0 * u.ln()).sqrt() * (std::f32::consts::TAU * v).cos()
+  }
+}
+
+/// Returns one noise value per pixel with mean 0 and standard deviation of about 1.
+#[must_use]
+pub fn noise_field(kind: Noise, width: u32, height: u32, seed: u64) -> Vec<f32> {
+  let mut rng = Rng::new(seed);
+  let white: Vec<f32> = (0..width as usize * height as usize).map(|_| rng.next_gaussian()).collect();
+  match kind {
+    Noise::Gaussian => white,
+    Noise::Blue => high_pass(&white, width as usize, height as usize),
+  }
+}
+
+/// Subtracts the 3x3 neighborhood mean (wrapping around the edges) from white noise.
+fn high_pass(white: &[f32], width: usize, height: usize) -> Vec<f32> {
+  // the variance of `x - mean3x3(x)` is 8/9 of the variance of `x`
+  let scale = (9.0f32 / 8.0).sqrt();
+  let mut blue = Vec::with_capacity(white.len());
+  for y in 0..height {
+    for x in 0..width {
+      let mut sum = 0.0;
+      for dy in [height - 1, 0, 1] {
+        for dx in [width - 1, 0, 1] {
+          sum += white[(y + dy) % height * width + (x + dx) % width];
+        }
+      }
+      blue.push((white[y * width + x] - sum / 9.0) * scale);
+    }
+  }
+  blue
+}
+
+/// Adds noise with a standard deviation of `amplitude` levels to an RGB buffer.
+///
+/// The same offset is added to all three channels of a pixel, so the noise changes brightness
+/// without speckling the image with random colors.
+pub fn apply_noise(buffer: &mut [u8], kind: Noise, amplitude: f32, width: u32, height: u32, seed: u64) {
+  let field = noise_field(kind, width, height, seed);
+  for (pixel, offset) in buffer.chunks_exact_mut(3).zip(field) {
+    for channel in pixel {
+      *channel = (f32::from(*channel) + offset * amplitude).round().clamp(0.0, 255.0) as u8;
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn mean_and_variance(values: &[f32]) -> (f32, f32) {
+    let mean = values.iter().sum::<f32>() / values.len() as f32;
+    let variance = values.iter().map(|v| (v - mean).powi(2)).sum::<f32>() / values.len() as f32;
+    (mean, variance)
+  }
+
+  #[test]
+  fn test_rng_is_deterministic() {
+    let a: Vec<u64> = (0..4).scan(Rng::new(42), |rng, _| Some(rng.next_u64())).collect();
+    let b: Vec<u64> = (0..4).scan(Rng::new(42), |rng, _| Some(rng.next_u64())).collect();
+    let c: Vec<u64> = (0..4).scan(Rng::new(43), |rng, _| Some(rng.next_u64())).collect();
+    assert_eq!(a, b);
+    assert_ne!(a, c);
+  }
+
+  #[test]
+  fn test_noise_field_statistics() {
+    for kind in [Noise::Gaussian, Noise::Blue] {
+      let (mean, variance) = mean_and_variance(&noise_field(kind, 64, 64, 7));
+      assert!(mean.abs() < 0.1, "{:?} mean should be about 0, got {}", kind, mean);
+      assert!((variance - 1.0).abs() < 0.15, "{:?} variance should be about 1, got {}", kind, variance);
+    }
+  }
+
+  #[test]
+  fn test_blue_noise_has_no_low_frequencies() {
+    // neighbouring values of high-pass noise are anti-correlated
+    let correlation = |field: &[f32]| field.windows(2).map(|w| w[0] * w[1]).sum::<f32>() / field.len() as f32;
+    assert!(correlation(&noise_field(Noise::Blue, 64, 64, 1)) < -0.05);
+    assert!(correlation(&noise_field(Noise::Gaussian, 64, 64, 1)).abs() < 0.05);
+  }
+
+  #[test]
+  fn test_apply_noise() {
+    let mut buffer = vec![128; 3 * 16];
+    apply_noise(&mut buffer, Noise::Gaussian, DEFAULT_AMPLITUDE, 4, 4, 0);
+    assert!(buffer.iter().any(|&v| v != 128), "Noise should change the buffer");
+    assert!(buffer.chunks_exact(3).all(|p| p[0] == p[1] && p[1] == p[2]), "Noise should be monochrome");
+
+    let mut again = vec![128; 3 * 16];
+    apply_noise(&mut again, Noise::Gaussian, DEFAULT_AMPLITUDE, 4, 4, 0);
+    assert_eq!(buffer, again, "Same seed should give the same noise");
+  }
+}
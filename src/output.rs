@@ -0,0 +1,961 @@
+//! Image encoding for the processing pipeline.
+
+use std::fs::File;
+use std::io::{BufWriter, Cursor, Seek, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+use image::codecs::jpeg::{JpegEncoder, PixelDensity};
+use image::codecs::png::PngEncoder;
+use image::{ExtendedColorType, ImageEncoder, ImageFormat};
+use tiff::TiffResult;
+use tiff::encoder::{Rational, TiffEncoder, colortype};
+use tiff::tags::ResolutionUnit;
+
+use crate::ansi;
+use crate::error::DitherError;
+use crate::indexed;
+use crate::info;
+use crate::jpeg;
+use crate::pdf;
+use crate::pipeline::{Image, Settings};
+use crate::target::{self, Target};
+
+/// Output formats that are not selected by the file extension.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum OutputFormat {
+  /// Raw packed RGB565, little-endian, 2 bytes per pixel
+  Rgb565Le,
+  /// Raw packed RGB565, big-endian, 2 bytes per pixel
+  Rgb565Be,
+  /// Raw packed RGB555 (top bit clear), little-endian, 2 bytes per pixel
+  Rgb555Le,
+  /// Raw packed RGB555 (top bit clear), big-endian, 2 bytes per pixel
+  Rgb555Be,
+  /// 1-bit TIFF with CCITT Group 4 (fax) compression, for archival and fax pipelines
+  TiffG4,
+  /// Single-page PDF at the given --dpi and --page-size, for printing
+  Pdf,
+  /// ANSI art with half-block characters, use a terminal palette such as ansi16 or xterm256
+  Ans,
+}
+
+serde_value_enum!(OutputFormat);
+
+impl OutputFormat {
+  /// Returns the bit-depth target whose levels the format stores exactly, if it is a packed RGB format.
+  #[must_use]
+  pub fn target(&self) -> Option<Target> {
+    match self {
+      OutputFormat::Rgb565Le | OutputFormat::Rgb565Be => Some(Target::Rgb565),
+      OutputFormat::Rgb555Le | OutputFormat::Rgb555Be => Some(Target::Rgb555),
+      OutputFormat::TiffG4 | OutputFormat::Pdf | OutputFormat::Ans => None,
+    }
+  }
+
+  /// Returns whether the format only stores black and white pixels.
+  #[must_use]
+  pub fn is_bilevel(&self) -> bool {
+    matches!(self, OutputFormat::TiffG4)
+  }
+
+  fn is_big_endian(&self) -> bool {
+    matches!(self, OutputFormat::Rgb565Be | OutputFormat::Rgb555Be)
+  }
+}
+
+/// Encodes an image into the format of the settings, or the format given by the file extension if there is none.
+///
+/// The alpha channel is written as well if the image has one and the format supports transparency.
+/// With a resolution in the settings, PNG, JPEG and TIFF files record it so they print at the intended size.
+/// PNG files can be interlaced and JPEG files progressive, so that they show a preview while loading.
+/// PNG files also store the settings in a `dithers` text chunk, see [`metadata`].
+///
+/// The file is written atomically, see [`write_atomically`].
+pub fn save(image: &Image, path: &Path, settings: &Settings) -> Result<(), DitherError> {
+  log::debug!("encoding {:?} ({}x{}, format {:?})", path, image.width, image.height, settings.format);
+  image.validate()?;
+  let text = metadata(settings).filter(|_| settings.format.is_none() && matches!(ImageFormat::from_path(path), Ok(ImageFormat::Png)));
+  let write = |data: Vec<u8>| match &text {
+    Some(text) => write_atomically(path, &with_png_text(data, info::METADATA_KEYWORD, text)),
+    None => write_atomically(path, &data),
+  };
+  if let Some(format) = settings.format {
+    let data = encode(image, format, settings).map_err(|message| DitherError::Encode {
+      path: path.to_path_buf(),
+      message,
+    })?;
+    return write(data);
+  }
+
+  let extension_format = ImageFormat::from_path(path);
+  if settings.interlaced && !matches!(extension_format, Ok(ImageFormat::Png)) {
+    log::warn!("only PNG files can be interlaced, ignoring --interlaced for {:?}", path);
+  }
+  if settings.progressive && !matches!(extension_format, Ok(ImageFormat::Jpeg)) {
+    log::warn!("only JPEG files can be progressive, ignoring --progressive for {:?}", path);
+  }
+  if settings.jpeg_quality.is_some() && !matches!(extension_format, Ok(ImageFormat::Jpeg)) {
+    log::warn!("{:?} is not a JPEG file, ignoring --jpeg-quality", path);
+  }
+  let jpeg_quality = settings.jpeg_quality.unwrap_or(jpeg::DEFAULT_QUALITY);
+  if let (Some(_), Ok(ImageFormat::Tiff)) = (settings.dpi, &extension_format) {
+    // the TIFF encoder of the image crate does not write a resolution
+    return save_pages(std::slice::from_ref(image), path, settings);
+  }
+
+  if settings.indexed {
+    let encoded = match extension_format {
+      Ok(ImageFormat::Png) => Some(encode_indexed_png(image, settings)),
+      Ok(ImageFormat::Gif) => Some(encode_indexed_gif(image, settings)),
+      _ => {
+        log::warn!("indexed output is only available for PNG and GIF, writing {:?} in true color", path);
+        None
+      }
+    };
+    if let Some(encoded) = encoded {
+      let data = encoded.map_err(|message| DitherError::Encode {
+        path: path.to_path_buf(),
+        message,
+      })?;
+      return write(data);
+    }
+  }
+
+  let image_error = |source| DitherError::Image {
+    path: path.to_path_buf(),
+    source,
+  };
+  let rgba: Vec<u8>;
+  let (data, color_type) = match &image.alpha {
+    Some(alpha) if supports_alpha(path) => {
+      rgba = image
+        .buffer
+        .chunks_exact(3)
+        .zip(alpha)
+        .flat_map(|(rgb, &a)| [rgb[0], rgb[1], rgb[2], a])
+        .collect();
+      (&rgba, ExtendedColorType::Rgba8)
+    }
+    _ => (&image.buffer, ExtendedColorType::Rgb8),
+  };
+  let mut encoded = Vec::new();
+  match (settings.dpi, extension_format) {
+    (dpi, Ok(ImageFormat::Png)) if dpi.is_some() || settings.interlaced => {
+      PngEncoder::new(&mut encoded)
+        .write_image(data, image.width, image.height, color_type)
+        .map_err(image_error)?;
+      if settings.interlaced {
+        let bits = if color_type == ExtendedColorType::Rgba8 { 32 } else { 24 };
+        encoded = with_png_interlacing(encoded, data, image.width, image.height, bits);
+      }
+      write(match dpi {
+        Some(dpi) => with_png_density(encoded, dpi),
+        None => encoded,
+      })
+    }
+    (dpi, Ok(ImageFormat::Jpeg)) if settings.progressive => {
+      let data = jpeg::encode_progressive(&image.buffer, image.width, image.height, dpi, jpeg_quality).map_err(|message| DitherError::Encode {
+        path: path.to_path_buf(),
+        message,
+      })?;
+      write(data)
+    }
+    (dpi, Ok(ImageFormat::Jpeg)) => {
+      let mut encoder = JpegEncoder::new_with_quality(&mut encoded, jpeg_quality);
+      if let Some(dpi) = dpi {
+        encoder.set_pixel_density(PixelDensity::dpi(u16::try_from(dpi).unwrap_or(u16::MAX)));
+      }
+      encoder.write_image(data, image.width, image.height, color_type).map_err(image_error)?;
+      write(encoded)
+    }
+    (dpi, format) => {
+      if dpi.is_some() {
+        log::warn!("the format of {:?} cannot store a resolution, ignoring --dpi", path);
+      }
+      let format = format.map_err(image_error)?;
+      image::write_buffer_with_format(&mut Cursor::new(&mut encoded), data, image.width, image.height, color_type, format).map_err(image_error)?;
+      write(encoded)
+    }
+  }
+}
+
+/// Writes a file so that it is either complete or not changed at all.
+///
+/// The data goes to a temporary file next to the destination first, which is then renamed into place.
+/// A crash or a full disk while writing leaves the previous file, never a truncated one.
+pub fn write_atomically(path: &Path, data: &[u8]) -> Result<(), DitherError> {
+  write_atomically_with(path, |file| {
+    file.write_all(data).map_err(|source| DitherError::Io {
+      path: path.to_path_buf(),
+      source,
+    })
+  })
+}
+
+/// Like [`write_atomically`], but `write` produces the content, so that it does not have to be held in memory.
+pub fn write_atomically_with(path: &Path, write: impl FnOnce(&mut BufWriter<File>) -> Result<(), DitherError>) -> Result<(), DitherError> {
+  static COUNTER: AtomicU64 = AtomicU64::new(0);
+  let io_error = |source| DitherError::Io {
+    path: path.to_path_buf(),
+    source,
+  };
+  // unique per write, parallel jobs may write the same output
+  let name = path.file_name().map(|name| name.to_string_lossy()).unwrap_or_default();
+  let temporary = path.with_file_name(format!(".{name}.{}-{}.tmp", std::process::id(), COUNTER.fetch_add(1, Ordering::Relaxed)));
+  let mut file = BufWriter::new(File::create(&temporary).map_err(io_error)?);
+  let written = write(&mut file).and_then(|()| {
+    let file = file.into_inner().map_err(|err| io_error(err.into_error()))?;
+    file.sync_all().map_err(io_error)?;
+    std::fs::rename(&temporary, path).map_err(io_error)
+  });
+  if written.is_err() {
+    // the rename failed or never happened, the temporary file is the only thing to clean up
+    let _ = std::fs::remove_file(&temporary);
+  }
+  written
+}
+
+/// Encodes a PNG with a palette of the colors of the image, at the smallest bit depth that holds it.
+fn encode_indexed_png(image: &Image, settings: &Settings) -> Result<Vec<u8>, String> {
+  let indexed = indexed::index(image, settings.palette_order)?;
+  let depth = match indexed.palette.len() {
+    0..=2 => png::BitDepth::One,
+    3..=4 => png::BitDepth::Two,
+    5..=16 => png::BitDepth::Four,
+    _ => png::BitDepth::Eight,
+  };
+  let bits = depth as usize;
+  // indices are packed into bytes from the most significant bit, each row starts on a new byte
+  let row_bytes = (image.width as usize * bits).div_ceil(8);
+  let mut data = vec![0u8; row_bytes * image.height as usize];
+  for (i, &index) in indexed.indices.iter().enumerate() {
+    let (x, y) = (i % image.width as usize, i / image.width as usize);
+    let bit = x * bits;
+    data[y * row_bytes + bit / 8] |= index << (8 - bits - bit % 8);
+  }
+
+  let mut out = Vec::new();
+  let mut encoder = png::Encoder::new(&mut out, image.width, image.height);
+  encoder.set_color(png::ColorType::Indexed);
+  encoder.set_depth(depth);
+  encoder.set_palette(indexed.rgb());
+  if indexed.has_transparency() {
+    encoder.set_trns(indexed.alpha());
+  }
+  if let Some(dpi) = settings.dpi {
+    let ppm = pixels_per_meter(dpi);
+    encoder.set_pixel_dims(Some(png::PixelDimensions {
+      xppu: ppm,
+      yppu: ppm,
+      unit: png::Unit::Meter,
+    }));
+  }
+  let mut writer = encoder.write_header().map_err(|err| err.to_string())?;
+  writer.write_image_data(&data).map_err(|err| err.to_string())?;
+  writer.finish().map_err(|err| err.to_string())?;
+  if settings.interlaced {
+    out = with_png_interlacing(out, &data, image.width, image.height, bits);
+  }
+  Ok(out)
+}
+
+/// Encodes a single-frame GIF with a palette of the colors of the image.
+///
+/// GIF has no partial transparency, only fully transparent pixels are stored as transparent.
+fn encode_indexed_gif(image: &Image, settings: &Settings) -> Result<Vec<u8>, String> {
+  let indexed = indexed::index(image, settings.palette_order)?;
+  let too_large = || format!("GIF supports at most {} pixels per side", u16::MAX);
+  let width = u16::try_from(image.width).map_err(|_| too_large())?;
+  let height = u16::try_from(image.height).map_err(|_| too_large())?;
+  // the color table size is a power of two of at least 2 entries
+  let mut palette = indexed.rgb();
+  palette.resize(indexed.palette.len().next_power_of_two().max(2) * 3, 0);
+
+  let mut out = Vec::new();
+  let mut encoder = gif::Encoder::new(&mut out, width, height, &palette).map_err(|err| err.to_string())?;
+  let frame = gif::Frame {
+    width,
+    height,
+    // the transparent entry is always at index 0
+    transparent: indexed.palette.first().filter(|entry| entry[3] == 0).map(|_| 0),
+    buffer: indexed.indices.into(),
+    ..gif::Frame::default()
+  };
+  encoder.write_frame(&frame).map_err(|err| err.to_string())?;
+  drop(encoder);
+  Ok(out)
+}
+
+/// Encodes images as the pages of a single multi-page TIFF file.
+///
+/// Pages with an alpha channel are written as RGBA, all others as RGB. The resolution of the settings is
+/// recorded on every page.
+pub fn save_pages(pages: &[Image], path: &Path, settings: &Settings) -> Result<(), DitherError> {
+  log::debug!("encoding {} page(s) into {:?}", pages.len(), path);
+  for page in pages {
+    page.validate()?;
+  }
+  let tiff_error = |source| DitherError::Tiff {
+    path: path.to_path_buf(),
+    source,
+  };
+  let mut encoded = Cursor::new(Vec::new());
+  let mut encoder = TiffEncoder::new(&mut encoded).map_err(tiff_error)?;
+  for page in pages {
+    match &page.alpha {
+      Some(alpha) => {
+        let rgba: Vec<u8> = page
+          .buffer
+          .chunks_exact(3)
+          .zip(alpha)
+          .flat_map(|(rgb, &a)| [rgb[0], rgb[1], rgb[2], a])
+          .collect();
+        write_page::<colortype::RGBA8, _>(&mut encoder, page, &rgba, settings.dpi)
+      }
+      None => write_page::<colortype::RGB8, _>(&mut encoder, page, &page.buffer, settings.dpi),
+    }
+    .map_err(tiff_error)?;
+  }
+  write_atomically(path, &encoded.into_inner())
+}
+
+/// Writes one page of a TIFF file, with its resolution if one is given.
+fn write_page<C: colortype::ColorType<Inner = u8>, W: Write + Seek>(
+  encoder: &mut TiffEncoder<W>,
+  page: &Image,
+  data: &[u8],
+  dpi: Option<u32>,
+) -> TiffResult<()> {
+  let mut image = encoder.new_image::<C>(page.width, page.height)?;
+  if let Some(dpi) = dpi {
+    image.resolution(ResolutionUnit::Inch, Rational { n: dpi, d: 1 });
+  }
+  image.write_data(data)
+}
+
+/// Converts a resolution in dots per inch to the pixels per meter of PNG.
+#[must_use]
+pub fn pixels_per_meter(dpi: u32) -> u32 {
+  (f64::from(dpi) / 0.0254).round() as u32
+}
+
+/// Inserts a pHYs chunk with the resolution into an encoded PNG file.
+fn with_png_density(png: Vec<u8>, dpi: u32) -> Vec<u8> {
+  // the chunk follows the signature (8 bytes) and the IHDR chunk (25 bytes)
+  const IHDR_END: usize = 33;
+  let pixels_per_meter = pixels_per_meter(dpi);
+  let mut chunk = b"pHYs".to_vec();
+  chunk.extend_from_slice(&pixels_per_meter.to_be_bytes());
+  chunk.extend_from_slice(&pixels_per_meter.to_be_bytes());
+  // unit: meter
+  chunk.push(1);
+
+  let mut out = Vec::with_capacity(png.len() + 21);
+  out.extend_from_slice(&png[..IHDR_END]);
+  push_png_chunk(&mut out, &chunk);
+  out.extend_from_slice(&png[IHDR_END..]);
+  out
+}
+
+/// Returns the settings that differ from the defaults as TOML, the `dithers` text chunk of PNG outputs.
+///
+/// The text reads back as the settings of a job, so [`info`](crate::info) shows how a file was made.
+/// `None` if all settings are the defaults or they cannot be written as TOML.
+#[must_use]
+pub fn metadata(settings: &Settings) -> Option<String> {
+  let table = |settings: &Settings| toml::Table::try_from(settings).map_err(|err| log::warn!("cannot store the settings in the output: {err}"));
+  let (mut table, defaults) = (table(settings).ok()?, table(&Settings::default()).ok()?);
+  table.retain(|key, value| defaults.get(key) != Some(value));
+  (!table.is_empty()).then(|| table.to_string())
+}
+
+/// Inserts a text chunk before the image data of an encoded PNG file, tEXt for ASCII and iTXt for
+/// other text. Decoders read the chunks before the image data with the header.
+fn with_png_text(png: Vec<u8>, keyword: &str, text: &str) -> Vec<u8> {
+  let mut chunk = if text.is_ascii() { b"tEXt".to_vec() } else { b"iTXt".to_vec() };
+  chunk.extend_from_slice(keyword.as_bytes());
+  chunk.push(0);
+  if !text.is_ascii() {
+    // uncompressed, without a language tag or a translated keyword
+    chunk.extend_from_slice(&[0, 0, 0, 0]);
+  }
+  chunk.extend_from_slice(text.as_bytes());
+
+  // the chunks follow the signature (8 bytes), each with its length, type, data and checksum
+  let mut start = 8;
+  while start + 8 <= png.len() && &png[start + 4..start + 8] != b"IDAT" {
+    start += 12 + u32::from_be_bytes([png[start], png[start + 1], png[start + 2], png[start + 3]]) as usize;
+  }
+  let start = start.min(png.len());
+  let mut out = Vec::with_capacity(png.len() + chunk.len() + 8);
+  out.extend_from_slice(&png[..start]);
+  push_png_chunk(&mut out, &chunk);
+  out.extend_from_slice(&png[start..]);
+  out
+}
+
+/// Appends a PNG chunk, given as its type followed by its data, with its length and checksum.
+fn push_png_chunk(out: &mut Vec<u8>, chunk: &[u8]) {
+  let mut crc = Crc::new();
+  crc.update(chunk);
+  out.extend_from_slice(&(chunk.len() as u32 - 4).to_be_bytes());
+  out.extend_from_slice(chunk);
+  out.extend_from_slice(&crc.sum().to_be_bytes());
+}
+
+/// Column, row, horizontal and vertical spacing of the pixels of the seven Adam7 passes.
+const ADAM7: [(usize, usize, usize, usize); 7] = [(0, 0, 8, 8), (4, 0, 8, 8), (0, 4, 4, 8), (2, 0, 4, 4), (0, 2, 2, 4), (1, 0, 2, 2), (0, 1, 1, 2)];
+
+/// Rewrites an encoded PNG file as interlaced, from the unfiltered rows of its image data.
+///
+/// `data` holds the rows as the encoder received them, with `bits` bits per pixel, each row starting
+/// on a new byte. The other chunks are kept.
+fn with_png_interlacing(png: Vec<u8>, data: &[u8], width: u32, height: u32, bits: usize) -> Vec<u8> {
+  let (width, height) = (width as usize, height as usize);
+  let row_bytes = (width * bits).div_ceil(8);
+  let mut scanlines = Vec::with_capacity(data.len() + 7 * height);
+  for (x0, y0, dx, dy) in ADAM7 {
+    // passes without pixels have no rows at all
+    if x0 >= width || y0 >= height {
+      continue;
+    }
+    let pass_width = (width - x0).div_ceil(dx);
+    for y in (y0..height).step_by(dy) {
+      let source = &data[y * row_bytes..(y + 1) * row_bytes];
+      let mut row = vec![0u8; (pass_width * bits).div_ceil(8)];
+      for (i, x) in (x0..width).step_by(dx).enumerate() {
+        if bits >= 8 {
+          let bytes = bits / 8;
+          row[i * bytes..(i + 1) * bytes].copy_from_slice(&source[x * bytes..(x + 1) * bytes]);
+        } else {
+          // pixels are packed from the most significant bit
+          let value = (source[x * bits / 8] >> (8 - bits - x * bits % 8)) & ((1 << bits) - 1);
+          row[i * bits / 8] |= value << (8 - bits - i * bits % 8);
+        }
+      }
+      // filter type none
+      scanlines.push(0);
+      scanlines.extend(row);
+    }
+  }
+  let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+  // writing into memory cannot fail
+  encoder.write_all(&scanlines).expect("in-memory compression");
+  let compressed = encoder.finish().expect("in-memory compression");
+
+  // the chunks follow the 8 byte signature, each is its length, type, data and checksum
+  let mut out = png[..8].to_vec();
+  let mut offset = 8;
+  let mut idat_written = false;
+  while offset + 12 <= png.len() {
+    let length = u32::from_be_bytes(png[offset..offset + 4].try_into().expect("4 bytes")) as usize;
+    let chunk = &png[offset + 4..offset + 8 + length];
+    offset += 12 + length;
+    match &chunk[..4] {
+      b"IHDR" => {
+        let mut header = chunk.to_vec();
+        // the interlace method is the last byte of the header
+        header[4 + 12] = 1;
+        push_png_chunk(&mut out, &header);
+      }
+      b"IDAT" if idat_written => {}
+      b"IDAT" => {
+        let mut idat = b"IDAT".to_vec();
+        idat.extend_from_slice(&compressed);
+        push_png_chunk(&mut out, &idat);
+        idat_written = true;
+      }
+      _ => push_png_chunk(&mut out, chunk),
+    }
+  }
+  out
+}
+
+/// Encodes an image into the file contents of the given format.
+///
+/// Returns a description of the problem if the image cannot be stored in the format.
+pub fn encode(image: &Image, format: OutputFormat, settings: &Settings) -> Result<Vec<u8>, String> {
+  match (format, format.target()) {
+    (_, Some(target)) => Ok(pack(&image.buffer, target, format.is_big_endian())),
+    (OutputFormat::Pdf, _) => Ok(pdf::encode(image, settings.dpi.unwrap_or(pdf::DEFAULT_DPI), settings.page_size)),
+    (OutputFormat::Ans, _) => Ok(ansi::encode(image)),
+    _ => encode_g4(image, settings.dpi),
+  }
+}
+
+/// Packs an RGB buffer into 16-bit pixels, row by row without padding.
+///
+/// Channels are reduced to the nearest level of the target, so buffers that were not dithered to
+/// the target are still packed correctly.
+fn pack(buffer: &[u8], target: Target, big_endian: bool) -> Vec<u8> {
+  let (r_bits, g_bits, b_bits) = target.bits();
+  buffer
+    .chunks_exact(3)
+    .flat_map(|rgb| {
+      let r = target::level(rgb[0], r_bits);
+      let g = target::level(rgb[1], g_bits);
+      let b = target::level(rgb[2], b_bits);
+      let value = (r << (g_bits + b_bits)) | (g << b_bits) | b;
+      if big_endian { value.to_be_bytes() } else { value.to_le_bytes() }
+    })
+    .collect()
+}
+
+/// Encodes a single-page 1-bit TIFF with CCITT Group 4 compression.
+///
+/// Pixels darker than mid-gray become black, so the image should be dithered to monochrome first.
+/// The resolution defaults to the 200 dpi of fine fax mode.
+fn encode_g4(image: &Image, dpi: Option<u32>) -> Result<Vec<u8>, String> {
+  if image.width == 0 || image.height == 0 {
+    return Err(format!("CCITT G4 cannot store an empty {}x{} image", image.width, image.height));
+  }
+  let width = u16::try_from(image.width).map_err(|_| format!("CCITT G4 supports at most {} pixels per row", u16::MAX))?;
+  let mut encoder = fax::encoder::Encoder::new(fax::VecWriter::new());
+  for row in image.buffer.chunks_exact(image.width as usize * 3) {
+    let pels = row.chunks_exact(3).map(|p| {
+      let luma = 0.299 * f32::from(p[0]) + 0.587 * f32::from(p[1]) + 0.114 * f32::from(p[2]);
+      if luma < 127.5 { fax::Color::Black } else { fax::Color::White }
+    });
+    // writing into memory cannot fail
+    let Ok(()) = encoder.encode_line(pels, width);
+  }
+  let Ok(writer) = encoder.finish();
+  wrap_g4(&writer.finish(), image.width, image.height, dpi.unwrap_or(DEFAULT_G4_DPI))
+}
+
+/// Resolution of CCITT G4 TIFF files without `--dpi`, the fine mode of fax machines.
+const DEFAULT_G4_DPI: u32 = 200;
+
+/// Wraps CCITT G4 data into a little-endian TIFF with a single strip.
+///
+/// The directory is written here rather than by the `fax` crate, so that the resolution is a
+/// regular tag value instead of bytes patched at a guessed offset.
+fn wrap_g4(data: &[u8], width: u32, height: u32, dpi: u32) -> Result<Vec<u8>, String> {
+  const SHORT: u16 = 3;
+  const LONG: u16 = 4;
+  const RATIONAL: u16 = 5;
+  const ENTRIES: u32 = 11;
+  // header, entry count, entries and the offset of the next directory, then the two resolutions
+  let rationals = 8 + 2 + ENTRIES * 12 + 4;
+  let strip = rationals + 2 * 8;
+  let strip_len = u32::try_from(data.len()).map_err(|_| "the CCITT G4 data does not fit a TIFF file of 4 GiB".to_string())?;
+  // tag, type and value, a rational is stored after the directory and referenced by its offset
+  let entries: [(u16, u16, u32); ENTRIES as usize] = [
+    (256, LONG, width),             // ImageWidth
+    (257, LONG, height),            // ImageLength
+    (259, SHORT, 4),                // Compression: CCITT Group 4
+    (262, SHORT, 0),                // PhotometricInterpretation: white is zero
+    (273, LONG, strip),             // StripOffsets
+    (274, SHORT, 1),                // Orientation: top left
+    (278, LONG, height),            // RowsPerStrip
+    (279, LONG, strip_len),         // StripByteCounts
+    (282, RATIONAL, rationals),     // XResolution
+    (283, RATIONAL, rationals + 8), // YResolution
+    (296, SHORT, 2),                // ResolutionUnit: inch
+  ];
+  let mut out = Vec::with_capacity(strip as usize + data.len());
+  out.extend_from_slice(b"II");
+  out.extend_from_slice(&42u16.to_le_bytes());
+  out.extend_from_slice(&8u32.to_le_bytes());
+  out.extend_from_slice(&(ENTRIES as u16).to_le_bytes());
+  for (tag, kind, value) in entries {
+    out.extend_from_slice(&tag.to_le_bytes());
+    out.extend_from_slice(&kind.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes());
+    out.extend_from_slice(&value.to_le_bytes());
+  }
+  out.extend_from_slice(&0u32.to_le_bytes());
+  for _ in 0..2 {
+    out.extend_from_slice(&dpi.to_le_bytes());
+    out.extend_from_slice(&1u32.to_le_bytes());
+  }
+  out.extend_from_slice(data);
+  Ok(out)
+}
+
+/// Returns whether the format selected by the file extension can store an alpha channel.
+fn supports_alpha(path: &Path) -> bool {
+  matches!(
+    ImageFormat::from_path(path),
+    Ok(ImageFormat::Png | ImageFormat::Tiff | ImageFormat::WebP | ImageFormat::Gif | ImageFormat::Tga | ImageFormat::Ico | ImageFormat::Qoi | ImageFormat::Bmp)
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_pack_rgb565() {
+    let buffer = [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+    assert_eq!(pack(&buffer, Target::Rgb565, false), vec![0x00, 0xf8, 0xe0, 0x07, 0x1f, 0x00, 0xff, 0xff]);
+    assert_eq!(pack(&buffer, Target::Rgb565, true), vec![0xf8, 0x00, 0x07, 0xe0, 0x00, 0x1f, 0xff, 0xff]);
+  }
+
+  #[test]
+  fn test_pack_rgb555() {
+    let buffer = [255, 0, 0, 0, 255, 0, 0, 0, 255, 255, 255, 255];
+    assert_eq!(pack(&buffer, Target::Rgb555, false), vec![0x00, 0x7c, 0xe0, 0x03, 0x1f, 0x00, 0xff, 0x7f]);
+    assert_eq!(pack(&buffer, Target::Rgb555, true), vec![0x7c, 0x00, 0x03, 0xe0, 0x00, 0x1f, 0x7f, 0xff]);
+  }
+
+  #[test]
+  fn test_encode_packed_formats() {
+    let image = Image {
+      buffer: vec![255, 0, 0],
+      alpha: None,
+      width: 1,
+      height: 1,
+    };
+    let settings = Settings::default();
+    assert_eq!(encode(&image, OutputFormat::Rgb565Le, &settings), Ok(vec![0x00, 0xf8]));
+    assert_eq!(encode(&image, OutputFormat::Rgb555Be, &settings), Ok(vec![0x7c, 0x00]));
+  }
+
+  #[test]
+  fn test_encode_g4_round_trip() {
+    // checkerboard of 3x3 blocks with a white border column
+    let (width, height) = (13, 9);
+    let buffer = (0..width * height)
+      .flat_map(|i| {
+        let (x, y) = (i % width, i / width);
+        let black = x < 12 && (x / 3 + y / 3) % 2 == 0;
+        [if black { 0 } else { 255 }; 3]
+      })
+      .collect();
+    let image = Image {
+      buffer,
+      alpha: None,
+      width,
+      height,
+    };
+
+    let path = std::env::temp_dir().join("dithers_output_g4_test.tif");
+    let settings = Settings {
+      format: Some(OutputFormat::TiffG4),
+      ..Settings::default()
+    };
+    save(&image, &path, &settings).unwrap();
+    let decoded = image::open(&path).unwrap().into_rgb8();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(decoded.dimensions(), (width, height));
+    assert_eq!(decoded.into_raw(), image.buffer);
+  }
+
+  #[test]
+  fn test_encode_g4_rejects_wide_images() {
+    let image = Image {
+      buffer: vec![0; 70_000 * 3],
+      alpha: None,
+      width: 70_000,
+      height: 1,
+    };
+    assert!(encode(&image, OutputFormat::TiffG4, &Settings::default()).is_err());
+  }
+
+  #[test]
+  fn test_encode_g4_rejects_empty_images() {
+    for (width, height) in [(0, 4), (4, 0)] {
+      let image = Image {
+        buffer: Vec::new(),
+        alpha: None,
+        width,
+        height,
+      };
+      assert!(encode(&image, OutputFormat::TiffG4, &Settings::default()).is_err(), "{width}x{height}");
+    }
+  }
+
+  #[test]
+  fn test_save_and_load_pages() {
+    let path = std::env::temp_dir().join("dithers_output_pages_test.tif");
+    let rgb = Image {
+      buffer: vec![255, 0, 0, 0, 255, 0],
+      alpha: None,
+      width: 2,
+      height: 1,
+    };
+    let rgba = Image {
+      buffer: vec![0, 0, 255],
+      alpha: Some(vec![128]),
+      width: 1,
+      height: 1,
+    };
+
+    save_pages(&[rgb.clone(), rgba.clone()], &path, &Settings::default()).unwrap();
+    let pages = crate::input::load_pages(&path, &crate::input::LoadOptions::default()).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(pages, vec![rgb, rgba]);
+  }
+
+  #[test]
+  fn test_save_png_stores_the_settings() {
+    let image = Image {
+      buffer: vec![0, 0, 0, 255, 255, 255],
+      alpha: None,
+      width: 2,
+      height: 1,
+    };
+    let path = std::env::temp_dir().join("dithers_output_metadata_test.png");
+    for settings in [
+      Settings {
+        exposure: -1.0,
+        indexed: true,
+        ..Settings::default()
+      },
+      Settings {
+        caption: Some("Grüße".to_string()),
+        interlaced: true,
+        ..Settings::default()
+      },
+    ] {
+      save(&image, &path, &settings).unwrap();
+      let metadata = info::inspect(&path).unwrap().metadata;
+      assert_eq!(metadata.len(), 1);
+      assert_eq!(metadata[0].0, info::METADATA_KEYWORD);
+      assert_eq!(toml::from_str::<Settings>(&metadata[0].1).unwrap(), settings);
+      assert_eq!(image::open(&path).unwrap().into_rgb8().into_raw(), image.buffer);
+    }
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_save_records_dpi() {
+    let image = Image {
+      buffer: vec![0, 128, 255, 255, 255, 255],
+      alpha: None,
+      width: 2,
+      height: 1,
+    };
+    let settings = Settings {
+      dpi: Some(254),
+      ..Settings::default()
+    };
+    let dir = std::env::temp_dir();
+
+    let png = dir.join("dithers_output_dpi_test.png");
+    save(&image, &png, &settings).unwrap();
+    let data = std::fs::read(&png).unwrap();
+    // 254 dpi is 10000 pixels per meter
+    assert_eq!(&data[33..46], b"\0\0\0\x09pHYs\0\0\x27\x10\0");
+    assert_eq!(
+      image::open(&png).unwrap().into_rgb8().into_raw(),
+      image.buffer,
+      "pHYs chunk should keep the PNG valid"
+    );
+    std::fs::remove_file(&png).unwrap();
+
+    let jpeg = dir.join("dithers_output_dpi_test.jpg");
+    save(&image, &jpeg, &settings).unwrap();
+    let data = std::fs::read(&jpeg).unwrap();
+    // JFIF APP0 segment: units 1 (dpi), then the X and Y density
+    assert_eq!(&data[6..11], b"JFIF\0");
+    assert_eq!(&data[13..18], &[1, 0, 254, 0, 254]);
+    std::fs::remove_file(&jpeg).unwrap();
+
+    let tiff = dir.join("dithers_output_dpi_test.tif");
+    save(&image, &tiff, &settings).unwrap();
+    let mut decoder = tiff::decoder::Decoder::new(File::open(&tiff).unwrap()).unwrap();
+    let resolution = decoder.get_tag(tiff::tags::Tag::XResolution).unwrap();
+    let unit = decoder.get_tag_u32(tiff::tags::Tag::ResolutionUnit).unwrap();
+    std::fs::remove_file(&tiff).unwrap();
+    assert_eq!(resolution, tiff::decoder::ifd::Value::Rational(254, 1));
+    assert_eq!(unit, 2, "Resolution unit should be inches");
+  }
+
+  #[test]
+  fn test_save_interlaced_png_and_progressive_jpeg() {
+    let image = Image {
+      buffer: (0..11 * 9 * 3).map(|i| (i * 13 % 256) as u8).collect(),
+      alpha: None,
+      width: 11,
+      height: 9,
+    };
+    let settings = Settings {
+      interlaced: true,
+      progressive: true,
+      dpi: Some(254),
+      ..Settings::default()
+    };
+    let dir = std::env::temp_dir();
+
+    let png = dir.join("dithers_output_interlaced_test.png");
+    save(&image, &png, &settings).unwrap();
+    let data = std::fs::read(&png).unwrap();
+    assert_eq!(data[28], 1, "IHDR should declare Adam7 interlacing");
+    assert_eq!(&data[37..41], b"pHYs");
+    assert_eq!(image::open(&png).unwrap().into_rgb8().into_raw(), image.buffer);
+    std::fs::remove_file(&png).unwrap();
+
+    let jpeg = dir.join("dithers_output_progressive_test.jpg");
+    save(&image, &jpeg, &settings).unwrap();
+    let data = std::fs::read(&jpeg).unwrap();
+    assert_eq!(&data[13..18], &[1, 0, 254, 0, 254]);
+    assert!(data.windows(2).any(|marker| marker == [0xff, 0xc2]));
+    assert_eq!(image::open(&jpeg).unwrap().into_rgb8().dimensions(), (11, 9));
+    std::fs::remove_file(&jpeg).unwrap();
+  }
+
+  #[test]
+  fn test_save_jpeg_quality() {
+    let image = Image {
+      buffer: (0..32 * 32 * 3).map(|i| (i * 37 % 251) as u8).collect(),
+      alpha: None,
+      width: 32,
+      height: 32,
+    };
+    let path = std::env::temp_dir().join("dithers_output_jpeg_quality_test.jpg");
+    let size = |jpeg_quality, progressive| {
+      save(
+        &image,
+        &path,
+        &Settings {
+          jpeg_quality,
+          progressive,
+          ..Settings::default()
+        },
+      )
+      .unwrap();
+      std::fs::metadata(&path).unwrap().len()
+    };
+    for progressive in [false, true] {
+      assert!(size(Some(20), progressive) < size(None, progressive), "progressive: {progressive}");
+      assert!(size(Some(95), progressive) > size(None, progressive), "progressive: {progressive}");
+    }
+    std::fs::remove_file(&path).unwrap();
+  }
+
+  #[test]
+  fn test_save_interlaced_indexed_png() {
+    // two colors give a bit depth of 1, so pass rows are packed from odd pixel positions
+    let image = Image {
+      buffer: (0..13 * 5).flat_map(|i| [if i % 3 == 0 { 255 } else { 0 }; 3]).collect(),
+      alpha: None,
+      width: 13,
+      height: 5,
+    };
+    let settings = Settings {
+      interlaced: true,
+      indexed: true,
+      ..Settings::default()
+    };
+    let path = std::env::temp_dir().join("dithers_output_interlaced_indexed_test.png");
+    save(&image, &path, &settings).unwrap();
+    let decoded = image::open(&path).unwrap().into_rgb8().into_raw();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(decoded, image.buffer);
+  }
+
+  #[test]
+  fn test_write_atomically_replaces_file() {
+    let dir = std::env::temp_dir().join("dithers_output_atomic_test");
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("out.bin");
+    std::fs::write(&path, b"old").unwrap();
+    write_atomically(&path, b"new").unwrap();
+    assert_eq!(std::fs::read(&path).unwrap(), b"new");
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1, "No temporary file should be left behind");
+
+    // a directory cannot be replaced by a file, the error names the destination
+    let err = write_atomically(&dir, b"data").unwrap_err();
+    assert!(matches!(err, DitherError::Io { path, .. } if path == dir));
+    assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 1);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_encode_g4_resolution() {
+    let image = Image {
+      buffer: vec![0; 3],
+      alpha: None,
+      width: 1,
+      height: 1,
+    };
+    let path = std::env::temp_dir().join("dithers_output_g4_dpi_test.tif");
+    std::fs::write(&path, encode_g4(&image, Some(600)).unwrap()).unwrap();
+    let mut decoder = tiff::decoder::Decoder::new(File::open(&path).unwrap()).unwrap();
+    let resolution = [tiff::tags::Tag::XResolution, tiff::tags::Tag::YResolution].map(|tag| decoder.get_tag(tag).unwrap());
+    let unit = decoder.get_tag_u32(tiff::tags::Tag::ResolutionUnit).unwrap();
+    std::fs::write(&path, encode_g4(&image, None).unwrap()).unwrap();
+    let mut decoder = tiff::decoder::Decoder::new(File::open(&path).unwrap()).unwrap();
+    let default = decoder.get_tag(tiff::tags::Tag::XResolution).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(resolution, [const { tiff::decoder::ifd::Value::Rational(600, 1) }; 2]);
+    assert_eq!(unit, 2, "The resolution is per inch");
+    assert_eq!(default, tiff::decoder::ifd::Value::Rational(DEFAULT_G4_DPI, 1));
+  }
+
+  #[test]
+  fn test_save_indexed_png() {
+    let image = Image {
+      buffer: vec![255, 255, 255, 0, 0, 0, 255, 0, 0, 0, 0, 0],
+      alpha: Some(vec![255, 255, 255, 0]),
+      width: 2,
+      height: 2,
+    };
+    let settings = Settings {
+      indexed: true,
+      palette_order: indexed::PaletteOrder::Luminance,
+      ..Settings::default()
+    };
+    let path = std::env::temp_dir().join("dithers_output_indexed_test.png");
+    save(&image, &path, &settings).unwrap();
+    let data = std::fs::read(&path).unwrap();
+    let decoded = image::open(&path).unwrap().into_rgba8().into_raw();
+    std::fs::remove_file(&path).unwrap();
+
+    // IHDR: 2 bits per index for 4 entries (transparent, black, red, white), color type 3 (indexed)
+    assert_eq!(&data[24..26], &[2, 3]);
+    assert_eq!(decoded, vec![255, 255, 255, 255, 0, 0, 0, 255, 255, 0, 0, 255, 0, 0, 0, 0]);
+  }
+
+  #[test]
+  fn test_save_indexed_gif() {
+    let image = Image {
+      buffer: vec![255, 255, 255, 0, 0, 0, 10, 20, 30],
+      alpha: Some(vec![255, 255, 0]),
+      width: 3,
+      height: 1,
+    };
+    let settings = Settings {
+      indexed: true,
+      ..Settings::default()
+    };
+    let path = std::env::temp_dir().join("dithers_output_indexed_test.gif");
+    save(&image, &path, &settings).unwrap();
+    let decoded = image::open(&path).unwrap().into_rgba8().into_raw();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(&decoded[..8], &[255, 255, 255, 255, 0, 0, 0, 255]);
+    assert_eq!(decoded[11], 0, "Transparent pixels should stay transparent");
+  }
+
+  #[test]
+  fn test_save_indexed_rejects_many_colors() {
+    let image = Image {
+      buffer: (0..300u32).flat_map(|i| [i as u8, (i >> 8) as u8, 0]).collect(),
+      alpha: None,
+      width: 300,
+      height: 1,
+    };
+    let settings = Settings {
+      indexed: true,
+      ..Settings::default()
+    };
+    let path = std::env::temp_dir().join("dithers_output_indexed_many_test.png");
+    assert!(matches!(save(&image, &path, &settings), Err(DitherError::Encode { .. })));
+  }
+
+  #[test]
+  fn test_supports_alpha() {
+    assert!(supports_alpha(Path::new("sprite.png")));
+    assert!(supports_alpha(Path::new("sprite.webp")));
+    assert!(!supports_alpha(Path::new("photo.jpg")));
+    assert!(!supports_alpha(Path::new("no_extension")));
+  }
+}
@@ -0,0 +1,156 @@
+//! Captions and watermarks composited onto the image before dithering.
+//!
+//! Drawing labels before the dithering step makes them part of the dithered texture, so they use the
+//! same palette and look like the rest of the output.
+
+use crate::font;
+use crate::palette::Color;
+use crate::pipeline::Image;
+
+/// Caption text scale used when none is given.
+pub const DEFAULT_CAPTION_SCALE: u32 = 2;
+
+/// Places within the image.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
+pub enum Position {
+  /// Top left corner
+  TopLeft,
+  /// Centered at the top edge
+  Top,
+  /// Top right corner
+  TopRight,
+  /// Centered at the left edge
+  Left,
+  /// Center of the image
+  Center,
+  /// Centered at the right edge
+  Right,
+  /// Bottom left corner
+  BottomLeft,
+  /// Centered at the bottom edge
+  #[default]
+  Bottom,
+  /// Bottom right corner
+  BottomRight,
+}
+
+serde_value_enum!(Position);
+
+impl Position {
+  /// Returns the top left corner of an element of `size` placed within `canvas`, `margin` pixels from the edges.
+  ///
+  /// Elements larger than the canvas start before its top left corner, so that their center stays centered.
+  #[must_use]
+  pub fn origin(self, canvas: (u32, u32), size: (u32, u32), margin: u32) -> (i64, i64) {
+    let place = |canvas: u32, size: u32, alignment: u8| {
+      let (canvas, size, margin) = (i64::from(canvas), i64::from(size), i64::from(margin));
+      match alignment {
+        0 => margin,
+        1 => (canvas - size) / 2,
+        _ => canvas - size - margin,
+      }
+    };
+    let (column, row) = match self {
+      Position::TopLeft => (0, 0),
+      Position::Top => (1, 0),
+      Position::TopRight => (2, 0),
+      Position::Left => (0, 1),
+      Position::Center => (1, 1),
+      Position::Right => (2, 1),
+      Position::BottomLeft => (0, 2),
+      Position::Bottom => (1, 2),
+      Position::BottomRight => (2, 2),
+    };
+    (place(canvas.0, size.0, column), place(canvas.1, size.1, row))
+  }
+}
+
+/// Draws a line of text at the given position, with a shadow in the inverted color for contrast.
+pub fn draw_caption(image: &mut Image, text: &str, position: Position, scale: u32, color: Color) {
+  let scale = scale.max(1);
+  let size = (font::text_width(text, scale), font::GLYPH_HEIGHT * scale);
+  let (x, y) = position.origin((image.width, image.height), size, 2 * scale);
+  let shadow = Color {
+    r: 255 - color.r,
+    g: 255 - color.g,
+    b: 255 - color.b,
+  };
+  // text that starts outside the image is moved in, the font only clips at the right and bottom
+  let (x, y) = (x.max(0) as u32, y.max(0) as u32);
+  font::draw_text(image, text, x + scale, y + scale, scale, shadow);
+  font::draw_text(image, text, x, y, scale, color);
+}
+
+/// Blends an overlay image onto the image at the given position, using the alpha channel of the overlay.
+///
+/// Parts of the overlay outside the image are clipped. The image keeps its own alpha channel.
+pub fn composite(image: &mut Image, overlay: &Image, position: Position) {
+  let margin = image.width.min(image.height) / 50;
+  let (left, top) = position.origin((image.width, image.height), (overlay.width, overlay.height), margin);
+  for oy in 0..overlay.height {
+    let y = top + i64::from(oy);
+    if y < 0 || y >= i64::from(image.height) {
+      continue;
+    }
+    for ox in 0..overlay.width {
+      let x = left + i64::from(ox);
+      if x < 0 || x >= i64::from(image.width) {
+        continue;
+      }
+      let source = (oy * overlay.width + ox) as usize;
+      let alpha = overlay.alpha.as_ref().map_or(255, |alpha| u32::from(alpha[source]));
+      let target = (y as usize * image.width as usize + x as usize) * 3;
+      for c in 0..3 {
+        let (over, under) = (u32::from(overlay.buffer[source * 3 + c]), u32::from(image.buffer[target + c]));
+        image.buffer[target + c] = ((over * alpha + under * (255 - alpha) + 127) / 255) as u8;
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn filled(width: u32, height: u32, value: u8) -> Image {
+    Image {
+      buffer: vec![value; (width * height * 3) as usize],
+      alpha: None,
+      width,
+      height,
+    }
+  }
+
+  #[test]
+  fn test_position_origin() {
+    assert_eq!(Position::TopLeft.origin((100, 50), (10, 4), 2), (2, 2));
+    assert_eq!(Position::Center.origin((100, 50), (10, 4), 2), (45, 23));
+    assert_eq!(Position::BottomRight.origin((100, 50), (10, 4), 2), (88, 44));
+    assert_eq!(Position::Center.origin((10, 10), (20, 20), 0), (-5, -5));
+  }
+
+  #[test]
+  fn test_draw_caption_at_bottom() {
+    let mut image = filled(40, 20, 0);
+    draw_caption(&mut image, "HI", Position::Bottom, 1, Color { r: 255, g: 255, b: 255 });
+    let white_rows: Vec<usize> = (0..20).filter(|&y| image.buffer[y * 40 * 3..(y + 1) * 40 * 3].contains(&255)).collect();
+    assert_eq!(white_rows.first(), Some(&11), "7 rows of text end 2 pixels above the bottom");
+    assert_eq!(white_rows.last(), Some(&17));
+  }
+
+  #[test]
+  fn test_composite_blends_and_clips() {
+    let mut image = filled(4, 4, 0);
+    let overlay = Image {
+      alpha: Some(vec![255, 128, 0, 255]),
+      ..filled(2, 2, 200)
+    };
+    composite(&mut image, &overlay, Position::TopLeft);
+    assert_eq!(&image.buffer[..6], &[200, 200, 200, 100, 100, 100]);
+    assert_eq!(&image.buffer[4 * 3..4 * 3 + 6], &[0, 0, 0, 200, 200, 200]);
+
+    let mut image = filled(3, 3, 0);
+    composite(&mut image, &filled(5, 5, 50), Position::Center);
+    assert!(image.buffer.iter().all(|&v| v == 50));
+  }
+}
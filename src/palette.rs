@@ -1,6 +1,18 @@
 //! Color palette definitions and utilities.
 
+use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::BufReader;
+use std::path::Path;
+use std::str::FromStr;
+
+use image::error::{DecodingError, ImageFormatHint};
+use image::{ImageError, ImageFormat};
+
+use crate::distance::{DistanceMetric, MetricPalette};
 use crate::dither::QuantizationError;
+use crate::error::DitherError;
 
 /// Available color palettes for dithering.
 #[derive(clap::ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
@@ -12,9 +24,209 @@ pub enum ColorPalette {
   COLOR8,
   /// 16-color palette
   COLOR16,
+  /// Okabe-Ito colorblind-safe palette with white paper (9 colors)
+  OkabeIto,
+  /// Paul Tol's colorblind-safe "bright" scheme with black and white (9 colors)
+  TolBright,
+  /// Paul Tol's colorblind-safe "vibrant" scheme with black and white (9 colors)
+  TolVibrant,
+  /// IBM Design colorblind-safe palette with black and white (7 colors)
+  Ibm,
+  /// Standard ANSI terminal colors in their VGA shades (16 colors)
+  Ansi16,
+  /// xterm 256-color palette: the ANSI colors, a 6x6x6 color cube and 24 grays (256 colors)
+  Xterm256,
+  /// Original Game Boy green shades (4 colors)
+  Gameboy,
+  /// CGA palette 0 in low intensity: black, green, red and brown (4 colors)
+  Cga0,
+  /// CGA palette 0 in high intensity: black, light green, light red and yellow (4 colors)
+  Cga0High,
+  /// CGA palette 1 in low intensity: black, cyan, magenta and light gray (4 colors)
+  Cga1,
+  /// CGA palette 1 in high intensity: black, light cyan, light magenta and white (4 colors)
+  Cga1High,
+  /// EGA and VGA text-mode colors in the order of their color numbers (16 colors)
+  Ega,
+  /// NES master palette of the 2C02 picture processor (55 colors)
+  Nes,
+  /// Commodore 64 colors as measured by Pepto (16 colors)
+  C64,
+  /// ZX Spectrum colors in normal and bright, with a single black (15 colors)
+  ZxSpectrum,
+  /// PICO-8 fantasy console colors (16 colors)
+  Pico8,
+  /// Apple II high-resolution graphics colors: black, green, purple, white, orange and blue (6 colors)
+  Apple2,
+  /// Web-safe colors: a 6x6x6 color cube in steps of 0x33 (216 colors)
+  WebSafe,
+  /// Default palette of VGA mode 13h: the EGA colors, 16 grays and 9 rings of 24 hues (256 colors)
+  Vga256,
+  /// Evenly spaced grays from black to white, as many as --levels (4 colors by default)
+  Gray,
+  /// Default 16-color palette of the classic Macintosh (16 colors)
+  Mac16,
+  /// Standard Windows colors (16 colors)
+  Win16,
+  /// Windows system palette: the standard colors and the 4 reserved system colors (20 colors)
+  Win20,
+  /// Amstrad CPC hardware colors, 3 levels per channel (27 colors)
+  AmstradCpc,
+  /// TMS9918 video chip colors of the MSX and ColecoVision (15 colors)
+  Tms9918,
+}
+
+serde_value_enum!(ColorPalette);
+
+impl ColorPalette {
+  /// Returns the colors of the palette.
+  #[must_use]
+  pub fn colors(&self) -> &'static [Color] {
+    match self {
+      ColorPalette::Monochrome => &PALETTE_MONOCHROME,
+      ColorPalette::COLOR8 => &PALETTE_8C,
+      ColorPalette::COLOR16 => &PALETTE_16C,
+      ColorPalette::OkabeIto => &PALETTE_OKABE_ITO,
+      ColorPalette::TolBright => &PALETTE_TOL_BRIGHT,
+      ColorPalette::TolVibrant => &PALETTE_TOL_VIBRANT,
+      ColorPalette::Ibm => &PALETTE_IBM,
+      ColorPalette::Ansi16 => &PALETTE_ANSI16,
+      ColorPalette::Xterm256 => &PALETTE_XTERM256,
+      ColorPalette::Gameboy => &PALETTE_GAMEBOY,
+      ColorPalette::Cga0 => &PALETTE_CGA0,
+      ColorPalette::Cga0High => &PALETTE_CGA0_HIGH,
+      ColorPalette::Cga1 => &PALETTE_CGA1,
+      ColorPalette::Cga1High => &PALETTE_CGA1_HIGH,
+      ColorPalette::Ega => &PALETTE_EGA,
+      ColorPalette::Nes => &PALETTE_NES,
+      ColorPalette::C64 => &PALETTE_C64,
+      ColorPalette::ZxSpectrum => &PALETTE_ZX_SPECTRUM,
+      ColorPalette::Pico8 => &PALETTE_PICO8,
+      ColorPalette::Apple2 => &PALETTE_APPLE2,
+      ColorPalette::WebSafe => &PALETTE_WEB_SAFE,
+      ColorPalette::Vga256 => &PALETTE_VGA256,
+      ColorPalette::Gray => &PALETTE_GRAY4,
+      ColorPalette::Mac16 => &PALETTE_MAC16,
+      ColorPalette::Win16 => &PALETTE_WIN16,
+      ColorPalette::Win20 => &PALETTE_WIN20,
+      ColorPalette::AmstradCpc => &PALETTE_AMSTRAD_CPC,
+      ColorPalette::Tms9918 => &PALETTE_TMS9918,
+    }
+  }
+
+  /// Reads a palette from a text file of hex colors, one `#RRGGBB` (or `#RGB`) per line.
+  ///
+  /// The `#` is optional, blank lines and lines starting with `;` are skipped, so plain `.hex` files of
+  /// palette sites work as well. The colors can be passed to [`crate::dither::dither_with`].
+  pub fn from_hex_file(path: &Path) -> Result<Vec<Color>, DitherError> {
+    let content = std::fs::read_to_string(path).map_err(|source| DitherError::Io {
+      path: path.to_path_buf(),
+      source,
+    })?;
+    parse_hex_list(&content).map_err(|message| DitherError::PaletteFile {
+      path: path.to_path_buf(),
+      message,
+    })
+  }
+}
+
+/// Returns `levels` evenly spaced grays from black to white, at least 2 and at most 256.
+#[must_use]
+pub fn gray_levels(levels: usize) -> Vec<Color> {
+  let levels = levels.clamp(2, 256);
+  (0..levels)
+    .map(|i| {
+      let value = ((i * 255 + (levels - 1) / 2) / (levels - 1)) as u8;
+      Color { r: value, g: value, b: value }
+    })
+    .collect()
+}
+
+/// Parses a list of hex colors, one per line, see [`ColorPalette::from_hex_file`].
+pub fn parse_hex_list(content: &str) -> Result<Vec<Color>, String> {
+  let colors = content
+    .lines()
+    .enumerate()
+    .map(|(number, line)| (number, line.trim()))
+    .filter(|(_, line)| !line.is_empty() && !line.starts_with(';'))
+    .map(|(number, line)| line.parse().map_err(|err| format!("line {}: {err}", number + 1)))
+    .collect::<Result<Vec<Color>, String>>()?;
+  if colors.is_empty() {
+    return Err("no colors".to_string());
+  }
+  Ok(colors)
+}
+
+/// Colors of a built-in palette.
+impl AsRef<[Color]> for ColorPalette {
+  fn as_ref(&self) -> &[Color] {
+    self.colors()
+  }
+}
+
+/// An arbitrary palette, for colors that are not one of the built-in palettes.
+///
+/// It always holds at least one color, so it can be passed to [`crate::dither::dither`] like a
+/// [`ColorPalette`]:
+///
+/// ```
+/// use dithers::dither::{dither, DitherMethod};
+/// use dithers::distance::DistanceMetric;
+/// use dithers::palette::{Color, Palette};
+///
+/// let palette = Palette::new(vec![Color { r: 0x20, g: 0x10, b: 0x30 }, Color { r: 0xf0, g: 0xe0, b: 0xb0 }])?;
+/// let mut buffer = vec![40, 40, 40, 200, 200, 200];
+/// dither(&mut buffer, DitherMethod::None, &palette, DistanceMetric::Rgb, 2, 1);
+/// assert_eq!(buffer, [0x20, 0x10, 0x30, 0xf0, 0xe0, 0xb0]);
+/// # Ok::<(), dithers::error::DitherError>(())
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Palette {
+  colors: Vec<Color>,
+}
+
+impl Palette {
+  /// Creates a palette of the given colors, in their order.
+  ///
+  /// Returns [`DitherError::EmptyPalette`] if there are none.
+  pub fn new(colors: Vec<Color>) -> Result<Self, DitherError> {
+    if colors.is_empty() {
+      return Err(DitherError::EmptyPalette);
+    }
+    Ok(Palette { colors })
+  }
+
+  /// Returns the colors of the palette.
+  #[must_use]
+  pub fn colors(&self) -> &[Color] {
+    &self.colors
+  }
+}
+
+impl From<ColorPalette> for Palette {
+  fn from(palette: ColorPalette) -> Self {
+    Palette {
+      colors: palette.colors().to_vec(),
+    }
+  }
+}
+
+impl TryFrom<Vec<Color>> for Palette {
+  type Error = DitherError;
+
+  fn try_from(colors: Vec<Color>) -> Result<Self, Self::Error> {
+    Palette::new(colors)
+  }
+}
+
+impl AsRef<[Color]> for Palette {
+  fn as_ref(&self) -> &[Color] {
+    &self.colors
+  }
 }
 
 /// Represents an RGB color.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub struct Color {
   /// Red component (0-255)
   pub r: u8,
@@ -40,33 +252,438 @@ impl From<&[u8]> for Color {
   }
 }
 
-/// Maps a color to the closest color in the given palette.
+/// Parses a hex color such as `#f5e9d0` or the shorthand `#222`, the leading `#` is optional.
+impl FromStr for Color {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let hex = s.trim().trim_start_matches('#');
+    let invalid = || format!("invalid hex color {s:?}, expected #RGB or #RRGGBB");
+    if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+      return Err(invalid());
+    }
+    let value = u32::from_str_radix(hex, 16).map_err(|_| invalid())?;
+    match hex.len() {
+      // each shorthand digit is doubled, #abc is #aabbcc
+      3 => Ok(Color {
+        r: ((value >> 8) & 0xF) as u8 * 0x11,
+        g: ((value >> 4) & 0xF) as u8 * 0x11,
+        b: (value & 0xF) as u8 * 0x11,
+      }),
+      6 => Ok(Color::from(value)),
+      _ => Err(invalid()),
+    }
+  }
+}
+
+/// Formats the color as `#rrggbb`.
+impl fmt::Display for Color {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "#{:02x}{:02x}{:02x}", self.r, self.g, self.b)
+  }
+}
+
+impl serde::Serialize for Color {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> serde::Deserialize<'de> for Color {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let hex = <String as serde::Deserialize>::deserialize(deserializer)?;
+    hex.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// A palette of two arbitrary colors, dark ink on light paper or any other pair.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Duotone {
+  /// The first color, usually the darker one
+  pub ink: Color,
+  /// The second color, usually the lighter one
+  pub paper: Color,
+}
+
+impl Duotone {
+  /// Returns the two colors as a palette.
+  #[must_use]
+  pub fn colors(&self) -> [Color; 2] {
+    [self.ink, self.paper]
+  }
+}
+
+/// Parses two hex colors separated by a comma, e.g. `#112233,#eeddcc`.
+impl FromStr for Duotone {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s.split(',').collect::<Vec<_>>()[..] {
+      [ink, paper] => Ok(Duotone {
+        ink: ink.parse()?,
+        paper: paper.parse()?,
+      }),
+      _ => Err(format!("invalid duotone {s:?}, expected two colors like \"#112233,#eeddcc\"")),
+    }
+  }
+}
+
+/// Formats the colors as `#rrggbb,#rrggbb`.
+impl fmt::Display for Duotone {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{},{}", self.ink, self.paper)
+  }
+}
+
+impl serde::Serialize for Duotone {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> serde::Deserialize<'de> for Duotone {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let colors = <String as serde::Deserialize>::deserialize(deserializer)?;
+    colors.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// Colors blending from one anchor color to the next, e.g. black over orange to white.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Gradient {
+  anchors: Vec<Color>,
+}
+
+impl Gradient {
+  /// Creates a gradient through the colors in their order, returns `None` for fewer than two.
+  #[must_use]
+  pub fn new(anchors: Vec<Color>) -> Option<Self> {
+    (anchors.len() >= 2).then_some(Gradient { anchors })
+  }
+
+  /// Returns the anchor colors.
+  #[must_use]
+  pub fn anchors(&self) -> &[Color] {
+    &self.anchors
+  }
+
+  /// Returns `count` colors evenly spaced along the gradient, at least 2, from the first anchor to the last.
+  ///
+  /// Every pair of neighboring anchors spans the same share of the colors.
+  #[must_use]
+  pub fn colors(&self, count: usize) -> Vec<Color> {
+    let count = count.max(2);
+    let segments = self.anchors.len() - 1;
+    (0..count)
+      .map(|i| {
+        let position = i as f32 * segments as f32 / (count - 1) as f32;
+        let segment = (position as usize).min(segments - 1);
+        let (from, to, t) = (self.anchors[segment], self.anchors[segment + 1], position - segment as f32);
+        let mix = |a: u8, b: u8| (f32::from(a) + (f32::from(b) - f32::from(a)) * t).round() as u8;
+        Color {
+          r: mix(from.r, to.r),
+          g: mix(from.g, to.g),
+          b: mix(from.b, to.b),
+        }
+      })
+      .collect()
+  }
+}
+
+/// Parses two or more hex colors separated by commas, e.g. `#000,#f80,#fff`.
+impl FromStr for Gradient {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let anchors = s.split(',').map(str::parse).collect::<Result<Vec<Color>, String>>()?;
+    Gradient::new(anchors).ok_or_else(|| format!("invalid gradient {s:?}, expected two or more colors like \"#000,#f80,#fff\""))
+  }
+}
+
+/// Formats the anchors as `#rrggbb,#rrggbb,...`.
+impl fmt::Display for Gradient {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let anchors: Vec<String> = self.anchors.iter().map(Color::to_string).collect();
+    write!(f, "{}", anchors.join(","))
+  }
+}
+
+impl serde::Serialize for Gradient {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> serde::Deserialize<'de> for Gradient {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let anchors = <String as serde::Deserialize>::deserialize(deserializer)?;
+    anchors.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// Maps colors to the closest color an output can represent.
+pub trait Quantizer {
+  /// Returns the representable color closest to `color`.
+  fn quantize(&self, color: Color) -> Color;
+
+  /// Returns the colors of the palette, if the quantizer chooses among a list of colors.
+  ///
+  /// Methods that mix palette colors, such as [`DitherMethod::Yliluoma1`](crate::dither::DitherMethod::Yliluoma1),
+  /// need the list. Quantizers to a whole color space, like a bit-depth target, return `None`.
+  fn palette(&self) -> Option<&[Color]> {
+    None
+  }
+}
+
+/// A list of colors quantizes to its closest entry, see [`map_to_palette`].
+impl Quantizer for [Color] {
+  fn quantize(&self, color: Color) -> Color {
+    *map_to_palette(color, self).0
+  }
+
+  fn palette(&self) -> Option<&[Color]> {
+    Some(self)
+  }
+}
+
+impl<const N: usize> Quantizer for [Color; N] {
+  fn quantize(&self, color: Color) -> Color {
+    self.as_slice().quantize(color)
+  }
+
+  fn palette(&self) -> Option<&[Color]> {
+    Some(self)
+  }
+}
+
+impl Quantizer for Vec<Color> {
+  fn quantize(&self, color: Color) -> Color {
+    self.as_slice().quantize(color)
+  }
+
+  fn palette(&self) -> Option<&[Color]> {
+    Some(self)
+  }
+}
+
+impl Quantizer for Palette {
+  fn quantize(&self, color: Color) -> Color {
+    self.colors.quantize(color)
+  }
+
+  fn palette(&self) -> Option<&[Color]> {
+    Some(&self.colors)
+  }
+}
+
+/// A single quantization decision, as passed to the hook of a [`Hooked`] quantizer.
+pub struct Quantization {
+  /// Color handed to the quantizer, including diffused error or ordered dithering offsets
+  pub original: Color,
+  /// Color chosen by the wrapped quantizer
+  pub chosen: Color,
+  /// Error made by replacing the original with the chosen color
+  pub error: QuantizationError,
+}
+
+/// Wraps a quantizer and calls a hook for every pixel it quantizes.
+///
+/// The hook can collect statistics, or override the choice by returning `Some(color)`.
+/// Error diffusion then spreads the error of the override instead.
+///
+/// ```
+/// use dithers::dither::{dither_with, DitherMethod, DitherOptions};
+/// use dithers::palette::{Hooked, PALETTE_MONOCHROME};
+///
+/// let mut buffer = vec![100; 3 * 4];
+/// let mut white = 0;
+/// let hooked = Hooked::new(&PALETTE_MONOCHROME, |q| {
+///   white += usize::from(q.chosen.r == 255);
+///   None
+/// });
+/// dither_with(&mut buffer, DitherMethod::FloydSteinberg, &hooked, 2, 2, &DitherOptions::default()).unwrap();
+/// assert!(white > 0);
+/// ```
+pub struct Hooked<'a, Q: ?Sized, F> {
+  quantizer: &'a Q,
+  // quantizers are shared, the hook is not, so it is borrowed mutably per pixel
+  hook: RefCell<F>,
+}
+
+impl<'a, Q: Quantizer + ?Sized, F: FnMut(&Quantization) -> Option<Color>> Hooked<'a, Q, F> {
+  /// Wraps `quantizer`, calling `hook` for every quantized pixel.
+  pub fn new(quantizer: &'a Q, hook: F) -> Self {
+    Hooked {
+      quantizer,
+      hook: RefCell::new(hook),
+    }
+  }
+}
+
+impl<Q: Quantizer + ?Sized, F: FnMut(&Quantization) -> Option<Color>> Quantizer for Hooked<'_, Q, F> {
+  fn quantize(&self, color: Color) -> Color {
+    let chosen = self.quantizer.quantize(color);
+    let quantization = Quantization {
+      original: color,
+      chosen,
+      error: QuantizationError::between(color, chosen),
+    };
+    (self.hook.borrow_mut())(&quantization).unwrap_or(chosen)
+  }
+
+  fn palette(&self) -> Option<&[Color]> {
+    self.quantizer.palette()
+  }
+}
+
+/// Rules choosing between palette colors that are equally close to a color.
+///
+/// [`TieBreak::First`] depends on the order of the palette, the other rules only look at the colors,
+/// so their result stays the same when the palette is reordered.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
+pub enum TieBreak {
+  /// The color listed first in the palette wins
+  #[default]
+  First,
+  /// The darker color wins
+  Darker,
+  /// The lighter color wins
+  Lighter,
+}
+
+serde_value_enum!(TieBreak);
+
+impl TieBreak {
+  /// Returns whether `candidate` wins a tie against `current`, which comes earlier in the palette.
+  ///
+  /// Colors are compared by their Rec. 601 luma, then by their red, green and blue components.
+  /// [`TieBreak::First`] always keeps `current`.
+  #[must_use]
+  pub fn prefers(self, candidate: &Color, current: &Color) -> bool {
+    let key = |c: &Color| (299 * u32::from(c.r) + 587 * u32::from(c.g) + 114 * u32::from(c.b), c.r, c.g, c.b);
+    match self {
+      TieBreak::First => false,
+      TieBreak::Darker => key(candidate) < key(current),
+      TieBreak::Lighter => key(candidate) > key(current),
+    }
+  }
+}
+
+/// A palette searched with a chosen distance metric and tie rule, see [`map_to_palette_with`].
 ///
-/// Returns the closest palette color and the quantization error.
+/// With a perceptual metric every search converts the palette again, dithering whole images is faster
+/// with a [`MetricPalette`] built once from these fields.
+#[derive(Clone, Copy, Debug)]
+pub struct TieBroken<'a> {
+  /// Colors of the palette
+  pub colors: &'a [Color],
+  /// Distance the closest color is chosen by
+  pub metric: DistanceMetric,
+  /// Rule applied to equally close colors
+  pub tie_break: TieBreak,
+}
+
+impl Quantizer for TieBroken<'_> {
+  fn quantize(&self, color: Color) -> Color {
+    *map_to_palette_with(color, self.colors, self.metric, self.tie_break).0
+  }
+
+  fn palette(&self) -> Option<&[Color]> {
+    Some(self.colors)
+  }
+}
+
+/// Maps a color to the closest color in the given palette by the RGB distance.
+///
+/// Returns the closest palette color and the quantization error. Of equally close colors the one
+/// listed first wins, see [`TieBreak::First`].
 pub fn map_to_palette(orig_color: Color, palette: &[Color]) -> (&Color, QuantizationError) {
-  // simple stupid linear search
-  // this can be optimized with a better algorithm
-  let mut min_distance = f32::INFINITY;
+  map_to_palette_with(orig_color, palette, DistanceMetric::Rgb, TieBreak::default())
+}
+
+/// Maps a color to the closest color in the given palette by the given distance, breaking ties with the given rule.
+///
+/// The perceptual metrics convert every palette color on every call, a [`MetricPalette`] converts
+/// them once for many colors.
+pub fn map_to_palette_with(orig_color: Color, palette: &[Color], metric: DistanceMetric, tie_break: TieBreak) -> (&Color, QuantizationError) {
+  if metric != DistanceMetric::Rgb {
+    let color = MetricPalette::new(palette, metric, tie_break).nearest(orig_color);
+    return (color, QuantizationError::between(orig_color, *color));
+  }
+  // linear search, large palettes are faster searched with a `KdTree`
+  let mut min_distance = u32::MAX;
   let mut color = &palette[0];
   for c in palette {
-    let distance =
-      // sqrt not needed since we only compare distances, not actual values
-      //((orig_color.r as f32 - c.r as f32).powi(2) + (orig_color.g as f32 - c.g as f32).powi(2) + (orig_color.b as f32 - c.b as f32).powi(2)).sqrt();
-      (orig_color.r as f32 - c.r as f32).powi(2) + (orig_color.g as f32 - c.g as f32).powi(2) + (orig_color.b as f32 - c.b as f32).powi(2);
-    if distance < min_distance {
+    // sqrt not needed since we only compare distances, not actual values,
+    // integers keep ties exact
+    let (dr, dg, db) = (
+      u32::from(orig_color.r.abs_diff(c.r)),
+      u32::from(orig_color.g.abs_diff(c.g)),
+      u32::from(orig_color.b.abs_diff(c.b)),
+    );
+    let distance = dr * dr + dg * dg + db * db;
+    if distance < min_distance || (distance == min_distance && tie_break.prefers(c, color)) {
       color = c;
       min_distance = distance;
     }
   }
-  let qe = QuantizationError {
-    r: orig_color.r as f32 - color.r as f32,
-    g: orig_color.g as f32 - color.g as f32,
-    b: orig_color.b as f32 - color.b as f32,
-  };
+  let qe = QuantizationError::between(orig_color, *color);
 
   (color, qe)
 }
 
+/// Reads the palette embedded in an indexed PNG or GIF file, in the order of its entries.
+///
+/// Fully transparent entries are left out, they are never drawn. GIF files use their global color
+/// table, or the local table of the first frame if there is none; trailing entries the first frame does
+/// not use are left out as well, they usually pad the table to a power of two.
+/// Returns `None` for true color images and other formats.
+pub fn from_image(path: &Path) -> Result<Option<Vec<Color>>, DitherError> {
+  let format = match ImageFormat::from_path(path) {
+    Ok(format @ (ImageFormat::Png | ImageFormat::Gif)) => format,
+    _ => return Ok(None),
+  };
+  let decoding_error = |err: Box<dyn std::error::Error + Send + Sync>| DitherError::Image {
+    path: path.to_path_buf(),
+    source: ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(format), err)),
+  };
+  let file = BufReader::new(File::open(path).map_err(|source| DitherError::Io {
+    path: path.to_path_buf(),
+    source,
+  })?);
+  // RGB triples and the indices of transparent entries
+  let (entries, transparent): (Vec<u8>, Vec<usize>) = if format == ImageFormat::Png {
+    // only the chunks before the image data are read
+    let reader = png::Decoder::new(file).read_info().map_err(|err| decoding_error(err.into()))?;
+    let info = reader.info();
+    if info.color_type != png::ColorType::Indexed {
+      return Ok(None);
+    }
+    let alpha = info.trns.as_deref().unwrap_or_default();
+    let transparent = (0..alpha.len()).filter(|&i| alpha[i] == 0).collect();
+    (info.palette.as_deref().unwrap_or_default().to_vec(), transparent)
+  } else {
+    let mut decoder = gif::DecodeOptions::new().read_info(file).map_err(|err| decoding_error(err.into()))?;
+    let global = decoder.global_palette().map(<[u8]>::to_vec);
+    let Some(frame) = decoder.read_next_frame().map_err(|err| decoding_error(err.into()))? else {
+      return Ok(global.map(|entries| entries.chunks_exact(3).map(Color::from).collect()));
+    };
+    let Some(mut entries) = global.or_else(|| frame.palette.clone()) else {
+      return Ok(None);
+    };
+    let used = frame.buffer.iter().copied().max().map_or(0, |index| usize::from(index) + 1);
+    entries.truncate(used.max(1) * 3);
+    (entries, frame.transparent.map(usize::from).into_iter().collect())
+  };
+  let colors: Vec<Color> = entries
+    .chunks_exact(3)
+    .enumerate()
+    .filter(|(i, _)| !transparent.contains(i))
+    .map(|(_, entry)| Color::from(entry))
+    .collect();
+  Ok(Some(colors).filter(|colors| !colors.is_empty()))
+}
+
 /// 16-color palette with a diverse range of colors.
 pub const PALETTE_16C: [Color; 16] = [
   //Color::from(0x000000), // does not work since its a const
@@ -100,6 +717,477 @@ pub const PALETTE_8C: [Color; 8] = [
   Color { r: 0xff, g: 0xff, b: 0xff },
 ];
 
+/// Okabe-Ito palette, distinguishable with all common color vision deficiencies, plus white paper.
+pub const PALETTE_OKABE_ITO: [Color; 9] = [
+  Color { r: 0x00, g: 0x00, b: 0x00 },
+  Color { r: 0xe6, g: 0x9f, b: 0x00 },
+  Color { r: 0x56, g: 0xb4, b: 0xe9 },
+  Color { r: 0x00, g: 0x9e, b: 0x73 },
+  Color { r: 0xf0, g: 0xe4, b: 0x42 },
+  Color { r: 0x00, g: 0x72, b: 0xb2 },
+  Color { r: 0xd5, g: 0x5e, b: 0x00 },
+  Color { r: 0xcc, g: 0x79, b: 0xa7 },
+  Color { r: 0xff, g: 0xff, b: 0xff },
+];
+
+/// Paul Tol's "bright" qualitative scheme, plus black and white.
+pub const PALETTE_TOL_BRIGHT: [Color; 9] = [
+  Color { r: 0x00, g: 0x00, b: 0x00 },
+  Color { r: 0x44, g: 0x77, b: 0xaa },
+  Color { r: 0xee, g: 0x66, b: 0x77 },
+  Color { r: 0x22, g: 0x88, b: 0x33 },
+  Color { r: 0xcc, g: 0xbb, b: 0x44 },
+  Color { r: 0x66, g: 0xcc, b: 0xee },
+  Color { r: 0xaa, g: 0x33, b: 0x77 },
+  Color { r: 0xbb, g: 0xbb, b: 0xbb },
+  Color { r: 0xff, g: 0xff, b: 0xff },
+];
+
+/// Paul Tol's "vibrant" qualitative scheme, plus black and white.
+pub const PALETTE_TOL_VIBRANT: [Color; 9] = [
+  Color { r: 0x00, g: 0x00, b: 0x00 },
+  Color { r: 0x00, g: 0x77, b: 0xbb },
+  Color { r: 0x33, g: 0xbb, b: 0xee },
+  Color { r: 0x00, g: 0x99, b: 0x88 },
+  Color { r: 0xee, g: 0x77, b: 0x33 },
+  Color { r: 0xcc, g: 0x33, b: 0x11 },
+  Color { r: 0xee, g: 0x33, b: 0x77 },
+  Color { r: 0xbb, g: 0xbb, b: 0xbb },
+  Color { r: 0xff, g: 0xff, b: 0xff },
+];
+
+/// IBM Design Library colorblind-safe palette, plus black and white.
+pub const PALETTE_IBM: [Color; 7] = [
+  Color { r: 0x00, g: 0x00, b: 0x00 },
+  Color { r: 0x64, g: 0x8f, b: 0xff },
+  Color { r: 0x78, g: 0x5e, b: 0xf0 },
+  Color { r: 0xdc, g: 0x26, b: 0x7f },
+  Color { r: 0xfe, g: 0x61, b: 0x00 },
+  Color { r: 0xff, g: 0xb0, b: 0x00 },
+  Color { r: 0xff, g: 0xff, b: 0xff },
+];
+
+/// ANSI terminal colors in SGR order (black, red, green, yellow, blue, magenta, cyan, white, then the
+/// bright variants), with the VGA shades ANSI art is drawn for. Terminal themes may show them differently.
+pub const PALETTE_ANSI16: [Color; 16] = [
+  Color { r: 0x00, g: 0x00, b: 0x00 },
+  Color { r: 0xaa, g: 0x00, b: 0x00 },
+  Color { r: 0x00, g: 0xaa, b: 0x00 },
+  Color { r: 0xaa, g: 0x55, b: 0x00 },
+  Color { r: 0x00, g: 0x00, b: 0xaa },
+  Color { r: 0xaa, g: 0x00, b: 0xaa },
+  Color { r: 0x00, g: 0xaa, b: 0xaa },
+  Color { r: 0xaa, g: 0xaa, b: 0xaa },
+  Color { r: 0x55, g: 0x55, b: 0x55 },
+  Color { r: 0xff, g: 0x55, b: 0x55 },
+  Color { r: 0x55, g: 0xff, b: 0x55 },
+  Color { r: 0xff, g: 0xff, b: 0x55 },
+  Color { r: 0x55, g: 0x55, b: 0xff },
+  Color { r: 0xff, g: 0x55, b: 0xff },
+  Color { r: 0x55, g: 0xff, b: 0xff },
+  Color { r: 0xff, g: 0xff, b: 0xff },
+];
+
+/// xterm 256-color palette, in the order of the `38;5;n` color indices.
+///
+/// The first 16 entries are [`PALETTE_ANSI16`], followed by xterm's default 6x6x6 color cube and ramp of
+/// 24 grays. xterm itself shows the first 16 in other shades, and terminals can redefine every entry,
+/// so what the terminal shows only matches the dithered image with the default colors.
+pub const PALETTE_XTERM256: [Color; 256] = xterm256();
+
+const fn xterm256() -> [Color; 256] {
+  const LEVELS: [u8; 6] = [0x00, 0x5f, 0x87, 0xaf, 0xd7, 0xff];
+  let mut colors = [Color { r: 0, g: 0, b: 0 }; 256];
+  let mut i = 0;
+  while i < 16 {
+    colors[i] = PALETTE_ANSI16[i];
+    i += 1;
+  }
+  while i < 232 {
+    let cube = i - 16;
+    colors[i] = Color {
+      r: LEVELS[cube / 36],
+      g: LEVELS[cube / 6 % 6],
+      b: LEVELS[cube % 6],
+    };
+    i += 1;
+  }
+  while i < 256 {
+    let gray = 8 + 10 * (i - 232) as u8;
+    colors[i] = Color { r: gray, g: gray, b: gray };
+    i += 1;
+  }
+  colors
+}
+
+/// The four green shades of the original Game Boy screen, from darkest to lightest.
+pub const PALETTE_GAMEBOY: [Color; 4] = [
+  Color { r: 0x0f, g: 0x38, b: 0x0f },
+  Color { r: 0x30, g: 0x62, b: 0x30 },
+  Color { r: 0x8b, g: 0xac, b: 0x0f },
+  Color { r: 0x9b, g: 0xbc, b: 0x0f },
+];
+
+/// CGA 320x200 graphics palette 0, in the order of the pixel values, with the default black background.
+///
+/// The CGA colors are those of [`PALETTE_ANSI16`], brown included.
+pub const PALETTE_CGA0: [Color; 4] = [PALETTE_ANSI16[0], PALETTE_ANSI16[2], PALETTE_ANSI16[1], PALETTE_ANSI16[3]];
+
+/// CGA graphics palette 0 in high intensity.
+pub const PALETTE_CGA0_HIGH: [Color; 4] = [PALETTE_ANSI16[0], PALETTE_ANSI16[10], PALETTE_ANSI16[9], PALETTE_ANSI16[11]];
+
+/// CGA graphics palette 1, in the order of the pixel values, with the default black background.
+pub const PALETTE_CGA1: [Color; 4] = [PALETTE_ANSI16[0], PALETTE_ANSI16[6], PALETTE_ANSI16[5], PALETTE_ANSI16[7]];
+
+/// CGA graphics palette 1 in high intensity.
+pub const PALETTE_CGA1_HIGH: [Color; 4] = [PALETTE_ANSI16[0], PALETTE_ANSI16[14], PALETTE_ANSI16[13], PALETTE_ANSI16[15]];
+
+/// Standard EGA palette, the 16 colors of EGA and VGA text mode.
+///
+/// These are the colors of [`PALETTE_ANSI16`], in the order of the IRGB color numbers of the PC
+/// (0 black, 1 blue, 2 green, ... 6 brown, 7 light gray, 8 dark gray, ... 15 white), which indexed
+/// output keeps.
+pub const PALETTE_EGA: [Color; 16] = {
+  const ORDER: [usize; 16] = [0, 4, 2, 6, 1, 5, 3, 7, 8, 12, 10, 14, 9, 13, 11, 15];
+  let mut colors = [Color { r: 0, g: 0, b: 0 }; 16];
+  let mut i = 0;
+  while i < 16 {
+    colors[i] = PALETTE_ANSI16[ORDER[i]];
+    i += 1;
+  }
+  colors
+};
+
+/// NES master palette, the colors the 2C02 picture processor can show.
+///
+/// The 64 color numbers repeat black ten times ($0D-$0F, $1D-$1F, $2E-$2F, $3E-$3F), which are a
+/// single entry here, otherwise the colors are in the order of their numbers. The PPU generates
+/// composite video instead of RGB, so these are one common rendition of it.
+pub const PALETTE_NES: [Color; 55] = [
+  Color { r: 0x7c, g: 0x7c, b: 0x7c },
+  Color { r: 0x00, g: 0x00, b: 0xfc },
+  Color { r: 0x00, g: 0x00, b: 0xbc },
+  Color { r: 0x44, g: 0x28, b: 0xbc },
+  Color { r: 0x94, g: 0x00, b: 0x84 },
+  Color { r: 0xa8, g: 0x00, b: 0x20 },
+  Color { r: 0xa8, g: 0x10, b: 0x00 },
+  Color { r: 0x88, g: 0x14, b: 0x00 },
+  Color { r: 0x50, g: 0x30, b: 0x00 },
+  Color { r: 0x00, g: 0x78, b: 0x00 },
+  Color { r: 0x00, g: 0x68, b: 0x00 },
+  Color { r: 0x00, g: 0x58, b: 0x00 },
+  Color { r: 0x00, g: 0x40, b: 0x58 },
+  Color { r: 0x00, g: 0x00, b: 0x00 },
+  Color { r: 0xbc, g: 0xbc, b: 0xbc },
+  Color { r: 0x00, g: 0x78, b: 0xf8 },
+  Color { r: 0x00, g: 0x58, b: 0xf8 },
+  Color { r: 0x68, g: 0x44, b: 0xfc },
+  Color { r: 0xd8, g: 0x00, b: 0xcc },
+  Color { r: 0xe4, g: 0x00, b: 0x58 },
+  Color { r: 0xf8, g: 0x38, b: 0x00 },
+  Color { r: 0xe4, g: 0x5c, b: 0x10 },
+  Color { r: 0xac, g: 0x7c, b: 0x00 },
+  Color { r: 0x00, g: 0xb8, b: 0x00 },
+  Color { r: 0x00, g: 0xa8, b: 0x00 },
+  Color { r: 0x00, g: 0xa8, b: 0x44 },
+  Color { r: 0x00, g: 0x88, b: 0x88 },
+  Color { r: 0xf8, g: 0xf8, b: 0xf8 },
+  Color { r: 0x3c, g: 0xbc, b: 0xfc },
+  Color { r: 0x68, g: 0x88, b: 0xfc },
+  Color { r: 0x98, g: 0x78, b: 0xf8 },
+  Color { r: 0xf8, g: 0x78, b: 0xf8 },
+  Color { r: 0xf8, g: 0x58, b: 0x98 },
+  Color { r: 0xf8, g: 0x78, b: 0x58 },
+  Color { r: 0xfc, g: 0xa0, b: 0x44 },
+  Color { r: 0xf8, g: 0xb8, b: 0x00 },
+  Color { r: 0xb8, g: 0xf8, b: 0x18 },
+  Color { r: 0x58, g: 0xd8, b: 0x54 },
+  Color { r: 0x58, g: 0xf8, b: 0x98 },
+  Color { r: 0x00, g: 0xe8, b: 0xd8 },
+  Color { r: 0x78, g: 0x78, b: 0x78 },
+  Color { r: 0xfc, g: 0xfc, b: 0xfc },
+  Color { r: 0xa4, g: 0xe4, b: 0xfc },
+  Color { r: 0xb8, g: 0xb8, b: 0xf8 },
+  Color { r: 0xd8, g: 0xb8, b: 0xf8 },
+  Color { r: 0xf8, g: 0xb8, b: 0xf8 },
+  Color { r: 0xf8, g: 0xa4, b: 0xc0 },
+  Color { r: 0xf0, g: 0xd0, b: 0xb0 },
+  Color { r: 0xfc, g: 0xe0, b: 0xa8 },
+  Color { r: 0xf8, g: 0xd8, b: 0x78 },
+  Color { r: 0xd8, g: 0xf8, b: 0x78 },
+  Color { r: 0xb8, g: 0xf8, b: 0xb8 },
+  Color { r: 0xb8, g: 0xf8, b: 0xd8 },
+  Color { r: 0x00, g: 0xfc, b: 0xfc },
+  Color { r: 0xf8, g: 0xd8, b: 0xf8 },
+];
+
+/// Commodore 64 palette by Philip "Pepto" Timmermann, measured from a PAL VIC-II, in the order of
+/// the color numbers (0 black, 1 white, 2 red, ... 15 light gray).
+pub const PALETTE_C64: [Color; 16] = [
+  Color { r: 0x00, g: 0x00, b: 0x00 },
+  Color { r: 0xff, g: 0xff, b: 0xff },
+  Color { r: 0x68, g: 0x37, b: 0x2b },
+  Color { r: 0x70, g: 0xa4, b: 0xb2 },
+  Color { r: 0x6f, g: 0x3d, b: 0x86 },
+  Color { r: 0x58, g: 0x8d, b: 0x43 },
+  Color { r: 0x35, g: 0x28, b: 0x79 },
+  Color { r: 0xb8, g: 0xc7, b: 0x6f },
+  Color { r: 0x6f, g: 0x4f, b: 0x25 },
+  Color { r: 0x43, g: 0x39, b: 0x00 },
+  Color { r: 0x9a, g: 0x67, b: 0x59 },
+  Color { r: 0x44, g: 0x44, b: 0x44 },
+  Color { r: 0x6c, g: 0x6c, b: 0x6c },
+  Color { r: 0x9a, g: 0xd2, b: 0x84 },
+  Color { r: 0x6c, g: 0x5e, b: 0xb5 },
+  Color { r: 0x95, g: 0x95, b: 0x95 },
+];
+
+/// ZX Spectrum palette: the 8 colors in normal brightness, in the order of their numbers (0 black,
+/// 1 blue, 2 red, 3 magenta, 4 green, 5 cyan, 6 yellow, 7 white), followed by the bright variants of
+/// the 7 colors besides black, which is the same in both.
+pub const PALETTE_ZX_SPECTRUM: [Color; 15] = {
+  let mut colors = [Color { r: 0, g: 0, b: 0 }; 15];
+  let mut i = 1;
+  while i < 15 {
+    let (number, level) = if i < 8 { (i, 0xd7) } else { (i - 7, 0xff) };
+    colors[i] = Color {
+      r: if number & 2 != 0 { level } else { 0 },
+      g: if number & 4 != 0 { level } else { 0 },
+      b: if number & 1 != 0 { level } else { 0 },
+    };
+    i += 1;
+  }
+  colors
+};
+
+/// PICO-8 palette, in the order of the color numbers of the fantasy console.
+pub const PALETTE_PICO8: [Color; 16] = [
+  Color { r: 0x00, g: 0x00, b: 0x00 },
+  Color { r: 0x1d, g: 0x2b, b: 0x53 },
+  Color { r: 0x7e, g: 0x25, b: 0x53 },
+  Color { r: 0x00, g: 0x87, b: 0x51 },
+  Color { r: 0xab, g: 0x52, b: 0x36 },
+  Color { r: 0x5f, g: 0x57, b: 0x4f },
+  Color { r: 0xc2, g: 0xc3, b: 0xc7 },
+  Color { r: 0xff, g: 0xf1, b: 0xe8 },
+  Color { r: 0xff, g: 0x00, b: 0x4d },
+  Color { r: 0xff, g: 0xa3, b: 0x00 },
+  Color { r: 0xff, g: 0xec, b: 0x27 },
+  Color { r: 0x00, g: 0xe4, b: 0x36 },
+  Color { r: 0x29, g: 0xad, b: 0xff },
+  Color { r: 0x83, g: 0x76, b: 0x9c },
+  Color { r: 0xff, g: 0x77, b: 0xa8 },
+  Color { r: 0xff, g: 0xcc, b: 0xaa },
+];
+
+/// Apple II high-resolution palette, as shown on an NTSC color monitor.
+///
+/// The hi-res color numbers 0-7 hold black and white twice, here each is a single entry: black,
+/// green, purple, white, orange, blue. Which colors a real Apple II can place next to each other is
+/// limited by its bit layout, which is not modeled.
+pub const PALETTE_APPLE2: [Color; 6] = [
+  Color { r: 0x00, g: 0x00, b: 0x00 },
+  Color { r: 0x14, g: 0xf5, b: 0x3c },
+  Color { r: 0xff, g: 0x44, b: 0xfd },
+  Color { r: 0xff, g: 0xff, b: 0xff },
+  Color { r: 0xff, g: 0x6a, b: 0x3c },
+  Color { r: 0x14, g: 0xcf, b: 0xfd },
+];
+
+/// Web-safe palette, every combination of the channel values 0x00, 0x33, 0x66, 0x99, 0xcc and 0xff,
+/// with blue changing fastest.
+pub const PALETTE_WEB_SAFE: [Color; 216] = {
+  let mut colors = [Color { r: 0, g: 0, b: 0 }; 216];
+  let mut i = 0;
+  while i < 216 {
+    colors[i] = Color {
+      r: 0x33 * (i / 36) as u8,
+      g: 0x33 * (i / 6 % 6) as u8,
+      b: 0x33 * (i % 6) as u8,
+    };
+    i += 1;
+  }
+  colors
+};
+
+/// Default palette of VGA mode 13h, in the order of the DAC registers, so indexed output keeps the
+/// color numbers of DOS programs.
+///
+/// The first 16 entries are [`PALETTE_EGA`], followed by 16 grays, 9 rings of 24 hues from blue over
+/// red and green back to blue (high, medium and low intensity, each in high, medium and low saturation)
+/// and 8 blacks. The 6-bit DAC values are widened to 8 bits by repeating their top bits.
+pub const PALETTE_VGA256: [Color; 256] = vga256();
+
+const fn vga256() -> [Color; 256] {
+  const GRAYS: [u8; 16] = [0, 5, 8, 11, 14, 17, 20, 24, 28, 32, 36, 40, 45, 50, 56, 63];
+  // the channel levels of every ring, from the lowest to the highest
+  const RINGS: [[u8; 5]; 9] = [
+    [0, 16, 31, 47, 63],
+    [31, 39, 47, 55, 63],
+    [45, 49, 54, 58, 63],
+    [0, 7, 14, 21, 28],
+    [14, 17, 21, 24, 28],
+    [20, 22, 24, 26, 28],
+    [0, 4, 8, 12, 16],
+    [8, 10, 12, 14, 16],
+    [11, 12, 13, 15, 16],
+  ];
+  const fn widen(value: u8) -> u8 {
+    (value << 2) | (value >> 4)
+  }
+  let mut colors = [Color { r: 0, g: 0, b: 0 }; 256];
+  let mut i = 0;
+  while i < 16 {
+    colors[i] = PALETTE_EGA[i];
+    colors[16 + i] = Color {
+      r: widen(GRAYS[i]),
+      g: widen(GRAYS[i]),
+      b: widen(GRAYS[i]),
+    };
+    i += 1;
+  }
+  let mut ring = 0;
+  while ring < 9 {
+    let levels = RINGS[ring];
+    let mut hue = 0;
+    while hue < 24 {
+      // each sixth of the ring moves one channel between the lowest and the highest level
+      let step = hue % 4;
+      let (rising, falling) = (levels[step], levels[4 - step]);
+      let (low, high) = (levels[0], levels[4]);
+      let (r, g, b) = match hue / 4 {
+        0 => (rising, low, high),
+        1 => (high, low, falling),
+        2 => (high, rising, low),
+        3 => (falling, high, low),
+        4 => (low, high, rising),
+        _ => (low, falling, high),
+      };
+      colors[32 + ring * 24 + hue] = Color {
+        r: widen(r),
+        g: widen(g),
+        b: widen(b),
+      };
+      hue += 1;
+    }
+    ring += 1;
+  }
+  colors
+}
+
+/// Four evenly spaced grays, the colors of [`ColorPalette::Gray`] unless other levels are asked for
+/// (see [`gray_levels`]).
+pub const PALETTE_GRAY4: [Color; 4] = [
+  Color { r: 0x00, g: 0x00, b: 0x00 },
+  Color { r: 0x55, g: 0x55, b: 0x55 },
+  Color { r: 0xaa, g: 0xaa, b: 0xaa },
+  Color { r: 0xff, g: 0xff, b: 0xff },
+];
+
+/// Default 4-bit color table of the classic Mac OS, in the order of its color numbers, which starts
+/// with white and ends with black.
+pub const PALETTE_MAC16: [Color; 16] = [
+  Color { r: 0xff, g: 0xff, b: 0xff },
+  Color { r: 0xfc, g: 0xf3, b: 0x05 },
+  Color { r: 0xff, g: 0x64, b: 0x03 },
+  Color { r: 0xdd, g: 0x09, b: 0x07 },
+  Color { r: 0xf2, g: 0x08, b: 0x84 },
+  Color { r: 0x47, g: 0x00, b: 0xa5 },
+  Color { r: 0x00, g: 0x00, b: 0xd3 },
+  Color { r: 0x02, g: 0xab, b: 0xea },
+  Color { r: 0x1f, g: 0xb7, b: 0x14 },
+  Color { r: 0x00, g: 0x64, b: 0x12 },
+  Color { r: 0x56, g: 0x2c, b: 0x05 },
+  Color { r: 0x90, g: 0x71, b: 0x3a },
+  Color { r: 0xc0, g: 0xc0, b: 0xc0 },
+  Color { r: 0x80, g: 0x80, b: 0x80 },
+  Color { r: 0x40, g: 0x40, b: 0x40 },
+  Color { r: 0x00, g: 0x00, b: 0x00 },
+];
+
+/// Standard 16 colors of Windows, the dark colors first, in the order of the default 16-color palette.
+pub const PALETTE_WIN16: [Color; 16] = [
+  Color { r: 0x00, g: 0x00, b: 0x00 },
+  Color { r: 0x80, g: 0x00, b: 0x00 },
+  Color { r: 0x00, g: 0x80, b: 0x00 },
+  Color { r: 0x80, g: 0x80, b: 0x00 },
+  Color { r: 0x00, g: 0x00, b: 0x80 },
+  Color { r: 0x80, g: 0x00, b: 0x80 },
+  Color { r: 0x00, g: 0x80, b: 0x80 },
+  Color { r: 0xc0, g: 0xc0, b: 0xc0 },
+  Color { r: 0x80, g: 0x80, b: 0x80 },
+  Color { r: 0xff, g: 0x00, b: 0x00 },
+  Color { r: 0x00, g: 0xff, b: 0x00 },
+  Color { r: 0xff, g: 0xff, b: 0x00 },
+  Color { r: 0x00, g: 0x00, b: 0xff },
+  Color { r: 0xff, g: 0x00, b: 0xff },
+  Color { r: 0x00, g: 0xff, b: 0xff },
+  Color { r: 0xff, g: 0xff, b: 0xff },
+];
+
+/// The 20 static colors of the Windows 256-color system palette, in the order of their entries: the
+/// first 10 (the dark colors, money green and sky blue) and the last 10 (cream, medium gray and the
+/// light colors). In between are the 236 entries applications may change.
+pub const PALETTE_WIN20: [Color; 20] = [
+  Color { r: 0x00, g: 0x00, b: 0x00 },
+  Color { r: 0x80, g: 0x00, b: 0x00 },
+  Color { r: 0x00, g: 0x80, b: 0x00 },
+  Color { r: 0x80, g: 0x80, b: 0x00 },
+  Color { r: 0x00, g: 0x00, b: 0x80 },
+  Color { r: 0x80, g: 0x00, b: 0x80 },
+  Color { r: 0x00, g: 0x80, b: 0x80 },
+  Color { r: 0xc0, g: 0xc0, b: 0xc0 },
+  Color { r: 0xc0, g: 0xdc, b: 0xc0 },
+  Color { r: 0xa6, g: 0xca, b: 0xf0 },
+  Color { r: 0xff, g: 0xfb, b: 0xf0 },
+  Color { r: 0xa0, g: 0xa0, b: 0xa4 },
+  Color { r: 0x80, g: 0x80, b: 0x80 },
+  Color { r: 0xff, g: 0x00, b: 0x00 },
+  Color { r: 0x00, g: 0xff, b: 0x00 },
+  Color { r: 0xff, g: 0xff, b: 0x00 },
+  Color { r: 0x00, g: 0x00, b: 0xff },
+  Color { r: 0xff, g: 0x00, b: 0xff },
+  Color { r: 0x00, g: 0xff, b: 0xff },
+  Color { r: 0xff, g: 0xff, b: 0xff },
+];
+
+/// Amstrad CPC palette, every combination of off, half and full intensity per channel, in the order
+/// of the firmware color numbers (9 × green + 3 × red + blue, 0 black, 1 blue, ... 26 bright white).
+pub const PALETTE_AMSTRAD_CPC: [Color; 27] = {
+  const LEVELS: [u8; 3] = [0x00, 0x80, 0xff];
+  let mut colors = [Color { r: 0, g: 0, b: 0 }; 27];
+  let mut i = 0;
+  while i < 27 {
+    colors[i] = Color {
+      r: LEVELS[i / 3 % 3],
+      g: LEVELS[i / 9],
+      b: LEVELS[i % 3],
+    };
+    i += 1;
+  }
+  colors
+};
+
+/// Palette of the TMS9918 video display processor of the MSX, ColecoVision and TI-99/4A, color
+/// numbers 1 (black) to 15 (white). Color 0 is transparent and has no entry.
+pub const PALETTE_TMS9918: [Color; 15] = [
+  Color { r: 0x00, g: 0x00, b: 0x00 },
+  Color { r: 0x21, g: 0xc8, b: 0x42 },
+  Color { r: 0x5e, g: 0xdc, b: 0x78 },
+  Color { r: 0x54, g: 0x55, b: 0xed },
+  Color { r: 0x7d, g: 0x76, b: 0xfc },
+  Color { r: 0xd4, g: 0x52, b: 0x4d },
+  Color { r: 0x42, g: 0xeb, b: 0xf5 },
+  Color { r: 0xfc, g: 0x55, b: 0x54 },
+  Color { r: 0xff, g: 0x79, b: 0x78 },
+  Color { r: 0xd4, g: 0xc1, b: 0x54 },
+  Color { r: 0xe6, g: 0xce, b: 0x80 },
+  Color { r: 0x21, g: 0xb0, b: 0x3b },
+  Color { r: 0xc9, g: 0x5b, b: 0xba },
+  Color { r: 0xcc, g: 0xcc, b: 0xcc },
+  Color { r: 0xff, g: 0xff, b: 0xff },
+];
+
 pub const PALETTE_MONOCHROME: [Color; 2] = [Color { r: 0x00, g: 0x00, b: 0x00 }, Color { r: 0xff, g: 0xff, b: 0xff }];
 
 #[cfg(test)]
@@ -111,6 +1199,42 @@ mod tests {
     assert_eq!(ColorPalette::default(), ColorPalette::Monochrome);
   }
 
+  #[test]
+  fn test_parse_hex_list() {
+    let colors = parse_hex_list("; exported palette\n#000000\n\n  #FF8000 \nabc\n").unwrap();
+    assert_eq!(
+      colors,
+      vec![Color { r: 0, g: 0, b: 0 }, Color { r: 255, g: 128, b: 0 }, Color { r: 0xaa, g: 0xbb, b: 0xcc }]
+    );
+    assert_eq!(
+      parse_hex_list("#000\n#12345\n").unwrap_err(),
+      "line 2: invalid hex color \"#12345\", expected #RGB or #RRGGBB"
+    );
+    assert_eq!(parse_hex_list("; nothing\n").unwrap_err(), "no colors");
+  }
+
+  #[test]
+  fn test_palette_is_never_empty() {
+    assert!(matches!(Palette::new(Vec::new()), Err(DitherError::EmptyPalette)));
+    let palette = Palette::from(ColorPalette::COLOR8);
+    assert_eq!(palette.colors(), &PALETTE_8C);
+    assert_eq!(palette.quantize(Color { r: 250, g: 250, b: 250 }), Color { r: 255, g: 255, b: 255 });
+    assert_eq!(
+      Palette::try_from(vec![Color { r: 1, g: 2, b: 3 }]).unwrap().colors(),
+      &[Color { r: 1, g: 2, b: 3 }]
+    );
+  }
+
+  #[test]
+  fn test_color_palette_colors() {
+    assert_eq!(ColorPalette::Monochrome.colors(), &PALETTE_MONOCHROME);
+    assert_eq!(ColorPalette::COLOR8.colors(), &PALETTE_8C);
+    assert_eq!(ColorPalette::COLOR16.colors(), &PALETTE_16C);
+    assert_eq!(ColorPalette::Gameboy.colors(), &PALETTE_GAMEBOY);
+    assert_eq!(ColorPalette::C64.colors(), &PALETTE_C64);
+    assert_eq!(<ColorPalette as clap::ValueEnum>::from_str("pico8", true), Ok(ColorPalette::Pico8));
+  }
+
   #[test]
   fn test_color_from_u32() {
     let color = Color::from(0xFF8000u32); // Orange
@@ -128,11 +1252,177 @@ mod tests {
     assert_eq!(color.b, 200);
   }
 
+  #[test]
+  fn test_color_from_hex() {
+    assert_eq!("#f5e9d0".parse::<Color>(), Ok(Color { r: 0xf5, g: 0xe9, b: 0xd0 }));
+    assert_eq!("F5E9D0".parse::<Color>(), Ok(Color { r: 0xf5, g: 0xe9, b: 0xd0 }));
+    assert_eq!("#222".parse::<Color>(), Ok(Color { r: 0x22, g: 0x22, b: 0x22 }));
+    assert_eq!("#1af".parse::<Color>(), Ok(Color { r: 0x11, g: 0xaa, b: 0xff }));
+  }
+
+  #[test]
+  fn test_color_from_invalid_hex() {
+    assert!("#12345".parse::<Color>().is_err());
+    assert!("#ggg".parse::<Color>().is_err());
+    assert!("+1+2+3".parse::<Color>().is_err());
+    assert!("".parse::<Color>().is_err());
+  }
+
+  #[test]
+  fn test_duotone_from_str() {
+    let duotone: Duotone = "#112233, eeddcc".parse().unwrap();
+    assert_eq!(duotone.colors(), [Color { r: 0x11, g: 0x22, b: 0x33 }, Color { r: 0xee, g: 0xdd, b: 0xcc }]);
+    assert_eq!(duotone.to_string().parse::<Duotone>(), Ok(duotone));
+    assert!("#112233".parse::<Duotone>().unwrap_err().contains("expected two colors"));
+    assert!("#112233,#eed,#fff".parse::<Duotone>().is_err());
+    assert!("#112233,nope".parse::<Duotone>().unwrap_err().contains("invalid hex color"));
+  }
+
+  #[test]
+  fn test_gradient_colors() {
+    let gradient: Gradient = "#000,#f80,#fff".parse().unwrap();
+    assert_eq!(gradient.to_string().parse::<Gradient>(), Ok(gradient.clone()));
+    let colors = gradient.colors(5);
+    assert_eq!(colors, [0x000000u32, 0x804400, 0xff8800, 0xffc480, 0xffffff].map(Color::from));
+    assert_eq!(gradient.colors(0).len(), 2);
+    assert_eq!(gradient.colors(2), [Color::from(0x000000u32), Color::from(0xffffffu32)]);
+    assert!("#000".parse::<Gradient>().unwrap_err().contains("two or more colors"));
+  }
+
+  #[test]
+  fn test_color_display_roundtrip() {
+    let color = Color { r: 1, g: 0xab, b: 0xff };
+    assert_eq!(color.to_string(), "#01abff");
+    assert_eq!(color.to_string().parse::<Color>(), Ok(color));
+  }
+
   #[test]
   fn test_palette_sizes() {
     assert_eq!(PALETTE_MONOCHROME.len(), 2);
     assert_eq!(PALETTE_8C.len(), 8);
     assert_eq!(PALETTE_16C.len(), 16);
+    assert_eq!(PALETTE_GAMEBOY.len(), 4);
+    assert_eq!(PALETTE_C64[..3], [0x000000u32, 0xffffff, 0x68372b].map(Color::from));
+    assert_eq!(PALETTE_PICO8[8], Color::from(0xff004du32));
+    assert_eq!(ColorPalette::Apple2.colors().len(), 6);
+    assert_eq!((PALETTE_MAC16[0], PALETTE_MAC16[15]), (Color::from(0xffffffu32), Color::from(0x000000u32)));
+    assert_eq!((PALETTE_TMS9918[0], PALETTE_TMS9918[14]), (Color::from(0x000000u32), Color::from(0xffffffu32)));
+  }
+
+  #[test]
+  fn test_xterm256_palette() {
+    assert_eq!(PALETTE_XTERM256[..16], PALETTE_ANSI16);
+    assert_eq!(PALETTE_XTERM256[16], Color { r: 0, g: 0, b: 0 });
+    assert_eq!(PALETTE_XTERM256[196], Color { r: 0xff, g: 0, b: 0 });
+    assert_eq!(PALETTE_XTERM256[231], Color { r: 0xff, g: 0xff, b: 0xff });
+    assert_eq!(PALETTE_XTERM256[232], Color { r: 8, g: 8, b: 8 });
+    assert_eq!(PALETTE_XTERM256[255], Color { r: 0xee, g: 0xee, b: 0xee });
+  }
+
+  #[test]
+  fn test_cga_palettes() {
+    assert_eq!(
+      PALETTE_CGA0,
+      [0x000000u32, 0x00aa00, 0xaa0000, 0xaa5500].map(Color::from),
+      "Palette 0 has brown, not dark yellow"
+    );
+    assert_eq!(PALETTE_CGA1_HIGH, [0x000000u32, 0x55ffff, 0xff55ff, 0xffffff].map(Color::from));
+    assert_eq!(<ColorPalette as clap::ValueEnum>::from_str("cga0-high", true), Ok(ColorPalette::Cga0High));
+    assert_eq!(ColorPalette::Cga1.colors(), &PALETTE_CGA1);
+  }
+
+  #[test]
+  fn test_ega_palette_in_color_number_order() {
+    assert_eq!(PALETTE_EGA[1], Color::from(0x0000aau32));
+    assert_eq!(PALETTE_EGA[6], Color::from(0xaa5500u32));
+    assert_eq!(PALETTE_EGA[9], Color::from(0x5555ffu32));
+    assert_eq!(PALETTE_EGA[14], Color::from(0xffff55u32));
+    assert!(PALETTE_ANSI16.iter().all(|color| PALETTE_EGA.contains(color)));
+  }
+
+  #[test]
+  fn test_nes_palette_has_no_duplicates() {
+    assert!(PALETTE_NES.iter().enumerate().all(|(i, c)| !PALETTE_NES[..i].contains(c)));
+    assert_eq!(PALETTE_NES[13], Color { r: 0, g: 0, b: 0 });
+    assert_eq!(PALETTE_NES[14], Color::from(0xbcbcbcu32), "$10 follows the single black");
+    assert_eq!(PALETTE_NES[54], Color::from(0xf8d8f8u32));
+  }
+
+  #[test]
+  fn test_zx_spectrum_palette() {
+    assert_eq!(PALETTE_ZX_SPECTRUM[..3], [0x000000u32, 0x0000d7, 0xd70000].map(Color::from));
+    assert_eq!(PALETTE_ZX_SPECTRUM[7], Color::from(0xd7d7d7u32));
+    assert_eq!(PALETTE_ZX_SPECTRUM[8], Color::from(0x0000ffu32), "Bright colors start with blue");
+    assert_eq!(PALETTE_ZX_SPECTRUM[13], Color::from(0xffff00u32));
+    assert_eq!(PALETTE_ZX_SPECTRUM[14], Color::from(0xffffffu32));
+  }
+
+  #[test]
+  fn test_web_safe_nearest_color_rounds_every_channel() {
+    assert_eq!(PALETTE_WEB_SAFE[1], Color { r: 0, g: 0, b: 0x33 });
+    assert_eq!(PALETTE_WEB_SAFE[215], Color { r: 0xff, g: 0xff, b: 0xff });
+    let round = |value: u8| (u32::from(value) + 25) / 51 * 51;
+    for value in (0..=255u32).step_by(5) {
+      let color = Color::from((value << 16) | ((255 - value) << 8) | (value * 7 % 256));
+      let (nearest, _) = map_to_palette(color, &PALETTE_WEB_SAFE);
+      let expected = (round(color.r), round(color.g), round(color.b));
+      assert_eq!((u32::from(nearest.r), u32::from(nearest.g), u32::from(nearest.b)), expected, "{color}");
+    }
+  }
+
+  #[test]
+  fn test_vga256_palette() {
+    assert_eq!(PALETTE_VGA256[..16], PALETTE_EGA);
+    assert_eq!(PALETTE_VGA256[20], Color::from(0x383838u32));
+    assert_eq!(PALETTE_VGA256[31], Color::from(0xffffffu32));
+    let hues = [32, 33, 36, 40, 44, 48, 52, 55].map(|i| PALETTE_VGA256[i]);
+    let expected = [0x0000ffu32, 0x4100ff, 0xff00ff, 0xff0000, 0xffff00, 0x00ff00, 0x00ffff, 0x0041ff];
+    assert_eq!(hues, expected.map(Color::from));
+    assert_eq!(PALETTE_VGA256[56], Color::from(0x7d7dffu32));
+    assert_eq!(PALETTE_VGA256[104], Color::from(0x000071u32));
+    assert_eq!(PALETTE_VGA256[247], Color::from(0x2c3041u32));
+    assert!(PALETTE_VGA256[248..].iter().all(|&color| color == Color { r: 0, g: 0, b: 0 }));
+  }
+
+  #[test]
+  fn test_gray_levels() {
+    assert_eq!(gray_levels(4), PALETTE_GRAY4);
+    assert_eq!(gray_levels(2), PALETTE_MONOCHROME);
+    assert_eq!(gray_levels(0), PALETTE_MONOCHROME, "There are at least 2 levels");
+    let sixteen = gray_levels(16);
+    assert_eq!((sixteen[1].r, sixteen[15].r), (0x11, 0xff));
+    assert_eq!(gray_levels(1000).len(), 256);
+    assert_eq!(gray_levels(3)[1], Color { r: 128, g: 128, b: 128 });
+  }
+
+  #[test]
+  fn test_windows_palettes() {
+    assert_eq!(PALETTE_WIN16[1], Color::from(0x800000u32));
+    assert_eq!(PALETTE_WIN16[7], Color::from(0xc0c0c0u32));
+    assert_eq!(PALETTE_WIN20[..8], PALETTE_WIN16[..8]);
+    assert_eq!(PALETTE_WIN20[8..12], [0xc0dcc0u32, 0xa6caf0, 0xfffbf0, 0xa0a0a4].map(Color::from));
+    assert_eq!(PALETTE_WIN20[12..], PALETTE_WIN16[8..]);
+  }
+
+  #[test]
+  fn test_amstrad_cpc_palette_in_firmware_order() {
+    assert_eq!(PALETTE_AMSTRAD_CPC[1], Color::from(0x000080u32));
+    assert_eq!(PALETTE_AMSTRAD_CPC[6], Color::from(0xff0000u32));
+    assert_eq!(PALETTE_AMSTRAD_CPC[11], Color::from(0x0080ffu32));
+    assert_eq!(PALETTE_AMSTRAD_CPC[13], Color::from(0x808080u32));
+    assert_eq!(PALETTE_AMSTRAD_CPC[26], Color::from(0xffffffu32));
+  }
+
+  #[test]
+  fn test_colorblind_palettes_have_black_and_white() {
+    let black = Color { r: 0, g: 0, b: 0 };
+    let white = Color { r: 0xff, g: 0xff, b: 0xff };
+    for palette in [ColorPalette::OkabeIto, ColorPalette::TolBright, ColorPalette::TolVibrant, ColorPalette::Ibm] {
+      let colors = palette.colors();
+      assert!(colors.contains(&black) && colors.contains(&white), "{palette:?} should have black and white");
+      assert!(colors.iter().enumerate().all(|(i, c)| !colors[..i].contains(c)), "{palette:?} has duplicates");
+    }
+    assert_eq!(PALETTE_OKABE_ITO[1], Color { r: 0xe6, g: 0x9f, b: 0x00 });
   }
 
   #[test]
@@ -194,6 +1484,13 @@ mod tests {
     assert!(has_white, "16-color palette should contain white");
   }
 
+  #[test]
+  fn test_palette_quantizer_matches_map_to_palette() {
+    let color = Color { r: 200, g: 30, b: 10 };
+    assert_eq!(PALETTE_8C.quantize(color), *map_to_palette(color, &PALETTE_8C).0);
+    assert_eq!(PALETTE_8C.to_vec().quantize(color), PALETTE_8C[..].quantize(color));
+  }
+
   #[test]
   fn test_map_to_palette_finds_closest() {
     // Test with a color that should map to a specific color in 8-color palette
@@ -205,4 +1502,126 @@ mod tests {
     assert_eq!(closest.g, 0x35);
     assert_eq!(closest.b, 0x00);
   }
+
+  #[test]
+  fn test_map_to_palette_with_metric() {
+    let gray = Color { r: 125, g: 125, b: 125 };
+    let closest = |metric| *map_to_palette_with(gray, &PALETTE_MONOCHROME, metric, TieBreak::Darker).0;
+    assert_eq!(closest(DistanceMetric::Rgb), PALETTE_MONOCHROME[0]);
+    for metric in [DistanceMetric::Lab, DistanceMetric::Oklab, DistanceMetric::Ciede2000] {
+      assert_eq!(closest(metric), PALETTE_MONOCHROME[1], "{metric:?} splits grays by lightness");
+    }
+    let (_, error) = map_to_palette_with(gray, &PALETTE_MONOCHROME, DistanceMetric::Lab, TieBreak::Darker);
+    let expected = QuantizationError::between(gray, PALETTE_MONOCHROME[1]);
+    assert_eq!((error.r, error.g, error.b), (expected.r, expected.g, expected.b));
+  }
+
+  #[test]
+  fn test_map_to_palette_ties_ignore_order() {
+    // gray is equally close to both colors
+    let reddish = Color { r: 0x81, g: 0x80, b: 0x80 };
+    let bluish = Color { r: 0x80, g: 0x80, b: 0x81 };
+    let gray = Color { r: 0x80, g: 0x80, b: 0x80 };
+    for palette in [[reddish, bluish], [bluish, reddish]] {
+      assert_eq!(
+        *map_to_palette_with(gray, &palette, DistanceMetric::Rgb, TieBreak::Darker).0,
+        bluish,
+        "Blue has the lower luma"
+      );
+      assert_eq!(*map_to_palette_with(gray, &palette, DistanceMetric::Rgb, TieBreak::Lighter).0, reddish);
+    }
+  }
+
+  #[test]
+  fn test_map_to_palette_ties_keep_the_first_color() {
+    let reddish = Color { r: 0x81, g: 0x80, b: 0x80 };
+    let bluish = Color { r: 0x80, g: 0x80, b: 0x81 };
+    let gray = Color { r: 0x80, g: 0x80, b: 0x80 };
+    assert_eq!(*map_to_palette(gray, &[reddish, bluish]).0, reddish);
+    assert_eq!(*map_to_palette(gray, &[bluish, reddish]).0, bluish);
+  }
+
+  #[test]
+  fn test_from_image() {
+    let dir = std::env::temp_dir().join("dithers_palette_from_image_test");
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // indexed PNG with a transparent first entry
+    let path = dir.join("indexed.png");
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(&mut png, 2, 1);
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_palette(vec![0, 0, 0, 0xff, 0, 0, 0, 0, 0xff]);
+    encoder.set_trns(vec![0, 255, 255]);
+    encoder.write_header().unwrap().write_image_data(&[1, 2]).unwrap();
+    std::fs::write(&path, png).unwrap();
+    let red_blue = vec![Color { r: 0xff, g: 0, b: 0 }, Color { r: 0, g: 0, b: 0xff }];
+    assert_eq!(from_image(&path).unwrap(), Some(red_blue.clone()));
+
+    // GIF color tables are padded to a power of two
+    let path = dir.join("indexed.gif");
+    let mut gif = Vec::new();
+    let mut encoder = gif::Encoder::new(&mut gif, 3, 1, &[0xff, 0, 0, 0, 0, 0xff, 0, 0xff, 0, 0, 0, 0]).unwrap();
+    let frame = gif::Frame {
+      width: 3,
+      height: 1,
+      transparent: Some(2),
+      buffer: vec![0, 1, 2].into(),
+      ..gif::Frame::default()
+    };
+    encoder.write_frame(&frame).unwrap();
+    drop(encoder);
+    std::fs::write(&path, gif).unwrap();
+    assert_eq!(from_image(&path).unwrap(), Some(red_blue));
+
+    let path = dir.join("true_color.png");
+    image::save_buffer(&path, &[1, 2, 3], 1, 1, image::ExtendedColorType::Rgb8).unwrap();
+    assert_eq!(from_image(&path).unwrap(), None);
+    assert_eq!(from_image(Path::new("photo.jpg")).unwrap(), None);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_tie_broken_quantizer() {
+    let palette = [Color { r: 0, g: 0, b: 0 }, Color { r: 2, g: 2, b: 2 }];
+    let quantizer = TieBroken {
+      colors: &palette,
+      metric: DistanceMetric::Rgb,
+      tie_break: TieBreak::Lighter,
+    };
+    assert_eq!(quantizer.quantize(Color { r: 1, g: 1, b: 1 }), palette[1]);
+    assert_eq!(palette.quantize(Color { r: 1, g: 1, b: 1 }), palette[0]);
+  }
+
+  #[test]
+  fn test_hooked_sees_every_pixel() {
+    let mut seen = Vec::new();
+    let hooked = Hooked::new(&PALETTE_MONOCHROME, |q: &Quantization| {
+      seen.push((q.original, q.chosen, q.error.r));
+      None
+    });
+    assert_eq!(hooked.quantize(Color { r: 100, g: 100, b: 100 }), Color { r: 0, g: 0, b: 0 });
+    assert_eq!(hooked.quantize(Color { r: 200, g: 200, b: 200 }), Color { r: 255, g: 255, b: 255 });
+
+    assert_eq!(seen.len(), 2);
+    assert_eq!(seen[0], (Color { r: 100, g: 100, b: 100 }, Color { r: 0, g: 0, b: 0 }, 100.0));
+    assert_eq!(seen[1].2, -55.0);
+  }
+
+  #[test]
+  fn test_hooked_override_drives_error_diffusion() {
+    use crate::dither::{DitherMethod, DitherOptions, dither_with};
+
+    // forcing the first pixel to black diffuses its whole value to the right neighbor
+    let mut calls = 0;
+    let hooked = Hooked::new(&PALETTE_MONOCHROME, |_: &Quantization| {
+      calls += 1;
+      (calls == 1).then_some(Color { r: 0, g: 0, b: 0 })
+    });
+    let mut buffer = vec![200, 200, 200, 0, 0, 0];
+    dither_with(&mut buffer, DitherMethod::Simple2D, &hooked, 2, 1, &DitherOptions::default()).unwrap();
+
+    assert_eq!(calls, 2);
+    assert_eq!(buffer, vec![0, 0, 0, 0, 0, 0], "Neighbor receives half of the error (100) and rounds to black");
+  }
 }
@@ -0,0 +1,163 @@
+//! Synthetic test patterns.
+//!
+//! Generated images have controlled content, which makes it easy to compare how the dithering
+//! algorithms handle smooth gradients, saturated colors and fine detail.
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::pipeline::Image;
+
+/// Test patterns that can be generated.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum Pattern {
+  /// Smooth horizontal gradient from black to white
+  Gradient,
+  /// Gray, red, green and blue ramps from black to full intensity, stacked vertically
+  Ramp,
+  /// Hue around the center, saturation increasing towards the edge
+  Colorwheel,
+  /// Circular zone plate with the frequency rising to the pixel limit at the edges
+  Zoneplate,
+}
+
+/// Image dimensions written as `<width>x<height>`, e.g. `640x480`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Size {
+  /// Width in pixels
+  pub width: u32,
+  /// Height in pixels
+  pub height: u32,
+}
+
+impl FromStr for Size {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (width, height) = s
+      .split_once(['x', 'X'])
+      .ok_or_else(|| format!("invalid size {s:?}, expected <width>x<height>"))?;
+    let dimension = |d: &str| match d.trim().parse::<u32>() {
+      Ok(0) | Err(_) => Err(format!("invalid size {s:?}, dimensions must be positive integers")),
+      Ok(d) => Ok(d),
+    };
+    Ok(Size {
+      width: dimension(width)?,
+      height: dimension(height)?,
+    })
+  }
+}
+
+impl fmt::Display for Size {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}x{}", self.width, self.height)
+  }
+}
+
+/// Generates a test pattern image.
+#[must_use]
+pub fn generate(pattern: Pattern, size: Size) -> Image {
+  let Size { width, height } = size;
+  let mut buffer = Vec::with_capacity(width as usize * height as usize * 3);
+  for y in 0..height {
+    for x in 0..width {
+      buffer.extend_from_slice(&pixel(pattern, x, y, width, height));
+    }
+  }
+  Image {
+    buffer,
+    alpha: None,
+    width,
+    height,
+  }
+}
+
+fn pixel(pattern: Pattern, x: u32, y: u32, width: u32, height: u32) -> [u8; 3] {
+  // position across the width, 0.0 at the left to 1.0 at the right
+  let t = if width > 1 { x as f32 / (width - 1) as f32 } else { 0.0 };
+  let level = (t * 255.0).round() as u8;
+  match pattern {
+    Pattern::Gradient => [level; 3],
+    Pattern::Ramp => match 4 * y / height {
+      0 => [level; 3],
+      1 => [level, 0, 0],
+      2 => [0, level, 0],
+      _ => [0, 0, level],
+    },
+    Pattern::Colorwheel => {
+      let (dx, dy) = (x as f32 - width as f32 / 2.0, y as f32 - height as f32 / 2.0);
+      let radius = width.min(height) as f32 / 2.0;
+      let hue = dy.atan2(dx).to_degrees().rem_euclid(360.0);
+      let saturation = ((dx * dx + dy * dy).sqrt() / radius).min(1.0);
+      hsv_to_rgb(hue, saturation, 1.0)
+    }
+    Pattern::Zoneplate => {
+      let (dx, dy) = (x as f32 + 0.5 - width as f32 / 2.0, y as f32 + 0.5 - height as f32 / 2.0);
+      // phase k * r^2 has the local frequency 2 * k * r, which reaches pi (the pixel limit) at the edge
+      let k = std::f32::consts::PI / width.max(height) as f32;
+      let value = 0.5 + 0.5 * (k * (dx * dx + dy * dy)).cos();
+      [(value * 255.0).round() as u8; 3]
+    }
+  }
+}
+
+/// Converts hue (degrees), saturation and value (0.0-1.0) to RGB.
+fn hsv_to_rgb(hue: f32, saturation: f32, value: f32) -> [u8; 3] {
+  let channel = |n: f32| {
+    let k = (n + hue / 60.0) % 6.0;
+    let v = value - value * saturation * k.min(4.0 - k).clamp(0.0, 1.0);
+    (v * 255.0).round() as u8
+  };
+  [channel(5.0), channel(3.0), channel(1.0)]
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn size(width: u32, height: u32) -> Size {
+    Size { width, height }
+  }
+
+  #[test]
+  fn test_parse_size() {
+    assert_eq!("640x480".parse::<Size>(), Ok(size(640, 480)));
+    assert_eq!("16X9".parse::<Size>(), Ok(size(16, 9)));
+    assert!("640".parse::<Size>().is_err());
+    assert!("0x480".parse::<Size>().is_err());
+    assert!("axb".parse::<Size>().is_err());
+    assert_eq!(size(640, 480).to_string(), "640x480");
+  }
+
+  #[test]
+  fn test_generate_dimensions() {
+    for pattern in [Pattern::Gradient, Pattern::Ramp, Pattern::Colorwheel, Pattern::Zoneplate] {
+      let image = generate(pattern, size(33, 17));
+      assert_eq!((image.width, image.height), (33, 17));
+      assert_eq!(image.buffer.len(), 33 * 17 * 3, "{:?} buffer size", pattern);
+    }
+  }
+
+  #[test]
+  fn test_gradient_spans_full_range() {
+    let image = generate(Pattern::Gradient, size(256, 1));
+    assert_eq!(&image.buffer[..3], &[0, 0, 0]);
+    assert_eq!(&image.buffer[3 * 128..3 * 129], &[128, 128, 128]);
+    assert_eq!(&image.buffer[3 * 255..], &[255, 255, 255]);
+  }
+
+  #[test]
+  fn test_ramp_bands() {
+    let image = generate(Pattern::Ramp, size(2, 4));
+    let right_column: Vec<&[u8]> = image.buffer.chunks_exact(3).skip(1).step_by(2).collect();
+    assert_eq!(right_column, vec![&[255, 255, 255][..], &[255, 0, 0], &[0, 255, 0], &[0, 0, 255]]);
+  }
+
+  #[test]
+  fn test_hsv_to_rgb() {
+    assert_eq!(hsv_to_rgb(0.0, 1.0, 1.0), [255, 0, 0]);
+    assert_eq!(hsv_to_rgb(120.0, 1.0, 1.0), [0, 255, 0]);
+    assert_eq!(hsv_to_rgb(240.0, 1.0, 1.0), [0, 0, 255]);
+    assert_eq!(hsv_to_rgb(60.0, 0.0, 1.0), [255, 255, 255]);
+  }
+}
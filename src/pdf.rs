@@ -0,0 +1,230 @@
+//! Single-page PDF output for printing.
+//!
+//! The image is embedded losslessly and placed in the center of the page at its physical size,
+//! given by the resolution in DPI. Black and white images are stored with 1 bit per pixel.
+
+use std::io::Write;
+
+use flate2::Compression;
+use flate2::write::ZlibEncoder;
+
+use crate::mono;
+use crate::pipeline::Image;
+
+/// Resolution used when none is given.
+pub const DEFAULT_DPI: u32 = 300;
+
+/// PDF units (points) per inch.
+const POINTS_PER_INCH: f32 = 72.0;
+
+/// Paper sizes of the PDF page.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, Default, PartialEq)]
+pub enum PageSize {
+  /// ISO A3, 297 x 420 mm
+  A3,
+  /// ISO A4, 210 x 297 mm
+  #[default]
+  A4,
+  /// ISO A5, 148 x 210 mm
+  A5,
+  /// US Letter, 8.5 x 11 in
+  Letter,
+  /// US Legal, 8.5 x 14 in
+  Legal,
+  /// Page of exactly the image size
+  Fit,
+}
+
+serde_value_enum!(PageSize);
+
+impl PageSize {
+  /// Returns the portrait page width and height in points, `None` for [`PageSize::Fit`].
+  #[must_use]
+  pub fn points(&self) -> Option<(f32, f32)> {
+    let mm = |w: f32, h: f32| Some((w / 25.4 * POINTS_PER_INCH, h / 25.4 * POINTS_PER_INCH));
+    match self {
+      PageSize::A3 => mm(297.0, 420.0),
+      PageSize::A4 => mm(210.0, 297.0),
+      PageSize::A5 => mm(148.0, 210.0),
+      PageSize::Letter => Some((612.0, 792.0)),
+      PageSize::Legal => Some((612.0, 1008.0)),
+      PageSize::Fit => None,
+    }
+  }
+}
+
+/// Encodes an image into a single-page PDF.
+///
+/// Images larger than the page are scaled down to fit, landscape images are placed on a landscape page.
+#[must_use]
+pub fn encode(image: &Image, dpi: u32, page_size: PageSize) -> Vec<u8> {
+  let scale = POINTS_PER_INCH / dpi.max(1) as f32;
+  let (mut width, mut height) = (image.width as f32 * scale, image.height as f32 * scale);
+  let (page_width, page_height) = match page_size.points() {
+    Some((w, h)) if width > height => (h, w),
+    Some(page) => page,
+    None => (width, height),
+  };
+  if width > page_width || height > page_height {
+    let fit = (page_width / width).min(page_height / height);
+    log::warn!(
+      "image does not fit the {:?} page at {} dpi, scaling it down to {:.0}%",
+      page_size,
+      dpi,
+      fit * 100.0
+    );
+    width *= fit;
+    height *= fit;
+  }
+  let (x, y) = ((page_width - width) / 2.0, (page_height - height) / 2.0);
+
+  let (color_space, bits, samples) = match bilevel(image) {
+    Some(bits) => ("/DeviceGray", 1, bits),
+    None => ("/DeviceRGB", 8, image.buffer.clone()),
+  };
+  let content = format!("q {width:.3} 0 0 {height:.3} {x:.3} {y:.3} cm /Im0 Do Q");
+
+  let mut pdf = PdfWriter::default();
+  pdf.object(b"<< /Type /Catalog /Pages 2 0 R >>");
+  pdf.object(b"<< /Type /Pages /Kids [3 0 R] /Count 1 >>");
+  pdf.object(
+    format!("<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {page_width:.3} {page_height:.3}] /Resources << /XObject << /Im0 4 0 R >> >> /Contents 5 0 R >>")
+      .as_bytes(),
+  );
+  let dictionary = format!(
+    "/Type /XObject /Subtype /Image /Width {} /Height {} /ColorSpace {color_space} /BitsPerComponent {bits} /Interpolate false /Filter /FlateDecode",
+    image.width, image.height
+  );
+  pdf.stream(&dictionary, &deflate(&samples));
+  pdf.stream("", content.as_bytes());
+  pdf.finish()
+}
+
+/// Packs a black and white image into 1-bit rows (1 = white), `None` if it has other colors.
+fn bilevel(image: &Image) -> Option<Vec<u8>> {
+  let luma: Option<Vec<u8>> = image
+    .buffer
+    .chunks_exact(3)
+    .map(|pixel| match pixel {
+      [0, 0, 0] => Some(0),
+      [255, 255, 255] => Some(255),
+      _ => None,
+    })
+    .collect();
+  Some(mono::pack(&luma?, image.width, image.height))
+}
+
+fn deflate(data: &[u8]) -> Vec<u8> {
+  let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+  // writing into memory cannot fail
+  encoder.write_all(data).expect("in-memory compression");
+  encoder.finish().expect("in-memory compression")
+}
+
+/// Writes numbered objects and the cross-reference table of a PDF file.
+#[derive(Default)]
+struct PdfWriter {
+  data: Vec<u8>,
+  offsets: Vec<usize>,
+}
+
+impl PdfWriter {
+  fn begin_object(&mut self) {
+    if self.data.is_empty() {
+      // the binary comment marks the file as binary for transfer tools
+      self.data.extend_from_slice(b"%PDF-1.4\n%\xe2\xe3\xcf\xd3\n");
+    }
+    self.offsets.push(self.data.len());
+    self.data.extend_from_slice(format!("{} 0 obj\n", self.offsets.len()).as_bytes());
+  }
+
+  fn object(&mut self, body: &[u8]) {
+    self.begin_object();
+    self.data.extend_from_slice(body);
+    self.data.extend_from_slice(b"\nendobj\n");
+  }
+
+  fn stream(&mut self, dictionary: &str, data: &[u8]) {
+    self.begin_object();
+    self
+      .data
+      .extend_from_slice(format!("<< {dictionary} /Length {} >>\nstream\n", data.len()).as_bytes());
+    self.data.extend_from_slice(data);
+    self.data.extend_from_slice(b"\nendstream\nendobj\n");
+  }
+
+  fn finish(mut self) -> Vec<u8> {
+    let xref = self.data.len();
+    let mut table = format!("xref\n0 {}\n0000000000 65535 f \n", self.offsets.len() + 1);
+    for offset in &self.offsets {
+      table.push_str(&format!("{offset:010} 00000 n \n"));
+    }
+    table.push_str(&format!(
+      "trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{xref}\n%%EOF\n",
+      self.offsets.len() + 1
+    ));
+    self.data.extend_from_slice(table.as_bytes());
+    self.data
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn image(buffer: Vec<u8>, width: u32, height: u32) -> Image {
+    Image {
+      buffer,
+      alpha: None,
+      width,
+      height,
+    }
+  }
+
+  fn contains(haystack: &[u8], needle: &str) -> bool {
+    haystack.windows(needle.len()).any(|w| w == needle.as_bytes())
+  }
+
+  #[test]
+  fn test_page_points() {
+    let (w, h) = PageSize::A4.points().unwrap();
+    assert!((w - 595.28).abs() < 0.01 && (h - 841.89).abs() < 0.01);
+    assert_eq!(PageSize::Letter.points(), Some((612.0, 792.0)));
+    assert_eq!(PageSize::Fit.points(), None);
+  }
+
+  #[test]
+  fn test_bilevel() {
+    assert_eq!(bilevel(&image(vec![255, 255, 255, 0, 0, 0, 255, 255, 255], 3, 1)), Some(vec![0b1010_0000]));
+    assert_eq!(bilevel(&image(vec![255, 255, 255, 0, 0, 0, 1, 2, 3], 3, 1)), None);
+  }
+
+  #[test]
+  fn test_encode_structure() {
+    // 300x150 pixels at 150 dpi is 2x1 inches = 144x72 points
+    let pdf = encode(&image(vec![255; 300 * 150 * 3], 300, 150), 150, PageSize::Fit);
+    assert!(pdf.starts_with(b"%PDF-1.4"));
+    assert!(pdf.ends_with(b"%%EOF\n"));
+    assert!(contains(&pdf, "/MediaBox [0 0 144.000 72.000]"));
+    assert!(contains(&pdf, "/ColorSpace /DeviceGray /BitsPerComponent 1"));
+    assert!(contains(&pdf, "q 144.000 0 0 72.000 0.000 0.000 cm /Im0 Do Q"));
+
+    // the cross-reference table points at the objects
+    let text = String::from_utf8_lossy(&pdf);
+    let offset: usize = text.lines().find(|l| l.ends_with(" 00000 n ")).unwrap()[..10].parse().unwrap();
+    assert!(pdf[offset..].starts_with(b"1 0 obj"));
+  }
+
+  #[test]
+  fn test_encode_centers_and_fits_page() {
+    let pdf = encode(&image(vec![10; 3 * 4], 2, 2), 1, PageSize::Letter);
+    assert!(contains(&pdf, "/ColorSpace /DeviceRGB /BitsPerComponent 8"));
+    // 2x2 inches centered on a 8.5x11 inch page
+    assert!(contains(&pdf, "q 144.000 0 0 144.000 234.000 324.000 cm"));
+
+    // 20 inches wide does not fit, landscape page and scaled to its width
+    let pdf = encode(&image(vec![10; 3 * 20 * 10], 20, 10), 1, PageSize::Letter);
+    assert!(contains(&pdf, "/MediaBox [0 0 792.000 612.000]"));
+    assert!(contains(&pdf, "q 792.000 0 0 396.000 0.000 108.000 cm"));
+  }
+}
@@ -0,0 +1,1384 @@
+//! Processing pipeline wrapping the dithering step.
+//!
+//! [`Settings`] gathers the options that are shared by the command line and jobs files,
+//! [`process`] applies them together with the dithering itself to an [`Image`].
+
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use clap::Args;
+use image::imageops::{self, FilterType};
+use image::{GrayImage, RgbImage};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::adaptive::{self, PaletteQuantizer};
+use crate::adjust;
+use crate::clash::{self, Console};
+use crate::display::{self, Display};
+use crate::distance::{DistanceMetric, MetricPalette};
+use crate::dither::{self, DitherMethod, DitherOptions, ScanDirection};
+use crate::ensemble::{self, EnsembleMode};
+use crate::error::DitherError;
+use crate::halftone::{self, Screen};
+use crate::ham;
+use crate::indexed::PaletteOrder;
+use crate::ink;
+use crate::input::{self, LoadOptions};
+use crate::kdtree::{self, KdTree};
+use crate::lut::ColorLut;
+use crate::mono;
+use crate::noise::{self, Noise};
+use crate::output::OutputFormat;
+use crate::overlay::{self, Position};
+use crate::palette::{Color, ColorPalette, Duotone, Gradient, TieBreak, TieBroken};
+use crate::pdf::PageSize;
+use crate::sprite::{self, SpriteLayout};
+use crate::stream::ByteSize;
+use crate::swatches;
+use crate::target::Target;
+
+/// An image being processed.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Image {
+  /// RGB pixel data, 3 bytes per pixel
+  pub buffer: Vec<u8>,
+  /// Alpha channel, 1 byte per pixel, if the image has one
+  pub alpha: Option<Vec<u8>>,
+  /// Image width in pixels
+  pub width: u32,
+  /// Image height in pixels
+  pub height: u32,
+}
+
+impl Image {
+  /// Checks that the color and alpha buffers have the size the dimensions require.
+  pub fn validate(&self) -> Result<(), DitherError> {
+    dither::validate_buffer(&self.buffer, self.width, self.height, 3)?;
+    match &self.alpha {
+      Some(alpha) => dither::validate_buffer(alpha, self.width, self.height, 1),
+      None => Ok(()),
+    }
+  }
+
+  /// Scales the image down to fit within the given size, keeping its aspect ratio.
+  ///
+  /// Images that already fit are returned unchanged, they are never enlarged.
+  #[must_use]
+  pub fn shrink_to_fit(self, max_width: u32, max_height: u32) -> Image {
+    if self.width <= max_width && self.height <= max_height {
+      return self;
+    }
+    let scale = (max_width as f32 / self.width as f32).min(max_height as f32 / self.height as f32);
+    let width = ((self.width as f32 * scale).round() as u32).clamp(1, max_width.max(1));
+    let height = ((self.height as f32 * scale).round() as u32).clamp(1, max_height.max(1));
+    self.resize(width, height, FilterType::Triangle)
+  }
+
+  /// Scales the image up or down so that its longer side has `size` pixels, keeping its aspect ratio.
+  #[must_use]
+  pub fn scale_to(self, size: u32) -> Image {
+    let (width, height) = scaled_size(self.width, self.height, size);
+    if (width, height) == (self.width, self.height) {
+      return self;
+    }
+    self.resize(width, height, FilterType::Lanczos3)
+  }
+
+  /// Surrounds the image with a frame of `width` pixels in the given color.
+  ///
+  /// The frame is opaque if the image has an alpha channel.
+  #[must_use]
+  pub fn pad(self, width: u32, color: Color) -> Image {
+    if width == 0 {
+      return self;
+    }
+    let (new_width, new_height) = (self.width + 2 * width, self.height + 2 * width);
+    let mut buffer = [color.r, color.g, color.b].repeat(new_width as usize * new_height as usize);
+    let mut alpha = self.alpha.as_ref().map(|_| vec![255; new_width as usize * new_height as usize]);
+    for y in 0..self.height as usize {
+      let (src, dst) = (y * self.width as usize, (y + width as usize) * new_width as usize + width as usize);
+      let len = self.width as usize;
+      buffer[dst * 3..(dst + len) * 3].copy_from_slice(&self.buffer[src * 3..(src + len) * 3]);
+      if let (Some(alpha), Some(source)) = (alpha.as_mut(), self.alpha.as_ref()) {
+        alpha[dst..dst + len].copy_from_slice(&source[src..src + len]);
+      }
+    }
+    Image {
+      buffer,
+      alpha,
+      width: new_width,
+      height: new_height,
+    }
+  }
+
+  /// Resizes the color and alpha channels to exactly the given size.
+  #[must_use]
+  pub fn resize(self, width: u32, height: u32, filter: FilterType) -> Image {
+    let rgb = RgbImage::from_raw(self.width, self.height, self.buffer).expect("buffer matches the image size");
+    let alpha = self.alpha.map(|alpha| {
+      let gray = GrayImage::from_raw(self.width, self.height, alpha).expect("alpha matches the image size");
+      imageops::resize(&gray, width, height, filter).into_raw()
+    });
+    Image {
+      buffer: imageops::resize(&rgb, width, height, filter).into_raw(),
+      alpha,
+      width,
+      height,
+    }
+  }
+}
+
+/// Returns the size of an image scaled so that its longer side has `size` pixels, see [`Image::scale_to`].
+fn scaled_size(width: u32, height: u32, size: u32) -> (u32, u32) {
+  let size = size.max(1);
+  let scale = size as f32 / width.max(height) as f32;
+  (
+    ((width as f32 * scale).round() as u32).clamp(1, size),
+    ((height as f32 * scale).round() as u32).clamp(1, size),
+  )
+}
+
+/// Processing options shared by the CLI and job manifests.
+#[derive(Args, Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+  /// Scale the image before dithering so that its longer side has this many pixels
+  #[clap(long, value_name = "PIXELS")]
+  pub resize: Option<u32>,
+
+  /// Keep the input as stored instead of turning it upright according to its EXIF orientation
+  #[clap(long)]
+  pub no_auto_orient: bool,
+
+  /// Recolor black output pixels with this ink color, e.g. "#222"
+  #[clap(long, value_name = "COLOR")]
+  pub ink: Option<Color>,
+
+  /// Recolor white output pixels with this paper color, e.g. "#f5e9d0"
+  #[clap(long, value_name = "COLOR")]
+  pub paper: Option<Color>,
+
+  /// Leave fully transparent pixels untouched and keep their error away from opaque neighbors
+  #[clap(long)]
+  pub skip_transparent: bool,
+
+  /// Dither the alpha channel to fully opaque or fully transparent (1-bit transparency)
+  #[clap(long)]
+  pub alpha_dither: bool,
+
+  /// Palette color standing for transparency: transparent pixels get it and the others are dithered to the remaining colors
+  #[clap(long, value_name = "COLOR")]
+  pub transparent_color: Option<Color>,
+
+  /// Alpha below which pixels are transparent with --transparent-color [default: 128]
+  #[clap(long, value_name = "ALPHA")]
+  pub alpha_threshold: Option<u8>,
+
+  /// Order in which error diffusion visits the pixels
+  #[clap(long, value_enum, default_value_t)]
+  pub scan: ScanDirection,
+
+  /// Angle of the screen of --dither halftone in degrees [default: 45]
+  #[clap(long, value_name = "DEGREES")]
+  pub halftone_angle: Option<f32>,
+
+  /// Distance between the dots of --dither halftone in pixels, the resolution divided by the lines per inch [default: 6]
+  #[clap(long, value_name = "PIXELS")]
+  pub halftone_size: Option<f32>,
+
+  /// Size of the matrix of --dither bayer, a power of two up to 256 [default: 8]
+  #[clap(long, value_name = "SIZE", value_parser = dither::parse_bayer_size)]
+  pub bayer_size: Option<u32>,
+
+  /// Which of two equally close palette colors is chosen
+  #[clap(long, value_enum, default_value_t)]
+  pub tie_break: TieBreak,
+
+  /// Color distance the closest palette color is chosen by
+  #[clap(long, value_enum, default_value_t)]
+  pub distance: DistanceMetric,
+
+  /// Look up palette colors in a table of this many cells per channel, e.g. 32, faster for large palettes (a power of two up to 64)
+  #[clap(long, value_name = "SIZE")]
+  pub lut: Option<u16>,
+
+  /// Dither to black and white on the brightness only, several times faster; gray inputs give the same result as without
+  #[clap(long)]
+  pub fast_mono: bool,
+
+  /// Dither to the palette embedded in an indexed PNG or GIF input instead of the palette option
+  #[clap(long)]
+  pub input_palette: bool,
+
+  /// Dither to the colors of a palette file (one hex color per line, GIMP .gpl, Adobe .ase or .aco, JASC .pal, Lospec .json) instead of the palette option
+  #[clap(long, value_name = "FILE")]
+  pub palette_file: Option<PathBuf>,
+
+  /// Dither to the dominant colors of another image instead of the palette option, picked with --colors and --quantizer
+  #[clap(long, value_name = "IMAGE")]
+  pub palette_from: Option<PathBuf>,
+
+  /// Dither to two colors instead of the palette option, e.g. "#112233,#eeddcc"
+  #[clap(long, value_name = "COLORS")]
+  pub duotone: Option<Duotone>,
+
+  /// Dither to colors blending between these anchors instead of the palette option, as many as --colors [default: 8]
+  #[clap(long, value_name = "COLORS")]
+  pub gradient: Option<Gradient>,
+
+  /// Number of grays of the gray palette, from 2 to 256 [default: 4]
+  #[clap(long, value_name = "N")]
+  pub levels: Option<u16>,
+
+  /// Generate a palette of this many colors from the image instead of using the palette option, or of the gradient
+  #[clap(long, value_name = "N")]
+  pub colors: Option<u16>,
+
+  /// Algorithm generating the palette from the image, implies --colors 16 [default: octree]
+  #[clap(long, value_enum, value_name = "METHOD")]
+  pub quantizer: Option<PaletteQuantizer>,
+
+  /// Write the colors dithered to into this file, a GIMP palette for .gpl and one hex color per line otherwise
+  #[clap(long, value_name = "FILE")]
+  pub export_palette: Option<PathBuf>,
+
+  /// Dither in square tiles of this size, each limited to its own few colors of the palette (sprite-sheet mode)
+  #[clap(long, value_name = "PIXELS")]
+  pub tile_palette: Option<u32>,
+
+  /// Largest number of colors per tile of --tile-palette [default: 4]
+  #[clap(long, value_name = "COLORS")]
+  pub tile_colors: Option<usize>,
+
+  /// Tiles of --tile-palette share this many sub-palettes instead of having their own (attribute clash)
+  #[clap(long, value_name = "COUNT")]
+  pub sub_palettes: Option<usize>,
+
+  /// Use the tile, color and sub-palette limits of a console, implies --tile-palette
+  #[clap(long, value_enum)]
+  pub console: Option<Console>,
+
+  /// Encode for the Amiga hold-and-modify mode (experimental), with base colors chosen from the image
+  #[clap(long)]
+  pub ham6: bool,
+
+  /// Quantize to the levels of a packed bit depth instead of the palette
+  #[clap(long, value_enum)]
+  pub target: Option<Target>,
+
+  /// Write a special format instead of the one given by the file extension, implies the matching --target or palette
+  #[clap(long, value_enum)]
+  pub format: Option<OutputFormat>,
+
+  /// Print resolution in dots per inch, stored in PNG, JPEG and TIFF output and the scale of PDF output [default: 300 for PDF]
+  #[clap(long)]
+  pub dpi: Option<u32>,
+
+  /// Write PNG and GIF output with a palette of the colors used instead of true color
+  #[clap(long)]
+  pub indexed: bool,
+
+  /// Write PNG output interlaced (Adam7), so that it shows a coarse preview while loading
+  #[clap(long)]
+  pub interlaced: bool,
+
+  /// Write JPEG output progressive, so that it shows a blurry preview while loading
+  #[clap(long)]
+  pub progressive: bool,
+
+  /// Quality of JPEG output from 1 to 100, lower values give smaller files with more artifacts [default: 75]
+  #[clap(long, value_name = "QUALITY", value_parser = clap::value_parser!(u8).range(1..=100))]
+  pub jpeg_quality: Option<u8>,
+
+  /// Order of the palette entries of indexed output
+  #[clap(long, value_enum, default_value_t)]
+  pub palette_order: PaletteOrder,
+
+  /// Maximum total ink coverage in percent, palette colors that need more ink are not used
+  #[clap(long, value_name = "PERCENT")]
+  pub max_ink: Option<f32>,
+
+  /// Paper size of PDF output
+  #[clap(long, value_enum, default_value_t)]
+  pub page_size: PageSize,
+
+  /// Frame the image with a border of this many pixels in --border-color before dithering
+  #[clap(long, value_name = "PIXELS")]
+  pub border: Option<u32>,
+
+  /// Color of the --border [default: #000]
+  #[clap(long, value_name = "COLOR")]
+  pub border_color: Option<Color>,
+
+  /// Add a margin of this many pixels in --pad-color around the image (and its border) before dithering
+  #[clap(long, value_name = "PIXELS")]
+  pub pad: Option<u32>,
+
+  /// Color of the --pad margin [default: #fff]
+  #[clap(long, value_name = "COLOR")]
+  pub pad_color: Option<Color>,
+
+  /// Exposure correction in stops applied before dithering, e.g. 0.5 or -1
+  #[clap(long, default_value_t, allow_negative_numbers = true)]
+  pub exposure: f32,
+
+  /// White balance temperature from -1.0 (cooler) to 1.0 (warmer)
+  #[clap(long, default_value_t, allow_negative_numbers = true)]
+  pub temperature: f32,
+
+  /// White balance tint from -1.0 (greener) to 1.0 (more magenta)
+  #[clap(long, default_value_t, allow_negative_numbers = true)]
+  pub tint: f32,
+
+  /// Saturation change from -1.0 (grayscale) to 1.0 (double) applied before dithering
+  #[clap(long, default_value_t, allow_negative_numbers = true)]
+  pub saturation: f32,
+
+  /// Like --saturation, but mostly boosts muted colors and leaves saturated ones alone
+  #[clap(long, default_value_t, allow_negative_numbers = true)]
+  pub vibrance: f32,
+
+  /// Compensate the response curve of the display the output is shown on
+  #[clap(long, value_enum)]
+  pub display: Option<Display>,
+
+  /// Gamma of the target display, overrides the curve of --display
+  #[clap(long, value_name = "GAMMA")]
+  pub display_gamma: Option<f32>,
+
+  /// Draw this text onto the image before dithering, so it becomes part of the dithered texture
+  #[clap(long, value_name = "TEXT")]
+  pub caption: Option<String>,
+
+  /// Position of the --caption
+  #[clap(long, value_enum, default_value_t)]
+  pub caption_pos: Position,
+
+  /// Color of the --caption, drawn with a shadow in the inverted color [default: #fff]
+  #[clap(long, value_name = "COLOR")]
+  pub caption_color: Option<Color>,
+
+  /// Size of the --caption in pixels per font pixel [default: 2]
+  #[clap(long, value_name = "SCALE")]
+  pub caption_scale: Option<u32>,
+
+  /// Blend this image onto the image before dithering, e.g. a watermark with transparency
+  #[clap(long, value_name = "FILE")]
+  pub overlay: Option<PathBuf>,
+
+  /// Position of the --overlay
+  #[clap(long, value_enum, default_value_t)]
+  pub overlay_pos: Position,
+
+  /// Inject noise before dithering to break up banding
+  #[clap(long, value_enum)]
+  pub noise: Option<Noise>,
+
+  /// Standard deviation of the injected noise in 8-bit levels [default: 16]
+  #[clap(long, value_name = "LEVELS")]
+  pub noise_amplitude: Option<f32>,
+
+  /// Seed for everything random, equal seeds give equal outputs
+  #[clap(long, default_value_t)]
+  pub seed: u64,
+
+  /// Dither K times with consecutive seeds and combine the results, needs a random input such as --noise
+  #[clap(long, value_name = "K")]
+  pub ensemble: Option<u32>,
+
+  /// How the results of --ensemble are combined per pixel
+  #[clap(long, value_enum, default_value_t)]
+  pub ensemble_mode: EnsembleMode,
+
+  /// Largest amount of memory for processing an image, e.g. 512M; larger PNG images are processed in strips
+  #[clap(long, value_name = "SIZE")]
+  pub max_memory: Option<ByteSize>,
+
+  /// Reject inputs wider or higher than this many pixels, e.g. untrusted uploads
+  #[clap(long, value_name = "PIXELS")]
+  pub max_dimension: Option<u32>,
+
+  /// Reject inputs with more pixels than this in total
+  #[clap(long, value_name = "PIXELS")]
+  pub max_pixels: Option<u64>,
+}
+
+/// Number of gradient colors used when `--colors` is not given.
+pub const DEFAULT_GRADIENT_COLORS: u16 = 8;
+
+/// Dithers an image and applies the processing requested by the settings.
+///
+/// Returns an error if the `--overlay` image cannot be loaded or the `--export-palette` file cannot be written.
+///
+/// # Panics
+///
+/// Panics if the image does not pass [`Image::validate`].
+pub fn process(image: &mut Image, method: DitherMethod, palette: ColorPalette, settings: &Settings) -> Result<(), DitherError> {
+  process_with_colors(image, method, palette.colors(), settings)
+}
+
+/// Like [`process`], but dithers to the given colors instead of a built-in palette.
+pub fn process_with_colors(image: &mut Image, method: DitherMethod, colors: &[Color], settings: &Settings) -> Result<(), DitherError> {
+  if let Some(size) = settings.resize {
+    stage("resize", || *image = std::mem::take(image).scale_to(size));
+  }
+  adjust_colors(image, settings);
+
+  // the frame sits between the image and the margin, both in exactly the given colors
+  if let Some(border) = settings.border {
+    let color = settings.border_color.unwrap_or(Color { r: 0, g: 0, b: 0 });
+    stage("border", || *image = std::mem::take(image).pad(border, color));
+  }
+  if let Some(pad) = settings.pad {
+    let color = settings.pad_color.unwrap_or(Color { r: 255, g: 255, b: 255 });
+    stage("padding", || *image = std::mem::take(image).pad(pad, color));
+  }
+  if let Some(path) = &settings.overlay {
+    let overlay = input::load(path)?;
+    stage("overlay", || overlay::composite(image, &overlay, settings.overlay_pos));
+  }
+  if let Some(caption) = &settings.caption {
+    let color = settings.caption_color.unwrap_or(Color { r: 255, g: 255, b: 255 });
+    let scale = settings.caption_scale.unwrap_or(overlay::DEFAULT_CAPTION_SCALE);
+    stage("caption", || overlay::draw_caption(image, caption, settings.caption_pos, scale, color));
+  }
+  match settings.adaptive_palette() {
+    Some((quantizer, count)) => {
+      if settings.input_palette || settings.palette_file.is_some() {
+        log::warn!("the palette is generated from the image, --input-palette and --palette-file are ignored");
+      }
+      let mut colors = Vec::new();
+      stage("palette generation", || colors = adaptive::palette(image, quantizer, count));
+      log::debug!("generated a palette of {} colors with {:?}", colors.len(), quantizer);
+      dither_image(image, method, &colors, settings, (0, 0))
+    }
+    None => dither_image(image, method, colors, settings, (0, 0)),
+  }
+}
+
+/// Processes a horizontal strip of an image whose top row is `top`, for images too large to process at once.
+///
+/// Only the color adjustments, the dithering and the recoloring are applied, the stages that need the
+/// whole image (resizing, borders, overlays and captions) are left out. Ordered patterns continue across
+/// strips, methods that diffuse errors are not split into strips, see [`blocker`](crate::stream::blocker).
+pub fn process_strip(strip: &mut Image, method: DitherMethod, colors: &[Color], settings: &Settings, top: u32) -> Result<(), DitherError> {
+  process_tile(strip, method, colors, settings, (0, top))
+}
+
+/// Like [`process_strip`], for a rectangle of an image whose top left pixel is at `origin`.
+pub fn process_tile(tile: &mut Image, method: DitherMethod, colors: &[Color], settings: &Settings, origin: (u32, u32)) -> Result<(), DitherError> {
+  adjust_colors(tile, settings);
+  dither_image(tile, method, colors, settings, origin)
+}
+
+/// Returns the colors an input is dithered to.
+///
+/// With `--input-palette` these are the colors embedded in an indexed input. Otherwise, and for inputs
+/// without a palette, they are those of [`Settings::dither_colors`].
+pub fn palette_colors(input: &Path, palette: ColorPalette, settings: &Settings) -> Result<Vec<Color>, DitherError> {
+  if settings.input_palette {
+    let name = settings
+      .palette_from
+      .as_ref()
+      .or(settings.palette_file.as_ref())
+      .map_or_else(|| format!("{palette:?}"), |path| format!("{path:?}"));
+    match crate::palette::from_image(input) {
+      Ok(Some(colors)) => {
+        log::debug!("dithering {:?} to its {} embedded colors", input, colors.len());
+        return Ok(colors);
+      }
+      Ok(None) => log::warn!("{:?} has no embedded palette, using {name}", input),
+      Err(err) => log::warn!("{err}, using {name}"),
+    }
+  }
+  settings.dither_colors(palette)
+}
+
+/// Applies the per-pixel color adjustments of the settings.
+fn adjust_colors(image: &mut Image, settings: &Settings) {
+  if settings.exposure != 0.0 || settings.temperature != 0.0 || settings.tint != 0.0 {
+    stage("exposure and white balance", || {
+      // both are gains in linear light, combined into a single pass
+      let exposure = adjust::exposure_gain(settings.exposure);
+      let gains = adjust::white_balance_gains(settings.temperature, settings.tint).map(|gain| gain * exposure);
+      adjust::apply_gains(&mut image.buffer, gains);
+    });
+  }
+  if settings.saturation != 0.0 || settings.vibrance != 0.0 {
+    stage("saturation", || {
+      adjust::apply_saturation(&mut image.buffer, settings.saturation, settings.vibrance)
+    });
+  }
+  if let Some(gamma) = settings.display_gamma.or(settings.display.and_then(Display::gamma)) {
+    stage("display response", || display::apply_lut(&mut image.buffer, &display::response_lut(gamma)));
+  }
+}
+
+/// Dithers an image whose top left pixel is at `origin` within the whole image, and recolors the result.
+///
+/// Returns an error if `--export-palette` cannot be written.
+fn dither_image(image: &mut Image, method: DitherMethod, colors: &[Color], settings: &Settings, origin: (u32, u32)) -> Result<(), DitherError> {
+  let (width, height) = (image.width, image.height);
+  let options = DitherOptions {
+    alpha: None,
+    origin,
+    scan: settings.scan,
+    screen: Screen {
+      angle: settings.halftone_angle.unwrap_or(halftone::DEFAULT_ANGLE),
+      size: settings.halftone_size.unwrap_or(halftone::DEFAULT_SIZE),
+    },
+    seed: settings.seed,
+    bayer_size: settings.bayer_size.unwrap_or(dither::DEFAULT_BAYER_SIZE),
+  };
+  // binary alpha first, so that skipping transparent pixels uses the final transparency, with the
+  // options of the colors so that the pattern continues across strips and tiles
+  if let Some(alpha) = image.alpha.as_mut().filter(|_| settings.alpha_dither) {
+    let mut dithered = Ok(());
+    stage("alpha dithering", || dithered = dither::dither_alpha(alpha, method, width, height, &options));
+    dithered?;
+  }
+  // a transparent color makes the alpha binary, its transparent pixels are left out of dithering
+  let transparent = settings.transparent_color.filter(|_| image.alpha.is_some());
+  if let Some(alpha) = image.alpha.as_mut().filter(|_| transparent.is_some()) {
+    let threshold = settings.alpha_threshold.unwrap_or(dither::DEFAULT_ALPHA_THRESHOLD);
+    stage("alpha threshold", || dither::threshold_alpha(alpha, threshold));
+  }
+
+  let options = DitherOptions {
+    alpha: image.alpha.as_deref().filter(|_| settings.skip_transparent || transparent.is_some()),
+    ..options
+  };
+  if (settings.halftone_angle.is_some() || settings.halftone_size.is_some()) && method != DitherMethod::Halftone && origin == (0, 0) {
+    log::warn!("--halftone-angle and --halftone-size only apply to --dither halftone");
+  }
+  if settings.bayer_size.is_some() && method != DitherMethod::Bayer && origin == (0, 0) {
+    log::warn!("--bayer-size only applies to --dither bayer");
+  }
+  // a packed format can only store its own levels and a bilevel one only black and white,
+  // so dither to them unless told otherwise
+  let palette = if settings.format.is_some_and(|format| format.is_bilevel()) {
+    ColorPalette::Monochrome.colors()
+  } else {
+    colors
+  };
+  let target = settings.target.or(settings.format.and_then(|format| format.target()));
+  let mut colors = match settings.max_ink {
+    Some(_) if target.is_some() => {
+      log::warn!("--max-ink only applies to palettes, ignored for the bit-depth target");
+      palette.to_vec()
+    }
+    Some(max) => ink::limit_palette(palette, max),
+    None => palette.to_vec(),
+  };
+  // opaque pixels must not look transparent, unless the palette has nothing else
+  if let Some(color) = settings.transparent_color.filter(|_| target.is_none()) {
+    if colors.iter().all(|&c| c == color) {
+      log::warn!("--transparent-color {color} is the only palette color, opaque pixels keep it");
+    } else {
+      colors.retain(|&c| c != color);
+    }
+  }
+  // strips share their colors, the first one exports them for all
+  if let Some(path) = settings.export_palette.as_ref().filter(|_| origin == (0, 0)) {
+    if target.is_some() || settings.ham6 {
+      log::warn!("--export-palette needs a palette, nothing is written for the bit-depth target or --ham6");
+    } else {
+      let mut exported = Ok(());
+      stage("palette export", || exported = export_palette(path, &colors, transparent, settings));
+      exported?;
+    }
+  }
+  let amplitude = settings.noise_amplitude.unwrap_or(noise::DEFAULT_AMPLITUDE);
+  // explicit options override the layout of the console
+  let console = settings.console.map(Console::layout);
+  let sprite = settings.tile_palette.or(console.map(|layout| layout.tile_size)).map(|tile_size| SpriteLayout {
+    tile_size,
+    tile_colors: settings
+      .tile_colors
+      .or(console.map(|layout| layout.tile_colors))
+      .unwrap_or(sprite::DEFAULT_TILE_COLORS),
+    sub_palettes: settings.sub_palettes.or(console.and_then(|layout| layout.sub_palettes)),
+  });
+  if sprite.is_some() && target.is_some() {
+    log::warn!("--tile-palette only applies to palettes, ignored for the bit-depth target");
+  }
+  if settings.fast_mono && (!mono::is_monochrome(&colors) || settings.distance != DistanceMetric::Rgb || sprite.is_some() || target.is_some() || settings.ham6)
+  {
+    log::warn!("--fast-mono only applies to the monochrome palette, dithering with the generic path");
+  }
+  if settings.ham6 && (sprite.is_some() || target.is_some()) {
+    log::warn!("--ham6 chooses its own colors, ignoring --tile-palette and the bit-depth target");
+  }
+  if settings.lut.is_some() && (sprite.is_some() || target.is_some() || settings.ham6) {
+    log::warn!("--lut only applies to palettes, ignored with --tile-palette, --ham6 or the bit-depth target");
+  }
+  let palette_only = target.is_none() && sprite.is_none() && !settings.ham6;
+  let mixes = matches!(
+    method,
+    DitherMethod::Yliluoma1 | DitherMethod::Yliluoma2 | DitherMethod::Yliluoma3 | DitherMethod::Knoll
+  );
+  if mixes && (target.is_some() || settings.ham6) {
+    log::warn!("Yliluoma's and Knoll's dithering only mix palette colors, dithering with bayer8x8 for --ham6 or the bit-depth target");
+  }
+  let rgb = settings.distance == DistanceMetric::Rgb;
+  if !rgb && (target.is_some() || settings.ham6) {
+    log::warn!("--distance only applies to palettes, ignored with --ham6 or the bit-depth target");
+  }
+  if settings.lut.is_some() && !rgb && palette_only {
+    log::warn!("--lut only searches RGB distances, ignored with --distance {:?}", settings.distance);
+  }
+  // built once, the ensemble renders the image several times
+  let metric = (palette_only && !rgb).then(|| MetricPalette::new(&colors, settings.distance, settings.tie_break));
+  let mut lut = None;
+  if let Some(size) = settings.lut.filter(|_| palette_only && rgb) {
+    stage("lookup table", || lut = Some(ColorLut::new(&colors, size, settings.tie_break)));
+  }
+  let tree = (palette_only && rgb && lut.is_none() && colors.len() >= kdtree::MIN_COLORS).then(|| KdTree::new(&colors, settings.tie_break));
+  let render = |buffer: &mut Vec<u8>, seed: u64| {
+    // every run of an ensemble dithers randomly with its own seed
+    let options = DitherOptions { seed, ..options };
+    if let Some(kind) = settings.noise {
+      stage("noise", || noise::apply_noise(buffer, kind, amplitude, width, height, seed));
+    }
+    // the pipeline keeps the alpha channel at the size of the image, only an invalid input image fails
+    stage("dithering", || {
+      match target {
+        _ if settings.ham6 => ham::dither(buffer, method, width, height, &options),
+        Some(target) => dither::dither_with(buffer, method, &target, width, height, &options),
+        None => {
+          let quantizer = TieBroken {
+            colors: &colors,
+            metric: settings.distance,
+            tie_break: settings.tie_break,
+          };
+          match sprite {
+            Some(layout) if layout.sub_palettes.is_some() => clash::dither(buffer, method, quantizer, width, height, layout, &options),
+            Some(layout) => sprite::dither_tiles(buffer, method, quantizer, width, height, layout, &options),
+            None if rgb && settings.fast_mono && mono::is_monochrome(&colors) => {
+              let mut luma = mono::to_luma(buffer);
+              mono::dither(&mut luma, method, width, height, &options).map(|()| {
+                for (i, (pixel, &value)) in buffer.chunks_exact_mut(3).zip(&luma).enumerate() {
+                  // transparent pixels keep their color, like on the generic path
+                  if options.alpha.is_none_or(|alpha| alpha[i] > 0) {
+                    pixel.fill(value);
+                  }
+                }
+              })
+            }
+            None => match (&metric, &lut, &tree) {
+              (Some(metric), _, _) => dither::dither_with(buffer, method, metric, width, height, &options),
+              (None, Some(lut), _) => dither::dither_with(buffer, method, lut, width, height, &options),
+              (None, None, Some(tree)) => dither::dither_with(buffer, method, tree, width, height, &options),
+              (None, None, None) => dither::dither_with(buffer, method, &quantizer, width, height, &options),
+            },
+          }
+        }
+      }
+      .unwrap_or_else(|err| panic!("{err}"))
+    });
+  };
+
+  match settings.ensemble.filter(|&runs| runs > 1) {
+    Some(_) if settings.noise.is_none() && !matches!(method, DitherMethod::Random | DitherMethod::ZhouFang) => {
+      log::warn!("--ensemble needs a random input such as --noise, --dither random or --dither zhou-fang, dithering once");
+      render(&mut image.buffer, settings.seed);
+    }
+    Some(runs) => stage("ensemble", || {
+      let candidates: Vec<Vec<u8>> = (0..u64::from(runs))
+        .into_par_iter()
+        .map(|run| {
+          let mut buffer = image.buffer.clone();
+          render(&mut buffer, settings.seed.wrapping_add(run));
+          buffer
+        })
+        .collect();
+      image.buffer = ensemble::combine(&candidates, &image.buffer, width, height, settings.ensemble_mode);
+    }),
+    None => render(&mut image.buffer, settings.seed),
+  }
+
+  if settings.ink.is_some() || settings.paper.is_some() {
+    stage("ink and paper", || apply_ink_paper(&mut image.buffer, settings.ink, settings.paper));
+  }
+  if let (Some(color), Some(alpha)) = (transparent, &image.alpha) {
+    for (pixel, _) in image.buffer.chunks_exact_mut(3).zip(alpha).filter(|(_, a)| **a == 0) {
+      pixel.copy_from_slice(&[color.r, color.g, color.b]);
+    }
+  }
+  Ok(())
+}
+
+impl Settings {
+  /// Returns the size [`process`] gives an image of the given size, without processing it.
+  #[must_use]
+  pub fn output_size(&self, width: u32, height: u32) -> (u32, u32) {
+    let (width, height) = match self.resize {
+      Some(size) => scaled_size(width, height, size),
+      None => (width, height),
+    };
+    let frame = 2 * (self.border.unwrap_or(0) + self.pad.unwrap_or(0));
+    (width + frame, height + frame)
+  }
+
+  /// Returns the options for decoding inputs with these settings.
+  #[must_use]
+  pub fn load_options(&self) -> LoadOptions {
+    LoadOptions {
+      auto_orient: !self.no_auto_orient,
+      max_dimension: self.max_dimension,
+      max_pixels: self.max_pixels,
+    }
+  }
+
+  /// Returns the algorithm and number of colors of the palette generated from the image, if any.
+  #[must_use]
+  pub fn adaptive_palette(&self) -> Option<(PaletteQuantizer, usize)> {
+    let other_palette = self.palette_from.is_some() || self.duotone.is_some() || self.gradient.is_some();
+    if other_palette || (self.colors.is_none() && self.quantizer.is_none()) {
+      return None;
+    }
+    Some(self.quantization())
+  }
+
+  /// Returns the algorithm and number of colors of generated palettes.
+  fn quantization(&self) -> (PaletteQuantizer, usize) {
+    let colors = self.colors.unwrap_or(adaptive::DEFAULT_COLORS);
+    (self.quantizer.unwrap_or_default(), usize::from(colors))
+  }
+
+  /// Returns the colors of the palette options, otherwise those of `palette` (with `--levels` grays for the
+  /// gray palette).
+  ///
+  /// The palette options are, from the first to be used: `--duotone`, `--gradient`, `--palette-from`
+  /// and `--palette-file`.
+  pub fn dither_colors(&self, palette: ColorPalette) -> Result<Vec<Color>, DitherError> {
+    let given: Vec<&str> = [
+      ("--duotone", self.duotone.is_some()),
+      ("--gradient", self.gradient.is_some()),
+      ("--palette-from", self.palette_from.is_some()),
+      ("--palette-file", self.palette_file.is_some()),
+    ]
+    .into_iter()
+    .filter_map(|(option, given)| given.then_some(option))
+    .collect();
+    if given.len() > 1 {
+      log::warn!("{} are given, only {} is used", given.join(", "), given[0]);
+    }
+    if let Some(duotone) = self.duotone {
+      return Ok(duotone.colors().to_vec());
+    }
+    if let Some(gradient) = &self.gradient {
+      return Ok(gradient.colors(usize::from(self.colors.unwrap_or(DEFAULT_GRADIENT_COLORS))));
+    }
+    if let Some(path) = &self.palette_from {
+      let reference = input::load_with(path, &self.load_options())?;
+      let (quantizer, count) = self.quantization();
+      let colors = adaptive::palette(&reference, quantizer, count);
+      log::debug!("generated a palette of {} colors from {:?} with {:?}", colors.len(), path, quantizer);
+      return Ok(colors);
+    }
+    match (&self.palette_file, self.levels) {
+      (Some(path), _) => swatches::read(path),
+      (None, Some(levels)) if palette == ColorPalette::Gray => Ok(crate::palette::gray_levels(usize::from(levels))),
+      (None, Some(_)) => {
+        log::warn!("--levels only applies to the gray palette, using {:?}", palette);
+        Ok(palette.colors().to_vec())
+      }
+      (None, None) => Ok(palette.colors().to_vec()),
+    }
+  }
+}
+
+/// Runs a processing stage, logging when it starts and how long it took at debug level.
+fn stage(name: &str, run: impl FnOnce()) {
+  log::debug!("{name}: started");
+  let start = Instant::now();
+  run();
+  log::debug!("{name}: done in {:.1?}", start.elapsed());
+}
+
+/// Writes the colors of the output into a palette file: the palette recolored with ink and paper,
+/// followed by the color of transparent pixels if there is one.
+fn export_palette(path: &Path, colors: &[Color], transparent: Option<Color>, settings: &Settings) -> Result<(), DitherError> {
+  let mut buffer: Vec<u8> = colors.iter().flat_map(|color| [color.r, color.g, color.b]).collect();
+  apply_ink_paper(&mut buffer, settings.ink, settings.paper);
+  let mut colors: Vec<Color> = buffer.chunks_exact(3).map(Color::from).collect();
+  colors.extend(transparent.filter(|color| !colors.contains(color)));
+  swatches::write(path, &colors)?;
+  log::info!("Exported the palette of {} colors to {:?}", colors.len(), path);
+  Ok(())
+}
+
+/// Recolors the two monochrome levels of a dithered buffer.
+///
+/// Black pixels become `ink` and white pixels become `paper`, all other colors are left untouched.
+pub fn apply_ink_paper(buffer: &mut [u8], ink: Option<Color>, paper: Option<Color>) {
+  const BLACK: Color = Color { r: 0x00, g: 0x00, b: 0x00 };
+  const WHITE: Color = Color { r: 0xff, g: 0xff, b: 0xff };
+
+  for pixel in buffer.chunks_exact_mut(3) {
+    let replacement = match Color::from(&*pixel) {
+      BLACK => ink,
+      WHITE => paper,
+      _ => None,
+    };
+    if let Some(color) = replacement {
+      pixel.copy_from_slice(&[color.r, color.g, color.b]);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn image(buffer: Vec<u8>, width: u32, height: u32) -> Image {
+    Image {
+      buffer,
+      alpha: None,
+      width,
+      height,
+    }
+  }
+
+  #[test]
+  fn test_settings_default_is_noop() {
+    let mut image = image(vec![0, 0, 0, 255, 255, 255], 2, 1);
+    process(&mut image, DitherMethod::None, ColorPalette::Monochrome, &Settings::default()).unwrap();
+    assert_eq!(image.buffer, vec![0, 0, 0, 255, 255, 255]);
+  }
+
+  #[test]
+  fn test_shrink_to_fit_keeps_aspect_ratio() {
+    let shrunk = image(vec![128; 400 * 100 * 3], 400, 100).shrink_to_fit(100, 100);
+    assert_eq!((shrunk.width, shrunk.height), (100, 25));
+    assert_eq!(shrunk.buffer.len(), 100 * 25 * 3);
+
+    let small = image(vec![128; 20 * 10 * 3], 20, 10).shrink_to_fit(100, 100);
+    assert_eq!((small.width, small.height), (20, 10), "Small images should not be enlarged");
+  }
+
+  #[test]
+  fn test_scale_to_longer_side() {
+    let scaled = image(vec![128; 40 * 20 * 3], 40, 20).scale_to(16);
+    assert_eq!((scaled.width, scaled.height), (16, 8));
+    let enlarged = image(vec![128; 4 * 4 * 3], 4, 4).scale_to(32);
+    assert_eq!((enlarged.width, enlarged.height), (32, 32), "Small images should be enlarged");
+    assert_eq!(enlarged.buffer.len(), 32 * 32 * 3);
+  }
+
+  #[test]
+  fn test_pad_surrounds_image() {
+    let padded = Image {
+      alpha: Some(vec![7; 2]),
+      ..image(vec![1, 2, 3, 4, 5, 6], 2, 1)
+    }
+    .pad(1, Color { r: 9, g: 9, b: 9 });
+    assert_eq!((padded.width, padded.height), (4, 3));
+    assert_eq!(&padded.buffer[4 * 3..8 * 3], &[9, 9, 9, 1, 2, 3, 4, 5, 6, 9, 9, 9]);
+    assert_eq!(padded.alpha.unwrap(), vec![255, 255, 255, 255, 255, 7, 7, 255, 255, 255, 255, 255]);
+  }
+
+  #[test]
+  fn test_process_border_inside_padding() {
+    let mut image = image(vec![0; 2 * 2 * 3], 2, 2);
+    let settings = Settings {
+      border: Some(1),
+      pad: Some(2),
+      ..Settings::default()
+    };
+    process(&mut image, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, &settings).unwrap();
+    assert_eq!((image.width, image.height), (8, 8));
+    let row = |y: usize| image.buffer[y * 8 * 3..(y + 1) * 8 * 3].chunks_exact(3).map(|p| p[0]).collect::<Vec<u8>>();
+    assert_eq!(row(0), vec![255; 8]);
+    assert_eq!(row(3), vec![255, 255, 0, 0, 0, 0, 255, 255]);
+  }
+
+  #[test]
+  fn test_output_size_matches_process() {
+    let settings = Settings {
+      resize: Some(16),
+      border: Some(1),
+      pad: Some(3),
+      ..Settings::default()
+    };
+    let mut image = image(vec![100; 40 * 20 * 3], 40, 20);
+    process(&mut image, DitherMethod::None, ColorPalette::Monochrome, &settings).unwrap();
+    assert_eq!(settings.output_size(40, 20), (image.width, image.height));
+    assert_eq!(Settings::default().output_size(40, 20), (40, 20));
+  }
+
+  #[test]
+  fn test_process_resizes_before_dithering() {
+    let mut image = image(vec![100; 64 * 64 * 3], 64, 64);
+    let settings = Settings {
+      resize: Some(16),
+      ..Settings::default()
+    };
+    process(&mut image, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, &settings).unwrap();
+    assert_eq!((image.width, image.height), (16, 16));
+    assert!(image.buffer.iter().all(|&v| v == 0 || v == 255), "Resized pixels should be dithered");
+  }
+
+  #[test]
+  fn test_apply_ink_paper() {
+    let mut buffer = vec![0, 0, 0, 255, 255, 255, 10, 20, 30];
+    let ink = Color { r: 0x22, g: 0x22, b: 0x22 };
+    let paper = Color { r: 0xf5, g: 0xe9, b: 0xd0 };
+
+    apply_ink_paper(&mut buffer, Some(ink), Some(paper));
+
+    assert_eq!(buffer, vec![0x22, 0x22, 0x22, 0xf5, 0xe9, 0xd0, 10, 20, 30]);
+  }
+
+  #[test]
+  fn test_apply_ink_paper_is_single_pass() {
+    let mut buffer = vec![0, 0, 0, 255, 255, 255];
+    apply_ink_paper(&mut buffer, Some(Color { r: 255, g: 255, b: 255 }), None);
+    // recoloring is a single pass, the new white ink is not turned into paper
+    assert_eq!(buffer, vec![255, 255, 255, 255, 255, 255]);
+  }
+
+  #[test]
+  fn test_process_recolors_monochrome_output() {
+    let mut image = image(vec![10, 10, 10, 240, 240, 240], 2, 1);
+    let settings = Settings {
+      ink: Some("#222".parse().unwrap()),
+      paper: Some("#f5e9d0".parse().unwrap()),
+      ..Settings::default()
+    };
+
+    process(&mut image, DitherMethod::None, ColorPalette::Monochrome, &settings).unwrap();
+
+    assert_eq!(image.buffer, vec![0x22, 0x22, 0x22, 0xf5, 0xe9, 0xd0]);
+  }
+
+  #[test]
+  fn test_process_skip_transparent() {
+    let mut transparent = image(vec![100; 6], 2, 1);
+    transparent.alpha = Some(vec![0, 255]);
+    let settings = Settings {
+      skip_transparent: true,
+      ..Settings::default()
+    };
+
+    let mut dithered = transparent.clone();
+    process(&mut dithered, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, &Settings::default()).unwrap();
+    assert_eq!(
+      dithered.buffer,
+      vec![0, 0, 0, 255, 255, 255],
+      "Without the flag transparent pixels are dithered"
+    );
+
+    process(&mut transparent, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, &settings).unwrap();
+    assert_eq!(transparent.buffer, vec![100, 100, 100, 0, 0, 0]);
+    assert_eq!(transparent.alpha, Some(vec![0, 255]), "Alpha channel should be preserved");
+  }
+
+  #[test]
+  fn test_process_alpha_dither() {
+    let mut translucent = image(vec![100; 12], 4, 1);
+    translucent.alpha = Some(vec![64, 128, 192, 255]);
+    let settings = Settings {
+      alpha_dither: true,
+      ..Settings::default()
+    };
+
+    process(&mut translucent, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, &settings).unwrap();
+
+    let alpha = translucent.alpha.unwrap();
+    assert!(alpha.iter().all(|&a| a == 0 || a == 255), "Alpha should be binary, got {:?}", alpha);
+    assert_eq!(alpha[3], 255);
+  }
+
+  #[test]
+  fn test_process_transparent_color() {
+    let magenta = Color { r: 255, g: 0, b: 255 };
+    let colors = [Color { r: 0, g: 0, b: 0 }, Color { r: 255, g: 255, b: 255 }, magenta];
+    let mut sprite = image(vec![10, 20, 30, 255, 0, 255, 200, 200, 200], 3, 1);
+    sprite.alpha = Some(vec![10, 127, 128]);
+    let settings = Settings {
+      transparent_color: Some(magenta),
+      alpha_threshold: Some(128),
+      ..Settings::default()
+    };
+
+    process_with_colors(&mut sprite, DitherMethod::None, &colors, &settings).unwrap();
+
+    assert_eq!(sprite.alpha, Some(vec![0, 0, 255]), "Alpha should be thresholded");
+    assert_eq!(
+      sprite.buffer,
+      vec![255, 0, 255, 255, 0, 255, 255, 255, 255],
+      "Only transparent pixels get magenta"
+    );
+
+    let mut opaque = image(vec![255, 0, 255], 1, 1);
+    opaque.alpha = Some(vec![255]);
+    process_with_colors(&mut opaque, DitherMethod::None, &colors, &settings).unwrap();
+    assert_eq!(opaque.buffer, vec![255; 3], "Opaque magenta should not map to the transparent color");
+  }
+
+  #[test]
+  fn test_process_halftone_screen() {
+    let halftone = |angle: f32, size: f32| {
+      let mut image = image(vec![160; 3 * 32 * 32], 32, 32);
+      let settings = Settings {
+        halftone_angle: Some(angle),
+        halftone_size: Some(size),
+        ..Settings::default()
+      };
+      process(&mut image, DitherMethod::Halftone, ColorPalette::Monochrome, &settings).unwrap();
+      image.buffer
+    };
+
+    let screened = halftone(45.0, 6.0);
+    assert!(screened.iter().all(|&v| v == 0 || v == 255));
+    assert_ne!(screened, halftone(0.0, 6.0), "The angle should rotate the screen");
+    assert_ne!(screened, halftone(45.0, 10.0), "The size should scale the screen");
+  }
+
+  #[test]
+  fn test_process_random_uses_seed() {
+    let random = |seed: u64| {
+      let mut image = image(vec![100; 3 * 16 * 16], 16, 16);
+      let settings = Settings { seed, ..Settings::default() };
+      process(&mut image, DitherMethod::Random, ColorPalette::Monochrome, &settings).unwrap();
+      image.buffer
+    };
+    assert_eq!(random(7), random(7));
+    assert_ne!(random(7), random(8));
+  }
+
+  #[test]
+  fn test_process_target_replaces_palette() {
+    let mut image = image(vec![100, 150, 200], 1, 1);
+    let settings = Settings {
+      target: Some(Target::Rgb565),
+      ..Settings::default()
+    };
+
+    process(&mut image, DitherMethod::None, ColorPalette::Monochrome, &settings).unwrap();
+
+    assert_eq!(image.buffer, vec![99, 150, 197]);
+  }
+
+  #[test]
+  fn test_process_exposure_before_dithering() {
+    let mut image = image(vec![100; 3], 1, 1);
+    let settings = Settings {
+      exposure: 2.0,
+      ..Settings::default()
+    };
+
+    process(&mut image, DitherMethod::None, ColorPalette::Monochrome, &settings).unwrap();
+
+    assert_eq!(image.buffer, vec![255; 3], "Brightened gray should map to white");
+  }
+
+  #[test]
+  fn test_process_saturation_before_dithering() {
+    let muted = vec![170, 100, 80];
+    let settings = Settings {
+      saturation: 1.0,
+      ..Settings::default()
+    };
+
+    let mut plain = image(muted.clone(), 1, 1);
+    process(&mut plain, DitherMethod::None, ColorPalette::COLOR8, &Settings::default()).unwrap();
+    let mut boosted = image(muted, 1, 1);
+    process(&mut boosted, DitherMethod::None, ColorPalette::COLOR8, &settings).unwrap();
+
+    assert_ne!(plain.buffer, boosted.buffer, "Boosted color should map to a more saturated entry");
+  }
+
+  #[test]
+  fn test_process_noise_breaks_up_bands() {
+    let mut image = image(vec![100; 3 * 64], 8, 8);
+    let settings = Settings {
+      noise: Some(Noise::Blue),
+      noise_amplitude: Some(64.0),
+      ..Settings::default()
+    };
+
+    process(&mut image, DitherMethod::None, ColorPalette::Monochrome, &settings).unwrap();
+
+    let white = image.buffer.chunks_exact(3).filter(|p| p[0] == 255).count();
+    assert!(white > 0 && white < 64, "Noise should mix black and white, got {} white pixels", white);
+  }
+
+  #[test]
+  fn test_process_ensemble_combines_seeds() {
+    let noisy = |ensemble: Option<u32>, seed: u64| {
+      let mut image = image(vec![100; 3 * 256], 16, 16);
+      let settings = Settings {
+        noise: Some(Noise::Gaussian),
+        noise_amplitude: Some(64.0),
+        seed,
+        ensemble,
+        ..Settings::default()
+      };
+      process(&mut image, DitherMethod::None, ColorPalette::Monochrome, &settings).unwrap();
+      image.buffer
+    };
+
+    assert_eq!(noisy(Some(1), 3), noisy(None, 3), "A single run should not change the result");
+    let runs: Vec<Vec<u8>> = (3..8).map(|seed| noisy(None, seed)).collect();
+    let combined = noisy(Some(5), 3);
+    assert_eq!(combined, ensemble::combine(&runs, &[100; 3 * 256], 16, 16, EnsembleMode::Majority));
+    assert_ne!(combined, runs[0]);
+  }
+
+  #[test]
+  fn test_process_display_response_before_dithering() {
+    // sRGB mid-gray is about a fifth of the light, which a linear LED matrix shows with a fifth of the pixels lit
+    let lit = |settings: &Settings| {
+      let mut image = image(vec![128; 3 * 64], 8, 8);
+      process(&mut image, DitherMethod::Bayer8x8, ColorPalette::Monochrome, settings).unwrap();
+      image.buffer.chunks_exact(3).filter(|p| p[0] == 255).count()
+    };
+    let led = Settings {
+      display: Some(Display::Led),
+      ..Settings::default()
+    };
+    let plain = lit(&Settings::default());
+    assert_eq!(plain, 31);
+    assert_eq!(lit(&led), 13);
+
+    let srgb = Settings {
+      display: Some(Display::Srgb),
+      ..Settings::default()
+    };
+    assert_eq!(lit(&srgb), plain);
+    let gamma = Settings {
+      display_gamma: Some(1.0),
+      ..srgb
+    };
+    assert_eq!(lit(&gamma), 13, "--display-gamma should override the preset");
+  }
+
+  #[test]
+  fn test_process_max_ink_avoids_heavy_colors() {
+    let mut image = image(vec![255, 0, 0, 0, 0, 255], 2, 1);
+    let settings = Settings {
+      max_ink: Some(150.0),
+      ..Settings::default()
+    };
+    process(&mut image, DitherMethod::None, ColorPalette::COLOR8, &settings).unwrap();
+    assert!(
+      image.buffer.chunks_exact(3).all(|p| ink::coverage(Color::from(p)) <= 150.0),
+      "got {:?}",
+      image.buffer
+    );
+  }
+
+  #[test]
+  fn test_process_tile_palette() {
+    let mut image = image((0..16 * 16 * 3).map(|i| (i % 251) as u8).collect(), 16, 16);
+    let settings = Settings {
+      tile_palette: Some(8),
+      tile_colors: Some(3),
+      ..Settings::default()
+    };
+    process(&mut image, DitherMethod::Bayer4x4, ColorPalette::COLOR16, &settings).unwrap();
+    for tile in crate::tiles::grid(16, 16, 8) {
+      let pixels = tile.extract(&image.buffer, 16, 3);
+      let colors: std::collections::HashSet<Color> = pixels.chunks_exact(3).map(Color::from).collect();
+      assert!(colors.len() <= 3);
+    }
+  }
+
+  #[test]
+  fn test_process_console_shares_sub_palettes() {
+    let mut image = image((0..32 * 32 * 3).map(|i| (i * 7 % 256) as u8).collect(), 32, 32);
+    let settings = Settings {
+      console: Some(Console::Gbc),
+      sub_palettes: Some(2),
+      ..Settings::default()
+    };
+    process(&mut image, DitherMethod::FloydSteinberg, ColorPalette::COLOR16, &settings).unwrap();
+    let colors: std::collections::HashSet<Color> = image.buffer.chunks_exact(3).map(Color::from).collect();
+    assert!(
+      colors.len() <= 2 * 4,
+      "Two sub-palettes of four colors allow at most 8 colors, got {}",
+      colors.len()
+    );
+  }
+
+  #[test]
+  fn test_process_ham6_uses_12_bit_colors() {
+    let mut image = image((0..16 * 8 * 3).map(|i| (i * 11 % 256) as u8).collect(), 16, 8);
+    let settings = Settings {
+      ham6: true,
+      ..Settings::default()
+    };
+    process(&mut image, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, &settings).unwrap();
+    assert!(image.buffer.iter().all(|&value| value % 17 == 0));
+    assert!(image.buffer.chunks_exact(3).any(|p| p[0] != p[1] || p[1] != p[2]), "HAM6 ignores the palette");
+  }
+
+  #[test]
+  fn test_process_draws_caption_before_dithering() {
+    let mut image = image(vec![0; 40 * 20 * 3], 40, 20);
+    let settings = Settings {
+      caption: Some("OK".to_string()),
+      caption_scale: Some(1),
+      ..Settings::default()
+    };
+    process(&mut image, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, &settings).unwrap();
+    assert!(image.buffer.contains(&255), "The caption should survive dithering");
+  }
+
+  #[test]
+  fn test_process_bilevel_format_implies_monochrome() {
+    let mut image = image(vec![255, 0, 0, 30, 30, 200], 2, 1);
+    let settings = Settings {
+      format: Some(OutputFormat::TiffG4),
+      ..Settings::default()
+    };
+
+    process(&mut image, DitherMethod::None, ColorPalette::COLOR16, &settings).unwrap();
+
+    assert!(image.buffer.iter().all(|&v| v == 0 || v == 255), "got {:?}", image.buffer);
+  }
+
+  #[test]
+  fn test_process_format_implies_target() {
+    let mut image = image(vec![100, 150, 200], 1, 1);
+    let settings = Settings {
+      format: Some(OutputFormat::Rgb565Be),
+      ..Settings::default()
+    };
+
+    process(&mut image, DitherMethod::None, ColorPalette::Monochrome, &settings).unwrap();
+
+    assert_eq!(image.buffer, vec![99, 150, 197]);
+  }
+
+  #[test]
+  fn test_settings_colors_gray_levels() {
+    let settings = Settings {
+      levels: Some(8),
+      ..Settings::default()
+    };
+    let grays = settings.dither_colors(ColorPalette::Gray).unwrap();
+    assert_eq!(grays.len(), 8);
+    assert_eq!((grays[1], grays[7]), (Color { r: 36, g: 36, b: 36 }, Color { r: 255, g: 255, b: 255 }));
+    assert_eq!(settings.dither_colors(ColorPalette::COLOR8).unwrap(), ColorPalette::COLOR8.colors());
+    assert_eq!(Settings::default().dither_colors(ColorPalette::Gray).unwrap().len(), 4);
+  }
+
+  #[test]
+  fn test_process_duotone() {
+    let mut image = image(vec![30, 30, 30, 100, 100, 100, 240, 240, 240], 3, 1);
+    let settings = Settings {
+      duotone: Some("#112233,#eeddcc".parse().unwrap()),
+      colors: Some(8),
+      ..Settings::default()
+    };
+    let colors = settings.dither_colors(ColorPalette::COLOR16).unwrap();
+    assert_eq!(colors, settings.duotone.unwrap().colors());
+
+    process_with_colors(&mut image, DitherMethod::None, &colors, &settings).unwrap();
+    assert_eq!(image.buffer, vec![0x11, 0x22, 0x33, 0x11, 0x22, 0x33, 0xee, 0xdd, 0xcc]);
+  }
+
+  #[test]
+  fn test_lut_and_kd_tree_match_linear_search() {
+    let buffer: Vec<u8> = (0..48 * 16 * 3).map(|i| ((i * 37 + i / 7 * 11) % 256) as u8).collect();
+    let colors = ColorPalette::Vga256.colors();
+    let mut linear = buffer.clone();
+    dither::dither_with(&mut linear, DitherMethod::Atkinson, colors, 48, 16, &DitherOptions::default()).unwrap();
+    for lut in [None, Some(16)] {
+      let mut image = image(buffer.clone(), 48, 16);
+      let settings = Settings { lut, ..Settings::default() };
+      process_with_colors(&mut image, DitherMethod::Atkinson, colors, &settings).unwrap();
+      assert_eq!(image.buffer, linear, "with --lut {lut:?}");
+    }
+  }
+
+  #[test]
+  fn test_export_palette() {
+    let dir = std::env::temp_dir().join("dithers_export_palette_test");
+    std::fs::create_dir_all(&dir).unwrap();
+    let paper = Color { r: 0xf5, g: 0xe9, b: 0xd0 };
+    let mut settings = Settings {
+      export_palette: Some(dir.join("paper.gpl")),
+      paper: Some(paper),
+      ..Settings::default()
+    };
+    let mut paper_image = image(vec![30, 30, 30, 240, 240, 240], 2, 1);
+    process_with_colors(&mut paper_image, DitherMethod::None, ColorPalette::Monochrome.colors(), &settings).unwrap();
+    assert_eq!(swatches::read(&dir.join("paper.gpl")).unwrap(), [Color { r: 0, g: 0, b: 0 }, paper]);
+
+    // generated palettes are exported as well
+    settings.export_palette = Some(dir.join("generated.hex"));
+    settings.colors = Some(2);
+    settings.paper = None;
+    let mut generated = image(vec![200, 20, 20, 20, 20, 200], 2, 1);
+    process_with_colors(&mut generated, DitherMethod::None, ColorPalette::Monochrome.colors(), &settings).unwrap();
+    let mut exported = swatches::read(&dir.join("generated.hex")).unwrap();
+    exported.sort_by_key(|color| color.r);
+    assert_eq!(exported, [Color { r: 20, g: 20, b: 200 }, Color { r: 200, g: 20, b: 20 }]);
+
+    // transparent pixels get their color, so the palette lists it
+    let magenta = Color { r: 0xff, g: 0, b: 0xff };
+    let settings = Settings {
+      export_palette: Some(dir.join("transparent.hex")),
+      transparent_color: Some(magenta),
+      ..Settings::default()
+    };
+    let mut transparent = Image {
+      alpha: Some(vec![0, 255]),
+      ..image(vec![30, 30, 30, 240, 240, 240], 2, 1)
+    };
+    process_with_colors(&mut transparent, DitherMethod::None, ColorPalette::Monochrome.colors(), &settings).unwrap();
+    assert_eq!(
+      swatches::read(&dir.join("transparent.hex")).unwrap(),
+      [ColorPalette::Monochrome.colors()[0], ColorPalette::Monochrome.colors()[1], magenta]
+    );
+
+    let settings = Settings {
+      export_palette: Some(dir.join("missing").join("palette.hex")),
+      ..Settings::default()
+    };
+    let mut unwritten = image(vec![30, 30, 30], 1, 1);
+    assert!(process_with_colors(&mut unwritten, DitherMethod::None, ColorPalette::Monochrome.colors(), &settings).is_err());
+
+    std::fs::remove_dir_all(dir).unwrap();
+  }
+
+  #[test]
+  fn test_settings_colors_gradient() {
+    let mut settings = Settings {
+      gradient: Some("#000,#f80,#fff".parse().unwrap()),
+      ..Settings::default()
+    };
+    assert_eq!(
+      settings.dither_colors(ColorPalette::Monochrome).unwrap().len(),
+      usize::from(DEFAULT_GRADIENT_COLORS)
+    );
+    settings.colors = Some(3);
+    assert_eq!(settings.adaptive_palette(), None, "--colors sizes the gradient");
+    assert_eq!(
+      settings.dither_colors(ColorPalette::Monochrome).unwrap(),
+      [0x000000u32, 0xff8800, 0xffffff].map(Color::from)
+    );
+  }
+
+  #[test]
+  fn test_process_fails_without_overlay() {
+    let mut image = Image {
+      buffer: vec![128; 4 * 4 * 3],
+      alpha: None,
+      width: 4,
+      height: 4,
+    };
+    let settings = Settings {
+      overlay: Some(std::env::temp_dir().join("dithers_missing_overlay.png")),
+      ..Settings::default()
+    };
+    let result = process(&mut image, DitherMethod::None, ColorPalette::Monochrome, &settings);
+    assert!(matches!(result, Err(DitherError::Io { .. })), "{result:?}");
+  }
+}
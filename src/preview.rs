@@ -0,0 +1,138 @@
+//! Interactive terminal preview for A/B comparisons.
+//!
+//! Images are drawn with truecolor half-block characters, two pixels per character cell, so results can
+//! be judged over SSH without copying files around. The image is shrunk to the terminal before
+//! dithering, so every pixel of the dither pattern is visible. Keys toggle between the original and
+//! the dithered result and step through the dithering methods.
+
+use std::io::{self, Write};
+
+use clap::ValueEnum;
+use crossterm::event::{self, Event, KeyCode, KeyEventKind, KeyModifiers};
+use crossterm::{cursor, execute, queue, terminal};
+
+use crate::ansi::{self, ColorCodes};
+use crate::dither::DitherMethod;
+use crate::palette::ColorPalette;
+use crate::pipeline::{self, Image, Settings};
+
+/// Renders an image as lines of half-block characters with 24-bit colors.
+///
+/// Each character shows two pixels, the upper one as foreground and the lower one as background.
+/// Lines are separated by `\r\n`, so the output also works in raw mode.
+#[must_use]
+pub fn render(image: &Image) -> String {
+  ansi::render(image, ColorCodes::TrueColor)
+}
+
+/// Shows the image in the terminal until the user quits.
+///
+/// Space or Tab toggles between the original and the dithered image, the arrow keys (or `n` and `p`)
+/// select the next and previous dithering method, `q` or Escape quits.
+pub fn run(image: &Image, method: DitherMethod, palette: ColorPalette, settings: &Settings) -> io::Result<()> {
+  let mut stdout = io::stdout();
+  terminal::enable_raw_mode()?;
+  execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+  let result = event_loop(&mut stdout, image, method, palette, settings);
+  // restore the terminal even if drawing failed
+  execute!(stdout, cursor::Show, terminal::LeaveAlternateScreen)?;
+  terminal::disable_raw_mode()?;
+  result
+}
+
+fn event_loop(stdout: &mut impl Write, image: &Image, method: DitherMethod, palette: ColorPalette, settings: &Settings) -> io::Result<()> {
+  let methods = DitherMethod::value_variants();
+  let mut index = methods.iter().position(|&m| m == method).unwrap_or(0);
+  let mut show_original = false;
+  // the shrunk original and the dithered versions of it, rebuilt when the terminal is resized
+  let mut fitted_size = None;
+  let mut original = image.clone();
+  let mut dithered: Vec<Option<Image>> = vec![None; methods.len()];
+
+  loop {
+    let (columns, rows) = terminal::size()?;
+    // the last line holds the status
+    let size = (u32::from(columns), u32::from(rows.saturating_sub(1)) * 2);
+    if fitted_size != Some(size) {
+      fitted_size = Some(size);
+      original = image.clone().shrink_to_fit(size.0.max(1), size.1.max(2));
+      dithered.iter_mut().for_each(|d| *d = None);
+    }
+
+    let name = methods[index].to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default();
+    let (frame, label) = if show_original {
+      (&original, "original".to_string())
+    } else {
+      let frame = match dithered[index].take() {
+        Some(frame) => frame,
+        None => {
+          let mut frame = original.clone();
+          pipeline::process(&mut frame, methods[index], palette, settings).map_err(io::Error::other)?;
+          frame
+        }
+      };
+      (&*dithered[index].insert(frame), name)
+    };
+    queue!(stdout, terminal::Clear(terminal::ClearType::All), cursor::MoveTo(0, 0))?;
+    write!(stdout, "{}", render(frame))?;
+    queue!(stdout, cursor::MoveTo(0, rows.saturating_sub(1)))?;
+    write!(stdout, "{label}  [space] original/dithered  [\u{2190}/\u{2192}] method  [q] quit")?;
+    stdout.flush()?;
+
+    // redraw after every key press and terminal resize
+    loop {
+      match event::read()? {
+        Event::Key(key) if key.kind == KeyEventKind::Press => {
+          match key.code {
+            KeyCode::Char(' ') | KeyCode::Tab => show_original = !show_original,
+            KeyCode::Right | KeyCode::Char('n') => {
+              index = (index + 1) % methods.len();
+              show_original = false;
+            }
+            KeyCode::Left | KeyCode::Char('p') => {
+              index = (index + methods.len() - 1) % methods.len();
+              show_original = false;
+            }
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Char('c') if key.modifiers.contains(KeyModifiers::CONTROL) => return Ok(()),
+            _ => continue,
+          }
+          break;
+        }
+        Event::Resize(..) => break,
+        _ => {}
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_render_pairs_rows() {
+    let image = Image {
+      buffer: vec![255, 0, 0, 0, 0, 255],
+      alpha: None,
+      width: 1,
+      height: 2,
+    };
+    assert_eq!(render(&image), "\x1b[38;2;255;0;0m\x1b[48;2;0;0;255m\u{2580}\x1b[0m");
+  }
+
+  #[test]
+  fn test_render_odd_height() {
+    let image = Image {
+      buffer: vec![10; 2 * 3 * 3],
+      alpha: None,
+      width: 2,
+      height: 3,
+    };
+    let out = render(&image);
+    let lines: Vec<&str> = out.split("\r\n").collect();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[1].matches('\u{2580}').count(), 2);
+    assert!(lines[1].contains("\x1b[49m"), "The last line should have no background");
+  }
+}
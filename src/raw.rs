@@ -0,0 +1,224 @@
+//! Camera RAW decoding, available with the `raw` feature.
+//!
+//! The sensor data is decoded by `rawloader` and developed with a basic pipeline: black and white
+//! level scaling, a bilinear demosaic, the camera white balance and the camera to sRGB color matrix.
+//! This is no replacement for a RAW converter, but good enough to dither straight from RAW.
+
+use std::path::Path;
+
+use rawloader::{RawImage, RawImageData};
+
+use crate::adjust;
+use crate::error::DitherError;
+use crate::pipeline::Image;
+
+/// File extensions of the RAW formats handed to `rawloader`.
+const EXTENSIONS: [&str; 14] = ["3fr", "arw", "cr2", "crw", "dcr", "dng", "erf", "kdc", "mef", "mos", "nef", "orf", "raf", "rw2"];
+
+/// Linear sRGB (D65) to XYZ matrix.
+const SRGB_TO_XYZ: [[f32; 3]; 3] = [
+  [0.412_456_4, 0.357_576_1, 0.180_437_5],
+  [0.212_672_9, 0.715_152_2, 0.072_175],
+  [0.019_333_9, 0.119_192, 0.950_304_1],
+];
+
+/// Returns whether the file extension belongs to a camera RAW format.
+#[must_use]
+pub fn is_raw(path: &Path) -> bool {
+  path
+    .extension()
+    .is_some_and(|ext| EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str()))
+}
+
+/// Opens a camera RAW file and develops it into an sRGB image.
+pub fn load(path: &Path) -> Result<Image, DitherError> {
+  let raw = rawloader::decode_file(path).map_err(|err| DitherError::Raw {
+    path: path.to_path_buf(),
+    message: err.to_string(),
+  })?;
+  log::debug!("decoded RAW {:?}: {} {} ({}x{})", path, raw.clean_make, raw.clean_model, raw.width, raw.height);
+  Ok(develop(&raw))
+}
+
+/// Develops decoded sensor data into an sRGB image.
+fn develop(raw: &RawImage) -> Image {
+  let samples: Vec<f32> = match &raw.data {
+    RawImageData::Integer(data) => data.iter().map(|&v| f32::from(v)).collect(),
+    RawImageData::Float(data) => data.clone(),
+  };
+  // crops are top, right, bottom, left
+  let [top, right, bottom, left] = raw.crops;
+  let width = raw.width - left - right;
+  let height = raw.height - top - bottom;
+
+  let level = |value: f32, color: usize| {
+    let black = f32::from(raw.blacklevels[color]);
+    let white = f32::from(raw.whitelevels[color]);
+    ((value - black) / (white - black).max(1.0)).max(0.0)
+  };
+  let linear: Vec<[f32; 3]> = if raw.cpp == 3 {
+    (top..top + height)
+      .flat_map(|y| (left..left + width).map(move |x| y * raw.width + x))
+      .map(|i| std::array::from_fn(|c| level(samples[i * 3 + c], c)))
+      .collect()
+  } else {
+    let scaled: Vec<f32> = samples
+      .iter()
+      .enumerate()
+      .map(|(i, &v)| level(v, raw.cfa.color_at(i / raw.width, i % raw.width)))
+      .collect();
+    // the fourth CFA color ("emerald" or a second green) is treated as green
+    let color_at = |y: usize, x: usize| match raw.cfa.color_at(y, x) {
+      3 => 1,
+      color => color,
+    };
+    let full = demosaic(&scaled, raw.width, raw.height, color_at);
+    (top..top + height)
+      .flat_map(|y| (left..left + width).map(move |x| y * raw.width + x))
+      .map(|i| full[i])
+      .collect()
+  };
+
+  let balance = white_balance(raw.wb_coeffs);
+  let matrix = camera_to_srgb(raw.xyz_to_cam);
+  let buffer = linear
+    .iter()
+    .flat_map(|pixel| {
+      let balanced: [f32; 3] = std::array::from_fn(|c| pixel[c] * balance[c]);
+      let rgb: [f32; 3] = std::array::from_fn(|row| (0..3).map(|c| matrix[row][c] * balanced[c]).sum());
+      rgb.map(adjust::linear_to_srgb)
+    })
+    .collect();
+  Image {
+    buffer,
+    alpha: None,
+    width: width as u32,
+    height: height as u32,
+  }
+}
+
+/// Bilinear demosaic: every channel is the mean of the samples of that color in the 3x3 neighborhood.
+fn demosaic(samples: &[f32], width: usize, height: usize, color_at: impl Fn(usize, usize) -> usize) -> Vec<[f32; 3]> {
+  let mut pixels = Vec::with_capacity(width * height);
+  for y in 0..height {
+    for x in 0..width {
+      let mut sum = [0.0f32; 3];
+      let mut count = [0u32; 3];
+      for ny in y.saturating_sub(1)..(y + 2).min(height) {
+        for nx in x.saturating_sub(1)..(x + 2).min(width) {
+          let color = color_at(ny, nx);
+          sum[color] += samples[ny * width + nx];
+          count[color] += 1;
+        }
+      }
+      pixels.push(std::array::from_fn(|c| if count[c] > 0 { sum[c] / count[c] as f32 } else { 0.0 }));
+    }
+  }
+  pixels
+}
+
+/// Returns the camera white balance multipliers normalized to green.
+fn white_balance(coefficients: [f32; 4]) -> [f32; 3] {
+  let green = coefficients[1];
+  if !green.is_normal() || coefficients[..3].iter().any(|c| !c.is_finite() || *c <= 0.0) {
+    return [1.0; 3];
+  }
+  [coefficients[0] / green, 1.0, coefficients[2] / green]
+}
+
+/// Derives the camera to linear sRGB matrix from the camera's XYZ to camera matrix.
+///
+/// Rows of the sRGB to camera matrix are normalized so that white stays white after the white balance,
+/// cameras without a known matrix keep their colors (identity).
+fn camera_to_srgb(xyz_to_cam: [[f32; 3]; 4]) -> [[f32; 3]; 3] {
+  let mut srgb_to_cam = [[0.0f32; 3]; 3];
+  for (row, cam) in srgb_to_cam.iter_mut().zip(&xyz_to_cam) {
+    for (c, value) in row.iter_mut().enumerate() {
+      *value = (0..3).map(|k| cam[k] * SRGB_TO_XYZ[k][c]).sum();
+    }
+    let sum: f32 = row.iter().sum();
+    if sum.abs() > f32::EPSILON {
+      row.iter_mut().for_each(|v| *v /= sum);
+    }
+  }
+  invert(srgb_to_cam).unwrap_or([[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]])
+}
+
+/// Inverts a 3x3 matrix, returns `None` if it is singular.
+fn invert(m: [[f32; 3]; 3]) -> Option<[[f32; 3]; 3]> {
+  let cofactor = |r: usize, c: usize| {
+    let (r1, r2) = ((r + 1) % 3, (r + 2) % 3);
+    let (c1, c2) = ((c + 1) % 3, (c + 2) % 3);
+    m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+  };
+  let determinant: f32 = (0..3).map(|c| m[0][c] * cofactor(0, c)).sum();
+  if determinant.abs() < 1e-6 {
+    return None;
+  }
+  // the inverse is the transposed cofactor matrix divided by the determinant
+  Some(std::array::from_fn(|r| std::array::from_fn(|c| cofactor(c, r) / determinant)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Color of an RGGB Bayer pattern.
+  fn rggb(y: usize, x: usize) -> usize {
+    match (y % 2, x % 2) {
+      (0, 0) => 0,
+      (1, 1) => 2,
+      _ => 1,
+    }
+  }
+
+  #[test]
+  fn test_is_raw() {
+    assert!(is_raw(Path::new("IMG_0001.CR2")));
+    assert!(is_raw(Path::new("photo.nef")));
+    assert!(!is_raw(Path::new("photo.jpg")));
+  }
+
+  #[test]
+  fn test_demosaic_flat_field() {
+    // a gray scene gives the same value for every channel of every pixel
+    let pixels = demosaic(&[0.5; 16], 4, 4, rggb);
+    assert!(pixels.iter().all(|p| *p == [0.5; 3]));
+  }
+
+  #[test]
+  fn test_demosaic_interpolates_missing_colors() {
+    // only the red photosites see light
+    let samples: Vec<f32> = (0..16).map(|i| if rggb(i / 4, i % 4) == 0 { 1.0 } else { 0.0 }).collect();
+    let pixels = demosaic(&samples, 4, 4, rggb);
+    assert!(pixels.iter().all(|p| p[0] == 1.0 && p[1] == 0.0 && p[2] == 0.0), "{:?}", pixels);
+  }
+
+  #[test]
+  fn test_invert() {
+    let m = [[2.0, 0.0, 0.0], [0.0, 4.0, 0.0], [1.0, 0.0, 1.0]];
+    let inverse = invert(m).unwrap();
+    assert_eq!(inverse, [[0.5, 0.0, 0.0], [0.0, 0.25, 0.0], [-0.5, 0.0, 1.0]]);
+    assert!(invert([[1.0, 2.0, 3.0], [2.0, 4.0, 6.0], [0.0, 0.0, 1.0]]).is_none());
+  }
+
+  #[test]
+  fn test_white_balance() {
+    assert_eq!(white_balance([2.0, 1.0, 1.5, f32::NAN]), [2.0, 1.0, 1.5]);
+    assert_eq!(white_balance([4.0, 2.0, 3.0, 2.0]), [2.0, 1.0, 1.5]);
+    assert_eq!(white_balance([f32::NAN; 4]), [1.0; 3], "Unknown white balance should be neutral");
+  }
+
+  #[test]
+  fn test_camera_to_srgb_of_srgb_camera_is_identity() {
+    // a "camera" whose native space is linear sRGB
+    let xyz_to_srgb = invert(SRGB_TO_XYZ).unwrap();
+    let matrix = camera_to_srgb([xyz_to_srgb[0], xyz_to_srgb[1], xyz_to_srgb[2], [0.0; 3]]);
+    for (r, row) in matrix.iter().enumerate() {
+      for (c, value) in row.iter().enumerate() {
+        let expected = if r == c { 1.0 } else { 0.0 };
+        assert!((value - expected).abs() < 1e-3, "matrix[{r}][{c}] = {value}");
+      }
+    }
+  }
+}
@@ -0,0 +1,157 @@
+//! Riemersma dithering: error diffusion along a Hilbert curve.
+//!
+//! The pixels are visited along a space-filling curve instead of row by row, and every pixel receives
+//! the errors of the last few pixels on the curve, the most recent weighing the most. The curve never
+//! runs in one direction for long, so the texture is organic and free of the diagonal worms of
+//! row-based kernels. Images that are not a power-of-two square follow a generalized Hilbert curve.
+
+use crate::dither::{DitherOptions, QuantizationError};
+use crate::palette::{Color, Quantizer};
+
+/// Number of past errors every pixel receives.
+pub const QUEUE_SIZE: usize = 16;
+
+/// Weight of the most recent error relative to the oldest one.
+pub const WEIGHT_RATIO: f32 = 16.0;
+
+/// Calls `visit` with every pixel of a `width` x `height` image in the order of a generalized Hilbert curve.
+///
+/// Consecutive pixels are neighbors, only rectangles with odd sides can take a single diagonal step.
+pub fn hilbert_curve(width: u32, height: u32, mut visit: impl FnMut(u32, u32)) {
+  let (width, height) = (i64::from(width), i64::from(height));
+  if width == 0 || height == 0 {
+    return;
+  }
+  // the curve runs along the longer side
+  if width >= height {
+    curve((0, 0), (width, 0), (0, height), &mut visit);
+  } else {
+    curve((0, 0), (0, height), (width, 0), &mut visit);
+  }
+}
+
+/// Covers the rectangle at `(x, y)` spanned by the major direction `a` and the minor direction `b`.
+fn curve((x, y): (i64, i64), a: (i64, i64), b: (i64, i64), visit: &mut impl FnMut(u32, u32)) {
+  let (w, h) = ((a.0 + a.1).abs(), (b.0 + b.1).abs());
+  let (da, db) = ((a.0.signum(), a.1.signum()), (b.0.signum(), b.1.signum()));
+  if h == 1 || w == 1 {
+    let (step, length) = if h == 1 { (da, w) } else { (db, h) };
+    for i in 0..length {
+      visit((x + i * step.0) as u32, (y + i * step.1) as u32);
+    }
+    return;
+  }
+  let (mut a2, mut b2) = ((a.0.div_euclid(2), a.1.div_euclid(2)), (b.0.div_euclid(2), b.1.div_euclid(2)));
+  if 2 * w > 3 * h {
+    // long rectangle, split it in two along the major direction, with even halves where possible
+    if (a2.0 + a2.1).abs() % 2 == 1 && w > 2 {
+      a2 = (a2.0 + da.0, a2.1 + da.1);
+    }
+    curve((x, y), a2, b, visit);
+    curve((x + a2.0, y + a2.1), (a.0 - a2.0, a.1 - a2.1), b, visit);
+  } else {
+    // split in three: up the minor direction, along the major one and back down
+    if (b2.0 + b2.1).abs() % 2 == 1 && h > 2 {
+      b2 = (b2.0 + db.0, b2.1 + db.1);
+    }
+    curve((x, y), b2, a2, visit);
+    curve((x + b2.0, y + b2.1), a, (b.0 - b2.0, b.1 - b2.1), visit);
+    curve(
+      (x + (a.0 - da.0) + (b2.0 - db.0), y + (a.1 - da.1) + (b2.1 - db.1)),
+      (-b2.0, -b2.1),
+      (a2.0 - a.0, a2.1 - a.1),
+      visit,
+    );
+  }
+}
+
+/// Dithers an RGB buffer along a Hilbert curve, see the [module documentation](self).
+///
+/// Transparent pixels are skipped, the curve continues behind them. The scan direction does not apply.
+pub fn dither<Q: Quantizer + ?Sized>(buffer: &mut [u8], quantizer: &Q, width: u32, height: u32, options: &DitherOptions) {
+  // the oldest error weighs 1 / WEIGHT_RATIO, the most recent 1
+  let weights: [f32; QUEUE_SIZE] = std::array::from_fn(|i| WEIGHT_RATIO.powf(i as f32 / (QUEUE_SIZE - 1) as f32) / WEIGHT_RATIO);
+  // the errors on the curve, `next` is the oldest and is overwritten next
+  let mut errors = [[0.0f32; 3]; QUEUE_SIZE];
+  let mut next = 0;
+  hilbert_curve(width, height, |x, y| {
+    let pixel = y as usize * width as usize + x as usize;
+    if options.alpha.is_some_and(|alpha| alpha[pixel] == 0) {
+      return;
+    }
+    let i = pixel * 3;
+    let original = Color::from(&buffer[i..i + 3]);
+    let correction = |c: usize| (0..QUEUE_SIZE).map(|age| errors[(next + age) % QUEUE_SIZE][c] * weights[age]).sum::<f32>();
+    let corrected = |value: u8, c: usize| (f32::from(value) + correction(c)).round().clamp(0.0, 255.0) as u8;
+    let new_color = quantizer.quantize(Color {
+      r: corrected(original.r, 0),
+      g: corrected(original.g, 1),
+      b: corrected(original.b, 2),
+    });
+    // the error is taken from the original color, the weights add up to more than one
+    let error = QuantizationError::between(original, new_color);
+    errors[next] = [error.r, error.g, error.b];
+    next = (next + 1) % QUEUE_SIZE;
+    buffer[i..i + 3].copy_from_slice(&[new_color.r, new_color.g, new_color.b]);
+  });
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::palette::PALETTE_MONOCHROME;
+
+  #[test]
+  fn test_hilbert_curve_visits_every_pixel_once() {
+    for (width, height) in [(1, 1), (1, 9), (9, 1), (2, 2), (8, 8), (5, 3), (13, 7), (6, 20), (64, 48)] {
+      let mut visited = vec![false; (width * height) as usize];
+      let mut previous: Option<(u32, u32)> = None;
+      hilbert_curve(width, height, |x, y| {
+        assert!(x < width && y < height, "({x}, {y}) is outside {width}x{height}");
+        let pixel = (y * width + x) as usize;
+        assert!(!visited[pixel], "({x}, {y}) visited twice in {width}x{height}");
+        visited[pixel] = true;
+        if let Some((px, py)) = previous {
+          assert!(
+            x.abs_diff(px).max(y.abs_diff(py)) == 1,
+            "jump from ({px}, {py}) to ({x}, {y}) in {width}x{height}"
+          );
+        }
+        previous = Some((x, y));
+      });
+      assert!(visited.iter().all(|&v| v), "{width}x{height} not covered");
+    }
+  }
+
+  #[test]
+  fn test_hilbert_curve_of_square_steps_to_neighbors() {
+    let mut path = Vec::new();
+    hilbert_curve(16, 16, |x, y| path.push((x, y)));
+    assert_eq!(path.first(), Some(&(0, 0)));
+    assert!(path.windows(2).all(|step| step[0].0.abs_diff(step[1].0) + step[0].1.abs_diff(step[1].1) == 1));
+  }
+
+  #[test]
+  fn test_dither_preserves_gray_level() {
+    let (width, height) = (32, 32);
+    let mut buffer = vec![64; width * height * 3];
+    dither(&mut buffer, &PALETTE_MONOCHROME, width as u32, height as u32, &DitherOptions::default());
+
+    assert!(buffer.iter().all(|&v| v == 0 || v == 255));
+    let white = buffer.chunks_exact(3).filter(|pixel| pixel[0] == 255).count();
+    let expected = width * height / 4;
+    assert!(white.abs_diff(expected) <= 16, "{white} white pixels, expected about {expected}");
+  }
+
+  #[test]
+  fn test_dither_skips_transparent_pixels() {
+    let mut buffer = vec![100; 4 * 3];
+    let alpha = [255, 0, 0, 255];
+    let options = DitherOptions {
+      alpha: Some(&alpha),
+      ..DitherOptions::default()
+    };
+    dither(&mut buffer, &PALETTE_MONOCHROME, 2, 2, &options);
+    assert_eq!(&buffer[3..9], &[100; 6]);
+  }
+}
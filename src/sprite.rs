@@ -0,0 +1,208 @@
+//! Per-tile palettes for sprite and tile based hardware.
+//!
+//! Retro consoles draw the screen from small tiles, each of which may only use a few colors picked
+//! from the master palette. In sprite-sheet mode the image is split into square tiles, every tile gets
+//! the colors of the master palette it needs most, and is dithered with only those.
+
+use rayon::prelude::*;
+
+use crate::distance::{DistanceMetric, MetricPalette};
+use crate::dither::{self, DitherMethod, DitherOptions};
+use crate::error::DitherError;
+use crate::palette::{self, Color, TieBreak, TieBroken};
+use crate::tiles;
+
+/// Number of colors per tile used when none is given.
+pub const DEFAULT_TILE_COLORS: usize = 4;
+
+/// Picks the `count` palette colors that the most pixels of an RGB buffer are closest to.
+///
+/// Colors used equally often keep their palette order. Palettes with at most `count` colors are
+/// returned unchanged.
+#[must_use]
+pub fn tile_palette(pixels: &[u8], colors: &[Color], count: usize, metric: DistanceMetric, tie_break: TieBreak) -> Vec<Color> {
+  if colors.len() <= count {
+    return colors.to_vec();
+  }
+  let mut uses = vec![0usize; colors.len()];
+  let converted = (metric != DistanceMetric::Rgb).then(|| MetricPalette::new(colors, metric, tie_break));
+  for pixel in pixels.chunks_exact(3) {
+    let color = Color::from(pixel);
+    let closest = match &converted {
+      Some(converted) => converted.nearest(color),
+      None => palette::map_to_palette_with(color, colors, metric, tie_break).0,
+    };
+    if let Some(i) = colors.iter().position(|color| color == closest) {
+      uses[i] += 1;
+    }
+  }
+  let mut order: Vec<usize> = (0..colors.len()).collect();
+  // stable, so ties keep the palette order
+  order.sort_by_key(|&i| std::cmp::Reverse(uses[i]));
+  order.truncate(count.max(1));
+  order.sort_unstable();
+  order.into_iter().map(|i| colors[i]).collect()
+}
+
+/// Tiling of sprite-sheet mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SpriteLayout {
+  /// Width and height of the square tiles in pixels
+  pub tile_size: u32,
+  /// Largest number of colors per tile
+  pub tile_colors: usize,
+  /// Number of sub-palettes shared by all tiles, `None` gives every tile its own palette (see [`crate::clash`])
+  pub sub_palettes: Option<usize>,
+}
+
+/// Dithers an RGB buffer in tiles, each with its own palette taken from the colors of `palette`.
+///
+/// Error diffusion stays within a tile, ordered methods continue their pattern across tiles.
+pub fn dither_tiles(
+  buffer: &mut [u8],
+  method: DitherMethod,
+  palette: TieBroken,
+  width: u32,
+  height: u32,
+  layout: SpriteLayout,
+  options: &DitherOptions,
+) -> Result<(), DitherError> {
+  options.validate(buffer, width, height, 3)?;
+  let palettes: Vec<Vec<Color>> = tiles::grid(width, height, layout.tile_size)
+    .map(|tile| {
+      tile_palette(
+        &opaque_pixels(buffer, width, &tile, options.alpha),
+        palette.colors,
+        layout.tile_colors,
+        palette.metric,
+        palette.tie_break,
+      )
+    })
+    .collect();
+  let quantizers: Vec<TieBroken> = palettes.iter().map(|colors| TieBroken { colors, ..palette }).collect();
+  dither_with_palettes(buffer, method, &quantizers, width, height, layout.tile_size, options)
+}
+
+/// Dithers an RGB buffer in square tiles of `tile_size` pixels with the given palette per tile.
+///
+/// `palettes` holds one palette per tile, in the order of [`tiles::grid`].
+pub fn dither_with_palettes(
+  buffer: &mut [u8],
+  method: DitherMethod,
+  palettes: &[TieBroken],
+  width: u32,
+  height: u32,
+  tile_size: u32,
+  options: &DitherOptions,
+) -> Result<(), DitherError> {
+  options.validate(buffer, width, height, 3)?;
+  let tiles: Vec<tiles::Tile> = tiles::grid(width, height, tile_size).collect();
+  let dithered: Vec<Vec<u8>> = tiles
+    .par_iter()
+    .zip(palettes)
+    .map(|(tile, quantizer)| {
+      let mut pixels = tile.extract(buffer, width, 3);
+      let alpha = options.alpha.map(|alpha| tile.extract(alpha, width, 1));
+      let tile_options = DitherOptions {
+        alpha: alpha.as_deref(),
+        origin: (options.origin.0 + tile.x, options.origin.1 + tile.y),
+        ..*options
+      };
+      // perceptual metrics convert the palette once per tile instead of for every pixel
+      match quantizer.metric {
+        DistanceMetric::Rgb => dither::dither_with(&mut pixels, method, quantizer, tile.width, tile.height, &tile_options)?,
+        metric => {
+          let converted = MetricPalette::new(quantizer.colors, metric, quantizer.tie_break);
+          dither::dither_with(&mut pixels, method, &converted, tile.width, tile.height, &tile_options)?;
+        }
+      }
+      Ok(pixels)
+    })
+    .collect::<Result<_, DitherError>>()?;
+  for (tile, pixels) in tiles.iter().zip(dithered) {
+    tile.insert(&pixels, buffer, width, 3);
+  }
+  Ok(())
+}
+
+/// Returns the RGB pixels of a tile, without the fully transparent ones.
+///
+/// Transparent pixels are never drawn, so they do not count towards the palette of their tile.
+#[must_use]
+pub fn opaque_pixels(buffer: &[u8], width: u32, tile: &tiles::Tile, alpha: Option<&[u8]>) -> Vec<u8> {
+  let pixels = tile.extract(buffer, width, 3);
+  match alpha {
+    Some(alpha) => {
+      let alpha = tile.extract(alpha, width, 1);
+      pixels
+        .chunks_exact(3)
+        .zip(alpha)
+        .filter(|(_, a)| *a > 0)
+        .flat_map(|(p, _)| p.iter().copied())
+        .collect()
+    }
+    None => pixels,
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::palette::ColorPalette;
+  use std::collections::HashSet;
+
+  #[test]
+  fn test_tile_palette_picks_most_used() {
+    let colors = ColorPalette::COLOR16.colors();
+    // mostly black, some white, one pixel of the red entry
+    let mut pixels = vec![0; 3 * 6];
+    pixels.extend([255; 3 * 3]);
+    pixels.extend([0xbe, 0x26, 0x33]);
+    assert_eq!(
+      tile_palette(&pixels, colors, 2, DistanceMetric::Rgb, TieBreak::Darker),
+      vec![colors[0], colors[2]]
+    );
+    assert_eq!(tile_palette(&pixels, &colors[..2], 4, DistanceMetric::Rgb, TieBreak::Darker), &colors[..2]);
+  }
+
+  #[test]
+  fn test_dither_tiles_limits_colors_per_tile() {
+    // a two-dimensional gradient needs more colors overall than a tile may use
+    let (width, height) = (16, 16);
+    let mut buffer = Vec::new();
+    for y in 0..height {
+      for x in 0..width {
+        buffer.extend([(x * 16) as u8, (y * 16) as u8, 128]);
+      }
+    }
+    let palette = TieBroken {
+      colors: ColorPalette::COLOR16.colors(),
+      metric: DistanceMetric::Rgb,
+      tie_break: TieBreak::Darker,
+    };
+    let layout = SpriteLayout {
+      tile_size: 8,
+      tile_colors: 2,
+      sub_palettes: None,
+    };
+    dither_tiles(
+      &mut buffer,
+      DitherMethod::FloydSteinberg,
+      palette,
+      width,
+      height,
+      layout,
+      &DitherOptions::default(),
+    )
+    .unwrap();
+
+    let mut all = HashSet::new();
+    for tile in tiles::grid(width, height, 8) {
+      let pixels = tile.extract(&buffer, width, 3);
+      let used: HashSet<Color> = pixels.chunks_exact(3).map(Color::from).collect();
+      assert!(used.len() <= 2, "Tile at {},{} uses {} colors", tile.x, tile.y, used.len());
+      all.extend(used);
+    }
+    assert!(all.len() > 2, "Tiles should use different palettes");
+  }
+}
@@ -0,0 +1,331 @@
+//! Memory-bounded processing of large images.
+//!
+//! With a memory limit, jobs whose image would not fit are processed in horizontal strips instead of
+//! as a whole: the input is decoded, dithered and encoded a few rows at a time, so the memory used only
+//! depends on the width of the image. This keeps servers answering untrusted requests from running out
+//! of memory on huge uploads.
+//!
+//! Strips need a non-interlaced PNG input, a PNG output, an ordered dithering method and no stage that
+//! works on the whole image, such as `--resize` or `--caption`. Jobs that need more memory and cannot
+//! use strips fail instead.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use image::error::{DecodingError, EncodingError, ImageFormatHint};
+use image::metadata::Orientation;
+use image::{ImageDecoder, ImageError, ImageFormat, ImageReader};
+
+use crate::dither::ScanDirection;
+use crate::error::DitherError;
+use crate::jobs::Job;
+use crate::palette::Color;
+use crate::pipeline::{self, Image, Settings};
+use crate::{info, input, output};
+
+/// Number of rows processed at once, a multiple of the sizes of the ordered dithering matrices.
+pub const STRIP_ROWS: u32 = 64;
+
+/// An amount of memory in bytes.
+///
+/// Parsed from a number of bytes with an optional `K`, `M` or `G` suffix for kibi-, mebi- and gibibytes, e.g. `512M`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let invalid = || format!("invalid memory size {s:?}, expected bytes or a number with a K, M or G suffix");
+    let upper = s.trim().to_ascii_uppercase();
+    // 512M, 512MB and 512MiB are all accepted
+    let unit = upper.strip_suffix("IB").or_else(|| upper.strip_suffix('B')).unwrap_or(&upper);
+    let (number, shift) = match unit.as_bytes().last() {
+      Some(b'K') => (&unit[..unit.len() - 1], 10),
+      Some(b'M') => (&unit[..unit.len() - 1], 20),
+      Some(b'G') => (&unit[..unit.len() - 1], 30),
+      _ => (unit, 0),
+    };
+    let number: u64 = number.trim().parse().map_err(|_| invalid())?;
+    number.checked_mul(1 << shift).map(ByteSize).ok_or_else(invalid)
+  }
+}
+
+/// Formats the size with the largest suffix that represents it exactly, e.g. `512M`.
+impl fmt::Display for ByteSize {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match [(30, "G"), (20, "M"), (10, "K")]
+      .iter()
+      .find(|&&(shift, _)| self.0 != 0 && self.0.is_multiple_of(1 << shift))
+    {
+      Some(&(shift, suffix)) => write!(f, "{}{suffix}", self.0 >> shift),
+      None => write!(f, "{}", self.0),
+    }
+  }
+}
+
+impl serde::Serialize for ByteSize {
+  fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> serde::Deserialize<'de> for ByteSize {
+  fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let size = <String as serde::Deserialize>::deserialize(deserializer)?;
+    size.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// Estimates the memory needed to process an image as a whole, from the header of the file.
+///
+/// Counts the decoded image, the RGB and alpha channels of the pipeline and the RGBA copy of the
+/// encoder, rounded up to whole mebibytes. Returns `None` if the header cannot be read.
+#[must_use]
+pub fn estimate(path: &Path) -> Option<ByteSize> {
+  let decoder = ImageReader::open(path).ok()?.with_guessed_format().ok()?.into_decoder().ok()?;
+  let (width, height) = decoder.dimensions();
+  let per_pixel = u64::from(decoder.color_type().bytes_per_pixel()) + 3 + 1 + 4;
+  let bytes = u64::from(width) * u64::from(height) * per_pixel;
+  Some(ByteSize(bytes.div_ceil(1 << 20) << 20))
+}
+
+/// Returns the option of the first stage that works on the whole image, `None` if every stage only
+/// depends on the pixels of a strip or tile and their position.
+#[must_use]
+pub fn whole_image_option(settings: &Settings) -> Option<&'static str> {
+  let whole_image = [
+    (settings.resize.is_some(), "--resize"),
+    (settings.border.is_some(), "--border"),
+    (settings.pad.is_some(), "--pad"),
+    (settings.overlay.is_some(), "--overlay"),
+    (settings.caption.is_some(), "--caption"),
+    (settings.noise.is_some(), "--noise"),
+    (settings.ensemble.is_some_and(|runs| runs > 1), "--ensemble"),
+    (settings.tile_palette.is_some() || settings.console.is_some(), "--tile-palette"),
+    (settings.ham6, "--ham6"),
+    (settings.adaptive_palette().is_some(), "--colors"),
+    (settings.format.is_some(), "--format"),
+    (settings.indexed, "--indexed"),
+    (settings.interlaced, "--interlaced"),
+    (settings.scan != ScanDirection::default(), "--scan"),
+  ];
+  whole_image.iter().find(|(used, _)| *used).map(|&(_, option)| option)
+}
+
+/// Returns why a job cannot be processed in strips, `None` if it can.
+#[must_use]
+pub fn blocker(job: &Job, output: &Path) -> Option<String> {
+  if let Some(option) = whole_image_option(&job.settings) {
+    return Some(format!("{option} needs the whole image"));
+  }
+  // errors diffused across the edges of the strips would be lost, which shows as seams
+  if !job.method.is_ordered() {
+    return Some(format!(
+      "{:?} diffuses errors across the whole image, only ordered methods can use strips",
+      job.method
+    ));
+  }
+  let is_png = |path: &Path| matches!(ImageFormat::from_path(path), Ok(ImageFormat::Png));
+  if !is_png(&job.input) || !is_png(output) {
+    return Some("only PNG files can be processed in strips".to_string());
+  }
+  None
+}
+
+/// Runs a job in strips if its image needs more memory than `limit`, dithering to `colors`.
+///
+/// Returns the path of the written output, `None` if the image fits and is left to the regular pipeline.
+/// Images that do not fit and cannot be processed in strips (see [`blocker`]) give a
+/// [`DitherError::MemoryLimit`] without being decoded.
+pub fn run_if_needed(job: &Job, limit: ByteSize, colors: &[Color]) -> Result<Option<PathBuf>, DitherError> {
+  let Some(needed) = estimate(&job.input).filter(|&needed| needed > limit) else {
+    return Ok(None);
+  };
+  let too_large = |reason: String| DitherError::MemoryLimit {
+    path: job.input.clone(),
+    needed,
+    limit,
+    reason,
+  };
+  let (width, height) = image::image_dimensions(&job.input).map_err(|source| DitherError::Image {
+    path: job.input.clone(),
+    source,
+  })?;
+  job.settings.load_options().check(&job.input, width, height)?;
+  let output = job.output_path(width, height);
+  if let Some(reason) = blocker(job, &output) {
+    return Err(too_large(reason));
+  }
+  if !job.settings.no_auto_orient && input::orientation(&job.input) != Orientation::NoTransforms {
+    return Err(too_large("turning it upright by its EXIF orientation needs the whole image".to_string()));
+  }
+
+  let decoding_error = |err: png::DecodingError| DitherError::Image {
+    path: job.input.clone(),
+    source: ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::Png), err)),
+  };
+  let file = File::open(&job.input).map_err(|source| DitherError::Io {
+    path: job.input.clone(),
+    source,
+  })?;
+  let mut decoder = png::Decoder::new(BufReader::new(file));
+  decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+  let mut reader = decoder.read_info().map_err(decoding_error)?;
+  if reader.info().interlaced {
+    return Err(too_large("interlaced PNG files cannot be processed in strips".to_string()));
+  }
+  let (color_type, _) = reader.output_color_type();
+  let channels = color_type.samples();
+  let has_alpha = matches!(color_type, png::ColorType::GrayscaleAlpha | png::ColorType::Rgba);
+  log::info!("{:?} needs about {needed} of memory, processing it in strips of {STRIP_ROWS} rows", job.input);
+
+  let encoding_error = |err: png::EncodingError| DitherError::Image {
+    path: output.clone(),
+    source: ImageError::Encoding(EncodingError::new(ImageFormatHint::Exact(ImageFormat::Png), err)),
+  };
+  output::write_atomically_with(&output, |file| {
+    let mut encoder = png::Encoder::new(file, width, height);
+    encoder.set_color(if has_alpha { png::ColorType::Rgba } else { png::ColorType::Rgb });
+    encoder.set_depth(png::BitDepth::Eight);
+    if let Some(dpi) = job.settings.dpi {
+      let pixels_per_meter = output::pixels_per_meter(dpi);
+      encoder.set_pixel_dims(Some(png::PixelDimensions {
+        xppu: pixels_per_meter,
+        yppu: pixels_per_meter,
+        unit: png::Unit::Meter,
+      }));
+    }
+    if let Some(text) = output::metadata(&job.settings) {
+      let keyword = info::METADATA_KEYWORD.to_string();
+      if text.is_ascii() {
+        encoder.add_text_chunk(keyword, text).map_err(encoding_error)?;
+      } else {
+        encoder.add_itxt_chunk(keyword, text).map_err(encoding_error)?;
+      }
+    }
+    let mut writer = encoder.write_header().map_err(encoding_error)?;
+    let mut stream = writer.stream_writer().map_err(encoding_error)?;
+
+    for top in (0..height).step_by(STRIP_ROWS as usize) {
+      let rows = STRIP_ROWS.min(height - top);
+      let mut strip = Image {
+        buffer: Vec::with_capacity(width as usize * rows as usize * 3),
+        alpha: has_alpha.then(|| Vec::with_capacity(width as usize * rows as usize)),
+        width,
+        height: rows,
+      };
+      for _ in 0..rows {
+        let row = reader.next_row().map_err(decoding_error)?.ok_or_else(|| DitherError::Image {
+          path: job.input.clone(),
+          source: ImageError::Decoding(DecodingError::new(ImageFormatHint::Exact(ImageFormat::Png), "the image data ends early")),
+        })?;
+        for pixel in row.data().chunks_exact(channels) {
+          // grayscale has one color sample, with or without alpha
+          let color = if channels < 3 { [pixel[0]; 3] } else { [pixel[0], pixel[1], pixel[2]] };
+          strip.buffer.extend(color);
+          if let Some(alpha) = strip.alpha.as_mut() {
+            alpha.push(pixel[channels - 1]);
+          }
+        }
+      }
+      pipeline::process_strip(&mut strip, job.method, colors, &job.settings, top)?;
+      let data = match &strip.alpha {
+        Some(alpha) => strip
+          .buffer
+          .chunks_exact(3)
+          .zip(alpha)
+          .flat_map(|(rgb, &a)| [rgb[0], rgb[1], rgb[2], a])
+          .collect(),
+        None => strip.buffer,
+      };
+      stream.write_all(&data).map_err(|source| DitherError::Io { path: output.clone(), source })?;
+      log::trace!("streamed rows {}..{} of {}", top, top + rows, height);
+    }
+    stream.finish().map_err(encoding_error)?;
+    writer.finish().map_err(encoding_error)
+  })?;
+  Ok(Some(output))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::dither::DitherMethod;
+  use crate::palette::ColorPalette;
+  use crate::pipeline::Settings;
+
+  #[test]
+  fn test_byte_size_parse_and_display() {
+    assert_eq!("512M".parse(), Ok(ByteSize(512 << 20)));
+    assert_eq!("2GiB".parse(), Ok(ByteSize(2 << 30)));
+    assert_eq!("1kb".parse(), Ok(ByteSize(1024)));
+    assert_eq!("1000".parse(), Ok(ByteSize(1000)));
+    assert!("lots".parse::<ByteSize>().is_err());
+    assert_eq!(ByteSize(512 << 20).to_string(), "512M");
+    assert_eq!(ByteSize(3 << 10).to_string(), "3K");
+    assert_eq!(ByteSize(1536).to_string(), "1536");
+  }
+
+  #[test]
+  fn test_run_in_strips_matches_whole_image() {
+    let dir = std::env::temp_dir().join("dithers_stream_test");
+    std::fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("input.png");
+    // taller than a strip, with a last strip of a different height
+    let (width, height) = (300, 2 * STRIP_ROWS + 10);
+    let gradient: Vec<u8> = (0..width * height).flat_map(|i| [(i % width) as u8, (i / width) as u8, 128]).collect();
+    image::save_buffer(&input, &gradient, width, height, image::ExtendedColorType::Rgb8).unwrap();
+
+    let mut job = Job::new(&input);
+    job.output = Some(dir.join("streamed.png"));
+    job.method = DitherMethod::Bayer8x8;
+    job.palette = ColorPalette::COLOR16;
+    job.settings = Settings {
+      max_memory: Some(ByteSize(0)),
+      ..Settings::default()
+    };
+    let colors = job.palette.colors();
+    assert_eq!(
+      run_if_needed(&job, ByteSize(1 << 30), colors).unwrap(),
+      None,
+      "Images that fit are not streamed"
+    );
+    let output = run_if_needed(&job, ByteSize(0), colors).unwrap().unwrap();
+
+    // ordered patterns continue across strips, so the result equals dithering the image at once
+    let mut whole = crate::input::load(&input).unwrap();
+    pipeline::process(&mut whole, job.method, job.palette, &job.settings).unwrap();
+    assert_eq!(crate::input::load(&output).unwrap(), whole);
+    let metadata = info::inspect(&output).unwrap().metadata;
+    assert_eq!(metadata, vec![(info::METADATA_KEYWORD.to_string(), output::metadata(&job.settings).unwrap())]);
+
+    job.settings.resize = Some(100);
+    let err = run_if_needed(&job, ByteSize(0), colors).unwrap_err();
+    assert!(matches!(&err, DitherError::MemoryLimit { reason, .. } if reason.contains("--resize")), "{err}");
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_strips_need_an_ordered_method() {
+    let mut job = Job::new("input.png");
+    job.method = DitherMethod::Bayer8x8;
+    let output = Path::new("output.png");
+    assert_eq!(blocker(&job, output), None);
+    for method in [
+      DitherMethod::FloydSteinberg,
+      DitherMethod::Riemersma,
+      DitherMethod::ZhouFang,
+      DitherMethod::DotDiffusion,
+    ] {
+      job.method = method;
+      assert!(blocker(&job, output).is_some_and(|reason| reason.contains("ordered")), "{method:?}");
+    }
+    job.method = DitherMethod::Bayer8x8;
+    job.settings.scan = ScanDirection::RightToLeft;
+    assert_eq!(blocker(&job, output).as_deref(), Some("--scan needs the whole image"));
+  }
+}
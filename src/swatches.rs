@@ -0,0 +1,505 @@
+//! Palette and swatch files of other applications, read by `--palette-file` and written by `--export-palette`.
+//!
+//! The format is chosen by the extension of the file, files with other extensions are recognized by
+//! their header and read as lists of hex colors otherwise (see [`crate::palette::parse_hex_list`]).
+//! Palettes are written as GIMP palettes or hex lists, the two formats every palette tool reads.
+
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::error::DitherError;
+use crate::output;
+use crate::palette::{self, Color};
+
+/// Formats of palette files.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PaletteFormat {
+  /// One hex color per line, `.hex` and `.txt` files of palette sites
+  Hex,
+  /// GIMP palette, `.gpl`
+  Gimp,
+  /// Adobe Swatch Exchange, `.ase`
+  Ase,
+  /// Photoshop color swatches, `.aco`
+  Aco,
+  /// JASC palette of Paint Shop Pro and pixel-art editors, `.pal`
+  Jasc,
+  /// JSON export of the Lospec palette list, `.json`
+  Lospec,
+}
+
+impl PaletteFormat {
+  /// Returns the format of a palette file with the given path and content.
+  #[must_use]
+  pub fn detect(path: &Path, content: &[u8]) -> Self {
+    let extension = path.extension().and_then(|extension| extension.to_str()).map(str::to_ascii_lowercase);
+    match extension.as_deref() {
+      Some("gpl") => PaletteFormat::Gimp,
+      Some("hex") => PaletteFormat::Hex,
+      Some("ase") => PaletteFormat::Ase,
+      Some("aco") => PaletteFormat::Aco,
+      Some("pal") => PaletteFormat::Jasc,
+      Some("json") => PaletteFormat::Lospec,
+      _ if content.starts_with(b"JASC-PAL") => PaletteFormat::Jasc,
+      _ if content.starts_with(b"GIMP Palette") => PaletteFormat::Gimp,
+      _ if content.starts_with(b"ASEF") => PaletteFormat::Ase,
+      _ => PaletteFormat::Hex,
+    }
+  }
+}
+
+/// Reads the colors of a palette file in any of the supported formats.
+pub fn read(path: &Path) -> Result<Vec<Color>, DitherError> {
+  let content = std::fs::read(path).map_err(|source| DitherError::Io {
+    path: path.to_path_buf(),
+    source,
+  })?;
+  let format = PaletteFormat::detect(path, &content);
+  log::debug!("reading {:?} as a {:?} palette", path, format);
+  parse(&content, format).map_err(|message| DitherError::PaletteFile {
+    path: path.to_path_buf(),
+    message,
+  })
+}
+
+/// Parses the content of a palette file in the given format.
+pub fn parse(content: &[u8], format: PaletteFormat) -> Result<Vec<Color>, String> {
+  match format {
+    PaletteFormat::Hex => palette::parse_hex_list(text(content)?),
+    PaletteFormat::Gimp => parse_gpl(text(content)?),
+    PaletteFormat::Ase => parse_ase(content),
+    PaletteFormat::Aco => parse_aco(content),
+    PaletteFormat::Jasc => parse_jasc(text(content)?),
+    PaletteFormat::Lospec => parse_lospec(text(content)?),
+  }
+}
+
+/// Writes colors into a palette file, a GIMP palette for the `.gpl` extension and a hex list otherwise.
+///
+/// GIMP palettes are named after the file.
+pub fn write(path: &Path, colors: &[Color]) -> Result<(), DitherError> {
+  let content = match PaletteFormat::detect(path, &[]) {
+    PaletteFormat::Gimp => to_gpl(&path.file_stem().unwrap_or_default().to_string_lossy(), colors),
+    _ => to_hex(colors),
+  };
+  output::write_atomically(path, content.as_bytes())
+}
+
+/// Formats colors as a GIMP palette, each color named by its hex value.
+#[must_use]
+pub fn to_gpl(name: &str, colors: &[Color]) -> String {
+  let mut content = format!("GIMP Palette\nName: {name}\nColumns: {}\n#\n", colors.len().min(16));
+  for color in colors {
+    content += &format!("{:3} {:3} {:3}\t{color}\n", color.r, color.g, color.b);
+  }
+  content
+}
+
+/// Formats colors as a hex list, one `RRGGBB` color per line like the downloads of palette sites.
+#[must_use]
+pub fn to_hex(colors: &[Color]) -> String {
+  colors.iter().map(|color| format!("{:02x}{:02x}{:02x}\n", color.r, color.g, color.b)).collect()
+}
+
+fn text(content: &[u8]) -> Result<&str, String> {
+  std::str::from_utf8(content).map_err(|_| "not a text file".to_string())
+}
+
+/// Parses a GIMP palette: a `GIMP Palette` header line, optional `Name:` and `Columns:` lines, then one
+/// color per line as three decimal channels, optionally followed by the name of the color.
+///
+/// Blank lines and lines starting with `#` are skipped, the names are not used.
+pub fn parse_gpl(content: &str) -> Result<Vec<Color>, String> {
+  let mut lines = content.lines().enumerate();
+  if lines.next().is_none_or(|(_, header)| header.trim() != "GIMP Palette") {
+    return Err("missing \"GIMP Palette\" header".to_string());
+  }
+  let mut colors = Vec::new();
+  for (number, line) in lines {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') || line.starts_with("Name:") || line.starts_with("Columns:") {
+      continue;
+    }
+    let channels = line
+      .split_whitespace()
+      .take(3)
+      .map(|channel| channel.parse::<u8>())
+      .collect::<Result<Vec<u8>, _>>()
+      .ok()
+      .filter(|channels| channels.len() == 3)
+      .ok_or_else(|| format!("line {}: expected red, green and blue from 0 to 255, got {line:?}", number + 1))?;
+    colors.push(Color::from(&channels[..]));
+  }
+  if colors.is_empty() {
+    return Err("no colors".to_string());
+  }
+  Ok(colors)
+}
+
+/// Parses a JASC palette: a `JASC-PAL` header line, the version `0100`, the number of colors, then one
+/// color per line as three decimal channels.
+pub fn parse_jasc(content: &str) -> Result<Vec<Color>, String> {
+  let mut lines = content.lines().map(str::trim).enumerate().filter(|(_, line)| !line.is_empty());
+  if lines.next().is_none_or(|(_, header)| header != "JASC-PAL") {
+    return Err("missing \"JASC-PAL\" header".to_string());
+  }
+  lines.next().ok_or("missing version")?;
+  let count: usize = match lines.next() {
+    Some((_, count)) => count.parse().map_err(|_| format!("invalid number of colors {count:?}"))?,
+    None => return Err("missing number of colors".to_string()),
+  };
+  let colors = lines
+    .take(count)
+    .map(|(number, line)| {
+      let channels: Vec<u8> = line.split_whitespace().map_while(|channel| channel.parse().ok()).collect();
+      match channels[..] {
+        [r, g, b] => Ok(Color { r, g, b }),
+        _ => Err(format!("line {}: expected red, green and blue from 0 to 255, got {line:?}", number + 1)),
+      }
+    })
+    .collect::<Result<Vec<Color>, String>>()?;
+  if colors.len() < count {
+    return Err(format!("expected {count} colors, found {}", colors.len()));
+  }
+  if colors.is_empty() {
+    return Err("no colors".to_string());
+  }
+  Ok(colors)
+}
+
+/// A palette as exported by Lospec, other fields such as the author are ignored.
+#[derive(Deserialize)]
+struct LospecPalette {
+  #[serde(default)]
+  name: Option<String>,
+  colors: Vec<Color>,
+}
+
+/// Parses the JSON export of a Lospec palette, an object with the hex colors in `colors`, e.g.
+/// `{"name": "Dawnbringer 16", "colors": ["140c1c", "442434", ...]}`.
+///
+/// Lospec's `.hex` export is a list of hex colors, which is read without this.
+pub fn parse_lospec(content: &str) -> Result<Vec<Color>, String> {
+  let palette: LospecPalette = serde_json::from_str(content).map_err(|err| err.to_string())?;
+  if palette.colors.is_empty() {
+    return Err("no colors".to_string());
+  }
+  if let Some(name) = &palette.name {
+    log::debug!("read the {} colors of {name:?}", palette.colors.len());
+  }
+  Ok(palette.colors)
+}
+
+/// Parses an Adobe Swatch Exchange file, the swatch libraries of Illustrator, InDesign and Photoshop.
+///
+/// Colors in groups are read like all others. RGB, CMYK, Lab and gray swatches are converted to RGB, the
+/// CMYK inks without a color profile, Lab from the D50 white point Adobe applications use.
+pub fn parse_ase(content: &[u8]) -> Result<Vec<Color>, String> {
+  let mut reader = Reader::new(content);
+  if reader.bytes(4)? != b"ASEF" {
+    return Err("missing \"ASEF\" signature".to_string());
+  }
+  reader.bytes(4)?; // version
+  let blocks = reader.u32()?;
+  let mut colors = Vec::new();
+  for _ in 0..blocks {
+    let kind = reader.u16()?;
+    let length = reader.u32()? as usize;
+    let mut block = Reader::new(reader.bytes(length)?);
+    // group starts and ends carry no color
+    if kind != 0x0001 {
+      continue;
+    }
+    let name_length = usize::from(block.u16()?);
+    block.bytes(2 * name_length)?;
+    let model = block.bytes(4)?;
+    let mut values = |count: usize| (0..count).map(|_| block.f32()).collect::<Result<Vec<f32>, String>>();
+    let color = match model {
+      b"RGB " => {
+        let rgb = values(3)?;
+        Color {
+          r: unit(rgb[0]),
+          g: unit(rgb[1]),
+          b: unit(rgb[2]),
+        }
+      }
+      b"CMYK" => {
+        let cmyk = values(4)?;
+        cmyk_to_rgb(cmyk[0], cmyk[1], cmyk[2], cmyk[3])
+      }
+      b"LAB " => {
+        let lab = values(3)?;
+        lab_to_rgb(lab[0] * 100.0, lab[1], lab[2])
+      }
+      b"Gray" => cmyk_to_rgb(0.0, 0.0, 0.0, values(1)?[0]),
+      _ => return Err(format!("unsupported color model {:?}", String::from_utf8_lossy(model))),
+    };
+    colors.push(color);
+  }
+  if colors.is_empty() {
+    return Err("no colors".to_string());
+  }
+  Ok(colors)
+}
+
+/// Parses a Photoshop swatches file.
+///
+/// RGB, HSB, CMYK, Lab and grayscale swatches are converted to RGB like in [`parse_ase`], swatches of
+/// color books (e.g. Pantone) are an error. Both versions of the format are read. Photoshop writes
+/// the colors twice, in version 1 and then in version 2 with names, only the first are read.
+pub fn parse_aco(content: &[u8]) -> Result<Vec<Color>, String> {
+  let mut reader = Reader::new(content);
+  let version = reader.u16()?;
+  if version != 1 && version != 2 {
+    return Err(format!("unsupported version {version}"));
+  }
+  let count = reader.u16()?;
+  let mut colors = Vec::with_capacity(usize::from(count));
+  for _ in 0..count {
+    let space = reader.u16()?;
+    let [w, x, y, z] = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+    if version == 2 {
+      let name_length = reader.u32()? as usize;
+      reader.bytes(2 * name_length)?;
+    }
+    let word = |value: u16| f32::from(value) / 65535.0;
+    let color = match space {
+      0 => Color {
+        r: (w / 257) as u8,
+        g: (x / 257) as u8,
+        b: (y / 257) as u8,
+      },
+      1 => hsb_to_rgb(word(w) * 360.0, word(x), word(y)),
+      // 0 is full ink, 65535 no ink
+      2 => cmyk_to_rgb(1.0 - word(w), 1.0 - word(x), 1.0 - word(y), 1.0 - word(z)),
+      7 => lab_to_rgb(f32::from(w) / 100.0, f32::from(x as i16) / 100.0, f32::from(y as i16) / 100.0),
+      8 => cmyk_to_rgb(0.0, 0.0, 0.0, f32::from(w) / 10000.0),
+      _ => return Err(format!("unsupported color space {space}")),
+    };
+    colors.push(color);
+  }
+  if colors.is_empty() {
+    return Err("no colors".to_string());
+  }
+  Ok(colors)
+}
+
+/// Reads big-endian values from a binary file.
+struct Reader<'a> {
+  data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+  fn new(data: &'a [u8]) -> Self {
+    Reader { data }
+  }
+
+  fn bytes(&mut self, count: usize) -> Result<&'a [u8], String> {
+    if count > self.data.len() {
+      return Err("unexpected end of file".to_string());
+    }
+    let (bytes, rest) = self.data.split_at(count);
+    self.data = rest;
+    Ok(bytes)
+  }
+
+  fn u16(&mut self) -> Result<u16, String> {
+    self.bytes(2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]))
+  }
+
+  fn u32(&mut self) -> Result<u32, String> {
+    self.bytes(4).map(|bytes| u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+  }
+
+  fn f32(&mut self) -> Result<f32, String> {
+    self.u32().map(f32::from_bits)
+  }
+}
+
+/// Converts a channel from 0.0-1.0 to 0-255.
+fn unit(value: f32) -> u8 {
+  (value.clamp(0.0, 1.0) * 255.0).round() as u8
+}
+
+/// Converts ink amounts from 0.0 (none) to 1.0 (full) to RGB, without a color profile.
+fn cmyk_to_rgb(c: f32, m: f32, y: f32, k: f32) -> Color {
+  Color {
+    r: unit((1.0 - c) * (1.0 - k)),
+    g: unit((1.0 - m) * (1.0 - k)),
+    b: unit((1.0 - y) * (1.0 - k)),
+  }
+}
+
+/// Converts a hue in degrees, saturation and brightness from 0.0 to 1.0 to RGB.
+fn hsb_to_rgb(hue: f32, saturation: f32, brightness: f32) -> Color {
+  let f = |n: f32| {
+    let k = (n + hue / 60.0) % 6.0;
+    unit(brightness - brightness * saturation * k.min(4.0 - k).clamp(0.0, 1.0))
+  };
+  Color {
+    r: f(5.0),
+    g: f(3.0),
+    b: f(1.0),
+  }
+}
+
+/// Converts a CIE L*a*b* color relative to D50 to sRGB.
+fn lab_to_rgb(l: f32, a: f32, b: f32) -> Color {
+  const WHITE: [f32; 3] = [0.964_22, 1.0, 0.825_21];
+  // XYZ relative to D50 to linear sRGB, with Bradford adaptation to D65
+  const MATRIX: [[f32; 3]; 3] = [
+    [3.133_856, -1.616_867, -0.490_615],
+    [-0.978_768, 1.916_142, 0.033_454],
+    [0.071_945, -0.228_991, 1.405_243],
+  ];
+  let fy = (l + 16.0) / 116.0;
+  let inverse = |t: f32| {
+    if t > 6.0 / 29.0 {
+      t.powi(3)
+    } else {
+      3.0 * (6.0f32 / 29.0).powi(2) * (t - 4.0 / 29.0)
+    }
+  };
+  let xyz = [inverse(fy + a / 500.0) * WHITE[0], inverse(fy) * WHITE[1], inverse(fy - b / 200.0) * WHITE[2]];
+  let channel = |row: [f32; 3]| {
+    let linear = row[0] * xyz[0] + row[1] * xyz[1] + row[2] * xyz[2];
+    unit(if linear <= 0.003_130_8 {
+      12.92 * linear
+    } else {
+      1.055 * linear.powf(1.0 / 2.4) - 0.055
+    })
+  };
+  Color {
+    r: channel(MATRIX[0]),
+    g: channel(MATRIX[1]),
+    b: channel(MATRIX[2]),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_gpl() {
+    let gpl = "GIMP Palette\nName: Dawn\nColumns: 4\n#\n  0   0   0\tBlack\n255 128  16 Warm orange\n\n 20  30  40\n";
+    assert_eq!(
+      parse_gpl(gpl).unwrap(),
+      vec![Color { r: 0, g: 0, b: 0 }, Color { r: 255, g: 128, b: 16 }, Color { r: 20, g: 30, b: 40 }]
+    );
+    assert_eq!(parse_gpl("0 0 0\n").unwrap_err(), "missing \"GIMP Palette\" header");
+    assert_eq!(parse_gpl("GIMP Palette\nName: Empty\n").unwrap_err(), "no colors");
+    assert!(parse_gpl("GIMP Palette\n0 0 256 Too bright\n").unwrap_err().starts_with("line 2:"));
+    assert!(parse_gpl("GIMP Palette\n0 0\n").is_err());
+  }
+
+  #[test]
+  fn test_write_gpl_and_hex() {
+    let colors = [Color { r: 0, g: 0, b: 0 }, Color { r: 255, g: 128, b: 16 }];
+    let gpl = to_gpl("warm", &colors);
+    assert_eq!(gpl, "GIMP Palette\nName: warm\nColumns: 2\n#\n  0   0   0\t#000000\n255 128  16\t#ff8010\n");
+    assert_eq!(parse_gpl(&gpl).unwrap(), colors);
+    let hex = to_hex(&colors);
+    assert_eq!(hex, "000000\nff8010\n");
+    assert_eq!(palette::parse_hex_list(&hex).unwrap(), colors);
+  }
+
+  #[test]
+  fn test_parse_jasc() {
+    let pal = "JASC-PAL\r\n0100\r\n3\r\n0 0 0\r\n255 128 16\r\n20 30 40\r\n";
+    assert_eq!(
+      parse_jasc(pal).unwrap(),
+      vec![Color { r: 0, g: 0, b: 0 }, Color { r: 255, g: 128, b: 16 }, Color { r: 20, g: 30, b: 40 }]
+    );
+    assert_eq!(PaletteFormat::detect(Path::new("dawn.pal"), pal.as_bytes()), PaletteFormat::Jasc);
+    assert_eq!(parse_jasc("JASC-PAL\n0100\n4\n0 0 0\n").unwrap_err(), "expected 4 colors, found 1");
+    assert_eq!(
+      parse_jasc("JASC-PAL\n0100\n1\n0 0 300\n").unwrap_err(),
+      "line 4: expected red, green and blue from 0 to 255, got \"0 0 300\""
+    );
+    assert_eq!(parse_jasc("RIFF").unwrap_err(), "missing \"JASC-PAL\" header");
+  }
+
+  #[test]
+  fn test_parse_lospec() {
+    let json = r##"{"name": "Tiny", "author": "someone", "colors": ["000000", "ff8010", "#14f"]}"##;
+    assert_eq!(
+      parse_lospec(json).unwrap(),
+      vec![Color { r: 0, g: 0, b: 0 }, Color { r: 255, g: 128, b: 16 }, Color { r: 0x11, g: 0x44, b: 0xff }]
+    );
+    assert_eq!(PaletteFormat::detect(Path::new("tiny.json"), json.as_bytes()), PaletteFormat::Lospec);
+    assert!(parse_lospec(r#"{"colors": ["00000g"]}"#).unwrap_err().contains("invalid hex color"));
+    assert_eq!(parse_lospec(r#"{"colors": []}"#).unwrap_err(), "no colors");
+    assert!(parse_lospec("[]").is_err());
+  }
+
+  fn ase_color(model: &[u8; 4], values: &[f32]) -> Vec<u8> {
+    let name: Vec<u8> = "red\0".encode_utf16().flat_map(u16::to_be_bytes).collect();
+    let mut data = vec![0, 4];
+    data.extend(name);
+    data.extend(model);
+    data.extend(values.iter().flat_map(|value| value.to_be_bytes()));
+    data.extend([0, 2]);
+    let mut block = vec![0x00, 0x01];
+    block.extend((data.len() as u32).to_be_bytes());
+    block.extend(data);
+    block
+  }
+
+  #[test]
+  fn test_parse_ase() {
+    let mut ase = b"ASEF\x00\x01\x00\x00\x00\x00\x00\x05".to_vec();
+    // a group around the first two colors
+    ase.extend([0xc0, 0x01, 0, 0, 0, 4, 0, 1, 0, 0]);
+    ase.extend(ase_color(b"RGB ", &[1.0, 0.5, 0.0]));
+    ase.extend(ase_color(b"CMYK", &[0.0, 1.0, 1.0, 0.0]));
+    ase.extend([0xc0, 0x02, 0, 0, 0, 0]);
+    ase.extend(ase_color(b"Gray", &[0.25]));
+    assert_eq!(
+      parse_ase(&ase).unwrap(),
+      vec![Color { r: 255, g: 128, b: 0 }, Color { r: 255, g: 0, b: 0 }, Color { r: 191, g: 191, b: 191 }]
+    );
+    assert_eq!(PaletteFormat::detect(Path::new("swatches.bin"), &ase), PaletteFormat::Ase);
+    assert_eq!(parse_ase(&ase[..ase.len() - 3]).unwrap_err(), "unexpected end of file");
+    assert!(parse_ase(b"GIMP Palette").is_err());
+  }
+
+  #[test]
+  fn test_parse_aco() {
+    let entry = |space: u16, values: [u16; 4]| std::iter::once(space).chain(values).flat_map(u16::to_be_bytes);
+    let mut aco = vec![0, 1, 0, 4];
+    aco.extend(entry(0, [0xffff, 0x8080, 0, 0]));
+    aco.extend(entry(1, [0, 0xffff, 0xffff, 0]));
+    aco.extend(entry(2, [0xffff, 0xffff, 0xffff, 0]));
+    aco.extend(entry(7, [10000, 0, 0, 0]));
+    assert_eq!(
+      parse_aco(&aco).unwrap(),
+      vec![
+        Color { r: 255, g: 128, b: 0 },
+        Color { r: 255, g: 0, b: 0 },
+        Color { r: 0, g: 0, b: 0 },
+        Color { r: 255, g: 255, b: 255 }
+      ]
+    );
+    let mut pantone = vec![0, 1, 0, 1];
+    pantone.extend(entry(3, [0, 0, 0, 0]));
+    assert_eq!(parse_aco(&pantone).unwrap_err(), "unsupported color space 3");
+  }
+
+  #[test]
+  fn test_lab_to_rgb() {
+    assert_eq!(lab_to_rgb(100.0, 0.0, 0.0), Color { r: 255, g: 255, b: 255 });
+    assert_eq!(lab_to_rgb(0.0, 0.0, 0.0), Color { r: 0, g: 0, b: 0 });
+    // sRGB red is L 54.29, a 80.80, b 69.89 relative to D50
+    assert_eq!(lab_to_rgb(54.29, 80.80, 69.89), Color { r: 255, g: 0, b: 0 });
+  }
+
+  #[test]
+  fn test_detect_format() {
+    assert_eq!(PaletteFormat::detect(Path::new("dawn.GPL"), b""), PaletteFormat::Gimp);
+    assert_eq!(PaletteFormat::detect(Path::new("dawn.txt"), b"GIMP Palette\n"), PaletteFormat::Gimp);
+    assert_eq!(PaletteFormat::detect(Path::new("dawn.txt"), b"#000\n"), PaletteFormat::Hex);
+    assert_eq!(PaletteFormat::detect(Path::new("dawn.hex"), b"GIMP Palette\n"), PaletteFormat::Hex);
+  }
+}
@@ -0,0 +1,110 @@
+//! Bit-depth targets for packed pixel formats.
+//!
+//! Instead of the nearest palette entry, a target quantizes each channel to the levels a packed
+//! pixel format such as RGB565 can represent, which is what embedded LCD panels expect.
+
+use crate::palette::{Color, Quantizer};
+
+/// Packed RGB bit depths that can be used in place of a palette.
+#[derive(clap::ValueEnum, Copy, Clone, Debug, PartialEq)]
+pub enum Target {
+  /// 16-bit, 5 bits red, 6 bits green, 5 bits blue
+  Rgb565,
+  /// 15-bit, 5 bits per channel
+  Rgb555,
+  /// 12-bit, 4 bits per channel
+  Rgb444,
+  /// 8-bit, 3 bits red, 3 bits green, 2 bits blue
+  Rgb332,
+}
+
+serde_value_enum!(Target);
+
+impl Target {
+  /// Returns the number of bits of the red, green and blue channels.
+  #[must_use]
+  pub fn bits(&self) -> (u8, u8, u8) {
+    match self {
+      Target::Rgb565 => (5, 6, 5),
+      Target::Rgb555 => (5, 5, 5),
+      Target::Rgb444 => (4, 4, 4),
+      Target::Rgb332 => (3, 3, 2),
+    }
+  }
+}
+
+impl Quantizer for Target {
+  fn quantize(&self, color: Color) -> Color {
+    let (r, g, b) = self.bits();
+    Color {
+      r: expand(level(color.r, r), r),
+      g: expand(level(color.g, g), g),
+      b: expand(level(color.b, b), b),
+    }
+  }
+}
+
+/// Returns the nearest of the `2^bits` levels for an 8-bit channel value.
+#[must_use]
+pub fn level(value: u8, bits: u8) -> u16 {
+  let max = (1u32 << bits) - 1;
+  ((u32::from(value) * max + 127) / 255) as u16
+}
+
+/// Expands a `bits`-wide channel level back to the 8-bit value it is displayed as.
+#[must_use]
+pub fn expand(level: u16, bits: u8) -> u8 {
+  let max = (1u32 << bits) - 1;
+  ((u32::from(level) * 255 + max / 2) / max) as u8
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_level_and_expand_extremes() {
+    for bits in 1..=8 {
+      assert_eq!(level(0, bits), 0);
+      assert_eq!(level(255, bits), (1 << bits) - 1);
+      assert_eq!(expand(0, bits), 0);
+      assert_eq!(expand((1 << bits) - 1, bits), 255);
+    }
+  }
+
+  #[test]
+  fn test_quantize_is_idempotent() {
+    for target in [Target::Rgb565, Target::Rgb555, Target::Rgb444, Target::Rgb332] {
+      for v in 0..=255u8 {
+        let color = Color { r: v, g: v, b: 255 - v };
+        let quantized = target.quantize(color);
+        assert_eq!(
+          target.quantize(quantized),
+          quantized,
+          "{:?} should map representable colors to themselves",
+          target
+        );
+      }
+    }
+  }
+
+  #[test]
+  fn test_quantize_level_count() {
+    let levels = |bits: u8| {
+      (0..=255u8)
+        .map(|v| expand(level(v, bits), bits))
+        .collect::<std::collections::BTreeSet<_>>()
+        .len()
+    };
+    assert_eq!(levels(5), 32);
+    assert_eq!(levels(6), 64);
+    assert_eq!(levels(2), 4);
+  }
+
+  #[test]
+  fn test_rgb332_quantize() {
+    let color = Target::Rgb332.quantize(Color { r: 100, g: 100, b: 100 });
+    // 3-bit channels step by 255/7 ~ 36.4, 2-bit channels by 85
+    assert_eq!(color, Color { r: 109, g: 109, b: 85 });
+  }
+}
@@ -0,0 +1,204 @@
+//! Output filename templates.
+//!
+//! A template such as `{stem}_{method}_{palette}.{ext}` names the output of a job from its settings.
+//! The available placeholders are:
+//!
+//! | Placeholder | Replaced with                                   |
+//! | ----------- | ----------------------------------------------- |
+//! | `{stem}`    | input file name without extension               |
+//! | `{ext}`     | input file extension                            |
+//! | `{method}`  | dithering method, e.g. `floyd-steinberg`        |
+//! | `{palette}` | color palette, e.g. `color8`                    |
+//! | `{size}`    | output dimensions as `<width>x<height>`         |
+//! | `{date}`    | current date (UTC) as `YYYY-MM-DD`              |
+//!
+//! Rendered names are relative to the directory of the input image.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::dither::DitherMethod;
+use crate::palette::ColorPalette;
+
+/// Placeholders recognized inside an [`OutputTemplate`].
+const PLACEHOLDERS: [&str; 6] = ["stem", "ext", "method", "palette", "size", "date"];
+
+/// A validated output filename template.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct OutputTemplate(String);
+
+/// Values substituted into an [`OutputTemplate`].
+pub struct TemplateContext<'a> {
+  /// Input image file path
+  pub input: &'a Path,
+  /// Dithering algorithm used
+  pub method: DitherMethod,
+  /// Color palette used
+  pub palette: ColorPalette,
+  /// Output image width
+  pub width: u32,
+  /// Output image height
+  pub height: u32,
+}
+
+impl OutputTemplate {
+  /// Returns whether the template uses the given placeholder, e.g. `"size"`.
+  #[must_use]
+  pub fn uses(&self, placeholder: &str) -> bool {
+    self.0.contains(&format!("{{{placeholder}}}"))
+  }
+
+  /// Renders the template and returns the output path next to the input image.
+  #[must_use]
+  pub fn render(&self, context: &TemplateContext) -> PathBuf {
+    let mut name = String::new();
+    let mut rest = self.0.as_str();
+    while let Some(start) = rest.find('{') {
+      // validated on construction, so every `{` has a matching `}` around a known placeholder
+      let end = start + rest[start..].find('}').expect("validated template");
+      name.push_str(&rest[..start]);
+      name.push_str(&placeholder_value(&rest[start + 1..end], context));
+      rest = &rest[end + 1..];
+    }
+    name.push_str(rest);
+
+    context.input.parent().unwrap_or(Path::new("")).join(name)
+  }
+}
+
+fn placeholder_value(placeholder: &str, context: &TemplateContext) -> String {
+  let os_str = |s: Option<&std::ffi::OsStr>| s.map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+  match placeholder {
+    "stem" => os_str(context.input.file_stem()),
+    "ext" => os_str(context.input.extension()),
+    "method" => value_name(&context.method),
+    "palette" => value_name(&context.palette),
+    "size" => format!("{}x{}", context.width, context.height),
+    "date" => current_date(),
+    _ => unreachable!("validated template"),
+  }
+}
+
+/// Returns the command-line spelling of a value enum variant.
+fn value_name<T: ValueEnum>(value: &T) -> String {
+  value.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default()
+}
+
+/// Returns today's date (UTC) formatted as `YYYY-MM-DD`.
+fn current_date() -> String {
+  let seconds = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+  let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+  format!("{year:04}-{month:02}-{day:02}")
+}
+
+/// Converts days since 1970-01-01 into a (year, month, day) Gregorian date.
+///
+/// See Howard Hinnant's `civil_from_days` algorithm.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+  let z = days + 719_468;
+  let era = z.div_euclid(146_097);
+  let doe = z.rem_euclid(146_097);
+  let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+  let year = yoe + era * 400 + i64::from(month <= 2);
+  (year, month, day)
+}
+
+impl FromStr for OutputTemplate {
+  type Err = String;
+
+  fn from_str(template: &str) -> Result<Self, Self::Err> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+      let Some(end) = rest[start..].find('}').map(|end| start + end) else {
+        return Err(format!("unclosed '{{' in template {template:?}"));
+      };
+      let placeholder = &rest[start + 1..end];
+      if !PLACEHOLDERS.contains(&placeholder) {
+        return Err(format!(
+          "unknown placeholder {{{placeholder}}}, expected one of {{{}}}",
+          PLACEHOLDERS.join("}, {")
+        ));
+      }
+      rest = &rest[end + 1..];
+    }
+    if rest.contains('}') {
+      return Err(format!("unmatched '}}' in template {template:?}"));
+    }
+    Ok(OutputTemplate(template.to_string()))
+  }
+}
+
+impl TryFrom<String> for OutputTemplate {
+  type Error = String;
+
+  fn try_from(template: String) -> Result<Self, Self::Error> {
+    template.parse()
+  }
+}
+
+impl From<OutputTemplate> for String {
+  fn from(template: OutputTemplate) -> Self {
+    template.0
+  }
+}
+
+impl fmt::Display for OutputTemplate {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn context(input: &Path) -> TemplateContext<'_> {
+    TemplateContext {
+      input,
+      method: DitherMethod::TwoRowSierra,
+      palette: ColorPalette::COLOR8,
+      width: 640,
+      height: 480,
+    }
+  }
+
+  #[test]
+  fn test_render_all_placeholders() {
+    let template: OutputTemplate = "{stem}_{method}_{palette}_{size}.{ext}".parse().unwrap();
+    let path = template.render(&context(Path::new("photos/cat.jpg")));
+    assert_eq!(path, PathBuf::from("photos/cat_two-row-sierra_color8_640x480.jpg"));
+  }
+
+  #[test]
+  fn test_render_date() {
+    let template: OutputTemplate = "{date}.png".parse().unwrap();
+    let name = template.render(&context(Path::new("cat.jpg"))).to_string_lossy().into_owned();
+    assert_eq!(name.len(), "YYYY-MM-DD.png".len());
+    assert_eq!(&name[4..5], "-");
+    assert_eq!(&name[7..8], "-");
+  }
+
+  #[test]
+  fn test_invalid_templates_rejected() {
+    assert!("{stem".parse::<OutputTemplate>().is_err());
+    assert!("{colour}.png".parse::<OutputTemplate>().is_err());
+    assert!("stem}.png".parse::<OutputTemplate>().is_err());
+  }
+
+  #[test]
+  fn test_civil_from_days() {
+    assert_eq!(civil_from_days(0), (1970, 1, 1));
+    assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    assert_eq!(civil_from_days(20_740), (2026, 10, 14));
+  }
+}
@@ -0,0 +1,324 @@
+//! Rectangular tiles of an image and incremental re-dithering of changed tiles.
+//!
+//! Sprite sheets and attribute clash dither an image tile by tile, each with its own palette. A
+//! [`Tile`] is a rectangle of the image, [`grid`] covers an image with them row by row.
+//!
+//! Watch, GUI and server modes dither the same image over and over with small edits. With an ordered
+//! method every pixel only depends on its own color and position, so [`IncrementalDither`] only
+//! dithers the tiles whose input changed again, each continuing the pattern from its position, and
+//! updates the output in place. Error diffusion carries errors across the whole image, and some
+//! stages such as `--resize` work on the whole image, so with these any change re-dithers everything.
+//! The [daemon](crate::daemon) keeps an engine per connection for requests with raw pixels.
+//!
+//! ```
+//! use dithers::dither::DitherMethod;
+//! use dithers::palette::ColorPalette;
+//! use dithers::pipeline::{Image, Settings};
+//! use dithers::tiles::IncrementalDither;
+//!
+//! let mut image = Image { buffer: vec![128; 256 * 256 * 3], alpha: None, width: 256, height: 256 };
+//! let colors = ColorPalette::Monochrome.colors().to_vec();
+//! let mut engine = IncrementalDither::new(DitherMethod::Bayer8x8, colors, Settings::default(), 64);
+//! engine.update(&image).unwrap();
+//!
+//! // a single changed pixel only re-dithers its tile
+//! image.buffer[0] = 0;
+//! assert_eq!(engine.update(&image).unwrap().len(), 1);
+//! ```
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::dither::DitherMethod;
+use crate::error::DitherError;
+use crate::palette::Color;
+use crate::pipeline::{self, Image, Settings};
+use crate::stream;
+
+/// Tile size of [`IncrementalDither`] when none is given.
+pub const DEFAULT_TILE_SIZE: u32 = 64;
+
+/// A rectangle of an image, in pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Tile {
+  /// Left edge
+  pub x: u32,
+  /// Top edge
+  pub y: u32,
+  /// Width, smaller than the tile size at the right border
+  pub width: u32,
+  /// Height, smaller than the tile size at the bottom border
+  pub height: u32,
+}
+
+/// Keeps a dithered image up to date with a changing input.
+#[derive(Clone, Debug)]
+pub struct IncrementalDither {
+  method: DitherMethod,
+  colors: Vec<Color>,
+  settings: Settings,
+  tile_size: u32,
+  input: Option<Image>,
+  output: Image,
+}
+
+impl IncrementalDither {
+  /// Creates an engine that dithers to `colors` in tiles of `tile_size` pixels, processed like
+  /// [`pipeline::process_with_colors`].
+  #[must_use]
+  pub fn new(method: DitherMethod, colors: Vec<Color>, settings: Settings, tile_size: u32) -> Self {
+    IncrementalDither {
+      method,
+      colors,
+      settings,
+      tile_size: tile_size.max(1),
+      input: None,
+      output: Image::default(),
+    }
+  }
+
+  /// Returns whether the engine dithers with these parameters, so that it can update its output for a
+  /// new input instead of starting over.
+  #[must_use]
+  pub fn matches(&self, method: DitherMethod, colors: &[Color], settings: &Settings) -> bool {
+    self.method == method && self.colors == colors && self.settings == *settings
+  }
+
+  /// Returns the dithered image of the last update.
+  #[must_use]
+  pub fn output(&self) -> &Image {
+    &self.output
+  }
+
+  /// Dithers a new version of the input, returns the tiles of the output that changed.
+  ///
+  /// The first update, a change of size or of the alpha channel, and any change with an error
+  /// diffusion method or a stage that needs the whole image re-dither everything, which is reported as
+  /// a single tile covering the output. A failed update leaves the output of the last one.
+  pub fn update(&mut self, input: &Image) -> Result<Vec<Tile>, DitherError> {
+    input.validate()?;
+    let tiled = self.method.is_ordered() && stream::whole_image_option(&self.settings).is_none();
+    let changed: Option<Vec<Tile>> = match &self.input {
+      Some(previous) if (previous.width, previous.height, previous.alpha.is_some()) == (input.width, input.height, input.alpha.is_some()) => {
+        let changed: Vec<Tile> = grid(input.width, input.height, self.tile_size)
+          .filter(|tile| differs(previous, input, tile))
+          .collect();
+        (tiled || changed.is_empty()).then_some(changed)
+      }
+      _ => None,
+    };
+    let Some(changed) = changed else {
+      let mut output = input.clone();
+      pipeline::process_with_colors(&mut output, self.method, &self.colors, &self.settings)?;
+      log::debug!("re-dithering the whole {}x{} image", input.width, input.height);
+      self.output = output;
+      self.input = Some(input.clone());
+      return Ok(vec![Tile {
+        x: 0,
+        y: 0,
+        width: self.output.width,
+        height: self.output.height,
+      }]);
+    };
+    log::debug!("re-dithering {} tile(s)", changed.len());
+
+    let dithered: Vec<Image> = changed.par_iter().map(|tile| self.dither_tile(input, tile)).collect::<Result<_, _>>()?;
+    for (tile, image) in changed.iter().zip(dithered) {
+      tile.insert(&image.buffer, &mut self.output.buffer, input.width, 3);
+      if let (Some(pixels), Some(alpha)) = (&image.alpha, self.output.alpha.as_mut()) {
+        tile.insert(pixels, alpha, input.width, 1);
+      }
+    }
+    self.input = Some(input.clone());
+    Ok(changed)
+  }
+
+  fn dither_tile(&self, input: &Image, tile: &Tile) -> Result<Image, DitherError> {
+    let mut image = Image {
+      buffer: tile.extract(&input.buffer, input.width, 3),
+      alpha: input.alpha.as_ref().map(|alpha| tile.extract(alpha, input.width, 1)),
+      width: tile.width,
+      height: tile.height,
+    };
+    pipeline::process_tile(&mut image, self.method, &self.colors, &self.settings, (tile.x, tile.y))?;
+    Ok(image)
+  }
+}
+
+/// Splits an image into tiles of `size` pixels, row by row from the top left.
+///
+/// Tiles at the right and bottom border are smaller if the image size is not a multiple of `size`.
+pub fn grid(width: u32, height: u32, size: u32) -> impl Iterator<Item = Tile> {
+  let size = size.max(1);
+  (0..height).step_by(size as usize).flat_map(move |y| {
+    (0..width).step_by(size as usize).map(move |x| Tile {
+      x,
+      y,
+      width: size.min(width - x),
+      height: size.min(height - y),
+    })
+  })
+}
+
+impl Tile {
+  /// Copies the pixels of the tile out of an image buffer with `channels` bytes per pixel.
+  #[must_use]
+  pub fn extract(&self, buffer: &[u8], image_width: u32, channels: usize) -> Vec<u8> {
+    self.rows(buffer, image_width, channels).flat_map(|row| row.iter().copied()).collect()
+  }
+
+  /// Copies the pixels of the tile, as returned by [`Tile::extract`], back into an image buffer.
+  pub fn insert(&self, pixels: &[u8], buffer: &mut [u8], image_width: u32, channels: usize) {
+    let row_len = self.width as usize * channels;
+    for (row, src) in pixels.chunks_exact(row_len).enumerate() {
+      let start = ((self.y as usize + row) * image_width as usize + self.x as usize) * channels;
+      buffer[start..start + row_len].copy_from_slice(src);
+    }
+  }
+
+  fn rows<'a>(&self, buffer: &'a [u8], image_width: u32, channels: usize) -> impl Iterator<Item = &'a [u8]> {
+    let (x, width) = (self.x as usize, self.width as usize);
+    (self.y..self.y + self.height).map(move |y| {
+      let start = (y as usize * image_width as usize + x) * channels;
+      &buffer[start..start + width * channels]
+    })
+  }
+}
+
+fn differs(a: &Image, b: &Image, tile: &Tile) -> bool {
+  let alpha_differs = match (&a.alpha, &b.alpha) {
+    (Some(a_alpha), Some(b_alpha)) => tile.rows(a_alpha, a.width, 1).zip(tile.rows(b_alpha, b.width, 1)).any(|(a, b)| a != b),
+    _ => false,
+  };
+  alpha_differs || tile.rows(&a.buffer, a.width, 3).zip(tile.rows(&b.buffer, b.width, 3)).any(|(a, b)| a != b)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::palette::ColorPalette;
+  use crate::patterns::{self, Pattern, Size};
+
+  fn tile(x: u32, y: u32, width: u32, height: u32) -> Tile {
+    Tile { x, y, width, height }
+  }
+
+  fn gradient(width: u32, height: u32) -> Image {
+    patterns::generate(Pattern::Gradient, Size { width, height })
+  }
+
+  fn engine(method: DitherMethod, settings: Settings, tile_size: u32) -> IncrementalDither {
+    IncrementalDither::new(method, ColorPalette::COLOR8.colors().to_vec(), settings, tile_size)
+  }
+
+  fn dithered(image: &Image, method: DitherMethod, settings: &Settings) -> Image {
+    let mut image = image.clone();
+    pipeline::process_with_colors(&mut image, method, ColorPalette::COLOR8.colors(), settings).unwrap();
+    image
+  }
+
+  #[test]
+  fn test_tiles_cover_image() {
+    let tiles: Vec<Tile> = grid(40, 20, 16).collect();
+    assert_eq!(tiles.len(), 3 * 2);
+    assert_eq!(tiles[2], tile(32, 0, 8, 16));
+    assert_eq!(tiles[5], tile(32, 16, 8, 4));
+  }
+
+  #[test]
+  fn test_tiled_matches_whole_image() {
+    // tiles that are not a multiple of the matrix size must still continue the pattern
+    let image = gradient(50, 30);
+    for method in [DitherMethod::Bayer8x8, DitherMethod::Halftone, DitherMethod::Random] {
+      let settings = Settings {
+        saturation: 0.5,
+        seed: 7,
+        ..Settings::default()
+      };
+      let mut engine = engine(method, settings.clone(), 13);
+      engine.update(&image).unwrap();
+      let mut edited = image.clone();
+      edited.buffer[(20 * 50 + 30) * 3] = 0;
+
+      assert_eq!(engine.update(&edited).unwrap(), vec![tile(26, 13, 13, 13)], "{method:?}");
+      assert_eq!(*engine.output(), dithered(&edited, method, &settings), "{method:?}");
+    }
+  }
+
+  #[test]
+  fn test_tiles_patch_the_alpha_channel() {
+    let mut image = gradient(20, 20);
+    image.alpha = Some((0..20 * 20).map(|i| (i * 3 % 256) as u8).collect());
+    let settings = Settings {
+      alpha_dither: true,
+      seed: 7,
+      ..Settings::default()
+    };
+    for method in [DitherMethod::Bayer4x4, DitherMethod::Halftone, DitherMethod::Random] {
+      let mut image = image.clone();
+      let mut engine = engine(method, settings.clone(), 8);
+      engine.update(&image).unwrap();
+      image.alpha.as_mut().unwrap()[10 * 20 + 10] = 255;
+
+      assert_eq!(engine.update(&image).unwrap(), vec![tile(8, 8, 8, 8)], "{method:?}");
+      assert_eq!(*engine.output(), dithered(&image, method, &settings), "{method:?}");
+    }
+  }
+
+  #[test]
+  fn test_unchanged_input_does_nothing() {
+    let image = gradient(20, 20);
+    let mut engine = engine(DitherMethod::Bayer2x2, Settings::default(), 8);
+    assert_eq!(engine.update(&image).unwrap().len(), 1);
+    assert!(engine.update(&image).unwrap().is_empty());
+  }
+
+  #[test]
+  fn test_error_diffusion_redithers_everything() {
+    let image = gradient(20, 10);
+    let mut engine = engine(DitherMethod::FloydSteinberg, Settings::default(), 4);
+    engine.update(&image).unwrap();
+    let mut edited = image.clone();
+    edited.buffer[0] = 255;
+
+    assert_eq!(engine.update(&edited).unwrap(), vec![tile(0, 0, 20, 10)]);
+    assert_eq!(*engine.output(), dithered(&edited, DitherMethod::FloydSteinberg, &Settings::default()));
+  }
+
+  #[test]
+  fn test_whole_image_stages_redither_everything() {
+    let settings = Settings {
+      border: Some(2),
+      ..Settings::default()
+    };
+    let image = gradient(20, 10);
+    let mut engine = engine(DitherMethod::Bayer4x4, settings.clone(), 4);
+    engine.update(&image).unwrap();
+    let mut edited = image.clone();
+    edited.buffer[0] = 255;
+
+    assert_eq!(engine.update(&edited).unwrap(), vec![tile(0, 0, 24, 14)]);
+    assert_eq!(*engine.output(), dithered(&edited, DitherMethod::Bayer4x4, &settings));
+  }
+
+  #[test]
+  fn test_resize_redithers_everything() {
+    let mut engine = engine(DitherMethod::Bayer4x4, Settings::default(), 8);
+    engine.update(&gradient(16, 16)).unwrap();
+    let changed = engine.update(&gradient(24, 8)).unwrap();
+    assert_eq!(changed, vec![tile(0, 0, 24, 8)]);
+    assert_eq!(engine.output().width, 24);
+  }
+
+  #[test]
+  fn test_extract_and_insert_round_trip() {
+    let buffer: Vec<u8> = (0..5 * 4).map(|i| i as u8).collect();
+    let inner = tile(1, 1, 3, 2);
+    let pixels = inner.extract(&buffer, 5, 1);
+    assert_eq!(pixels, vec![6, 7, 8, 11, 12, 13]);
+    let mut copy = vec![0; buffer.len()];
+    inner.insert(&pixels, &mut copy, 5, 1);
+    assert_eq!(copy[11..14], buffer[11..14]);
+    assert_eq!(copy[0], 0, "Pixels outside the tile are not touched");
+  }
+}
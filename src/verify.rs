@@ -0,0 +1,156 @@
+//! Verification of job outputs against stored references.
+//!
+//! Re-running the jobs of a manifest and comparing the results with previously stored outputs shows
+//! whether an upgrade changed the dithering. References are matched by the file name of each job's
+//! output and compared pixel by pixel, raw packed outputs (see [`OutputFormat`](crate::output::OutputFormat))
+//! byte by byte.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::error::DitherError;
+use crate::jobs::Job;
+use crate::pipeline::Image;
+use crate::{input, output};
+
+/// Result of comparing a job output with its reference.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Outcome {
+  /// Output and reference are identical
+  Identical,
+  /// Output differs by the given mean absolute difference, within the tolerance
+  WithinTolerance(f64),
+  /// Output differs by the given mean absolute difference, beyond the tolerance
+  Different(f64),
+  /// Output and reference have different dimensions or lengths
+  SizeMismatch,
+  /// There is no reference for the output
+  Missing,
+}
+
+impl Outcome {
+  /// Returns whether the output is accepted.
+  #[must_use]
+  pub fn passed(&self) -> bool {
+    matches!(self, Outcome::Identical | Outcome::WithinTolerance(_))
+  }
+}
+
+/// Returns the path of the reference for a job output inside the reference directory.
+fn reference_path(job: &Job, image: &Image, against: &Path) -> PathBuf {
+  let output = job.output_path(image.width, image.height);
+  against.join(output.file_name().unwrap_or(output.as_os_str()))
+}
+
+/// Re-runs a job and compares its output with the reference in `against`.
+///
+/// `tolerance` is the largest accepted mean absolute difference per channel (0-255), with 0 only
+/// identical outputs pass. Returns the reference path together with the outcome.
+pub fn verify(job: &Job, against: &Path, tolerance: f64) -> Result<(PathBuf, Outcome), DitherError> {
+  let image = job.render()?;
+  let reference = reference_path(job, &image, against);
+  if !reference.exists() {
+    return Ok((reference, Outcome::Missing));
+  }
+
+  let difference = match job.settings.format {
+    Some(format) => {
+      let expected = fs::read(&reference).map_err(|source| DitherError::Io {
+        path: reference.clone(),
+        source,
+      })?;
+      let actual = output::encode(&image, format, &job.settings).map_err(|message| DitherError::Encode {
+        path: reference.clone(),
+        message,
+      })?;
+      mean_absolute_difference(&actual, &expected)
+    }
+    None => {
+      let expected = input::load(&reference)?;
+      if (expected.width, expected.height) != (image.width, image.height) {
+        None
+      } else {
+        // formats without alpha, such as JPEG, reload without it, so it is only compared when both have it
+        let both_alpha = image.alpha.is_some() && expected.alpha.is_some();
+        let channels = |image: &Image| {
+          let alpha = image.alpha.iter().filter(|_| both_alpha).flatten();
+          image.buffer.iter().chain(alpha).copied().collect::<Vec<u8>>()
+        };
+        mean_absolute_difference(&channels(&image), &channels(&expected))
+      }
+    }
+  };
+
+  let outcome = match difference {
+    None => Outcome::SizeMismatch,
+    Some(0.0) => Outcome::Identical,
+    Some(d) if d <= tolerance => Outcome::WithinTolerance(d),
+    Some(d) => Outcome::Different(d),
+  };
+  Ok((reference, outcome))
+}
+
+/// Runs a job and stores its output as the new reference in `against`.
+///
+/// Returns the path of the written reference.
+pub fn bless(job: &Job, against: &Path) -> Result<PathBuf, DitherError> {
+  let image = job.render()?;
+  let reference = reference_path(job, &image, against);
+  output::save(&image, &reference, &job.settings)?;
+  Ok(reference)
+}
+
+/// Returns the mean absolute difference of two byte slices, or `None` if their lengths differ.
+#[must_use]
+pub fn mean_absolute_difference(a: &[u8], b: &[u8]) -> Option<f64> {
+  if a.len() != b.len() {
+    return None;
+  }
+  if a.is_empty() {
+    return Some(0.0);
+  }
+  let sum: u64 = a.iter().zip(b).map(|(&a, &b)| u64::from(a.abs_diff(b))).sum();
+  Some(sum as f64 / a.len() as f64)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_mean_absolute_difference() {
+    assert_eq!(mean_absolute_difference(&[1, 2, 3], &[1, 2, 3]), Some(0.0));
+    assert_eq!(mean_absolute_difference(&[0, 10], &[4, 0]), Some(7.0));
+    assert_eq!(mean_absolute_difference(&[0, 10], &[0]), None);
+    assert_eq!(mean_absolute_difference(&[], &[]), Some(0.0));
+  }
+
+  #[test]
+  fn test_verify_output_without_alpha() {
+    let dir = std::env::temp_dir().join("dithers_verify_alpha_test");
+    fs::create_dir_all(&dir).unwrap();
+    let input = dir.join("sprite.png");
+    image::RgbaImage::from_fn(16, 16, |x, _| image::Rgba([200, 200, 200, (x * 16) as u8]))
+      .save(&input)
+      .unwrap();
+    let mut job = Job::new(&input);
+    job.method = crate::dither::DitherMethod::None;
+    job.output = Some(dir.join("sprite.jpg"));
+
+    let against = dir.join("references");
+    fs::create_dir_all(&against).unwrap();
+    bless(&job, &against).unwrap();
+    let (_, outcome) = verify(&job, &against, 50.0).unwrap();
+    assert!(outcome.passed(), "A reference without alpha should compare the colors, got {outcome:?}");
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn test_outcome_passed() {
+    assert!(Outcome::Identical.passed());
+    assert!(Outcome::WithinTolerance(0.5).passed());
+    assert!(!Outcome::Different(3.0).passed());
+    assert!(!Outcome::SizeMismatch.passed());
+    assert!(!Outcome::Missing.passed());
+  }
+}
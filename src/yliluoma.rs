@@ -0,0 +1,556 @@
+//! Yliluoma's arbitrary-palette positional dithering.
+//!
+//! Ordered dithering adds a threshold to every channel and quantizes the result, which suits evenly
+//! spaced palettes but leaves bands and stray colors with small hand-picked ones. Joel Yliluoma's
+//! algorithms plan the mix instead: every color is approximated by a blend of palette colors, and the
+//! threshold of the 8x8 Bayer matrix chooses the color of the blend each pixel gets. The output keeps
+//! the stable, tileable pattern of Bayer dithering with any palette.
+//!
+//! Algorithm 1 mixes two colors in one of [`LEVELS`] ratios and penalizes mixes of very different
+//! colors, which would be grainy even when their blend matches. The improved algorithms mix in linear
+//! light, where the eye averages the dots: algorithm 2 adds palette colors to a list of [`LEVELS`]
+//! candidates until their average matches, which can mix many colors, and algorithm 3 chooses between
+//! single colors, two-color mixes and tri-tones of three colors in equal parts.
+
+use std::collections::HashMap;
+
+use crate::adjust;
+use crate::dither::{self, BAYER8X8, DitherMethod, DitherOptions};
+use crate::error::DitherError;
+use crate::indexed;
+use crate::palette::{Color, Quantizer};
+
+/// Number of mixing ratios, one per threshold of the 8x8 Bayer matrix.
+pub const LEVELS: u8 = 64;
+
+/// Weight of the difference between the two mixed colors against the error of their blend.
+const CONTRAST_PENALTY: f32 = 0.1;
+
+/// Weight of the contrast in algorithm 3, lower so that black and white still mix in the shadows of linear light.
+const LINEAR_CONTRAST_PENALTY: f32 = 0.025;
+
+/// Palette colors closest to a color on their own, among which algorithm 3 looks for tri-tones.
+const TRITONE_CANDIDATES: usize = 8;
+
+/// Most plans kept at once, the plans are dropped when there are more so that photos with many colors
+/// do not grow the cache without bound.
+pub const MAX_PLANS: usize = 1 << 16;
+
+/// A blend of two palette colors, the second covering `ratio` / [`LEVELS`] of the pixels.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MixingPlan {
+  /// The colors mixed, the second one fills the pixels with the lower thresholds
+  pub colors: [Color; 2],
+  /// Share of the second color in [`LEVELS`]ths, from 0 to `LEVELS - 1`
+  pub ratio: u8,
+}
+
+/// Channels scaled to 0..1, algorithm 1 mixes and measures these.
+fn srgb(color: Color) -> [f32; 3] {
+  [color.r, color.g, color.b].map(|value| f32::from(value) / 255.0)
+}
+
+/// Channels in linear light, algorithms 2 and 3 mix and measure these.
+fn linear(color: Color) -> [f32; 3] {
+  [color.r, color.g, color.b].map(adjust::srgb_to_linear)
+}
+
+fn sub(a: [f32; 3], b: [f32; 3]) -> [f32; 3] {
+  std::array::from_fn(|c| a[c] - b[c])
+}
+
+/// Channel differences scaled to 0..1, the errors of algorithm 1 are measured on these.
+fn difference(a: Color, b: Color) -> [f32; 3] {
+  sub(srgb(a), srgb(b))
+}
+
+/// The bilinear form of [`color_compare`], so that mixing errors can be expanded along a blend.
+fn product(d: [f32; 3], e: [f32; 3]) -> f32 {
+  let luma = |c: [f32; 3]| c[0] * 0.299 + c[1] * 0.587 + c[2] * 0.114;
+  (d[0] * e[0] * 0.299 + d[1] * e[1] * 0.587 + d[2] * e[2] * 0.114) * 0.75 + luma(d) * luma(e)
+}
+
+/// Rec. 601 luma, the candidate lists are sorted by it so that the thresholds order the colors like Bayer dithering.
+fn luma(color: &Color) -> u32 {
+  299 * u32::from(color.r) + 587 * u32::from(color.g) + 114 * u32::from(color.b)
+}
+
+/// Returns the difference of two colors as algorithm 1 measures it, channels weighted by their share
+/// of the luma, plus the difference of the luma itself.
+#[must_use]
+pub fn color_compare(a: Color, b: Color) -> f32 {
+  let d = difference(a, b);
+  product(d, d)
+}
+
+/// Returns the error, the indices and the ratio of the best single color or blend of two `points` for
+/// `target`, with the contrast of the blended colors weighted by `penalty`.
+///
+/// The error of a blend is a quadratic in its ratio, so the best ratio of every pair of colors is
+/// solved for instead of trying all of them.
+fn best_pair(target: [f32; 3], points: &[[f32; 3]], penalty: f32) -> (f32, usize, usize, u8) {
+  let mut best = (f32::INFINITY, 0, 0, 0);
+  for (i, &first) in points.iter().enumerate() {
+    let d = sub(target, first);
+    let single = product(d, d);
+    if single < best.0 {
+      best = (single, i, i, 0);
+    }
+    for (j, &second) in points.iter().enumerate().skip(i + 1) {
+      // blending first + t * e leaves the error Q(d) - 2t B(d, e) + t² Q(e), the penalty grows with |t - 0.5|
+      let e = sub(second, first);
+      let q = product(e, e);
+      if q == 0.0 {
+        continue;
+      }
+      let b = product(d, e);
+      let blend = |t: f32| single - 2.0 * t * b + t * t * q + penalty * q * ((t - 0.5).abs() + 0.5);
+      // the minimum of the convex error, on either side of an even mix
+      let lowest = if b / q + penalty / 2.0 < 0.5 {
+        b / q + penalty / 2.0
+      } else {
+        (b / q - penalty / 2.0).max(0.5)
+      };
+      let ratio = (lowest * f32::from(LEVELS)).clamp(0.0, f32::from(LEVELS - 1));
+      for ratio in [ratio.floor() as u8, ratio.ceil() as u8] {
+        let error = blend(f32::from(ratio) / f32::from(LEVELS));
+        if error < best.0 {
+          best = (error, i, j, ratio);
+        }
+      }
+    }
+  }
+  best
+}
+
+/// Returns the mix of two colors from `palette` that best approximates `color` (algorithm 1), see the [module documentation](self).
+///
+/// # Panics
+///
+/// Panics if the palette is empty.
+#[must_use]
+pub fn mixing_plan(color: Color, palette: &[Color]) -> MixingPlan {
+  let points: Vec<[f32; 3]> = palette.iter().map(|&color| srgb(color)).collect();
+  let (_, first, second, ratio) = best_pair(srgb(color), &points, CONTRAST_PENALTY);
+  MixingPlan {
+    colors: [palette[first], palette[second]],
+    ratio,
+  }
+}
+
+/// Returns [`LEVELS`] colors from `palette` whose average in linear light approximates `color`,
+/// darkest first (algorithm 2).
+///
+/// Every step adds the palette color that brings the average closest, in a count of 1, 2, 4 and so
+/// on up to the length of the list so far.
+///
+/// # Panics
+///
+/// Panics if the palette is empty.
+#[must_use]
+pub fn candidate_list(color: Color, palette: &[Color]) -> Vec<Color> {
+  let points: Vec<[f64; 3]> = palette.iter().map(|&color| linear(color).map(f64::from)).collect();
+  let product = |d: [f64; 3], e: [f64; 3]| {
+    let luma = |c: [f64; 3]| c[0] * 0.299 + c[1] * 0.587 + c[2] * 0.114;
+    (d[0] * e[0] * 0.299 + d[1] * e[1] * 0.587 + d[2] * e[2] * 0.114) * 0.75 + luma(d) * luma(e)
+  };
+  // the error of an average expands into products of the target, the sum so far and the palette
+  // colors, which are kept up to date as the list grows, so a step costs a few multiplications per candidate
+  let target = linear(color).map(f64::from);
+  let target_target = product(target, target);
+  let target_point: Vec<f64> = points.iter().map(|&point| product(target, point)).collect();
+  let point_point: Vec<f64> = points.iter().map(|&point| product(point, point)).collect();
+  let mut sum_point = vec![0.0; points.len()];
+  let (mut target_sum, mut sum_sum) = (0.0, 0.0);
+
+  let levels = usize::from(LEVELS);
+  let mut list = Vec::with_capacity(levels);
+  while list.len() < levels {
+    let count = list.len();
+    let mut best = (f64::INFINITY, 0, 1);
+    // adding 1, 2, 4 and so on copies of a color, at most as many as the list holds
+    let mut amount = 1;
+    while amount <= count.max(1) {
+      // Q(target - (sum + amount * point) / total), times total²
+      let (total, amount_f) = ((count + amount) as f64, amount as f64);
+      let residual = total * total * target_target - 2.0 * total * target_sum + sum_sum;
+      for (index, ((&toward, &along_sum), &own)) in target_point.iter().zip(&sum_point).zip(&point_point).enumerate() {
+        let along = total * toward - along_sum;
+        let error = (residual - 2.0 * amount_f * along + amount_f * amount_f * own) / (total * total);
+        if error < best.0 {
+          best = (error, index, amount);
+        }
+      }
+      amount *= 2;
+    }
+    let (_, chosen, amount) = best;
+    let amount = amount.min(levels - count);
+    let amount_f = amount as f64;
+    target_sum += amount_f * target_point[chosen];
+    sum_sum += 2.0 * amount_f * sum_point[chosen] + amount_f * amount_f * point_point[chosen];
+    for (sum_point, &point) in sum_point.iter_mut().zip(&points) {
+      *sum_point += amount_f * product(points[chosen], point);
+    }
+    list.extend(std::iter::repeat_n(palette[chosen], amount));
+  }
+  list.sort_by_key(luma);
+  list
+}
+
+/// Returns [`LEVELS`] colors from `palette` of the best single color, two-color mix or tri-tone for
+/// `color` in linear light, darkest first (algorithm 3).
+///
+/// Contrast is penalized less than in algorithm 1, tri-tones like an even two-color mix with the
+/// average contrast of their pairs. Tri-tones are only made of the [`TRITONE_CANDIDATES`] colors
+/// closest on their own.
+///
+/// # Panics
+///
+/// Panics if the palette is empty.
+#[must_use]
+pub fn tritone_list(color: Color, palette: &[Color]) -> Vec<Color> {
+  let target = linear(color);
+  let points: Vec<[f32; 3]> = palette.iter().map(|&color| linear(color)).collect();
+  let levels = usize::from(LEVELS);
+  let (mut best, first, second, ratio) = best_pair(target, &points, LINEAR_CONTRAST_PENALTY);
+  let mut counts = vec![(first, levels - usize::from(ratio)), (second, usize::from(ratio))];
+
+  let error = |d: [f32; 3]| product(d, d);
+  let singles: Vec<f32> = points.iter().map(|&point| error(sub(target, point))).collect();
+  let mut nearest: Vec<usize> = (0..points.len()).collect();
+  nearest.sort_by(|&a, &b| singles[a].total_cmp(&singles[b]));
+  nearest.truncate(TRITONE_CANDIDATES);
+  for (i, &a) in nearest.iter().enumerate() {
+    for (j, &b) in nearest.iter().enumerate().skip(i + 1) {
+      for &c in &nearest[j + 1..] {
+        let mean: [f32; 3] = std::array::from_fn(|k| (points[a][k] + points[b][k] + points[c][k]) / 3.0);
+        let contrast = [(a, b), (b, c), (a, c)].map(|(p, q)| error(sub(points[p], points[q]))).iter().sum::<f32>() / 3.0;
+        let penalty = error(sub(target, mean)) + LINEAR_CONTRAST_PENALTY * contrast / 2.0;
+        if penalty < best {
+          best = penalty;
+          counts = vec![(a, levels / 3), (b, levels / 3), (c, levels - 2 * (levels / 3))];
+        }
+      }
+    }
+  }
+  let mut list: Vec<Color> = counts
+    .into_iter()
+    .flat_map(|(index, count)| std::iter::repeat_n(palette[index], count))
+    .collect();
+  list.sort_by_key(luma);
+  list
+}
+
+/// Dithers an RGB buffer with one of Yliluoma's algorithms, see the [module documentation](self).
+///
+/// `method` chooses the algorithm, anything but [`DitherMethod::Yliluoma2`] and [`DitherMethod::Yliluoma3`]
+/// runs algorithm 1. The chosen colors still pass through the quantizer, so hooks see every pixel.
+/// Quantizers without a [palette](Quantizer::palette), or with more than [`indexed::MAX_COLORS`]
+/// colors, fall back to the 8x8 Bayer matrix. The matrix continues from [`DitherOptions::origin`] and
+/// transparent pixels are left untouched.
+pub fn dither<Q: Quantizer + ?Sized>(
+  buffer: &mut [u8],
+  method: DitherMethod,
+  quantizer: &Q,
+  width: u32,
+  height: u32,
+  options: &DitherOptions,
+) -> Result<(), DitherError> {
+  let Some(palette) = quantizer.palette().filter(|palette| !palette.is_empty()) else {
+    log::debug!("no palette to mix, dithering with the 8x8 Bayer matrix");
+    return dither::dither_with(buffer, DitherMethod::Bayer8x8, quantizer, width, height, options);
+  };
+  if palette.len() > indexed::MAX_COLORS {
+    log::warn!(
+      "cannot mix {} colors, at most {} are supported, dithering with the 8x8 Bayer matrix",
+      palette.len(),
+      indexed::MAX_COLORS
+    );
+    return dither::dither_with(buffer, DitherMethod::Bayer8x8, quantizer, width, height, options);
+  }
+  match method {
+    DitherMethod::Yliluoma2 => mix(
+      buffer,
+      quantizer,
+      palette,
+      width,
+      options,
+      |color| candidate_list(color, palette),
+      |list, level| list[level],
+    ),
+    DitherMethod::Yliluoma3 => mix(
+      buffer,
+      quantizer,
+      palette,
+      width,
+      options,
+      |color| tritone_list(color, palette),
+      |list, level| list[level],
+    ),
+    _ => mix(
+      buffer,
+      quantizer,
+      palette,
+      width,
+      options,
+      |color| mixing_plan(color, palette),
+      |plan, level| plan.colors[usize::from(level < usize::from(plan.ratio))],
+    ),
+  }
+  Ok(())
+}
+
+/// Colors every pixel with the color `pick` chooses from its plan for the level of its threshold,
+/// from 0 to [`LEVELS`] - 1. The picks of the colors met are kept as indices into `palette`, at most
+/// [`MAX_PLANS`] at once: photos repeat few of their colors but smooth areas and drawings a lot.
+fn mix<Q: Quantizer + ?Sized, P>(
+  buffer: &mut [u8],
+  quantizer: &Q,
+  palette: &[Color],
+  width: u32,
+  options: &DitherOptions,
+  plan: impl Fn(Color) -> P,
+  pick: impl Fn(&P, usize) -> Color,
+) {
+  // the first entry of colors listed twice, the palette holds at most 256 colors
+  let indices: HashMap<Color, u8> = palette.iter().enumerate().rev().map(|(index, &color)| (color, index as u8)).collect();
+  let mut plans: HashMap<Color, [u8; LEVELS as usize]> = HashMap::new();
+  let mut planned = 0;
+  for (i, pixel) in buffer.chunks_exact_mut(3).enumerate() {
+    if options.alpha.is_some_and(|alpha| alpha[i] == 0) {
+      continue;
+    }
+    let (x, y) = (i as u32 % width + options.origin.0, i as u32 / width + options.origin.1);
+    let color = Color::from(&pixel[..]);
+    let level = (BAYER8X8[(y % 8 * 8 + x % 8) as usize] * f32::from(LEVELS)) as usize;
+    if !plans.contains_key(&color) && plans.len() == MAX_PLANS {
+      plans.clear();
+    }
+    let picks = plans.entry(color).or_insert_with(|| {
+      planned += 1;
+      let plan = plan(color);
+      std::array::from_fn(|level| indices[&pick(&plan, level)])
+    });
+    let new_color = quantizer.quantize(palette[usize::from(picks[level])]);
+    pixel.copy_from_slice(&[new_color.r, new_color.g, new_color.b]);
+  }
+  log::trace!("mixed {} pixels from {planned} plans", buffer.len() / 3);
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::palette::{ColorPalette, PALETTE_MONOCHROME, Palette};
+  use crate::target::Target;
+
+  /// The search of the original description, every ratio of every pair.
+  fn exhaustive(color: Color, palette: &[Color]) -> f32 {
+    let mut best = f32::INFINITY;
+    for (i, &first) in palette.iter().enumerate() {
+      best = best.min(color_compare(color, first));
+      for &second in &palette[i + 1..] {
+        for ratio in 0..LEVELS {
+          let t = f32::from(ratio) / f32::from(LEVELS);
+          let d = difference(color, first);
+          let e = difference(second, first);
+          let blend = [0, 1, 2].map(|c| d[c] - t * e[c]);
+          best = best.min(product(blend, blend) + CONTRAST_PENALTY * color_compare(first, second) * ((t - 0.5).abs() + 0.5));
+        }
+      }
+    }
+    best
+  }
+
+  #[test]
+  fn test_mixing_plan_matches_exhaustive_search() {
+    let palette = ColorPalette::COLOR16.colors();
+    for value in (0..=255u8).step_by(15) {
+      for color in [
+        Color { r: value, g: value, b: value },
+        Color {
+          r: value,
+          g: 255 - value,
+          b: 40,
+        },
+        Color { r: 30, g: value, b: 200 },
+      ] {
+        let plan = mixing_plan(color, palette);
+        let t = f32::from(plan.ratio) / f32::from(LEVELS);
+        let d = difference(color, plan.colors[0]);
+        let e = difference(plan.colors[1], plan.colors[0]);
+        let blend = [0, 1, 2].map(|c| d[c] - t * e[c]);
+        let error = product(blend, blend) + CONTRAST_PENALTY * color_compare(plan.colors[0], plan.colors[1]) * ((t - 0.5).abs() + 0.5);
+        assert!((error - exhaustive(color, palette)).abs() < 1e-5, "{color:?} planned as {plan:?}");
+      }
+    }
+  }
+
+  #[test]
+  fn test_mixing_plan_of_palette_color_is_that_color() {
+    for &color in ColorPalette::COLOR8.colors() {
+      assert_eq!(mixing_plan(color, ColorPalette::COLOR8.colors()).colors, [color; 2]);
+    }
+    let gray = mixing_plan(Color { r: 128, g: 128, b: 128 }, &PALETTE_MONOCHROME);
+    assert!(gray.ratio.abs_diff(LEVELS / 2) <= 1, "{gray:?}");
+  }
+
+  #[test]
+  fn test_dither_mixes_neighboring_colors() {
+    let grays = [0u8, 85, 170, 255].map(|value| Color { r: value, g: value, b: value });
+    let (width, height) = (16, 16);
+    for (palette, level, mixed) in [(&PALETTE_MONOCHROME[..], 128u8, [0, 255]), (&grays[..], 128, [85, 170])] {
+      let mut buffer = vec![level; width * height * 3];
+      dither(
+        &mut buffer,
+        DitherMethod::Yliluoma1,
+        palette,
+        width as u32,
+        height as u32,
+        &DitherOptions::default(),
+      )
+      .unwrap();
+
+      assert!(buffer.iter().all(|v| mixed.contains(v)), "{palette:?} mixes other colors");
+      let upper = buffer.chunks_exact(3).filter(|pixel| pixel[0] == mixed[1]).count();
+      assert!(upper.abs_diff(width * height / 2) <= width * height / 32, "{upper} of {mixed:?}");
+    }
+    // the penalty on contrast keeps a dark gray solid black rather than sprinkling it with white
+    let mut buffer = vec![32; 4 * 4 * 3];
+    dither(&mut buffer, DitherMethod::Yliluoma1, &PALETTE_MONOCHROME, 4, 4, &DitherOptions::default()).unwrap();
+    assert!(buffer.iter().all(|&v| v == 0));
+  }
+
+  /// Algorithm 2 as described, measuring every average directly.
+  fn direct_candidate_list(color: Color, palette: &[Color]) -> Vec<Color> {
+    let target = linear(color).map(f64::from);
+    let points: Vec<[f64; 3]> = palette.iter().map(|&color| linear(color).map(f64::from)).collect();
+    let (mut list, mut sum) = (Vec::new(), [0.0f64; 3]);
+    while list.len() < usize::from(LEVELS) {
+      let count = list.len();
+      let mut best = (f64::INFINITY, 0, 1);
+      for amount in std::iter::successors(Some(1), |&amount| Some(amount * 2)).take_while(|&amount| amount <= count.max(1)) {
+        for (index, point) in points.iter().enumerate() {
+          let d: [f32; 3] = std::array::from_fn(|c| (target[c] - (sum[c] + point[c] * amount as f64) / (count + amount) as f64) as f32);
+          let error = f64::from(product(d, d));
+          if error < best.0 - 1e-9 {
+            best = (error, index, amount);
+          }
+        }
+      }
+      let amount = best.2.min(usize::from(LEVELS) - count);
+      for (sum, value) in sum.iter_mut().zip(points[best.1]) {
+        *sum += value * amount as f64;
+      }
+      list.extend(std::iter::repeat_n(palette[best.1], amount));
+    }
+    list.sort_by_key(luma);
+    list
+  }
+
+  #[test]
+  fn test_candidate_list_matches_direct_averages() {
+    let palette = ColorPalette::COLOR16.colors();
+    for value in (0..=255u8).step_by(51) {
+      for color in [
+        Color { r: value, g: value, b: value },
+        Color {
+          r: value,
+          g: 200,
+          b: 255 - value,
+        },
+      ] {
+        let list = candidate_list(color, palette);
+        assert_eq!(list.len(), usize::from(LEVELS));
+        assert!(list.windows(2).all(|pair| luma(&pair[0]) <= luma(&pair[1])), "{color:?} not sorted");
+        assert_eq!(list, direct_candidate_list(color, palette), "{color:?}");
+      }
+    }
+  }
+
+  #[test]
+  fn test_improved_algorithms_mix_in_linear_light() {
+    // sRGB 128 is a fifth of the light of white, sRGB 188 half of it
+    for (level, expected) in [(128u8, 14), (188, 32)] {
+      let gray = Color { r: level, g: level, b: level };
+      for list in [candidate_list(gray, &PALETTE_MONOCHROME), tritone_list(gray, &PALETTE_MONOCHROME)] {
+        let white = list.iter().filter(|color| color.r == 255).count();
+        assert!(white.abs_diff(expected) <= 1, "{white} of {} white for {level}", list.len());
+      }
+    }
+    let mut buffer = vec![128; 8 * 8 * 3];
+    dither(&mut buffer, DitherMethod::Yliluoma2, &PALETTE_MONOCHROME, 8, 8, &DitherOptions::default()).unwrap();
+    assert_eq!(buffer.chunks_exact(3).filter(|pixel| pixel[0] == 255).count(), 14);
+  }
+
+  #[test]
+  fn test_tritone_list_mixes_three_colors() {
+    let [black, red, green, blue, white] = [0x000000, 0xff0000, 0x00ff00, 0x0000ff, 0xffffff].map(|rgb: u32| Color {
+      r: (rgb >> 16) as u8,
+      g: (rgb >> 8) as u8,
+      b: rgb as u8,
+    });
+    // a third of the light of white, the average of red, green and blue
+    let gray = Color { r: 156, g: 156, b: 156 };
+    let list = tritone_list(gray, &[black, red, green, blue, white]);
+    for primary in [red, green, blue] {
+      let count = list.iter().filter(|&&color| color == primary).count();
+      assert!((21..=22).contains(&count), "{count} of {primary:?}");
+    }
+    // a palette color stays solid
+    assert!(tritone_list(red, &[black, red, green, blue, white]).iter().all(|&color| color == red));
+  }
+
+  #[test]
+  fn test_dither_without_palette_falls_back_to_bayer() {
+    let gray: Vec<u8> = (0..16 * 16 * 3).map(|i| (i % 256) as u8).collect();
+    let (mut mixed, mut bayer) = (gray.clone(), gray);
+    dither(&mut mixed, DitherMethod::Yliluoma1, &Target::Rgb332, 16, 16, &DitherOptions::default()).unwrap();
+    dither::dither_with(&mut bayer, DitherMethod::Bayer8x8, &Target::Rgb332, 16, 16, &DitherOptions::default()).unwrap();
+    assert_eq!(mixed, bayer);
+  }
+
+  #[test]
+  fn test_dither_skips_transparent_pixels() {
+    let mut buffer = vec![100; 4 * 3];
+    let alpha = [255, 0, 0, 255];
+    let options = DitherOptions {
+      alpha: Some(&alpha),
+      ..DitherOptions::default()
+    };
+    dither(&mut buffer, DitherMethod::Yliluoma1, &PALETTE_MONOCHROME, 2, 2, &options).unwrap();
+    assert_eq!(&buffer[3..9], &[100; 6]);
+  }
+
+  #[test]
+  fn test_dither_keeps_mixing_past_the_plan_limit() {
+    // every pixel has its own color, more than the cache holds
+    let (width, height) = (512, MAX_PLANS as u32 / 512 + 2);
+    let buffer: Vec<u8> = (0..width * height).flat_map(|i| [i as u8, (i >> 8) as u8, (i >> 16) as u8 * 64]).collect();
+    let mut mixed = buffer.clone();
+    dither(
+      &mut mixed,
+      DitherMethod::Yliluoma1,
+      &PALETTE_MONOCHROME,
+      width,
+      height,
+      &DitherOptions::default(),
+    )
+    .unwrap();
+    for (i, (pixel, original)) in mixed.chunks_exact(3).zip(buffer.chunks_exact(3)).enumerate().step_by(997) {
+      let plan = mixing_plan(Color::from(original), &PALETTE_MONOCHROME);
+      let (x, y) = (i as u32 % width, i as u32 / width);
+      let level = (BAYER8X8[(y % 8 * 8 + x % 8) as usize] * f32::from(LEVELS)) as usize;
+      assert_eq!(Color::from(pixel), plan.colors[usize::from(level < usize::from(plan.ratio))]);
+    }
+  }
+
+  #[test]
+  fn test_dither_with_too_many_colors_falls_back_to_bayer() {
+    let palette = Palette::new((0..=indexed::MAX_COLORS as u32).map(|i| Color::from(i * 0x0101)).collect()).unwrap();
+    let gray: Vec<u8> = (0..16 * 16 * 3).map(|i| (i % 256) as u8).collect();
+    let (mut mixed, mut bayer) = (gray.clone(), gray);
+    dither(&mut mixed, DitherMethod::Yliluoma1, &palette, 16, 16, &DitherOptions::default()).unwrap();
+    dither::dither_with(&mut bayer, DitherMethod::Bayer8x8, &palette, 16, 16, &DitherOptions::default()).unwrap();
+    assert_eq!(mixed, bayer);
+  }
+}
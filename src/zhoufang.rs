@@ -0,0 +1,138 @@
+//! Zhou-Fang error diffusion: variable coefficients with threshold modulation.
+//!
+//! A single kernel cannot suit all tones, Floyd-Steinberg leaves regular patterns in the midtones and
+//! worms in the highlights and shadows. Zhou and Fang (2003) diffuse the error to three neighbors with
+//! weights that depend on the tone of the pixel, interpolated between a few key levels, and add noise
+//! of a tone-dependent strength to the threshold, which breaks up the remaining midtone patterns. The
+//! rows are scanned in serpentine order, the noise is seeded with [`DitherOptions::seed`].
+
+use crate::dither::{DitherOptions, QuantizationError};
+use crate::noise::Rng;
+use crate::palette::{Color, Quantizer};
+
+/// Key levels of the darker half of the tones, with the weights of the error diffused to the right,
+/// down left and down, and the strength of the threshold modulation.
+///
+/// The lighter half mirrors the darker one, the tones in between are interpolated.
+pub const KEY_LEVELS: [(u8, [f32; 3], f32); 9] = [
+  (0, [13.0, 0.0, 5.0], 0.0),
+  (44, [6.0, 13.0, 0.0], 0.34),
+  (64, [36.0, 33.0, 17.0], 0.5),
+  (85, [7.0, 3.0, 5.0], 1.0),
+  (95, [4.0, 2.0, 3.0], 0.17),
+  (102, [5.0, 3.0, 4.0], 0.5),
+  (107, [7.0, 4.0, 5.0], 0.7),
+  (112, [8.0, 3.0, 5.0], 0.79),
+  (127, [7.0, 3.0, 5.0], 1.0),
+];
+
+/// Returns the normalized weights (right, down left, down) and the modulation strength of a tone.
+#[must_use]
+pub fn coefficients(value: u8) -> ([f32; 3], f32) {
+  let level = value.min(255 - value);
+  let upper = KEY_LEVELS.iter().position(|&(key, ..)| key >= level).unwrap_or(KEY_LEVELS.len() - 1);
+  let (high, high_weights, high_strength) = KEY_LEVELS[upper];
+  let (low, low_weights, low_strength) = KEY_LEVELS[upper.saturating_sub(1)];
+  let t = if high > low { f32::from(level - low) / f32::from(high - low) } else { 0.0 };
+  let weights: [f32; 3] = std::array::from_fn(|i| low_weights[i] + (high_weights[i] - low_weights[i]) * t);
+  let sum: f32 = weights.iter().sum();
+  (weights.map(|weight| weight / sum), low_strength + (high_strength - low_strength) * t)
+}
+
+/// Dithers an RGB buffer with Zhou-Fang error diffusion, see the [module documentation](self).
+///
+/// Every channel diffuses its error with the weights of its own tone. Transparent pixels neither
+/// receive nor spread error, the scan direction does not apply.
+pub fn dither<Q: Quantizer + ?Sized>(buffer: &mut [u8], quantizer: &Q, width: u32, height: u32, options: &DitherOptions) {
+  let (width, height) = (width as usize, height as usize);
+  let transparent = |pixel: usize| options.alpha.is_some_and(|alpha| alpha[pixel] == 0);
+  let mut rng = Rng::new(options.seed);
+  // the errors of the current and the next row, kept apart from the buffer to avoid rounding them
+  let (mut current, mut next) = (vec![[0.0f32; 3]; width], vec![[0.0f32; 3]; width]);
+  for y in 0..height {
+    let reverse = y % 2 == 1;
+    for step in 0..width {
+      let x = if reverse { width - 1 - step } else { step };
+      let pixel = y * width + x;
+      // drawn for every pixel, so the noise does not depend on the transparency
+      let noise = rng.next_f32() - 0.5;
+      if transparent(pixel) {
+        continue;
+      }
+      let i = pixel * 3;
+      let corrected: [f32; 3] = std::array::from_fn(|c| (f32::from(buffer[i + c]) + current[x][c]).clamp(0.0, 255.0));
+      let color = Color::from(&corrected.map(|value| value.round() as u8)[..]);
+      // the noise shifts the threshold, while the error is measured from the unmodulated color
+      let coefficients = corrected.map(|value| coefficients(value.round() as u8));
+      let modulated: [u8; 3] = std::array::from_fn(|c| (corrected[c] + noise * 128.0 * coefficients[c].1).round().clamp(0.0, 255.0) as u8);
+      let new_color = quantizer.quantize(Color::from(&modulated[..]));
+      let error = QuantizationError::between(color, new_color);
+      buffer[i..i + 3].copy_from_slice(&[new_color.r, new_color.g, new_color.b]);
+
+      let ahead = if reverse { x.checked_sub(1) } else { Some(x + 1).filter(|&x| x < width) };
+      let behind = if reverse { Some(x + 1).filter(|&x| x < width) } else { x.checked_sub(1) };
+      let last_row = y + 1 == height;
+      for (c, value) in [error.r, error.g, error.b].into_iter().enumerate() {
+        let [right, down_left, down] = coefficients[c].0;
+        if let Some(ahead) = ahead.filter(|&ahead| !transparent(y * width + ahead)) {
+          current[ahead][c] += value * right;
+        }
+        if last_row {
+          continue;
+        }
+        if let Some(behind) = behind.filter(|&behind| !transparent((y + 1) * width + behind)) {
+          next[behind][c] += value * down_left;
+        }
+        if !transparent((y + 1) * width + x) {
+          next[x][c] += value * down;
+        }
+      }
+    }
+    std::mem::swap(&mut current, &mut next);
+    next.fill([0.0; 3]);
+    log::trace!("diffused row {}/{}", y + 1, height);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::palette::PALETTE_MONOCHROME;
+
+  #[test]
+  fn test_coefficients_are_normalized_and_symmetric() {
+    for value in 0..=255u8 {
+      let (weights, strength) = coefficients(value);
+      assert!((weights.iter().sum::<f32>() - 1.0).abs() < 1e-5, "weights of {value} sum to {weights:?}");
+      assert!((0.0..=1.0).contains(&strength));
+      assert_eq!(coefficients(255 - value), (weights, strength));
+    }
+    assert_eq!(coefficients(0), ([13.0 / 18.0, 0.0, 5.0 / 18.0], 0.0));
+    assert_eq!(coefficients(44).1, 0.34);
+  }
+
+  #[test]
+  fn test_dither_preserves_gray_level() {
+    let (width, height) = (64, 64);
+    for level in [32u8, 128, 200] {
+      let mut buffer = vec![level; width * height * 3];
+      dither(&mut buffer, &PALETTE_MONOCHROME, width as u32, height as u32, &DitherOptions::default());
+
+      assert!(buffer.iter().all(|&v| v == 0 || v == 255));
+      let white = buffer.chunks_exact(3).filter(|pixel| pixel[0] == 255).count() as f32 / (width * height) as f32;
+      assert!((white - f32::from(level) / 255.0).abs() < 0.02, "{white} white for level {level}");
+    }
+  }
+
+  #[test]
+  fn test_dither_skips_transparent_pixels() {
+    let mut buffer = vec![100; 4 * 3];
+    let alpha = [255, 0, 0, 255];
+    let options = DitherOptions {
+      alpha: Some(&alpha),
+      ..DitherOptions::default()
+    };
+    dither(&mut buffer, &PALETTE_MONOCHROME, 2, 2, &options);
+    assert_eq!(&buffer[3..9], &[100; 6]);
+  }
+}
@@ -1,5 +1,11 @@
-use dithers::dither::{DitherMethod, dither, open_image, save_image};
+use dithers::distance::DistanceMetric;
+use dithers::dither::{DitherMethod, dither, open_image, save_image, try_open_image, try_save_image};
+use dithers::error::DitherError;
+use dithers::jobs::{Job, load_jobs};
 use dithers::palette::ColorPalette;
+use dithers::patterns::{Pattern, Size, generate};
+use dithers::pipeline::Settings;
+use dithers::verify::{self, Outcome};
 use std::fs;
 use std::path::PathBuf;
 
@@ -30,17 +36,38 @@ fn test_floyd_steinberg_all_palettes() {
 
   // Test with monochrome
   let mut test_buffer = original_buffer.clone();
-  dither(&mut test_buffer, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, width, height);
+  dither(
+    &mut test_buffer,
+    DitherMethod::FloydSteinberg,
+    ColorPalette::Monochrome,
+    DistanceMetric::Rgb,
+    width,
+    height,
+  );
   assert_ne!(test_buffer, original_buffer, "Buffer should be modified by dithering");
 
   // Test with 8-color
   let mut test_buffer = original_buffer.clone();
-  dither(&mut test_buffer, DitherMethod::FloydSteinberg, ColorPalette::COLOR8, width, height);
+  dither(
+    &mut test_buffer,
+    DitherMethod::FloydSteinberg,
+    ColorPalette::COLOR8,
+    DistanceMetric::Rgb,
+    width,
+    height,
+  );
   assert_ne!(test_buffer, original_buffer, "Buffer should be modified by dithering");
 
   // Test with 16-color
   let mut test_buffer = original_buffer.clone();
-  dither(&mut test_buffer, DitherMethod::FloydSteinberg, ColorPalette::COLOR16, width, height);
+  dither(
+    &mut test_buffer,
+    DitherMethod::FloydSteinberg,
+    ColorPalette::COLOR16,
+    DistanceMetric::Rgb,
+    width,
+    height,
+  );
   assert_ne!(test_buffer, original_buffer, "Buffer should be modified by dithering");
 }
 
@@ -63,7 +90,7 @@ fn test_error_diffusion_algorithms() {
 
   for algorithm in algorithms {
     let mut test_buffer = original_buffer.clone();
-    dither(&mut test_buffer, algorithm, ColorPalette::COLOR8, width, height);
+    dither(&mut test_buffer, algorithm, ColorPalette::COLOR8, DistanceMetric::Rgb, width, height);
     assert_ne!(test_buffer, original_buffer, "Algorithm {:?} should modify the buffer", algorithm);
   }
 }
@@ -77,7 +104,7 @@ fn test_bayer_algorithms() {
 
   for algorithm in algorithms {
     let mut test_buffer = original_buffer.clone();
-    dither(&mut test_buffer, algorithm, ColorPalette::COLOR8, width, height);
+    dither(&mut test_buffer, algorithm, ColorPalette::COLOR8, DistanceMetric::Rgb, width, height);
     assert_ne!(test_buffer, original_buffer, "Bayer algorithm {:?} should modify the buffer", algorithm);
   }
 }
@@ -88,7 +115,7 @@ fn test_no_dithering() {
   let original_buffer = buffer.clone();
 
   let mut test_buffer = original_buffer.clone();
-  dither(&mut test_buffer, DitherMethod::None, ColorPalette::COLOR8, width, height);
+  dither(&mut test_buffer, DitherMethod::None, ColorPalette::COLOR8, DistanceMetric::Rgb, width, height);
 
   // Should still modify buffer due to palette quantization
   assert_ne!(test_buffer, original_buffer, "Even 'None' dithering should quantize colors");
@@ -99,7 +126,14 @@ fn test_monochrome_palette_output() {
   let (buffer, width, height) = open_image(&PathBuf::from(TEST_IMAGE));
   let mut test_buffer = buffer;
 
-  dither(&mut test_buffer, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, width, height);
+  dither(
+    &mut test_buffer,
+    DitherMethod::FloydSteinberg,
+    ColorPalette::Monochrome,
+    DistanceMetric::Rgb,
+    width,
+    height,
+  );
 
   // Check that all pixels are either black (0,0,0) or white (255,255,255)
   for chunk in test_buffer.chunks_exact(3) {
@@ -114,11 +148,37 @@ fn test_monochrome_palette_output() {
   }
 }
 
+#[test]
+fn test_try_open_and_save_report_errors() {
+  let missing = try_open_image(&PathBuf::from("test/in/missing.png"));
+  assert!(matches!(missing, Err(DitherError::Io { .. })), "missing files are I/O errors: {missing:?}");
+
+  let not_an_image = std::env::temp_dir().join(format!("dithers_not_an_image_{}.png", std::process::id()));
+  fs::write(&not_an_image, b"not a png").unwrap();
+  assert!(matches!(try_open_image(&not_an_image), Err(DitherError::Image { .. })));
+  fs::remove_file(&not_an_image).unwrap();
+
+  let (buffer, width, height) = try_open_image(&PathBuf::from(TEST_IMAGE)).expect("the test image opens");
+  assert!(try_save_image(&buffer, &PathBuf::from("no/such/dir/out.png"), width, height).is_err());
+  assert!(matches!(
+    try_save_image(&buffer[3..], &PathBuf::from("short.png"), width, height),
+    Err(DitherError::InvalidBuffer { .. })
+  ));
+  assert!(!PathBuf::from("short.png").exists());
+}
+
 #[test]
 fn test_save_and_cleanup() {
   let (mut buffer, width, height) = open_image(&PathBuf::from(TEST_IMAGE));
 
-  dither(&mut buffer, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, width, height);
+  dither(
+    &mut buffer,
+    DitherMethod::FloydSteinberg,
+    ColorPalette::Monochrome,
+    DistanceMetric::Rgb,
+    width,
+    height,
+  );
 
   let output_path = PathBuf::from("test_output_integration.png");
   save_image(buffer, output_path.clone(), width, height);
@@ -144,9 +204,26 @@ fn test_all_algorithms_with_all_palettes() {
     DitherMethod::Sierra,
     DitherMethod::TwoRowSierra,
     DitherMethod::SierraLite,
+    DitherMethod::StevensonArce,
+    DitherMethod::ShiauFan,
+    DitherMethod::ShiauFan2,
+    DitherMethod::Fan,
+    DitherMethod::Riemersma,
+    DitherMethod::ZhouFang,
+    DitherMethod::DotDiffusion,
+    DitherMethod::Bayer,
     DitherMethod::Bayer2x2,
     DitherMethod::Bayer4x4,
     DitherMethod::Bayer8x8,
+    DitherMethod::Clustered4x4,
+    DitherMethod::Clustered8x8,
+    DitherMethod::Halftone,
+    DitherMethod::Random,
+    DitherMethod::InterleavedGradient,
+    DitherMethod::Yliluoma1,
+    DitherMethod::Yliluoma2,
+    DitherMethod::Yliluoma3,
+    DitherMethod::Knoll,
   ];
 
   let palettes = [ColorPalette::Monochrome, ColorPalette::COLOR8, ColorPalette::COLOR16];
@@ -156,7 +233,7 @@ fn test_all_algorithms_with_all_palettes() {
       let mut test_buffer = buffer.clone();
 
       // This should not panic
-      dither(&mut test_buffer, algorithm, palette, width, height);
+      dither(&mut test_buffer, algorithm, palette, DistanceMetric::Rgb, width, height);
 
       // Buffer should be valid RGB data
       assert_eq!(
@@ -182,7 +259,14 @@ fn test_buffer_bounds() {
 
   // Test with edge case: 1x1 image would be too small, so test with actual image
   // but verify no out-of-bounds access occurs
-  dither(&mut test_buffer, DitherMethod::FloydSteinberg, ColorPalette::Monochrome, width, height);
+  dither(
+    &mut test_buffer,
+    DitherMethod::FloydSteinberg,
+    ColorPalette::Monochrome,
+    DistanceMetric::Rgb,
+    width,
+    height,
+  );
 
   // If we get here without panicking, bounds checking worked
   assert_eq!(test_buffer.len(), (width * height * 3) as usize);
@@ -196,12 +280,250 @@ fn test_different_algorithms_produce_different_results() {
   let mut atkinson_buffer = buffer.clone();
   let mut bayer_buffer = buffer;
 
-  dither(&mut floyd_buffer, DitherMethod::FloydSteinberg, ColorPalette::COLOR8, width, height);
-  dither(&mut atkinson_buffer, DitherMethod::Atkinson, ColorPalette::COLOR8, width, height);
-  dither(&mut bayer_buffer, DitherMethod::Bayer4x4, ColorPalette::COLOR8, width, height);
+  dither(
+    &mut floyd_buffer,
+    DitherMethod::FloydSteinberg,
+    ColorPalette::COLOR8,
+    DistanceMetric::Rgb,
+    width,
+    height,
+  );
+  dither(
+    &mut atkinson_buffer,
+    DitherMethod::Atkinson,
+    ColorPalette::COLOR8,
+    DistanceMetric::Rgb,
+    width,
+    height,
+  );
+  dither(
+    &mut bayer_buffer,
+    DitherMethod::Bayer4x4,
+    ColorPalette::COLOR8,
+    DistanceMetric::Rgb,
+    width,
+    height,
+  );
 
   // Different algorithms should produce different results
   assert_ne!(floyd_buffer, atkinson_buffer, "Floyd-Steinberg and Atkinson should produce different results");
   assert_ne!(floyd_buffer, bayer_buffer, "Floyd-Steinberg and Bayer should produce different results");
   assert_ne!(atkinson_buffer, bayer_buffer, "Atkinson and Bayer should produce different results");
 }
+
+#[test]
+fn test_jobs_file_runs_each_job() {
+  let dir = std::env::temp_dir().join("dithers_jobs_file_test");
+  fs::create_dir_all(&dir).unwrap();
+  let input = std::env::current_dir().unwrap().join(TEST_IMAGE);
+  let manifest = dir.join("jobs.csv");
+  fs::write(
+    &manifest,
+    format!(
+      "input,output,method,palette\n{0},mono.png,atkinson,monochrome\n{0},color.png,bayer4x4,color8\n",
+      input.display()
+    ),
+  )
+  .unwrap();
+
+  let jobs = load_jobs(&manifest).expect("Jobs file should parse");
+  assert_eq!(jobs.len(), 2);
+  for job in &jobs {
+    let output = job.run().expect("Job should succeed");
+    assert_eq!(output.parent(), Some(dir.as_path()), "Relative outputs should resolve next to the jobs file");
+    assert!(output.exists(), "Output {:?} should be created", output);
+  }
+
+  // Cleanup
+  fs::remove_dir_all(dir).expect("Should be able to clean up test directory");
+}
+
+#[test]
+fn test_transparent_sprite_keeps_clean_border() {
+  let dir = std::env::temp_dir().join("dithers_transparent_test");
+  fs::create_dir_all(&dir).unwrap();
+  let input = dir.join("sprite.png");
+
+  // 8x8 mid-gray sprite surrounded by a fully transparent (and red) 1 pixel border
+  let sprite = image::RgbaImage::from_fn(8, 8, |x, y| {
+    let border = x == 0 || y == 0 || x == 7 || y == 7;
+    if border {
+      image::Rgba([255, 0, 0, 0])
+    } else {
+      image::Rgba([100, 100, 100, 255])
+    }
+  });
+  sprite.save(&input).unwrap();
+
+  let mut job = Job::new(&input);
+  job.output = Some(dir.join("sprite_out.png"));
+  job.settings.skip_transparent = true;
+  let output = job.run().expect("Job should succeed");
+
+  let result = image::open(&output).unwrap().into_rgba8();
+  for (x, y, pixel) in result.enumerate_pixels() {
+    let border = x == 0 || y == 0 || x == 7 || y == 7;
+    if border {
+      assert_eq!(pixel.0, [255, 0, 0, 0], "Transparent border pixel ({},{}) should be untouched", x, y);
+    } else {
+      assert_eq!(pixel.0[3], 255, "Opaque pixel ({},{}) should stay opaque", x, y);
+      assert!(pixel.0[0] == 0 || pixel.0[0] == 255, "Opaque pixel ({},{}) should be dithered", x, y);
+    }
+  }
+
+  // Cleanup
+  fs::remove_dir_all(dir).expect("Should be able to clean up test directory");
+}
+
+#[test]
+fn test_generated_gradient_dithers_to_both_levels() {
+  let size = Size { width: 64, height: 16 };
+  for method in [DitherMethod::FloydSteinberg, DitherMethod::Bayer4x4] {
+    let mut image = generate(Pattern::Gradient, size);
+    dither(
+      &mut image.buffer,
+      method,
+      ColorPalette::Monochrome,
+      DistanceMetric::Rgb,
+      size.width,
+      size.height,
+    );
+
+    // the share of white pixels in each column follows the gradient
+    let white_in_column = |x: u32| (0..size.height).filter(|y| image.buffer[((y * size.width + x) * 3) as usize] == 255).count();
+    assert_eq!(white_in_column(0), 0, "{:?}: left edge should be black", method);
+    assert_eq!(
+      white_in_column(size.width - 1),
+      size.height as usize,
+      "{:?}: right edge should be white",
+      method
+    );
+    let middle = white_in_column(size.width / 2);
+    assert!(
+      middle > 2 && middle < 14,
+      "{:?}: middle column should mix both levels, got {} white",
+      method,
+      middle
+    );
+  }
+}
+
+#[test]
+fn test_palette_file_limits_output_colors() {
+  let dir = std::env::temp_dir().join("dithers_palette_file_test");
+  fs::create_dir_all(&dir).unwrap();
+  let palette = dir.join("warm.hex");
+  fs::write(&palette, "; black, orange and cream\n#000000\n#ff8000\n#fff0d0\n").unwrap();
+  let input = dir.join("gradient.png");
+  save_image(generate(Pattern::Gradient, Size { width: 32, height: 8 }).buffer, input.clone(), 32, 8);
+
+  let mut job = Job::new(&input);
+  job.settings.palette_file = Some(palette.clone());
+  let (buffer, _, _) = open_image(&job.run().expect("Job should succeed"));
+  let allowed = [[0x00, 0x00, 0x00], [0xff, 0x80, 0x00], [0xff, 0xf0, 0xd0]];
+  assert!(buffer.chunks_exact(3).all(|pixel| allowed.iter().any(|color| color == pixel)));
+  assert!(
+    allowed.iter().all(|color| buffer.chunks_exact(3).any(|pixel| pixel == color)),
+    "Every color should be used"
+  );
+
+  fs::write(&palette, "#000000\nnot a color\n").unwrap();
+  let err = job.run().expect_err("Invalid palette files should fail the job");
+  assert!(err.to_string().contains("line 2"), "{err}");
+
+  // Cleanup
+  fs::remove_dir_all(dir).expect("Should be able to clean up test directory");
+}
+
+#[test]
+fn test_palette_from_reference_image() {
+  let dir = std::env::temp_dir().join("dithers_palette_from_test");
+  fs::create_dir_all(&dir).unwrap();
+  let allowed = [[0x10, 0x20, 0x60], [0xc0, 0x40, 0x20], [0xf0, 0xe0, 0xa0]];
+  let reference = dir.join("artwork.png");
+  let stripes: Vec<u8> = (0..12 * 4).flat_map(|i| allowed[i % 12 / 4]).collect();
+  save_image(stripes, reference.clone(), 12, 4);
+  let input = dir.join("gradient.png");
+  save_image(generate(Pattern::Gradient, Size { width: 32, height: 8 }).buffer, input.clone(), 32, 8);
+
+  let mut job = Job::new(&input);
+  job.settings.palette_from = Some(reference);
+  let (buffer, _, _) = open_image(&job.run().expect("Job should succeed"));
+  assert!(buffer.chunks_exact(3).all(|pixel| allowed.iter().any(|color| color == pixel)));
+
+  job.settings.palette_from = Some(dir.join("missing.png"));
+  assert!(job.run().is_err(), "A missing reference image should fail the job");
+
+  // Cleanup
+  fs::remove_dir_all(dir).expect("Should be able to clean up test directory");
+}
+
+#[test]
+fn test_verify_against_blessed_references() {
+  let dir = std::env::temp_dir().join("dithers_verify_test");
+  let references = dir.join("refs");
+  fs::create_dir_all(&references).unwrap();
+  let input = dir.join("ramp.png");
+  let ramp = generate(Pattern::Ramp, Size { width: 32, height: 16 });
+  image::save_buffer(&input, &ramp.buffer, ramp.width, ramp.height, image::ExtendedColorType::Rgb8).unwrap();
+
+  let mut job = Job::new(&input);
+  job.method = DitherMethod::Atkinson;
+  job.palette = ColorPalette::COLOR8;
+
+  let (_, outcome) = verify::verify(&job, &references, 0.0).unwrap();
+  assert_eq!(outcome, Outcome::Missing);
+
+  let reference = verify::bless(&job, &references).expect("Blessing should write the reference");
+  assert_eq!(reference, references.join("ramp_out.png"));
+  let (_, outcome) = verify::verify(&job, &references, 0.0).unwrap();
+  assert_eq!(outcome, Outcome::Identical);
+
+  // a different method changes the output
+  job.method = DitherMethod::Bayer4x4;
+  let (_, outcome) = verify::verify(&job, &references, 0.0).unwrap();
+  assert!(matches!(outcome, Outcome::Different(_)), "Changed output should fail, got {:?}", outcome);
+  let (_, outcome) = verify::verify(&job, &references, 255.0).unwrap();
+  assert!(
+    matches!(outcome, Outcome::WithinTolerance(_)),
+    "Generous tolerance should pass, got {:?}",
+    outcome
+  );
+
+  // Cleanup
+  fs::remove_dir_all(dir).expect("Should be able to clean up test directory");
+}
+
+#[test]
+fn test_multi_page_tiff_dithers_every_page() {
+  let dir = std::env::temp_dir().join("dithers_multi_page_test");
+  fs::create_dir_all(&dir).unwrap();
+  let input = dir.join("scan.tif");
+  let size = Size { width: 16, height: 8 };
+  let pages = [
+    generate(Pattern::Gradient, size),
+    generate(Pattern::Zoneplate, size),
+    generate(Pattern::Ramp, size),
+  ];
+  dithers::output::save_pages(&pages, &input, &Settings::default()).unwrap();
+
+  let mut job = Job::new(&input);
+  job.output = Some(dir.join("scan_mono.tif"));
+  let output = job.run().expect("Job should succeed");
+
+  let dithered = dithers::input::load_pages(&output, &dithers::input::LoadOptions::default()).expect("Output should be a readable TIFF");
+  assert_eq!(dithered.len(), 3, "Every page should be written");
+  for (page, original) in dithered.iter().zip(&pages) {
+    assert_eq!((page.width, page.height), (original.width, original.height));
+    assert!(page.buffer.iter().all(|&v| v == 0 || v == 255), "Every page should be dithered to monochrome");
+  }
+
+  // a non-TIFF output only receives the first page
+  job.output = Some(dir.join("scan_first.png"));
+  let output = job.run().expect("Job should succeed");
+  let (buffer, _, _) = open_image(&output);
+  assert!(buffer.iter().all(|&v| v == 0 || v == 255));
+
+  // Cleanup
+  fs::remove_dir_all(dir).expect("Should be able to clean up test directory");
+}